@@ -0,0 +1,229 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use bytes::{Buf, BufMut, BigEndian};
+use time::Timespec;
+use message::{self, Payload};
+use error;
+
+static SET: u8 = 0;
+static DEL: u8 = 1;
+static MARK: u8 = 2;
+
+/// The state of a key after a write, as recorded in an `AppendLog`. Recording the outcome rather
+/// than the request means replaying the log doesn't depend on versions, functions or the clock.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    /// The key holds the payload, until the expiry if there is one.
+    Set(Vec<u8>, Payload, Option<Timespec>),
+    /// The key holds nothing.
+    Del(Vec<u8>),
+    /// The snapshot with this mark was taken here, see `cache::Options::snapshot`: the records
+    /// after it, replayed over the snapshot, rebuild the store.
+    Mark(u64),
+}
+
+impl Record {
+    /// Appends the record to `buf`: a kind byte, 0 for a Set, 1 for a Del and 2 for a Mark. A Set
+    /// follows with the key and payload, see `message::put_entry`, and when it expires in seconds
+    /// since the Unix epoch as a u64, 0 if never, a Del with the key length as a u32 and the key,
+    /// and a Mark with the mark as a u64. All integers are big endian.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Record::Set(ref key, ref payload, expires_at) => {
+                buf.put_u8(SET);
+                message::put_entry(buf, key, payload);
+                buf.put_u64::<BigEndian>(expires_at.map_or(0, |at| at.sec as u64));
+            }
+            Record::Del(ref key) => {
+                buf.put_u8(DEL);
+                buf.put_u32::<BigEndian>(key.len() as u32);
+                buf.put_slice(key);
+            }
+            Record::Mark(mark) => {
+                buf.put_u8(MARK);
+                buf.put_u64::<BigEndian>(mark);
+            }
+        }
+    }
+
+    /// Decodes the record at the cursor, or returns `None` if `data` ends part way through it.
+    pub fn decode(cursor: &mut io::Cursor<&[u8]>) -> io::Result<Option<Record>> {
+        if cursor.remaining() < 1 + 4 {
+            return Ok(None);
+        }
+        let kind = cursor.get_u8();
+        if kind == DEL {
+            let key_len = cursor.get_u32::<BigEndian>() as usize;
+            if cursor.remaining() < key_len {
+                return Ok(None);
+            }
+            let mut key = vec![0; key_len];
+            cursor.copy_to_slice(&mut key);
+            return Ok(Some(Record::Del(key)));
+        } else if kind == MARK {
+            if cursor.remaining() < 8 {
+                return Ok(None);
+            }
+            return Ok(Some(Record::Mark(cursor.get_u64::<BigEndian>())));
+        } else if kind != SET {
+            return Err(
+                error::Error::new(error::ErrorKind::InvalidData, "unknown log record").into(),
+            );
+        }
+        let (key, payload) = match message::get_entry(cursor) {
+            Some(entry) if cursor.remaining() >= 8 => entry,
+            _ => return Ok(None),
+        };
+        let expires_at = match cursor.get_u64::<BigEndian>() {
+            0 => None,
+            at => Some(Timespec::new(at as i64, 0)),
+        };
+        Ok(Some(Record::Set(key, payload, expires_at)))
+    }
+}
+
+/// A file of `Record`s, appended to as the cache is written to, and replayed to rebuild it.
+pub struct AppendLog {
+    path: PathBuf,
+    file: File,
+    sync: bool,
+    len: u64,
+}
+
+impl AppendLog {
+    /// Opens the log at `path`, creating it if need be, and returns it along with the records it
+    /// holds. A record cut short at the end of the file, as a crash part way through appending
+    /// leaves, is dropped. With `sync`, every append is synced to disk before it returns.
+    pub fn open<P: AsRef<Path>>(path: P, sync: bool) -> io::Result<(Self, Vec<Record>)> {
+        let path = path.as_ref().to_owned();
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        let mut records = vec![];
+        let len = {
+            let mut cursor = io::Cursor::new(&data[..]);
+            let mut len = 0;
+            while let Some(record) = Record::decode(&mut cursor)? {
+                records.push(record);
+                len = cursor.position();
+            }
+            len
+        };
+        if len < data.len() as u64 {
+            file.set_len(len)?;
+        }
+
+        let log = AppendLog {
+            path: path,
+            file: file,
+            sync: sync,
+            len: len,
+        };
+        Ok((log, records))
+    }
+
+    /// The length of the log in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Appends `records` to the log with a single write.
+    pub fn append(&mut self, records: &[Record]) -> io::Result<()> {
+        let mut buf = vec![];
+        for record in records {
+            record.encode(&mut buf);
+        }
+        self.file.write_all(&buf)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Replaces the contents of the log with `records`. They're written to a temporary file
+    /// next to the log and renamed over it once complete, so a crash part way through leaves
+    /// the old log in place.
+    pub fn rewrite<I: IntoIterator<Item = Record>>(&mut self, records: I) -> io::Result<()> {
+        let mut buf = vec![];
+        for record in records {
+            record.encode(&mut buf);
+        }
+
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.len = buf.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rcache-{}-{}.aof", name, process::id()))
+    }
+
+    #[test]
+    fn test_append_and_replay() {
+        let path = temp_path("replay");
+        let set = Record::Set(b"foo".to_vec(), message::payload(1, b"bar".to_vec()), None);
+        let expiring = Record::Set(vec![], message::payload(7, vec![]), Some(Timespec::new(10, 0)));
+        let del = Record::Del(b"foo".to_vec());
+
+        let (mut log, records) = AppendLog::open(&path, false).unwrap();
+        assert!(records.is_empty());
+        log.append(&[set.clone(), expiring.clone()]).unwrap();
+        log.append(&[del.clone(), Record::Mark(42)]).unwrap();
+
+        let (log, records) = AppendLog::open(&path, false).unwrap();
+        assert_eq!(records, vec![set, expiring, del, Record::Mark(42)]);
+        assert_eq!(log.len(), fs::metadata(&path).unwrap().len());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let path = temp_path("torn");
+        let set = Record::Set(b"foo".to_vec(), message::payload(1, b"bar".to_vec()), None);
+        {
+            let (mut log, _) = AppendLog::open(&path, false).unwrap();
+            log.append(&[set.clone(), set.clone()]).unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 1).unwrap();
+
+        // The torn record is dropped, and appends carry on after the last whole one.
+        let (mut log, records) = AppendLog::open(&path, false).unwrap();
+        assert_eq!(records, vec![set.clone()]);
+        log.append(&[Record::Del(b"foo".to_vec())]).unwrap();
+        let (_, records) = AppendLog::open(&path, false).unwrap();
+        assert_eq!(records, vec![set, Record::Del(b"foo".to_vec())]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let path = temp_path("rewrite");
+        let set = Record::Set(b"foo".to_vec(), message::payload(1, b"bar".to_vec()), None);
+        let (mut log, _) = AppendLog::open(&path, false).unwrap();
+        log.append(&[set.clone(), Record::Del(b"foo".to_vec()), set.clone()]).unwrap();
+
+        log.rewrite(vec![set.clone()]).unwrap();
+        log.append(&[Record::Del(b"baz".to_vec())]).unwrap();
+        let (_, records) = AppendLog::open(&path, false).unwrap();
+        assert_eq!(records, vec![set, Record::Del(b"baz".to_vec())]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+extern crate rcache;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate futures;
+extern crate rand;
+extern crate clap;
+
+use rcache::cache::Cache;
+use rcache::client::Client;
+use rcache::message::{self, Code, Message, Op};
+use rcache::service::{self, CacheService, ServeOptions};
+use futures::{stream, Future, Stream};
+use tokio_core::reactor::Core;
+use tokio_service::Service;
+use rand::Rng;
+use clap::{Arg, App, ArgMatches};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A workload, as given on the command line.
+#[derive(Clone)]
+struct Workload {
+    clients: usize,
+    requests: usize,
+    pipeline: usize,
+    keys: usize,
+    value_size: usize,
+    get_ratio: f64,
+}
+
+/// What one client measured: the latency of each request it made, and how many failed.
+struct Measured {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+fn main() {
+    let matches = App::new("rcache-bench")
+        .about(
+            "Drives an rcache server with concurrent clients making a mix of gets and sets, and \
+             reports the throughput and latency",
+        )
+        .arg(Arg::with_name("addr").long("addr").takes_value(true).help(
+            "Address of the server to benchmark, by default one started in-process",
+        ))
+        .arg(Arg::with_name("capacity").long("capacity").takes_value(true).help(
+            "Entries held by the in-process server, default: 1,000,000",
+        ))
+        .arg(Arg::with_name("clients").long("clients").takes_value(true).help(
+            "Clients making requests at once, each on a thread and connection of its own, \
+             default: 4",
+        ))
+        .arg(Arg::with_name("requests").long("requests").takes_value(true).help(
+            "Requests made by each client, default: 100,000",
+        ))
+        .arg(Arg::with_name("pipeline").long("pipeline").takes_value(true).help(
+            "Requests each client keeps in flight on its connection, default: 32",
+        ))
+        .arg(Arg::with_name("keys").long("keys").takes_value(true).help(
+            "Keys requested, at random, default: 10,000",
+        ))
+        .arg(Arg::with_name("value_size").long("value_size").takes_value(true).help(
+            "Bytes in each value set, default: 100",
+        ))
+        .arg(Arg::with_name("get_ratio").long("get_ratio").takes_value(true).help(
+            "Share of the requests that are gets rather than sets, default: 0.9",
+        ))
+        .get_matches();
+
+    if let Err(err) = run(&matches) {
+        println!("err: {}", err);
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<(), String> {
+    let workload = Workload {
+        clients: parse(matches, "clients", 4)?,
+        requests: parse(matches, "requests", 100000)?,
+        pipeline: parse(matches, "pipeline", 32)?,
+        keys: parse(matches, "keys", 10000)?,
+        value_size: parse(matches, "value_size", 100)?,
+        get_ratio: parse(matches, "get_ratio", 0.9)?,
+    };
+    if workload.clients == 0 || workload.pipeline == 0 || workload.keys == 0 {
+        return Err("clients, pipeline and keys must be positive.".to_owned());
+    }
+
+    // Without an address, serve a cache on a thread of this process.
+    let server = match matches.value_of("addr") {
+        Some(_) => None,
+        None => {
+            let capacity = parse(matches, "capacity", 1000000)?;
+            let addr = "127.0.0.1:0".parse().unwrap();
+            let server = service::serve_on_thread(addr, ServeOptions::default(), move || {
+                Ok(CacheService { cache: Arc::new(Cache::new(capacity)?) })
+            }).map_err(|e| e.to_string())?;
+            Some(server)
+        }
+    };
+    let addr = match (matches.value_of("addr"), server.as_ref()) {
+        (Some(addr), _) => addr.parse().map_err(|_| "Failed to parse address.")?,
+        (None, Some(server)) => server.local_addr(),
+        (None, None) => unreachable!(),
+    };
+
+    fill(addr, &workload).map_err(|e| e.to_string())?;
+
+    let started = Instant::now();
+    let clients: Vec<_> = (0..workload.clients)
+        .map(|_| {
+            let workload = workload.clone();
+            thread::spawn(move || drive(addr, &workload))
+        })
+        .collect();
+    let mut latencies = vec![];
+    let mut errors = 0;
+    for client in clients {
+        let measured = client
+            .join()
+            .map_err(|_| "A client panicked.".to_owned())?
+            .map_err(|e| e.to_string())?;
+        latencies.extend(measured.latencies);
+        errors += measured.errors;
+    }
+    let elapsed = started.elapsed();
+
+    report(&workload, &mut latencies, errors, elapsed);
+    if let Some(server) = server {
+        server.shutdown().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Sets every key, so that gets hit unless the cache is too small to hold them all.
+fn fill(addr: SocketAddr, workload: &Workload) -> io::Result<()> {
+    let mut core = Core::new()?;
+    let client = core.run(Client::connect(&addr, &core.handle()))?;
+    let value = vec![0; workload.value_size];
+    let sets = stream::iter_ok(0..workload.keys)
+        .map(|i| client.set(key(i), value.clone()))
+        .buffer_unordered(workload.pipeline)
+        .for_each(|_| Ok(()));
+    core.run(sets)
+}
+
+/// Makes a client's share of the requests on a connection of its own, keeping up to
+/// `workload.pipeline` of them in flight.
+fn drive(addr: SocketAddr, workload: &Workload) -> io::Result<Measured> {
+    let mut core = Core::new()?;
+    let client = core.run(Client::connect(&addr, &core.handle()))?;
+    let mut rng = rand::thread_rng();
+    let requests: Vec<Message> = (0..workload.requests)
+        .map(|_| {
+            let key = key(rng.gen_range(0, workload.keys));
+            if rng.gen::<f64>() < workload.get_ratio {
+                message::request(Op::Get, key, None)
+            } else {
+                let mut value = vec![0; workload.value_size];
+                rng.fill_bytes(&mut value);
+                message::request(Op::Set, key, Some(message::payload(message::TYPE_I64, value)))
+            }
+        })
+        .collect();
+
+    let responses = stream::iter_ok(requests)
+        .map(|req| {
+            let sent = Instant::now();
+            client.call(req).map(move |resp| (resp.code(), sent.elapsed()))
+        })
+        .buffer_unordered(workload.pipeline)
+        .collect();
+    let responses = core.run(responses)?;
+    Ok(Measured {
+        errors: responses
+            .iter()
+            .filter(|&&(code, _)| code != Code::Ok && code != Code::Hit && code != Code::Miss)
+            .count(),
+        latencies: responses.into_iter().map(|(_, latency)| latency).collect(),
+    })
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key:{}", i).into_bytes()
+}
+
+fn report(workload: &Workload, latencies: &mut Vec<Duration>, errors: usize, elapsed: Duration) {
+    latencies.sort();
+    let secs = micros(elapsed) as f64 / 1e6;
+    println!(
+        "{} clients, {} requests each, {} in flight per client, {:.0}% gets over {} keys",
+        workload.clients,
+        workload.requests,
+        workload.pipeline,
+        workload.get_ratio * 100.0,
+        workload.keys
+    );
+    println!(
+        "{} requests in {:.3}s: {:.0} requests/s, {} failed",
+        latencies.len(),
+        secs,
+        latencies.len() as f64 / secs,
+        errors
+    );
+    if latencies.is_empty() {
+        return;
+    }
+    let percentile = |p: f64| micros(latencies[((latencies.len() - 1) as f64 * p) as usize]);
+    println!(
+        "latency in µs: p50 {}, p90 {}, p99 {}, p99.9 {}, max {}",
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(0.999),
+        micros(latencies[latencies.len() - 1])
+    );
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1000000 + (duration.subsec_nanos() / 1000) as u64
+}
+
+/// Parses the value of `name`, or gives `default` if it wasn't given.
+fn parse<T>(matches: &ArgMatches, name: &str, default: T) -> Result<T, String>
+where
+    T: ::std::str::FromStr,
+{
+    match matches.value_of(name) {
+        Some(value) => value.parse().map_err(|_| format!("Failed to parse {}.", name)),
+        None => Ok(default),
+    }
+}
@@ -3,27 +3,26 @@ extern crate test;
 extern crate rcache;
 extern crate tokio_core;
 extern crate futures;
-extern crate futures_cpupool;
 extern crate tokio_service;
 extern crate rand;
 extern crate time;
 extern crate clap;
+extern crate log;
 
 use rcache::client;
-use rcache::service;
-use rcache::cache;
+use rcache::tls;
+use rcache::config::{self, ServerConfig};
 use std::error::Error;
+use std::io;
 use std::net::SocketAddr;
 use rcache::message::{Message, Op, Code};
 use futures::Future;
-use std::sync::Arc;
 use tokio_core::reactor::Core;
-use rcache::stats::Stats;
+use rcache::stats::{self, ServerStats};
+use log::LevelFilter;
 use clap::{Arg, App, SubCommand, ArgMatches};
 
 
-static DEFAULT_CACHE_SIZE: usize = 2000000;
-
 fn main() {
     let set = SubCommand::with_name("SET")
         .arg(Arg::with_name("KEY").required(true).index(1))
@@ -31,18 +30,68 @@ fn main() {
 
     let get = SubCommand::with_name("GET").arg(Arg::with_name("KEY").required(true).index(1));
 
-    let stats = SubCommand::with_name("STATS").about("Retrieves stats from given server");
+    let del = SubCommand::with_name("DEL").arg(Arg::with_name("KEY").required(true).index(1));
+
+    let stats = SubCommand::with_name("STATS")
+        .about("Retrieves stats from given server")
+        .arg(Arg::with_name("json").long("json").help("Retrieve them as JSON"));
+
+    let info = SubCommand::with_name("INFO").about("Describes the given server");
 
     let client = SubCommand::with_name("client")
         .about("Run a client command on server at given address")
         .subcommand(get)
         .subcommand(set)
-        .subcommand(stats);
+        .subcommand(del)
+        .subcommand(stats)
+        .subcommand(info)
+        .arg(Arg::with_name("tls_domain").long("tls_domain").takes_value(true).help(
+            "Connect over TLS, expecting a certificate for the given domain",
+        ))
+        .arg(Arg::with_name("ca_cert").long("ca_cert").takes_value(true).help(
+            "PEM encoded certificate to trust when connecting over TLS",
+        ));
 
     let server = SubCommand::with_name("server")
         .about("Start a server at given address")
-        .arg(Arg::with_name("Cache Size").long("cache_size").help(
+        .arg(Arg::with_name("config").long("config").takes_value(true).help(
+            "TOML file to configure the server with, which the other flags override",
+        ))
+        .arg(Arg::with_name("cache_size").long("cache_size").takes_value(true).help(
             "Maximum number of entries in cache, default: 2,000,000",
+        ))
+        .arg(Arg::with_name("pkcs12").long("pkcs12").takes_value(true).help(
+            "Serve TLS, with the key and certificate chain in the given PKCS #12 archive",
+        ))
+        .arg(Arg::with_name("pkcs12_password").long("pkcs12_password").takes_value(true).help(
+            "Password of the PKCS #12 archive",
+        ))
+        .arg(Arg::with_name("metrics_addr").long("metrics_addr").takes_value(true).help(
+            "Address to serve Prometheus metrics at, under /metrics",
+        ))
+        .arg(Arg::with_name("memcache_addr").long("memcache_addr").takes_value(true).help(
+            "Address to also serve the memcached text protocol at",
+        ))
+        .arg(Arg::with_name("resp_addr").long("resp_addr").takes_value(true).help(
+            "Address to also serve the Redis protocol at",
+        ))
+        .arg(Arg::with_name("unix_socket").long("unix_socket").takes_value(true).help(
+            "Path of a UNIX domain socket to also serve at",
+        ))
+        .arg(Arg::with_name("replicas").long("replicas").help(
+            "Let replicas follow this server",
+        ))
+        .arg(Arg::with_name("replica_of").long("replica_of").takes_value(true).help(
+            "Address of a server to serve a read-only replica of",
+        ))
+        .arg(Arg::with_name("cluster_addr").long("cluster_addr").takes_value(true).help(
+            "Address clients reach this node at, to serve in cluster mode",
+        ))
+        .arg(Arg::with_name("hot_keys").long("hot_keys").help(
+            "Track the keys requested most, reported by stats",
+        ))
+        .arg(Arg::with_name("log_level").long("log_level").takes_value(true).help(
+            "Level to log at: off, error, warn, info, debug or trace, default: info",
         ));
 
     let matches = App::new("rcache")
@@ -77,11 +126,54 @@ fn run(matches: &ArgMatches) -> Result<String, String> {
         .map_err(|_| "Failed to parse socket address.")?;
 
     if let Some(matches) = matches.subcommand_matches("server") {
-        let cache_size: usize = matches
-            .value_of("cache_size")
-            .map(|s| s.parse().unwrap_or_else(|_| DEFAULT_CACHE_SIZE))
-            .unwrap_or_else(|| DEFAULT_CACHE_SIZE);
-        run_server(addr, cache_size).map(|_| "success".to_owned())
+        let mut config = match matches.value_of("config") {
+            Some(path) => ServerConfig::load(path).map_err(|e| e.to_string())?.addr(addr),
+            None => ServerConfig::new(addr),
+        };
+        if let Some(size) = matches.value_of("cache_size") {
+            config = config.capacity(size.parse().map_err(|_| "Failed to parse cache size.")?);
+        }
+        if let Some(path) = matches.value_of("pkcs12") {
+            config = config.tls(Some(path), matches.value_of("pkcs12_password").unwrap_or(""));
+        }
+        if let Some(metrics_addr) = matches.value_of("metrics_addr") {
+            let metrics_addr = metrics_addr
+                .parse()
+                .map_err(|_| "Failed to parse metrics address.")?;
+            config = config.metrics_addr(Some(metrics_addr));
+        }
+        if let Some(memcache_addr) = matches.value_of("memcache_addr") {
+            let memcache_addr = memcache_addr
+                .parse()
+                .map_err(|_| "Failed to parse memcache address.")?;
+            config = config.memcache_addr(Some(memcache_addr));
+        }
+        if let Some(resp_addr) = matches.value_of("resp_addr") {
+            let resp_addr = resp_addr.parse().map_err(|_| "Failed to parse RESP address.")?;
+            config = config.resp_addr(Some(resp_addr));
+        }
+        if let Some(path) = matches.value_of("unix_socket") {
+            config = config.unix_socket(Some(path), None);
+        }
+        if matches.is_present("replicas") {
+            config = config.replicas(true);
+        }
+        if let Some(primary) = matches.value_of("replica_of") {
+            let primary = primary.parse().map_err(|_| "Failed to parse primary address.")?;
+            config = config.replica_of(Some(primary));
+        }
+        if let Some(node) = matches.value_of("cluster_addr") {
+            let node = node.parse().map_err(|_| "Failed to parse cluster address.")?;
+            config = config.cluster_addr(Some(node));
+        }
+        if matches.is_present("hot_keys") {
+            config = config.hot_keys(true);
+        }
+        if let Some(level) = matches.value_of("log_level") {
+            let level: LevelFilter = level.parse().map_err(|_| "Failed to parse log level.")?;
+            config = config.log_level(level);
+        }
+        config::run(config).map(|_| "success".to_owned()).map_err(|e| e.to_string())
     } else if let Some(matches) = matches.subcommand_matches("client") {
         run_client(addr, matches)
     } else {
@@ -91,22 +183,36 @@ fn run(matches: &ArgMatches) -> Result<String, String> {
 
 fn run_client(addr: SocketAddr, matches: &ArgMatches) -> Result<String, String> {
     let mut core = Core::new().map_err(|e| e.description().to_owned())?;
-    let client = client::Client::connect(&addr, &core.handle());
+    let client: Box<Future<Item = client::Client, Error = io::Error>> =
+        match matches.value_of("tls_domain") {
+            Some(domain) => {
+                let connector = tls::connector(matches.value_of("ca_cert"))
+                    .map_err(|e| e.to_string())?;
+                Box::new(client::Client::connect_tls(&addr, domain, &connector, &core.handle()))
+            }
+            None => Box::new(client::Client::connect(&addr, &core.handle())),
+        };
 
     // Unwraps in here are safe because clap has already validated that required params are present
     let client_cmd = |client: client::Client| match matches.subcommand() {
         ("GET", Some(matches)) => {
             // handle GET
             let key = matches.value_of("KEY").unwrap();
-            client.get(key.to_owned().into_bytes())
+            client.get(key)
         }
         ("SET", Some(matches)) => {
             // handle SET
             let key = matches.value_of("KEY").unwrap();
             let value = matches.value_of("VALUE").unwrap();
-            client.set(key.to_owned().into_bytes(), value.to_owned().into_bytes())
+            client.set(key, value)
         }
+        ("DEL", Some(matches)) => {
+            let key = matches.value_of("KEY").unwrap();
+            client.del(key)
+        }
+        ("STATS", Some(matches)) if matches.is_present("json") => client.stats_json(),
         ("STATS", _) => client.stats(),
+        ("INFO", _) => client.info(),
         _ => unimplemented!(),
     };
 
@@ -117,19 +223,6 @@ fn run_client(addr: SocketAddr, matches: &ArgMatches) -> Result<String, String>
     core.run(exec).expect("core failure")
 }
 
-fn run_server(addr: SocketAddr, cache_size: usize) -> Result<(), String> {
-    let cache = cache::Cache::new(cache_size).unwrap();
-    cache.start(cache_size);
-
-    // TODO: Figure out the idiomatic way to build up these middleware
-    let service = service::StatService {
-        stats: Arc::new(Stats::default()),
-        inner: service::CacheService { cache: Arc::new(cache) },
-    };
-
-    service::serve(addr, service).map_err(|e| e.description().to_owned())
-}
-
 // Decode utf-8 strings if the message type_id is 1, otherwise just defer to builtin formatter
 fn handle_response(msg: &Message) -> Result<String, String> {
     match (msg.op(), msg.code(), msg.payload()) {
@@ -144,7 +237,13 @@ fn handle_response(msg: &Message) -> Result<String, String> {
                 Ok(format!("{}", msg))
             }
         }
-        (Op::Stats, _, Some(payload)) => {
+        (Op::Stats, _, Some(payload)) if payload.type_id() == stats::STATS_TYPE_ID => {
+            ServerStats::decode(payload.data()).map(|stats| stats.to_string()).map_err(|e| {
+                e.description().to_owned()
+            })
+        }
+        (Op::Stats, _, Some(payload)) |
+        (Op::Info, _, Some(payload)) => {
             String::from_utf8(payload.data().to_owned()).map_err(|_| {
                 "expected a utf8-encoded string".to_owned()
             })
@@ -197,7 +296,7 @@ mod tests {
         let mut core = Core::new().unwrap();
         let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
 
-        thread::spawn(move || run_server(addr.clone(), 200000));
+        thread::spawn(move || config::run(ServerConfig::new(addr).capacity(200000)));
         let duration = std::time::Duration::new(0, 1000);
         thread::sleep(duration);
 
@@ -1,137 +1,6129 @@
 use message::{self, Message, Op, Code, Payload};
-use tokio_core::reactor::Core;
 use std::error::Error;
-use futures::sync::oneshot::Sender;
-use futures_cpupool::CpuPool;
-use futures::future;
-use std::io;
+use futures::sync::oneshot::{self, Sender};
+use futures::{future, Async, Future, Poll, Stream};
+use futures::sync::mpsc as futures_mpsc;
+use futures_cpupool::{Builder as PoolBuilder, CpuPool};
+use std::io::{self, Read, Write};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hasher};
 use error;
+use clock::{Clock, SystemClock};
+use codec;
+use time::{Duration, Timespec};
 use lru_cache::LruCache;
-use deque::{self, Worker, Stealer, Stolen};
+use linked_hash_map::LinkedHashMap;
+use fxhash::FxHasher;
+use rand::{self, Rng};
+use bytes::{Buf, BufMut, BigEndian};
+use aof::{AppendLog, Record};
+use pubsub::{Change, Hub};
+use cluster::{self, Slots};
+use hotkeys::HotKeys;
+use std::net::SocketAddr;
 
 
-type Store = LruCache<Vec<u8>, Payload>;
+/// The cache's storage, owned by the worker.
+struct Store<S: Storage = LruStorage> {
+    entries: S,
+    last_version: u64,
+    functions: HashMap<String, ApplyFn>,
+    /// The total size of the stored entries, as counted by `entry_size`.
+    used_bytes: usize,
+    max_bytes: Option<usize>,
+    /// See `Options::max_key_len`.
+    max_key_len: Option<usize>,
+    /// See `Options::max_value_len`.
+    max_value_len: Option<usize>,
+    /// See `Options::ttl_jitter`.
+    ttl_jitter: Option<u32>,
+    /// See `Options::stale_grace`.
+    stale_grace: Option<Duration>,
+    /// The entries that expired less than `stale_grace` ago, until their keys are written.
+    stale: Stale,
+    /// The versions keys were removed at, for `Op::Watch`.
+    removals: Removals,
+    clock: Arc<Clock>,
+    /// When the store was created, for the uptime reported by `Op::Info`.
+    started: Timespec,
+    quotas: Vec<PrefixQuota>,
+    sweep_interval: Option<Duration>,
+    last_sweep: Timespec,
+    /// Entries evicted to make room for others, reported by `Op::Stats`.
+    evictions: u64,
+    /// The evictions from each namespace but the default one.
+    namespace_evictions: HashMap<Vec<u8>, u64>,
+    log: Option<AppendLog>,
+    /// See `LogOptions::compact_after`.
+    compact_after: Option<u64>,
+    /// The length of the log when it was last compacted.
+    compacted_len: u64,
+    /// Where changes to keys are published, see `Options::notifications`.
+    hub: Option<Hub>,
+    /// See `Options::replication`, which is `None` unless replicas may follow the store.
+    max_replica_backlog: Option<usize>,
+    replicas: Vec<Replica>,
+    /// The owners of the hash slots, in cluster mode, see `Options::cluster`.
+    cluster: Option<Slots>,
+    /// See `Options::hot_keys`.
+    hot_keys: Option<HotKeys>,
+}
 
-type Work = (Sender<Message>, Message);
+/// A limit on the number of keys starting with `prefix`, see `Options::prefix_quotas`.
+struct PrefixQuota {
+    prefix: Vec<u8>,
+    max_keys: usize,
+    keys: usize,
+}
 
-/// A thread safe wrapper around `LruCache` that synchronizes reads/writes via a single
-/// threaded worker that reads requests from a dequeue and pushes responses into a channel
-/// provided by the request (`Work`) payload.
-pub struct Cache {
-    pool: CpuPool,
-    core: Core,
-    stealer: Stealer<Work>,
-    worker: Worker<Work>,
+#[cfg(test)]
+impl Store<LruStorage> {
+    fn new(capacity: usize) -> Self {
+        Store::with_storage(LruStorage::new(capacity))
+    }
 }
 
-impl Cache {
-    /// Initialize a new `Cache` with `capacity` and start the worker thread.
-    pub fn new(capacity: usize) -> Result<Self, io::Error> {
-        let (worker, stealer) = deque::new();
-        let cache = Cache {
-            pool: CpuPool::new_num_cpus(),
-            core: Core::new()?,
-            worker: worker,
-            stealer: stealer,
-        };
-
-        cache.start(capacity);
-        Ok(cache)
-    }
-
-    /// Start the stealer thread, which has unsynchronized access to the underlying store.
-    /// `Work` is pushed to the worker via the deque. `Work` is a (Sender<Message>, Message) pair
-    /// where `Message` is a request to do work on the store and `Sender` is a channel to send the result.
-    ///
-    /// TODO: using `loop_fn` doesn't do what I thought, and this thread currently pegs the CPU just waiting for work.
-    /// I think I need to make the work queue a pollable stream so that we can wait for new work without pegging the CPU.
-    pub fn start(&self, capacity: usize) {
-        let stealer = self.stealer.clone();
-        // Loop infinitely, attempting to steal work from the deque.
-        // When work is obtained, it's dispatched to the `handle` method, which returns a Result containing
-        // the `Message::Response` variant. The response will be returned via the `Sender`
-        let work = future::loop_fn(
-            (stealer, LruCache::new(capacity)),
-            |(stealer, mut store): (Stealer<Work>, Store)| {
-                match stealer.steal() {
-                    Stolen::Empty => (), // Continue
-                    Stolen::Abort => (), // TODO: Handle aborts, the obvious manner of doing this doesn't seem to be working
-                    Stolen::Data(work) => {
-                        let (snd, msg) = work;
-                        let success = match handle(&mut store, msg) {
-                            Ok(msg) => snd.send(msg),
-                            Err(e) => snd.send(handle_error(&e)),
-                        };
-                        match success {
-                            Ok(_) => (),
-                            Err(e) => println!("Failed to send: {}.", e),
-                        }
+impl<S: Storage> Store<S> {
+    fn with_storage(entries: S) -> Self {
+        let stale = Stale::new(entries.capacity());
+        let removals = Removals::new(entries.capacity());
+        Store {
+            entries: entries,
+            last_version: 0,
+            functions: HashMap::new(),
+            used_bytes: 0,
+            max_bytes: None,
+            max_key_len: None,
+            max_value_len: None,
+            ttl_jitter: None,
+            stale_grace: None,
+            stale: stale,
+            removals: removals,
+            clock: Arc::new(SystemClock),
+            started: SystemClock.now(),
+            quotas: vec![],
+            sweep_interval: None,
+            last_sweep: SystemClock.now(),
+            evictions: 0,
+            namespace_evictions: HashMap::new(),
+            log: None,
+            compact_after: None,
+            compacted_len: 0,
+            hub: None,
+            max_replica_backlog: None,
+            replicas: vec![],
+            cluster: None,
+            hot_keys: None,
+        }
+    }
+
+    /// Whether storing `key` would create a key under a prefix that already holds its quota of
+    /// keys. Replacing an existing key never does.
+    fn over_quota(&mut self, key: &[u8]) -> bool {
+        !self.entries.contains_key(key) &&
+            self.quotas.iter().any(|q| key.starts_with(&q.prefix) && q.keys >= q.max_keys)
+    }
+
+    /// The code refusing `msg` for naming a key, or storing a value, longer than the store
+    /// takes, see `Options::max_key_len` and `Options::max_value_len`. Only writes are refused.
+    /// The values of `Op::Append`, `Op::Prepend` and `Op::Apply` are only known once applied, so
+    /// their handlers check them.
+    fn over_limits(&self, msg: &Message) -> Option<Code> {
+        if !msg.op().is_write() {
+            return None;
+        }
+        if let Some(max) = self.max_key_len {
+            if cluster::keys_of(msg).iter().any(|key| key.len() > max) {
+                return Some(Code::KeyTooLarge);
+            }
+        }
+        match (msg.op(), msg.payload(), self.max_value_len) {
+            (Op::Set, Some(payload), Some(max)) |
+            (Op::Add, Some(payload), Some(max)) |
+            (Op::Replace, Some(payload), Some(max)) |
+            (Op::SetIfEmpty, Some(payload), Some(max)) |
+            (Op::Cas, Some(payload), Some(max)) if payload.data().len() > max => {
+                Some(Code::ValueTooLarge)
+            }
+            (Op::Restore, Some(payload), Some(max)) => {
+                let entries = decode_dump(payload.data()).unwrap_or_default();
+                if entries.iter().any(|&(_, ref value, _)| value.data().len() > max) {
+                    Some(Code::ValueTooLarge)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            max_key_len: self.max_key_len,
+            max_value_len: self.max_value_len,
+        }
+    }
+
+    /// `ttl` moved by up to `ttl_jitter` percent of it either way, at random.
+    fn jittered(&self, ttl: Duration) -> Duration {
+        match self.ttl_jitter {
+            Some(percent) if percent > 0 => {
+                let spread = ttl.num_milliseconds() / 100 * percent.min(100) as i64;
+                ttl + Duration::milliseconds(rand::thread_rng().gen_range(-spread, spread + 1))
+            }
+            _ => ttl,
+        }
+    }
+
+    /// Whether an entry of `size` bytes can be stored at all.
+    fn fits(&self, size: usize) -> bool {
+        self.max_bytes.map_or(true, |max| size <= max)
+    }
+
+    /// Stores `entry` under `key`, replacing any existing entry, and evicting the least recently
+    /// used entries until both the new one fits under `max_bytes` and there is room for it.
+    /// The caller checks that the entry `fits`.
+    fn insert(&mut self, key: Vec<u8>, entry: Entry) {
+        let size = entry_size(&key, &entry.payload);
+        self.remove(&key);
+        while self.entries.len() >= self.entries.capacity() ||
+            self.max_bytes.map_or(false, |max| self.used_bytes + size > max)
+        {
+            match self.entries.remove_lru() {
+                Some((key, entry)) => {
+                    self.evictions += 1;
+                    if let Some(namespace) = namespace_of(&key) {
+                        *self.namespace_evictions.entry(namespace.to_vec()).or_insert(0) += 1;
                     }
-                };
-                future::ok(future::Loop::Continue((stealer, store)))
-            },
+                    self.forget(&key, &entry);
+                    let version = self.next_version();
+                    self.removals.insert(key.clone(), version);
+                    self.notify(Change::Evicted, &key);
+                }
+                None => break,
+            }
+        }
+        self.used_bytes += size;
+        for quota in self.quotas.iter_mut().filter(|q| key.starts_with(&q.prefix)) {
+            quota.keys += 1;
+        }
+        self.entries.insert(key, entry);
+    }
+
+    /// Takes a removed entry out of the store's accounting.
+    fn forget(&mut self, key: &[u8], entry: &Entry) {
+        self.used_bytes -= entry_size(key, &entry.payload);
+        for quota in self.quotas.iter_mut().filter(|q| key.starts_with(&q.prefix)) {
+            quota.keys -= 1;
+        }
+    }
+
+    /// Removes the entry under `key` if it has expired. Expired entries are only cleaned up
+    /// lazily, so handlers call this before looking at a key.
+    fn expire(&mut self, key: &[u8]) {
+        let now = self.clock.now();
+        let expired = self.entries.peek(key).map_or(false, |e| e.expired(now));
+        if expired {
+            if let Some(entry) = self.remove(key) {
+                self.keep_stale(key.to_vec(), entry);
+            }
+            self.notify(Change::Expired, key);
+        }
+    }
+
+    /// Keeps the expired `entry` for the Gets asking for stale values, if the store has a grace
+    /// window, see `Options::stale_grace`.
+    fn keep_stale(&mut self, key: Vec<u8>, entry: Entry) {
+        if self.stale_grace.is_some() {
+            self.stale.insert(key, entry, self.max_bytes);
+        }
+    }
+
+    /// The value under `key` that expired less than `stale_grace` ago, if any.
+    fn stale(&mut self, key: &[u8]) -> Option<&Entry> {
+        let (now, grace) = (self.clock.now(), self.stale_grace?);
+        let past = self.stale.get(key)?.expires_at.map_or(true, |at| now >= at + grace);
+        if past {
+            self.stale.remove(key);
+            return None;
+        }
+        self.stale.get(key)
+    }
+
+    /// Publishes a change to the stored key `key`, if the store has a hub.
+    fn notify(&self, change: Change, key: &[u8]) {
+        if let Some(ref hub) = self.hub {
+            match namespace_of(key) {
+                Some(name) => hub.publish(change, Some(name), &key[2 + name.len()..]),
+                None => hub.publish(change, None, key),
+            }
+        }
+    }
+
+    /// The versions of the entries under `keys`, `None` for those that aren't stored.
+    fn versions(&mut self, keys: &[Vec<u8>]) -> Vec<Option<u64>> {
+        keys.iter().map(|key| self.entries.get_mut(key).map(|e| e.version)).collect()
+    }
+
+    /// Publishes the changes to `keys` since their versions were `before`: a Set for those
+    /// written, and a Deleted for those no longer stored.
+    fn notify_writes(&mut self, keys: &[Vec<u8>], before: &[Option<u64>]) {
+        let after = self.versions(keys);
+        for ((key, before), after) in keys.iter().zip(before).zip(after) {
+            match (*before, after) {
+                (Some(_), None) => self.notify(Change::Deleted, key),
+                (before, Some(after)) if before != Some(after) => self.notify(Change::Set, key),
+                _ => {}
+            }
+        }
+    }
+
+    /// Rebuilds the store from the snapshot at `snapshot`, if there is one, and the log described
+    /// by `log`, which it then keeps. The log is replayed from the snapshot's `Record::Mark`
+    /// over the snapshot. A log without the mark was compacted since the snapshot was taken, and
+    /// holds everything on its own, unless it is empty, when the snapshot is restored and the
+    /// mark logged, so that the next start finds it. Nothing restored is logged again.
+    fn recover(&mut self, snapshot: Option<&Path>, log: Option<&LogOptions>) -> io::Result<()> {
+        let (log, mut records) = match log {
+            Some(options) => {
+                let (log, records) = AppendLog::open(&options.path, options.sync)?;
+                (Some((log, options.compact_after)), records)
+            }
+            None => (None, vec![]),
+        };
+        let saved = match snapshot {
+            Some(path) if path.exists() => Some((path, read_marked_snapshot(path)?)),
+            _ => None,
+        };
+        let mut unmarked = None;
+        if let Some((path, (mark, entries))) = saved {
+            let marked = records.iter().position(|record| *record == Record::Mark(mark));
+            if marked.is_some() || records.is_empty() {
+                records = records.split_off(marked.map_or(0, |at| at + 1));
+                let restored = self.restore(entries);
+                info!("Restored {} entries from {}.", restored, path.display());
+                if marked.is_none() {
+                    unmarked = Some(mark);
+                }
+            } else {
+                info!("Skipped {}, as the log was compacted since.", path.display());
+            }
+        }
+        self.replay(records);
+        if let Some((mut log, compact_after)) = log {
+            if let Some(mark) = unmarked {
+                log.append(&[Record::Mark(mark)])?;
+            }
+            self.compacted_len = log.len();
+            self.compact_after = compact_after;
+            self.log = Some(log);
+        }
+        Ok(())
+    }
+
+    /// Stores the unexpired `entries` of a snapshot with Sets, in order, returning how many were
+    /// stored. The capacity and limits apply as to any other Set.
+    fn restore(&mut self, entries: Vec<(Vec<u8>, Payload, Option<Timespec>)>) -> usize {
+        let now = self.clock.now();
+        let mut restored = 0;
+        for (key, payload, expires_at) in entries {
+            let mut req = stored_request(Op::Set, &key, Some(payload));
+            if let Some(expires_at) = expires_at {
+                if expires_at <= now {
+                    continue;
+                }
+                let at = message::encode_u64(expires_at.sec as u64);
+                req = req.with_extension(message::EXT_EXPIRES_AT, at);
+            }
+            if handle(self, req).ok().map(|resp| resp.code()) == Some(Code::Ok) {
+                restored += 1;
+            }
+        }
+        restored
+    }
+
+    /// Applies the `records` of a log.
+    fn replay(&mut self, records: Vec<Record>) {
+        let now = self.clock.now();
+        for record in records {
+            match record {
+                Record::Set(key, payload, expires_at) => {
+                    if expires_at.map_or(false, |at| at <= now) {
+                        self.remove(&key);
+                        continue;
+                    }
+                    let version = self.next_version();
+                    self.insert(key, Entry::new(payload, version, expires_at, now));
+                }
+                Record::Del(key) => {
+                    self.remove(&key);
+                }
+                Record::Mark(_) => {}
+            }
+        }
+    }
+
+    /// Copies every unexpired entry, least recently used first, along with when it expires.
+    fn live_entries(&self) -> Vec<(Vec<u8>, Payload, Option<Timespec>)> {
+        let now = self.clock.now();
+        self.entries
+            .iter()
+            .filter(|&(_, entry)| !entry.expired(now))
+            .map(|(key, entry)| (key.clone(), entry.payload.clone(), entry.expires_at))
+            .collect()
+    }
+
+    /// Copies every unexpired entry for a snapshot, see `live_entries`, marking where it was
+    /// taken in the log, if there is one, with a new mark, which is returned.
+    fn checkpoint(&mut self) -> io::Result<(u64, Vec<(Vec<u8>, Payload, Option<Timespec>)>)> {
+        let mark = rand::random();
+        self.log_writes(&[Record::Mark(mark)])?;
+        Ok((mark, self.live_entries()))
+    }
+
+    /// The current state of each of `keys`.
+    fn records(&mut self, keys: Vec<Vec<u8>>) -> Vec<Record> {
+        keys.into_iter()
+            .map(|key| match self.entries.get_mut(&key) {
+                Some(entry) => {
+                    let payload = entry.payload.clone();
+                    Record::Set(key, payload, entry.expires_at)
+                }
+                None => Record::Del(key),
+            })
+            .collect()
+    }
+
+    /// Appends `records` to the log, if there is one, compacting it if it has grown enough.
+    fn log_writes(&mut self, records: &[Record]) -> io::Result<()> {
+        if self.log.is_none() || records.is_empty() {
+            return Ok(());
+        }
+        let grown = match self.log {
+            Some(ref mut log) => {
+                log.append(records)?;
+                log.len() - self.compacted_len
+            }
+            None => 0,
+        };
+        if self.compact_after.map_or(false, |after| grown >= after) {
+            self.compact_log()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log from the unexpired entries in the store, least recently used first.
+    fn compact_log(&mut self) -> io::Result<()> {
+        let now = self.clock.now();
+        let records: Vec<Record> = self.entries
+            .iter()
+            .filter(|&(_, entry)| !entry.expired(now))
+            .map(|(key, entry)| {
+                Record::Set(key.clone(), entry.payload.clone(), entry.expires_at)
+            })
+            .collect();
+        match self.log {
+            Some(ref mut log) => {
+                log.rewrite(records)?;
+                self.compacted_len = log.len();
+                Ok(())
+            }
+            None => Err(io::Error::new(io::ErrorKind::Other, "cache has no log")),
+        }
+    }
+
+    /// Removes every expired entry, if `sweep_interval` has passed since the last sweep. This
+    /// catches entries that expire without being looked at again, which would otherwise hold on
+    /// to their memory until evicted.
+    fn sweep(&mut self) {
+        let now = self.clock.now();
+        match self.sweep_interval {
+            Some(interval) if now >= self.last_sweep + interval => self.last_sweep = now,
+            _ => return,
+        }
+        let expired: Vec<Vec<u8>> = self.entries
+            .iter()
+            .filter(|&(_, entry)| entry.expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            if let Some(entry) = self.remove(&key) {
+                self.keep_stale(key.clone(), entry);
+            }
+            self.notify(Change::Expired, &key);
+        }
+        if let Some(grace) = self.stale_grace {
+            self.stale.retain(|_, entry| entry.expires_at.map_or(false, |at| now < at + grace));
+        }
+    }
+
+    /// Starts streaming the writes to the store to a new replica, beginning with a snapshot of
+    /// every unexpired entry, least recently used first, see `Cache::replicate`.
+    fn replicate(&mut self) -> Feed {
+        let now = self.clock.now();
+        let mut snapshot = vec![replicated_write(now, None)];
+        snapshot.extend(
+            self.entries
+                .iter()
+                .filter(|&(_, entry)| !entry.expired(now))
+                .map(|(key, entry)| {
+                    let record = Record::Set(key.clone(), entry.payload.clone(), entry.expires_at);
+                    replicated_write(now, Some(&record))
+                }),
         );
-        self.core.handle().spawn(self.pool.spawn(work));
+        let (sender, writes) = futures_mpsc::unbounded();
+        let backlog = Arc::new(AtomicUsize::new(0));
+        self.replicas.push(Replica {
+            sender: sender,
+            backlog: backlog.clone(),
+        });
+        Feed {
+            snapshot: snapshot.into_iter(),
+            writes: writes,
+            backlog: backlog,
+        }
     }
 
-    /// Push work onto the queue. `snd` is a `futures::sync::oneshot::Sender<Message>`. When the
-    /// worker has completed the request, it will send its `Message::Response` via the sender.
-    pub fn process(&self, message: Message, snd: Sender<Message>) {
-        self.worker.push((snd, message));
+    /// Streams `records` to every replica, dropping those that have gone, and those that would
+    /// have more than `max_replica_backlog` writes waiting, which ends their feed.
+    fn replicate_writes(&mut self, records: &[Record]) {
+        if self.replicas.is_empty() || records.is_empty() {
+            return;
+        }
+        let now = self.clock.now();
+        let writes: Vec<Message> = records.iter().map(|r| replicated_write(now, Some(r))).collect();
+        let max_backlog = self.max_replica_backlog.unwrap_or(usize::max_value());
+        self.replicas.retain(|replica| {
+            let backlog = replica.backlog.fetch_add(writes.len(), Ordering::SeqCst);
+            if backlog + writes.len() > max_backlog {
+                warn!("Dropping a replica {} writes behind.", backlog);
+                return false;
+            }
+            writes.iter().all(|write| replica.sender.unbounded_send(write.clone()).is_ok())
+        });
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.stale.remove(key);
+        let entry = self.entries.remove(key);
+        if let Some(ref entry) = entry {
+            self.forget(key, entry);
+            let version = self.next_version();
+            self.removals.insert(key.to_vec(), version);
+        }
+        entry
+    }
+
+    /// Allocates the version for a write, or for a removal. Versions increase with every write
+    /// to the store, so a key's version changes whenever its value does.
+    fn next_version(&mut self) -> u64 {
+        self.last_version += 1;
+        self.last_version
     }
 }
 
-/// Handle the request. `Message` is a `Message::Request` variant from the front end.
-/// The response message should be a `Message::Response` variant.
-fn handle(store: &mut Store, message: Message) -> Result<Message, error::Error> {
-    let op = message.op();
-    let (key, payload) = message.consume_request()?;
+/// The entries a store keeps once they have expired, see `Options::stale_grace`. They are held
+/// apart from the live ones, up to as many entries as the store's capacity and as many bytes as
+/// its `max_bytes`, dropping the least recently used first.
+struct Stale {
+    entries: LruCache<Vec<u8>, Entry, KeyHasher>,
+    used_bytes: usize,
+}
 
-    let response = match op {
-        Op::Set => {
-            let key = key;
-            let payload = payload.ok_or_else(|| "no payload given to set op")?;
-            store.insert(key, payload);
-            message::response(Op::Set, Code::Ok, None)
+impl Stale {
+    fn new(capacity: usize) -> Self {
+        Stale {
+            entries: LruCache::with_hasher(capacity, KeyHasher::default()),
+            used_bytes: 0,
         }
+    }
 
-        Op::Get => {
-            if let Some(ref mut payload) = store.get_mut(key.as_slice()) {
-                message::response(Op::Get, Code::Hit, Some(payload.clone()))
-            } else {
-                message::response(Op::Get, Code::Miss, None)
+    fn insert(&mut self, key: Vec<u8>, entry: Entry, max_bytes: Option<usize>) {
+        let size = entry_size(&key, &entry.payload);
+        self.remove(&key);
+        if self.entries.capacity() == 0 || max_bytes.map_or(false, |max| size > max) {
+            return;
+        }
+        while self.entries.len() >= self.entries.capacity() ||
+            max_bytes.map_or(false, |max| self.used_bytes + size > max)
+        {
+            match self.entries.remove_lru() {
+                Some((key, entry)) => self.used_bytes -= entry_size(&key, &entry.payload),
+                None => break,
             }
         }
+        self.used_bytes += size;
+        self.entries.insert(key, entry);
+    }
 
-        // TODO
-        Op::Del => {
-            message::response(Op::Del, Code::Ok, None)
+    fn get(&mut self, key: &[u8]) -> Option<&Entry> {
+        self.entries.get_mut(key).map(|entry| &*entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.entries.remove(key);
+        if let Some(ref entry) = entry {
+            self.used_bytes -= entry_size(key, &entry.payload);
         }
-        Op::Stats => {
-            message::response(
-                Op::Stats,
-                Code::Ok,
-                Some(message::payload(store.len() as u32, vec![])),
-            )
+        entry
+    }
+
+    /// Drops the entries `keep` returns false for.
+    fn retain<F: Fn(&[u8], &Entry) -> bool>(&mut self, keep: F) {
+        let dropped: Vec<Vec<u8>> = self.entries
+            .iter()
+            .filter(|&(key, entry)| !keep(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in dropped {
+            self.remove(&key);
         }
-    };
+    }
 
-    Ok(response)
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.used_bytes = 0;
+    }
 }
 
-/// Creates a `Message::Response`, setting the error code and
-/// and passing the error description as the payload. Responses with an error code should
-/// enforce the invariant that the payload contain a UTF8-encoded string, so that clients
-/// can safely decode the payload for human consumption.
+/// The versions the most recently removed keys were removed at, so that a key watched while
+/// missing conflicts if it was created and removed again since. It holds up to as many keys as
+/// the store's capacity, forgetting the least recently removed first, after which any key
+/// watched before the latest version forgotten counts as removed since.
+struct Removals {
+    versions: LruCache<Vec<u8>, u64, KeyHasher>,
+    forgotten: u64,
+}
+
+impl Removals {
+    fn new(capacity: usize) -> Self {
+        Removals {
+            versions: LruCache::with_hasher(capacity, KeyHasher::default()),
+            forgotten: 0,
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, version: u64) {
+        if self.versions.len() >= self.versions.capacity() && !self.versions.contains_key(&key) {
+            match self.versions.remove_lru() {
+                Some((_, version)) => self.forgotten = version,
+                None => self.forgotten = version,
+            }
+        }
+        self.versions.insert(key, version);
+    }
+
+    /// Whether `key` may have been removed after `version`.
+    fn since(&mut self, key: &[u8], version: u64) -> bool {
+        self.forgotten > version || self.versions.get_mut(key).map_or(false, |v| *v > version)
+    }
+}
+
+/// The namespace of the stored key `stored`, `None` for the default one, and the key within it.
+fn split_key(stored: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    match namespace_of(stored) {
+        Some(name) => (Some(name), &stored[2 + name.len()..]),
+        None => (None, stored),
+    }
+}
+
+/// Builds an `op` request for the stored key `stored`, as a snapshot or the log holds it,
+/// addressing the key within its namespace.
+pub fn stored_request(op: Op, stored: &[u8], payload: Option<Payload>) -> Message {
+    let (namespace, key) = split_key(stored);
+    let req = message::request(op, key.to_vec(), payload);
+    match namespace {
+        Some(namespace) => req.with_extension(message::EXT_NAMESPACE, namespace.to_vec()),
+        None => req,
+    }
+}
+
+/// Starts the keys stored for a namespace other than the default one, followed by the length
+/// of the namespace's name as a u8, the name, and the key within the namespace. Requests in the
+/// default namespace naming keys starting with this byte are refused, so that they can't reach
+/// into another namespace, see `reserved_keys`.
+static NAMESPACE_MARKER: u8 = 0xFF;
+
+/// Whether `msg`, in the default namespace, names keys starting with `NAMESPACE_MARKER`.
+fn reserved_keys(msg: &Message) -> bool {
+    cluster::keys_of(msg).iter().any(|key| key.first() == Some(&NAMESPACE_MARKER))
+}
+
+/// The namespace a request addresses, see `message::EXT_NAMESPACE`, as the prefix of the keys
+/// stored for it. The default namespace has no prefix.
+struct Namespace(Option<Vec<u8>>);
+
+impl Namespace {
+    fn of(msg: &Message) -> Result<Self, error::Error> {
+        match msg.extension(message::EXT_NAMESPACE) {
+            Some(name) if name.len() > u8::max_value() as usize => Err(error::Error::new(
+                error::ErrorKind::InvalidData,
+                "namespace is longer than 255 bytes",
+            )),
+            Some(name) if !name.is_empty() => {
+                let mut prefix = Vec::with_capacity(2 + name.len());
+                prefix.push(NAMESPACE_MARKER);
+                prefix.push(name.len() as u8);
+                prefix.extend_from_slice(name);
+                Ok(Namespace(Some(prefix)))
+            }
+            _ => Ok(Namespace(None)),
+        }
+    }
+
+    /// The key `key` is stored under.
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        match self.0 {
+            Some(ref prefix) => [&prefix[..], key].concat(),
+            None => key.to_vec(),
+        }
+    }
+
+    /// The key within this namespace of the stored key `stored`, unless it's another
+    /// namespace's.
+    fn strip<'a>(&self, stored: &'a [u8]) -> Option<&'a [u8]> {
+        match self.0 {
+            Some(ref prefix) if stored.starts_with(prefix) => Some(&stored[prefix.len()..]),
+            Some(_) => None,
+            None if namespace_of(stored).is_some() => None,
+            None => Some(stored),
+        }
+    }
+}
+
+/// The name of the namespace the stored key `key` belongs to, or `None` for the default one.
+fn namespace_of(key: &[u8]) -> Option<&[u8]> {
+    match key.split_first() {
+        Some((&marker, rest)) if marker == NAMESPACE_MARKER && !rest.is_empty() && rest[0] > 0 &&
+            rest.len() > rest[0] as usize => Some(&rest[1..1 + rest[0] as usize]),
+        _ => None,
+    }
+}
+
+/// The bytes an entry counts against `Options::max_bytes`: its key and its value.
+fn entry_size(key: &[u8], payload: &Payload) -> usize {
+    key.len() + payload.data().len()
+}
+
+fn too_large() -> error::Error {
+    error::Error::new(error::ErrorKind::InvalidData, "entry is larger than the cache's max bytes")
+}
+
+/// Where a `Cache` keeps its entries, see `Cache::with_storage`. Only the cache's worker uses
+/// its storage, so implementations needn't synchronize. Entries are opaque to a storage, which
+/// only has to hand them back.
+pub trait Storage: Send + 'static {
+    /// The entry under `key`, marking it as the most recently used.
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry>;
+
+    /// Whether there is an entry under `key`, marking it as the most recently used.
+    fn contains_key(&mut self, key: &[u8]) -> bool {
+        self.get_mut(key).is_some()
+    }
+
+    /// The entry under `key`, without marking it as used, for requests that look at an entry
+    /// without reading its value, such as `Op::Inspect`. Storages should override it: this
+    /// looks through every entry.
+    fn peek(&self, key: &[u8]) -> Option<&Entry> {
+        self.iter().find(|&(k, _)| k.as_slice() == key).map(|(_, entry)| entry)
+    }
+
+    /// Stores `entry` under `key` as the most recently used, returning the entry it replaces.
+    fn insert(&mut self, key: Vec<u8>, entry: Entry) -> Option<Entry>;
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry>;
+
+    /// Removes the entry to evict to make room for another: the least recently used one, unless
+    /// the storage evicts by some other policy.
+    fn remove_lru(&mut self) -> Option<(Vec<u8>, Entry)>;
+
+    /// Visits every entry, the next to be evicted first, without marking them as used.
+    fn iter<'a>(&'a self) -> Box<Iterator<Item = (&'a Vec<u8>, &'a Entry)> + 'a>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The most entries the storage holds. The cache evicts entries to stay within it.
+    fn capacity(&self) -> usize;
+
+    /// Makes room for `additional` more entries ahead of storing them, as `Op::Restore` does,
+    /// if the storage can. `LruStorage` can't, its map growing as entries are stored.
+    fn reserve(&mut self, additional: usize) {
+        let _ = additional;
+    }
+
+    /// The name of the policy the storage evicts by, reported by `Op::Info`.
+    fn eviction_policy(&self) -> &str {
+        "custom"
+    }
+}
+
+/// The built-in eviction policies, see `Options::eviction`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict the least recently used entry, see `LruStorage`.
+    Lru,
+    /// Evict the least frequently used entry, see `LfuStorage`.
+    Lfu,
+    /// Evict the entry stored first, see `FifoStorage`.
+    Fifo,
+}
+
+impl EvictionPolicy {
+    /// The policy called `name`, as `name()` calls it.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [EvictionPolicy::Lru, EvictionPolicy::Lfu, EvictionPolicy::Fifo]
+            .iter()
+            .cloned()
+            .find(|policy| policy.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::Lfu => "lfu",
+            EvictionPolicy::Fifo => "fifo",
+        }
+    }
+}
+
+/// The hash functions the built-in storages can hash keys with, see `Options::hasher`.
 ///
-/// TODO: match over the error kind and translate it into an appropriate error for the front end.
-fn handle_error(err: &error::Error) -> Message {
-    message::response(
-        Op::Get,
-        Code::Error,
-        Some(message::payload(
-            0,
-            err.description().to_owned().into_bytes(),
-        )),
-    )
+/// SipHash, with a key picked at random for each cache, is the safe choice: clients can't tell
+/// which keys collide, so they can't send keys that all land in the same bucket and make every
+/// lookup slow. FxHash is much cheaper to compute, which shows on short keys, but the keys
+/// colliding under it are easy to find, so it is only safe when the clients are trusted. See
+/// the `bench_get_*` benchmarks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashFunction {
+    SipHash,
+    FxHash,
+}
+
+impl HashFunction {
+    /// The hash function called `name`, as `name()` calls it.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [HashFunction::SipHash, HashFunction::FxHash]
+            .iter()
+            .cloned()
+            .find(|function| function.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashFunction::SipHash => "siphash",
+            HashFunction::FxHash => "fxhash",
+        }
+    }
+}
+
+/// Builds the hashers of a `HashFunction`, for the maps of the built-in storages.
+#[derive(Clone)]
+pub struct KeyHasher {
+    function: HashFunction,
+    sip: RandomState,
+}
+
+impl KeyHasher {
+    pub fn new(function: HashFunction) -> Self {
+        KeyHasher {
+            function: function,
+            sip: RandomState::new(),
+        }
+    }
+
+    pub fn function(&self) -> HashFunction {
+        self.function
+    }
+}
+
+impl Default for KeyHasher {
+    fn default() -> Self {
+        KeyHasher::new(HashFunction::SipHash)
+    }
+}
+
+impl BuildHasher for KeyHasher {
+    type Hasher = KeyHash;
+
+    fn build_hasher(&self) -> KeyHash {
+        match self.function {
+            HashFunction::SipHash => KeyHash::Sip(self.sip.build_hasher()),
+            HashFunction::FxHash => KeyHash::Fx(FxHasher::default()),
+        }
+    }
+}
+
+/// A hasher built by a `KeyHasher`.
+pub enum KeyHash {
+    Sip(DefaultHasher),
+    Fx(FxHasher),
+}
+
+impl Hasher for KeyHash {
+    fn write(&mut self, bytes: &[u8]) {
+        match *self {
+            KeyHash::Sip(ref mut hasher) => hasher.write(bytes),
+            KeyHash::Fx(ref mut hasher) => hasher.write(bytes),
+        }
+    }
+
+    // Keys are hashed as their length and then their bytes, so the length is passed on whole
+    // rather than as bytes, which FxHash takes faster.
+    fn write_usize(&mut self, n: usize) {
+        match *self {
+            KeyHash::Sip(ref mut hasher) => hasher.write_usize(n),
+            KeyHash::Fx(ref mut hasher) => hasher.write_usize(n),
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        match *self {
+            KeyHash::Sip(ref hasher) => hasher.finish(),
+            KeyHash::Fx(ref hasher) => hasher.finish(),
+        }
+    }
+}
+
+/// The default `Storage`, a hash map ordered by use, of at most a fixed number of entries.
+pub struct LruStorage {
+    /// The least recently used first.
+    entries: LinkedHashMap<Vec<u8>, Entry, KeyHasher>,
+    capacity: usize,
+}
+
+impl LruStorage {
+    pub fn new(capacity: usize) -> Self {
+        LruStorage::with_hasher(capacity, KeyHasher::default())
+    }
+
+    /// Hashes keys with `hasher` rather than SipHash.
+    pub fn with_hasher(capacity: usize, hasher: KeyHasher) -> Self {
+        LruStorage {
+            entries: LinkedHashMap::with_hasher(hasher),
+            capacity: capacity,
+        }
+    }
+}
+
+impl Storage for LruStorage {
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        self.entries.get_refresh(key)
+    }
+
+    fn peek(&self, key: &[u8]) -> Option<&Entry> {
+        self.entries.get(key)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, entry: Entry) -> Option<Entry> {
+        let old = self.entries.insert(key, entry);
+        if self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+        old
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.entries.remove(key)
+    }
+
+    fn remove_lru(&mut self) -> Option<(Vec<u8>, Entry)> {
+        self.entries.pop_front()
+    }
+
+    fn iter<'a>(&'a self) -> Box<Iterator<Item = (&'a Vec<u8>, &'a Entry)> + 'a> {
+        Box::new(self.entries.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn eviction_policy(&self) -> &str {
+        EvictionPolicy::Lru.name()
+    }
+}
+
+/// Entries ordered by a rank, lowest first, for the storages evicting by something other than
+/// recency. Ranks are unique.
+struct Ranked {
+    ranks: HashMap<Vec<u8>, (u64, u64), KeyHasher>,
+    entries: BTreeMap<(u64, u64), (Vec<u8>, Entry)>,
+}
+
+impl Ranked {
+    fn new(hasher: KeyHasher) -> Self {
+        Ranked {
+            ranks: HashMap::with_hasher(hasher),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn rank(&self, key: &[u8]) -> Option<(u64, u64)> {
+        self.ranks.get(key).cloned()
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Entry> {
+        self.rank(key).and_then(|rank| self.entries.get(&rank)).map(|&(_, ref entry)| entry)
+    }
+
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        match self.rank(key) {
+            Some(rank) => self.entries.get_mut(&rank).map(|&mut (_, ref mut entry)| entry),
+            None => None,
+        }
+    }
+
+    /// Moves the entry under `key` to `rank`.
+    fn rerank(&mut self, key: &[u8], rank: (u64, u64)) -> Option<&mut Entry> {
+        let (key, entry) = match self.rank(key) {
+            Some(old) => self.entries.remove(&old).unwrap(),
+            None => return None,
+        };
+        self.ranks.insert(key.clone(), rank);
+        self.entries.insert(rank, (key, entry));
+        self.entries.get_mut(&rank).map(|&mut (_, ref mut entry)| entry)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, rank: (u64, u64), entry: Entry) -> Option<Entry> {
+        let old = self.remove(&key);
+        self.ranks.insert(key.clone(), rank);
+        self.entries.insert(rank, (key, entry));
+        old
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.ranks.remove(key).and_then(|rank| self.entries.remove(&rank)).map(|(_, e)| e)
+    }
+
+    fn remove_first(&mut self) -> Option<(Vec<u8>, Entry)> {
+        let first = self.entries.keys().next().cloned();
+        first.and_then(|rank| self.entries.remove(&rank)).map(|(key, entry)| {
+            self.ranks.remove(&key);
+            (key, entry)
+        })
+    }
+
+    fn iter<'a>(&'a self) -> Box<Iterator<Item = (&'a Vec<u8>, &'a Entry)> + 'a> {
+        Box::new(self.entries.values().map(|&(ref key, ref entry)| (key, entry)))
+    }
+
+    fn len(&self) -> usize {
+        self.ranks.len()
+    }
+}
+
+/// The uses after which an `LfuStorage` halves every count of uses, in multiples of its capacity.
+static LFU_DECAY_PERIOD: u64 = 10;
+
+/// A `Storage` evicting the least frequently used entry, the least recently used of those used
+/// as often. An entry's uses count the lookups since it was stored. So that entries popular once
+/// don't stay forever, every count is halved after the storage has been used `LFU_DECAY_PERIOD`
+/// times its capacity.
+pub struct LfuStorage {
+    entries: Ranked,
+    capacity: usize,
+    /// Counts every use, telling apart the entries used as often.
+    uses: u64,
+    /// The uses since the counts were last halved.
+    since_decay: u64,
+}
+
+impl LfuStorage {
+    pub fn new(capacity: usize) -> Self {
+        LfuStorage::with_hasher(capacity, KeyHasher::default())
+    }
+
+    /// Hashes keys with `hasher` rather than SipHash.
+    pub fn with_hasher(capacity: usize, hasher: KeyHasher) -> Self {
+        LfuStorage {
+            entries: Ranked::new(hasher),
+            capacity: capacity,
+            uses: 0,
+            since_decay: 0,
+        }
+    }
+
+    /// Counts a use, halving every count of uses once that is due.
+    fn use_once(&mut self) {
+        self.uses += 1;
+        self.since_decay += 1;
+        if self.since_decay >= LFU_DECAY_PERIOD * self.capacity.max(1) as u64 {
+            self.since_decay = 0;
+            let entries = ::std::mem::replace(&mut self.entries.entries, BTreeMap::new());
+            for ((count, last), (key, entry)) in entries {
+                self.entries.insert(key, (count / 2, last), entry);
+            }
+        }
+    }
+}
+
+impl Storage for LfuStorage {
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        if !self.contains_key(key) {
+            return None;
+        }
+        self.use_once();
+        let count = self.entries.rank(key).map_or(0, |(count, _)| count);
+        self.entries.rerank(key, (count.saturating_add(1), self.uses))
+    }
+
+    fn contains_key(&mut self, key: &[u8]) -> bool {
+        self.entries.rank(key).is_some()
+    }
+
+    fn peek(&self, key: &[u8]) -> Option<&Entry> {
+        self.entries.get(key)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, entry: Entry) -> Option<Entry> {
+        self.use_once();
+        self.entries.insert(key, (1, self.uses), entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.entries.remove(key)
+    }
+
+    fn remove_lru(&mut self) -> Option<(Vec<u8>, Entry)> {
+        self.entries.remove_first()
+    }
+
+    fn iter<'a>(&'a self) -> Box<Iterator<Item = (&'a Vec<u8>, &'a Entry)> + 'a> {
+        self.entries.iter()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.entries.ranks.reserve(additional);
+    }
+
+    fn eviction_policy(&self) -> &str {
+        EvictionPolicy::Lfu.name()
+    }
+}
+
+/// A `Storage` evicting the entry stored longest ago, however much it was used since.
+pub struct FifoStorage {
+    entries: Ranked,
+    capacity: usize,
+    inserts: u64,
+}
+
+impl FifoStorage {
+    pub fn new(capacity: usize) -> Self {
+        FifoStorage::with_hasher(capacity, KeyHasher::default())
+    }
+
+    /// Hashes keys with `hasher` rather than SipHash.
+    pub fn with_hasher(capacity: usize, hasher: KeyHasher) -> Self {
+        FifoStorage {
+            entries: Ranked::new(hasher),
+            capacity: capacity,
+            inserts: 0,
+        }
+    }
+}
+
+impl Storage for FifoStorage {
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        self.entries.get_mut(key)
+    }
+
+    fn peek(&self, key: &[u8]) -> Option<&Entry> {
+        self.entries.get(key)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, entry: Entry) -> Option<Entry> {
+        self.inserts += 1;
+        self.entries.insert(key, (0, self.inserts), entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.entries.remove(key)
+    }
+
+    fn remove_lru(&mut self) -> Option<(Vec<u8>, Entry)> {
+        self.entries.remove_first()
+    }
+
+    fn iter<'a>(&'a self) -> Box<Iterator<Item = (&'a Vec<u8>, &'a Entry)> + 'a> {
+        self.entries.iter()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.entries.ranks.reserve(additional);
+    }
+
+    fn eviction_policy(&self) -> &str {
+        EvictionPolicy::Fifo.name()
+    }
+}
+
+/// A stored value along with the bookkeeping the cache keeps for it. Opaque outside the cache.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Entry {
+    payload: Payload,
+    version: u64,
+    /// The idempotency token of the last Set applied to this key, if it carried one.
+    token: Option<Vec<u8>>,
+    /// When the entry stops being visible, if ever.
+    expires_at: Option<Timespec>,
+    /// The `Options::ttl_jitter` its expiry was spread by, if it was set from a TTL while the
+    /// cache spread them.
+    jitter: Option<u32>,
+    /// When the key was last Set, or created by an `Op::Apply`.
+    inserted_at: Timespec,
+    /// When the value was last read, if it was since it was last written.
+    accessed_at: Option<Timespec>,
+    /// The reads of the value since it was last written.
+    accesses: u64,
+}
+
+impl Entry {
+    /// An entry for a value written at `inserted_at`, not read since.
+    fn new(
+        payload: Payload,
+        version: u64,
+        expires_at: Option<Timespec>,
+        inserted_at: Timespec,
+    ) -> Self {
+        Entry {
+            payload: payload,
+            version: version,
+            token: None,
+            expires_at: expires_at,
+            jitter: None,
+            inserted_at: inserted_at,
+            accessed_at: None,
+            accesses: 0,
+        }
+    }
+
+    fn expired(&self, now: Timespec) -> bool {
+        self.expires_at.map_or(false, |at| now >= at)
+    }
+
+    /// Counts a read of the value, which `Op::Inspect` reports.
+    fn read(&mut self, now: Timespec) {
+        self.accessed_at = Some(now);
+        self.accesses += 1;
+    }
+}
+
+enum Work {
+    /// A request, and where to send its response.
+    Request(Sender<Message>, Message),
+    /// A request for a copy of every live entry, for `Cache::for_each`.
+    Snapshot(Sender<Vec<(Vec<u8>, Payload, Option<Timespec>)>>),
+    /// A request for a copy of every live entry for `Cache::save_to`, marked in the log.
+    Checkpoint(Sender<io::Result<(u64, Vec<(Vec<u8>, Payload, Option<Timespec>)>)>>),
+    CompactLog(Sender<io::Result<()>>),
+    /// The outcome of loading a stored key for the Get that missed on it, see `Options::loader`.
+    Loaded(Vec<u8>, Message, io::Result<Option<Payload>>),
+    /// A request for a `Feed` of the writes to the store, for `Cache::replicate`.
+    Replicate(Sender<Feed>),
+    /// Sent when the `Cache` is dropped, since loads in flight keep the queue open.
+    Stop,
+}
+
+/// Called with a description of the panic whenever handling a request panics.
+pub type PanicHook = Arc<Fn(&str) + Send + Sync>;
+
+/// A read-modify-write function for `Op::Apply`. Called with the current value of the key, if
+/// any, and the argument from the request, it returns the new value, or `None` to delete the key.
+pub type ApplyFn = Arc<Fn(Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// Fetches the value of a key a Get missed on, see `Options::loader`. Called with the namespace
+/// of the key, `None` for the default one, and the key within it, it returns a future resolving
+/// to the value, or to `None` if there is none.
+pub type LoadFn = Arc<Fn(Option<&[u8]>, &[u8]) -> LoadFuture + Send + Sync>;
+
+/// The future returned by a `LoadFn`. It runs on the cache's pool of `Options::load_threads`.
+pub type LoadFuture = Box<Future<Item = Option<Payload>, Error = io::Error> + Send>;
+
+/// Payload `type_id` flag on a `Op::Rename` request allowing the destination to be overwritten.
+pub static RENAME_OVERWRITE: u32 = 1;
+
+/// The most keys a single `Op::Sample` request will return.
+pub static MAX_SAMPLE: usize = 1000;
+
+/// Payload `type_id` flag on a `Op::Scan` request marking the key as the cursor to resume after.
+/// Without it the scan starts from the smallest key.
+pub static SCAN_AFTER: u32 = 1;
+
+/// The most keys a single `Op::Scan` request will return.
+pub static MAX_SCAN: usize = 1000;
+
+/// The most keys a single multi-key request may name.
+pub static MAX_MULTI_KEYS: usize = 1000;
+
+/// The longest TTL a request may give, in seconds: about a hundred years. Longer ones are refused
+/// rather than overflow the expiry.
+pub static MAX_TTL: u64 = 100 * 365 * 24 * 60 * 60;
+
+/// A TTL of `secs` seconds, refusing one longer than `MAX_TTL`.
+fn checked_ttl(secs: u64) -> Result<Duration, error::Error> {
+    if secs > MAX_TTL {
+        return Err(error::Error::new(error::ErrorKind::InvalidData, "ttl is too long"));
+    }
+    Ok(Duration::seconds(secs as i64))
+}
+
+/// Payload `type_id` flag on a `Op::Range` request asking for the newest keys rather than the
+/// oldest.
+pub static RANGE_NEWEST: u32 = 1;
+
+/// The most keys a single `Op::Range` request will return.
+pub static MAX_RANGE: usize = 1000;
+
+/// The most keys a single `Op::HotKeys` request will return.
+pub static MAX_HOT_KEYS: usize = 1000;
+
+/// The hot keys `Op::Stats` reports, see `Options::hot_keys`.
+pub static HOT_KEYS_IN_STATS: usize = 10;
+
+/// Unpacks the keys and their ages, in milliseconds, from the payload of an `Op::Range`
+/// response, or the keys and their request counts from that of an `Op::HotKeys` response. Each
+/// key is packed as its age or count as a u64, followed by its length as a u32 and the key.
+pub fn decode_range(data: &[u8]) -> Result<Vec<(Vec<u8>, u64)>, error::Error> {
+    let truncated = || error::Error::new(error::ErrorKind::InvalidData, "truncated range");
+    let mut keys = vec![];
+    let mut cursor = io::Cursor::new(data);
+    while cursor.remaining() > 0 {
+        if cursor.remaining() < 8 + 4 {
+            return Err(truncated());
+        }
+        let age = cursor.get_u64::<BigEndian>();
+        let len = cursor.get_u32::<BigEndian>() as usize;
+        if cursor.remaining() < len {
+            return Err(truncated());
+        }
+        let mut key = vec![0; len];
+        cursor.copy_to_slice(&mut key);
+        keys.push((key, age));
+    }
+    Ok(keys)
+}
+
+/// Payload `type_id` flag on an `Op::Get` request taking a value that expired within the cache's
+/// `Options::stale_grace` over a miss, answered with `Code::Stale`, see `message::flags`.
+pub static GET_STALE: u32 = 1;
+
+/// Builds an `Op::Get` request for `key` that takes a stale value over a miss, see `GET_STALE`.
+pub fn get_stale_request(key: Vec<u8>) -> Message {
+    message::request(Op::Get, key, Some(message::flags(GET_STALE)))
+}
+
+/// `type_id` of the marker stored for a key known to be missing from wherever the cache is in
+/// front of, see `not_found_request` and `Options::negative_ttl`. A Get finding it answers with
+/// `Code::NotFound` rather than a hit, and without asking the `Options::loader` again; other
+/// requests see it as a value of a single zero byte, there so that the marker's `type_id` is
+/// sent over the wire along with it.
+pub const NOT_FOUND_TYPE_ID: u32 = 25;
+
+/// The marker payload, see `NOT_FOUND_TYPE_ID`.
+pub fn not_found() -> Payload {
+    message::payload(NOT_FOUND_TYPE_ID, vec![0])
+}
+
+/// Builds an `Op::Set` request marking `key` as missing for `ttl` seconds, see
+/// `NOT_FOUND_TYPE_ID`.
+pub fn not_found_request(key: Vec<u8>, ttl: u64) -> Message {
+    message::request(Op::Set, key, Some(not_found()))
+        .with_extension(message::EXT_TTL, message::encode_u64(ttl))
+}
+
+/// Payload `type_id` flag on a `Op::Dump` request marking the key to resume after as following
+/// the counts. Without it the dump starts from the smallest key.
+pub static DUMP_AFTER: u32 = 1;
+
+/// The most entries a single page of an `Op::Dump` will hold.
+pub static MAX_DUMP: usize = 1000;
+
+/// `type_id` of the payload answering an `Op::Dump`, as packed by `encode_dump`.
+pub const DUMP_TYPE_ID: u32 = 24;
+
+/// Builds an `Op::Dump` request for the entries whose keys start with `prefix`, `count` to a
+/// page, following `after`. Sent to a server, it is answered with a stream of pages sent at
+/// most `per_second` entries a second, 0 for no limit, see `service::dump_stream`; the cache
+/// itself answers with a single page, and ignores `per_second`.
+pub fn dump_request(prefix: &[u8], after: Option<&[u8]>, count: u32, per_second: u32) -> Message {
+    let mut data = vec![];
+    data.put_u32::<BigEndian>(count);
+    data.put_u32::<BigEndian>(per_second);
+    data.put_slice(after.unwrap_or_default());
+    let flag = if after.is_some() { DUMP_AFTER } else { 0 };
+    message::request(Op::Dump, prefix.to_vec(), Some(message::payload(flag, data)))
+}
+
+/// Decodes the payload of a `dump_request` into the count, the rate and the key to resume
+/// after.
+pub fn decode_dump_request(payload: &Payload) -> Result<(u32, u32, Option<Vec<u8>>), error::Error> {
+    let data = payload.data();
+    if data.len() < 8 {
+        return Err(error::Error::new(error::ErrorKind::InvalidData, "invalid dump request"));
+    }
+    let mut cursor = io::Cursor::new(&data[..8]);
+    let (count, per_second) = (cursor.get_u32::<BigEndian>(), cursor.get_u32::<BigEndian>());
+    let after = if payload.type_id() == DUMP_AFTER {
+        Some(data[8..].to_vec())
+    } else {
+        None
+    };
+    Ok((count, per_second, after))
+}
+
+/// Packs the entries of a page of an `Op::Dump`, all integers big endian. For each entry: its key
+/// as a u32 length prefixed byte string, its value's `type_id` as a u32, the seconds it has left
+/// to live as a u64, 0 if it doesn't expire, and its value's data as a u64 length prefixed byte
+/// string.
+pub fn encode_dump(entries: &[(Vec<u8>, Payload, Option<u64>)]) -> Vec<u8> {
+    let mut data = vec![];
+    for &(ref key, ref payload, ttl) in entries {
+        data.put_u32::<BigEndian>(key.len() as u32);
+        data.put_slice(key);
+        data.put_u32::<BigEndian>(payload.type_id());
+        data.put_u64::<BigEndian>(ttl.unwrap_or(0));
+        data.put_u64::<BigEndian>(payload.data().len() as u64);
+        data.put_slice(payload.data());
+    }
+    data
+}
+
+/// Payload `type_id` flag on an `Op::Restore` request replacing the entries already under the
+/// keys restored. Without it, those keys are skipped.
+pub static RESTORE_REPLACE: u32 = 1;
+
+/// Builds an `Op::Restore` request storing `entries`, as a page of an `Op::Dump` holds them, each
+/// living for the seconds given, if any, up to `MAX_TTL`. Entries that can't be stored, being
+/// too large or over a quota, are skipped, as are those whose key is taken unless `replace` is
+/// set. Like other writes, the request is refused with `Code::KeyTooLarge` if any key is over
+/// `Options::max_key_len`, and `Code::ValueTooLarge` if any value is over
+/// `Options::max_value_len`. The response payload holds the number of entries stored, as a u64.
+pub fn restore_request(entries: &[(Vec<u8>, Payload, Option<u64>)], replace: bool) -> Message {
+    let flag = if replace { RESTORE_REPLACE } else { 0 };
+    let payload = message::payload(flag, encode_dump(entries));
+    message::request(Op::Restore, vec![], Some(payload))
+}
+
+/// Unpacks the entries packed by `encode_dump`, with the seconds each has left to live.
+pub fn decode_dump(data: &[u8]) -> Result<Vec<(Vec<u8>, Payload, Option<u64>)>, error::Error> {
+    let truncated = || error::Error::new(error::ErrorKind::InvalidData, "truncated dump");
+    let mut entries = vec![];
+    let mut cursor = io::Cursor::new(data);
+    while cursor.remaining() > 0 {
+        if cursor.remaining() < 4 {
+            return Err(truncated());
+        }
+        let len = cursor.get_u32::<BigEndian>() as usize;
+        if cursor.remaining() < len || cursor.remaining() - len < 4 + 8 + 8 {
+            return Err(truncated());
+        }
+        let mut key = vec![0; len];
+        cursor.copy_to_slice(&mut key);
+        let type_id = cursor.get_u32::<BigEndian>();
+        let ttl = Some(cursor.get_u64::<BigEndian>()).filter(|&ttl| ttl > 0);
+        let len = cursor.get_u64::<BigEndian>() as usize;
+        if cursor.remaining() < len {
+            return Err(truncated());
+        }
+        let mut value = vec![0; len];
+        cursor.copy_to_slice(&mut value);
+        entries.push((key, message::payload(type_id, value), ttl));
+    }
+    Ok(entries)
+}
+
+/// What the store reports in response to `Op::Stats`, see `decode_stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheStats {
+    pub keys: u32,
+    pub evictions: u64,
+    /// The total size of the stored keys and values, as counted against `Options::max_bytes`.
+    pub used_bytes: u64,
+    /// The keys requested most lately, with how often, most requested first. Empty unless the
+    /// cache tracks them, see `Options::hot_keys`.
+    pub hot_keys: Vec<(Vec<u8>, u64)>,
+}
+
+/// Unpacks the payload of an `Op::Stats` response. Its `type_id` is the number of keys, and its
+/// data the number of evictions followed by the bytes used, each a u64, then the hot keys packed
+/// as by `Op::HotKeys`, see `decode_range`.
+pub fn decode_stats(payload: &Payload) -> Result<CacheStats, error::Error> {
+    let data = payload.data();
+    if data.len() < 8 + 8 {
+        return Err(error::Error::new(error::ErrorKind::InvalidData, "expected cache stats"));
+    }
+    Ok(CacheStats {
+        keys: payload.type_id(),
+        evictions: message::decode_u64(&data[..8])?,
+        used_bytes: message::decode_u64(&data[8..16])?,
+        hot_keys: decode_range(&data[16..])?,
+    })
+}
+
+/// The longest keys and values a cache takes, see `Options::max_key_len` and
+/// `Options::max_value_len`, as advertised by the responses to `Op::Hello` and `Op::Stats`.
+/// `None` where unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Limits {
+    pub max_key_len: Option<usize>,
+    pub max_value_len: Option<usize>,
+}
+
+/// `resp` advertising `limits`, with the `message::EXT_MAX_KEY_LEN` and
+/// `message::EXT_MAX_VALUE_LEN` extensions, each only if limited.
+pub fn with_limits(mut resp: Message, limits: &Limits) -> Message {
+    if let Some(max) = limits.max_key_len {
+        resp = resp.with_extension(message::EXT_MAX_KEY_LEN, message::encode_u64(max as u64));
+    }
+    if let Some(max) = limits.max_value_len {
+        resp = resp.with_extension(message::EXT_MAX_VALUE_LEN, message::encode_u64(max as u64));
+    }
+    resp
+}
+
+/// The limits advertised by `resp`, see `with_limits`.
+pub fn decode_limits(resp: &Message) -> Result<Limits, error::Error> {
+    let limit = |ext| -> Result<Option<usize>, error::Error> {
+        match resp.extension(ext) {
+            Some(max) => Ok(Some(message::decode_u64(max)? as usize)),
+            None => Ok(None),
+        }
+    };
+    Ok(Limits {
+        max_key_len: limit(message::EXT_MAX_KEY_LEN)?,
+        max_value_len: limit(message::EXT_MAX_VALUE_LEN)?,
+    })
+}
+
+/// How long a key has left to live, as answered to `Op::Ttl`, see `decode_ttl`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ttl {
+    /// There is no such key.
+    Missing,
+    /// The key never expires.
+    Forever,
+    /// The key expires in this many seconds, rounded down.
+    Seconds(u64),
+}
+
+/// Reads the response to an `Op::Ttl`: a `Code::Miss` for a missing key, or a `Code::Hit`
+/// carrying the seconds left as a u64, without a payload if the key never expires.
+pub fn decode_ttl(resp: &Message) -> Result<Ttl, error::Error> {
+    match (resp.code(), resp.payload()) {
+        (Code::Miss, _) => Ok(Ttl::Missing),
+        (Code::Hit, None) => Ok(Ttl::Forever),
+        (Code::Hit, Some(payload)) => Ok(Ttl::Seconds(message::decode_u64(payload.data())?)),
+        (code, _) => {
+            let unexpected = format!("unexpected response to ttl: {}", code);
+            Err(error::Error::new(error::ErrorKind::InvalidData, &unexpected))
+        }
+    }
+}
+
+/// What `Op::Inspect` tells about a key, see `decode_inspect`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryInfo {
+    /// The size of the value, in bytes.
+    pub size: u64,
+    pub type_id: u32,
+    pub version: u64,
+    /// When the key was last Set, or created by an `Op::Apply`.
+    pub stored_at: Timespec,
+    /// When the value was last read, if it was since it was stored.
+    pub accessed_at: Option<Timespec>,
+    /// The reads of the value since it was stored.
+    pub accesses: u64,
+    /// The seconds the key has left to live, rounded down, if it expires.
+    pub ttl: Option<u64>,
+    /// How far the key's TTL was spread, as a percentage either way, if its expiry was set from
+    /// a TTL while the cache spread them, see `Options::ttl_jitter`. Expiry times given outright,
+    /// and those of a Touch, aren't spread.
+    pub ttl_jitter: Option<u32>,
+}
+
+/// Packs what `Op::Inspect` tells about `entry`, all integers big endian: the size of its value
+/// as a u64, its `type_id` as a u32, its version as a u64, when it was stored and last read in
+/// milliseconds since the Unix epoch as u64s, 0 if it wasn't read, how often it was read as a
+/// u64, and if it expires, the seconds it has left to live as a u64, followed by the jitter its
+/// TTL was spread by as a u32 if it was.
+fn encode_inspect(entry: &Entry, now: Timespec) -> Vec<u8> {
+    let millis = |at: Timespec| at.sec as u64 * 1000 + at.nsec as u64 / 1000000;
+    let mut data = vec![];
+    data.put_u64::<BigEndian>(entry.payload.data().len() as u64);
+    data.put_u32::<BigEndian>(entry.payload.type_id());
+    data.put_u64::<BigEndian>(entry.version);
+    data.put_u64::<BigEndian>(millis(entry.inserted_at));
+    data.put_u64::<BigEndian>(entry.accessed_at.map_or(0, millis));
+    data.put_u64::<BigEndian>(entry.accesses);
+    if let Some(at) = entry.expires_at {
+        data.put_u64::<BigEndian>((at - now).num_seconds().max(0) as u64);
+        if let Some(percent) = entry.jitter {
+            data.put_u32::<BigEndian>(percent);
+        }
+    }
+    data
+}
+
+/// Unpacks the payload of an `Op::Inspect` response, which is a `Code::Hit`, or a `Code::Miss`
+/// without a payload for a missing key.
+pub fn decode_inspect(payload: &Payload) -> Result<EntryInfo, error::Error> {
+    let data = payload.data();
+    if data.len() != 8 * 5 + 4 && data.len() != 8 * 6 + 4 && data.len() != 8 * 6 + 4 + 4 {
+        return Err(error::Error::new(error::ErrorKind::InvalidData, "expected entry info"));
+    }
+    let at = |millis: u64| Timespec::new((millis / 1000) as i64, (millis % 1000) as i32 * 1000000);
+    let mut cursor = io::Cursor::new(data);
+    let size = cursor.get_u64::<BigEndian>();
+    let type_id = cursor.get_u32::<BigEndian>();
+    let version = cursor.get_u64::<BigEndian>();
+    let stored_at = at(cursor.get_u64::<BigEndian>());
+    let accessed_at = match cursor.get_u64::<BigEndian>() {
+        0 => None,
+        millis => Some(at(millis)),
+    };
+    let accesses = cursor.get_u64::<BigEndian>();
+    let ttl = if cursor.remaining() > 0 { Some(cursor.get_u64::<BigEndian>()) } else { None };
+    let ttl_jitter = if cursor.remaining() > 0 {
+        Some(cursor.get_u32::<BigEndian>())
+    } else {
+        None
+    };
+    Ok(EntryInfo {
+        size: size,
+        type_id: type_id,
+        version: version,
+        stored_at: stored_at,
+        accessed_at: accessed_at,
+        accesses: accesses,
+        ttl: ttl,
+        ttl_jitter: ttl_jitter,
+    })
+}
+
+/// Length of an `Op::FieldIncr` descriptor, see `field_incr_request`.
+static FIELD_DESCRIPTOR_LEN: usize = 4 + 1 + 8;
+
+/// Builds an `Op::FieldIncr` request adding `delta` to the `width` byte field at `offset` in the
+/// value of `key`.
+///
+/// The payload data is the field descriptor: the offset as a u32, the width as a u8, and the
+/// delta as an i64, all big endian. The field is read as a big endian unsigned integer of 1, 2, 4
+/// or 8 bytes, and the addition wraps around within that width.
+pub fn field_incr_request(key: Vec<u8>, offset: u32, width: u8, delta: i64) -> Message {
+    let mut data = Vec::with_capacity(FIELD_DESCRIPTOR_LEN);
+    data.put_u32::<BigEndian>(offset);
+    data.put_u8(width);
+    data.put_i64::<BigEndian>(delta);
+    message::request(Op::FieldIncr, key, Some(message::payload(0, data)))
+}
+
+/// Builds an `Op::Scan` request for up to `count` keys following `after`.
+pub fn scan_request(after: Option<Vec<u8>>, count: u32) -> Message {
+    let flag = if after.is_some() { SCAN_AFTER } else { 0 };
+    message::request(
+        Op::Scan,
+        after.unwrap_or_default(),
+        Some(message::payload(flag, message::encode_u32(count))),
+    )
+}
+
+/// Builds an `Op::Scan` request for up to `count` keys following `after` that match `pattern`,
+/// see `message::EXT_PATTERN`.
+pub fn scan_matching_request(after: Option<Vec<u8>>, count: u32, pattern: &[u8]) -> Message {
+    scan_request(after, count).with_extension(message::EXT_PATTERN, pattern.to_vec())
+}
+
+/// The ops an `Op::Batch` may hold.
+pub static BATCH_OPS: &'static [Op] = &[
+    Op::Set,
+    Op::Add,
+    Op::Replace,
+    Op::Del,
+    Op::Incr,
+    Op::Decr,
+    Op::Get,
+    Op::Watch,
+];
+
+/// Builds an `Op::Batch` request applying the requests in `ops`, each one of `BATCH_OPS`, in
+/// order and with nothing else in between. If one fails, being answered with anything but
+/// `Code::Ok`, a hit or miss for a Get, or `Code::Miss` for a Del, the keys the batch names are
+/// put back as they were and the rest isn't applied, although entries evicted meanwhile stay
+/// evicted. The batch is answered with the first failing code, or `Code::Ok`, and the responses
+/// to the ops attempted, packed by `message::encode_messages`. The ops are in the batch's
+/// namespace, and a batch holding one that names a namespace of its own is refused.
+pub fn batch_request(ops: &[Message]) -> Message {
+    let payload = message::payload(message::MESSAGE_LIST_TYPE_ID, message::encode_messages(ops));
+    message::request(Op::Batch, vec![], Some(payload))
+}
+
+/// The ops of the `Op::Batch` `batch`, each addressing the batch's namespace, see
+/// `batch_request`.
+fn batched_ops(batch: &Message) -> Result<Vec<Message>, error::Error> {
+    let ops = batch.payload().ok_or_else(|| "no ops given to batch op")?;
+    let ops = message::decode_messages(ops.data())?;
+    if ops.iter().any(|op| op.extension(message::EXT_NAMESPACE).is_some()) {
+        return Err(error::Error::new(
+            error::ErrorKind::InvalidData,
+            "batch op holds an op naming a namespace",
+        ));
+    }
+    Ok(match batch.extension(message::EXT_NAMESPACE) {
+        Some(namespace) => {
+            ops.into_iter()
+                .map(|op| op.with_extension(message::EXT_NAMESPACE, namespace.to_vec()))
+                .collect()
+        }
+        None => ops,
+    })
+}
+
+/// A completed cache operation, as published to an `Observer`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Event {
+    pub op: Op,
+    pub key: Vec<u8>,
+    /// The code the operation was answered with.
+    pub code: Code,
+}
+
+/// What an `Observer` does with an event when its buffer is full.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Overflow {
+    /// Drop the event, so a slow observer never holds up the cache.
+    Drop,
+    /// Wait for the observer to make room. Every request waits on a slow observer.
+    Block,
+}
+
+/// Receives an `Event` after each operation the cache completes, through a channel buffering up
+/// to a fixed number of events.
+#[derive(Clone)]
+pub struct Observer {
+    sender: mpsc::SyncSender<Event>,
+    overflow: Overflow,
+}
+
+impl Observer {
+    /// Creates an observer buffering up to `bound` events, and the receiving end of its events.
+    pub fn new(bound: usize, overflow: Overflow) -> (Self, mpsc::Receiver<Event>) {
+        let (sender, receiver) = mpsc::sync_channel(bound);
+        let observer = Observer {
+            sender: sender,
+            overflow: overflow,
+        };
+        (observer, receiver)
+    }
+
+    /// Publishes `event`. Once the receiver hangs up, events are discarded.
+    fn publish(&self, event: Event) {
+        let _ = match self.overflow {
+            Overflow::Drop => self.sender.try_send(event).map_err(|_| ()),
+            Overflow::Block => self.sender.send(event).map_err(|_| ()),
+        };
+    }
+}
+
+/// Options for a `Cache`.
+pub struct Options {
+    /// Called whenever handling a request panics. The request is answered with `Code::ServerError`
+    /// and the worker carries on with the next request; the store is left as the panicking
+    /// operation left it.
+    pub panic_hook: PanicHook,
+    /// The time source for the cache, which middlewares in front of it can share.
+    pub clock: Arc<Clock>,
+    /// The functions `Op::Apply` requests can name.
+    pub functions: HashMap<String, ApplyFn>,
+    /// A ceiling on the total bytes of keys and values stored. A write that would go over it
+    /// evicts the least recently used entries until it fits, and a write too large to fit even
+    /// in an empty cache is rejected with `Code::Error`. Unlimited by default.
+    pub max_bytes: Option<usize>,
+    /// Limits on the number of keys starting with a prefix, as pairs of the prefix and the most
+    /// keys it may hold. A write that would create a key past the quota of any prefix it starts
+    /// with is refused with `Code::QuotaExceeded`; writes to existing keys are unaffected.
+    pub prefix_quotas: Vec<(Vec<u8>, usize)>,
+    /// Told about every operation once it completes, including those that fail.
+    pub observer: Option<Observer>,
+    /// How often the worker looks through the whole store for expired entries, in between
+    /// requests. Expired entries are never returned either way, but without sweeping they are
+    /// only removed once a request touches them or they're evicted.
+    pub sweep_interval: Option<Duration>,
+    /// Keep an append-only log of writes, which is replayed when the cache is created.
+    pub log: Option<LogOptions>,
+    /// Restore the snapshot saved by `Cache::save_to` at this path when the cache is created, if
+    /// there is one. With a `log`, only the writes logged since the snapshot was taken are
+    /// replayed over it, and the restored entries aren't logged.
+    pub snapshot: Option<PathBuf>,
+    /// The most requests waiting for the worker at once. Requests arriving while as many are
+    /// waiting are answered with `Code::Busy` at once, rather than queueing without bound.
+    pub max_queued: usize,
+    /// Publish every change to a key, for connections subscribed to it with `Op::Subscribe`, see
+    /// `ServeOptions::notifications`.
+    pub notifications: Option<Hub>,
+    /// Loads the keys Gets miss on, from wherever the cache is in front of. The loaded value is
+    /// stored, unless the key was written meanwhile, and answers the Get. While a key is being
+    /// loaded, other Gets missing on it wait for the same load rather than starting their own;
+    /// everything else carries on. A load that fails is answered with `Code::ServerError`, and
+    /// isn't retried until the next Get.
+    pub loader: Option<LoadFn>,
+    /// The threads the `loader`'s futures run on. Once they're all busy, further loads wait for
+    /// one to be free.
+    pub load_threads: usize,
+    /// How long loaded values are stored for. Without, they are kept until evicted.
+    pub load_ttl: Option<Duration>,
+    /// Remember the keys the loader didn't find for this long, storing a `NOT_FOUND_TYPE_ID`
+    /// marker for them, so that repeated Gets for a missing key are answered with
+    /// `Code::NotFound` rather than each loading it again. The Get that loaded it is answered
+    /// with `Code::Miss`, so that only the Gets spared a load count as negative hits. Off by
+    /// default, when every Get missing on such a key loads it.
+    pub negative_ttl: Option<Duration>,
+    /// Let replicas follow the cache, see `Cache::replicate` and `ServeOptions::replication`,
+    /// holding up to this many writes for a replica that is slow to take them. A replica that
+    /// falls further behind is dropped, and resyncs from a fresh snapshot once it reconnects.
+    pub replication: Option<usize>,
+    /// Refuse writes with `Code::Error`, as a replica does, see `replica::follow`. Its primary's
+    /// writes are still applied, through `Cache::apply_replicated`.
+    pub read_only: bool,
+    /// Serve in cluster mode as the node clients reach at this address, only answering the
+    /// requests for keys in the hash slots it owns, see `cluster::Slots`.
+    pub cluster: Option<SocketAddr>,
+    /// Track the keys requested most, counting one in this many requests, see
+    /// `hotkeys::HotKeys`. They are listed by `Op::HotKeys`, and the top `HOT_KEYS_IN_STATS` by
+    /// `Op::Stats`.
+    pub hot_keys: Option<u32>,
+    /// The longest key a write may name, not counting its namespace. Longer ones are refused
+    /// with `Code::KeyTooLarge`. Unlimited by default, though a server's codec refuses keys
+    /// longer than `ServeOptions::max_key_len` before they reach the cache.
+    pub max_key_len: Option<usize>,
+    /// The longest value a write may store, including by appending to a value. Longer ones are
+    /// refused with `Code::ValueTooLarge`. Unlimited by default.
+    pub max_value_len: Option<usize>,
+    /// Which entries are evicted to make room for others, `EvictionPolicy::Lru` by default.
+    /// Ignored by `Cache::with_storage`, where the storage decides.
+    pub eviction: EvictionPolicy,
+    /// What the storage hashes keys with, `HashFunction::SipHash` by default. Also ignored by
+    /// `Cache::with_storage`.
+    pub hasher: HashFunction,
+    /// Spread the expiry of entries given a TTL, with `message::EXT_TTL`, by up to this
+    /// percentage of the TTL either way, picked at random for each write, so that the keys set
+    /// together with the same TTL don't all expire, and all miss, at once. At most 100, and off
+    /// by default. Shown by `Op::Inspect`, see `EntryInfo::ttl_jitter`.
+    pub ttl_jitter: Option<u32>,
+    /// Keep entries for this long after they expire, for the Gets that would rather have a stale
+    /// value than a miss, see `GET_STALE`, so that clients can serve it while they refresh it.
+    /// Other requests see the entry as gone. Stale entries don't count against the capacity or
+    /// `max_bytes`, but are held apart up to as many entries and bytes again, the least recently
+    /// used dropped first. They are dropped once their key is written or flushed, and once past
+    /// the window, by the first sweep or Get to find them. Off by default.
+    pub stale_grace: Option<Duration>,
+}
+
+/// The writes held for a replica that is slow to take them, for servers whose configuration
+/// doesn't say, see `Options::replication`.
+pub static DEFAULT_REPLICA_BACKLOG: usize = 1000000;
+
+/// Options for the append-only log kept by a `Cache`. Every write is recorded in the log before
+/// it is acknowledged, as the state of each key it changed. A write that can't be recorded is
+/// answered with `Code::ServerError`, although it has still been applied in memory.
+pub struct LogOptions {
+    pub path: PathBuf,
+    /// Sync the log to disk after every write. Without, acknowledged writes survive the process
+    /// crashing, but not necessarily the machine.
+    pub sync: bool,
+    /// Compact the log, rewriting it from the contents of the cache, once this many bytes have
+    /// been appended since it was last compacted. Otherwise it is only compacted by
+    /// `Cache::compact_log`.
+    pub compact_after: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            panic_hook: Arc::new(|cause: &str| error!("Worker panicked: {}.", cause)),
+            clock: Arc::new(SystemClock),
+            functions: HashMap::new(),
+            max_bytes: None,
+            prefix_quotas: vec![],
+            observer: None,
+            sweep_interval: Some(Duration::seconds(10)),
+            log: None,
+            snapshot: None,
+            max_queued: 65536,
+            notifications: None,
+            loader: None,
+            load_threads: 4,
+            load_ttl: None,
+            negative_ttl: None,
+            replication: None,
+            read_only: false,
+            cluster: None,
+            hot_keys: None,
+            max_key_len: None,
+            max_value_len: None,
+            eviction: EvictionPolicy::Lru,
+            hasher: HashFunction::SipHash,
+            ttl_jitter: None,
+            stale_grace: None,
+        }
+    }
+}
+
+/// How long the worker waits for work before checking whether a sweep is due.
+static SWEEP_TICK_MS: u64 = 100;
+
+/// A cache served by a single worker thread, which owns the store and handles the requests sent
+/// to it over a bounded channel one at a time, answering each through the request's own
+/// oneshot `Sender`. Nothing is shared with the worker, so nothing is locked to serve a request.
+/// The worker stops once the `Cache` is dropped.
+pub struct Cache {
+    work: SyncSender<Work>,
+    options: Options,
+}
+
+impl Cache {
+    /// Initialize a new `Cache` with `capacity` and start the worker thread.
+    pub fn new(capacity: usize) -> Result<Self, io::Error> {
+        Cache::with_options(capacity, Options::default())
+    }
+
+    /// Initialize a new `Cache` with `capacity` and `options`, and start the worker thread. If
+    /// `options` has a snapshot or a log, the cache is rebuilt from them first.
+    pub fn with_options(capacity: usize, options: Options) -> Result<Self, io::Error> {
+        let hasher = KeyHasher::new(options.hasher);
+        match options.eviction {
+            EvictionPolicy::Lru => {
+                Cache::with_storage(LruStorage::with_hasher(capacity, hasher), options)
+            }
+            EvictionPolicy::Lfu => {
+                Cache::with_storage(LfuStorage::with_hasher(capacity, hasher), options)
+            }
+            EvictionPolicy::Fifo => {
+                Cache::with_storage(FifoStorage::with_hasher(capacity, hasher), options)
+            }
+        }
+    }
+
+    /// Like `with_options`, keeping the entries in `storage` rather than one of the storages
+    /// for `Options::eviction`.
+    pub fn with_storage<S: Storage>(storage: S, options: Options) -> Result<Self, io::Error> {
+        let mut store = new_store(storage, &options);
+        store.recover(options.snapshot.as_ref().map(|path| path.as_path()), options.log.as_ref())?;
+        let (work, queue) = mpsc::sync_channel(options.max_queued);
+        let (panic_hook, observer) = (options.panic_hook.clone(), options.observer.clone());
+        let loads = options.loader.clone().map(|loader| {
+            Loads {
+                loader: loader,
+                pool: PoolBuilder::new()
+                    .pool_size(options.load_threads.max(1))
+                    .name_prefix("rcache-loader-")
+                    .create(),
+                ttl: options.load_ttl,
+                negative_ttl: options.negative_ttl,
+                work: work.clone(),
+                waiting: HashMap::new(),
+            }
+        });
+        thread::Builder::new()
+            .name("rcache-worker".to_owned())
+            .spawn(move || run(store, queue, panic_hook, observer, loads))?;
+        Ok(Cache {
+            work: work,
+            options: options,
+        })
+    }
+
+    pub fn clock(&self) -> &Arc<Clock> {
+        &self.options.clock
+    }
+
+    /// The longest keys and values the cache takes, for `service::ServeOptions::limits`.
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_key_len: self.options.max_key_len,
+            max_value_len: self.options.max_value_len,
+        }
+    }
+
+    /// Queue a request for the worker. `snd` is a `futures::sync::oneshot::Sender<Message>`.
+    /// When the worker has completed the request, it will send its `Message::Response` via the
+    /// sender. If `Options::max_queued` requests are already waiting, the request is answered
+    /// with `Code::Busy` instead.
+    pub fn process(&self, message: Message, snd: Sender<Message>) {
+        let op = message.op();
+        if self.options.read_only && op.is_write() {
+            let refused = message::payload(0, b"the cache is a read-only replica".to_vec());
+            let _ = snd.send(message::response(op, Code::Error, Some(refused)));
+            return;
+        }
+        match self.work.try_send(Work::Request(snd, message)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(Work::Request(snd, _))) => {
+                let _ = snd.send(message::response(op, Code::Busy, None));
+            }
+            Err(TrySendError::Disconnected(Work::Request(snd, _))) => {
+                let _ = snd.send(message::server_error(op, "cache worker stopped"));
+            }
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Calls `f` with the key, `type_id` and value of every entry in the cache, in no particular
+    /// order. `f` runs on the calling thread over a copy of the entries taken by the worker
+    /// between requests, so it sees the cache as of one moment, and may call back into the cache
+    /// without deadlocking. Writes made meanwhile, including by `f`, aren't visited. The copy
+    /// holds every value at once, so this is best kept to maintenance of modestly sized caches.
+    pub fn for_each<F>(&self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8], u32, &[u8]),
+    {
+        for (key, payload, _) in self.snapshot()? {
+            f(&key, payload.type_id(), payload.data());
+        }
+        Ok(())
+    }
+
+    /// Rewrites the cache's log from its current contents, dropping the history of overwritten
+    /// and deleted keys. Fails if the cache has no log.
+    pub fn compact_log(&self) -> io::Result<()> {
+        let (snd, rcv) = oneshot::channel();
+        let _ = self.work.send(Work::CompactLog(snd));
+        rcv.wait().unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::Other, "cache worker stopped"))
+        })
+    }
+
+    /// Starts streaming the writes to the cache, for a replica to follow, see `replica::follow`.
+    /// The `Feed` starts with a snapshot of every unexpired entry, taken by the worker between
+    /// requests, followed by the state of every key written since, see `apply_replicated`.
+    /// Evictions and expiries aren't streamed: replicas evict and expire their own entries.
+    /// Fails unless the cache has `Options::replication`.
+    pub fn replicate(&self) -> Box<Future<Item = Feed, Error = io::Error>> {
+        if self.options.replication.is_none() {
+            let disabled = io::Error::new(io::ErrorKind::Other, "replication is disabled");
+            return Box::new(future::err(disabled));
+        }
+        let (snd, rcv) = oneshot::channel();
+        let _ = self.work.send(Work::Replicate(snd));
+        Box::new(rcv.map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "cache worker stopped")
+        }))
+    }
+
+    /// Applies a write streamed by a primary's `Feed`, even if the cache is `Options::read_only`,
+    /// and returns when the primary made it. The first write of a feed drops every entry, so
+    /// that the snapshot following it replaces what the cache held. Unlike `process`, this
+    /// waits for room in the worker's queue rather than refusing the write, although not for
+    /// the worker to apply it.
+    pub fn apply_replicated(&self, msg: &Message) -> io::Result<Timespec> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid replicated write");
+        let data = match msg.payload() {
+            Some(payload) if msg.op() == Op::Replicate && msg.code() == Code::Ok &&
+                payload.type_id() == REPLICATION_TYPE_ID => payload.data(),
+            _ => return Err(invalid()),
+        };
+        let mut cursor = io::Cursor::new(data);
+        if cursor.remaining() < 8 {
+            return Err(invalid());
+        }
+        let millis = cursor.get_u64::<BigEndian>() as i64;
+        let written_at = Timespec::new(millis / 1000, (millis % 1000) as i32 * 1000000);
+        let req = if !cursor.has_remaining() {
+            message::request(Op::FlushAll, vec![], None)
+        } else {
+            match Record::decode(&mut cursor)? {
+                Some(Record::Set(key, payload, Some(expires_at))) => {
+                    let at = message::encode_u64(expires_at.sec as u64);
+                    stored_request(Op::Set, &key, Some(payload))
+                        .with_extension(message::EXT_EXPIRES_AT, at)
+                }
+                Some(Record::Set(key, payload, None)) => {
+                    stored_request(Op::Set, &key, Some(payload))
+                }
+                Some(Record::Del(key)) => stored_request(Op::Del, &key, None),
+                Some(Record::Mark(_)) | None => return Err(invalid()),
+            }
+        };
+        if cursor.has_remaining() {
+            return Err(invalid());
+        }
+        let (snd, _) = oneshot::channel();
+        self.work.send(Work::Request(snd, req)).map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "cache worker stopped")
+        })?;
+        Ok(written_at)
+    }
+
+    /// Copies every unexpired entry in the cache, least recently used first, along with when it
+    /// expires.
+    fn snapshot(&self) -> io::Result<Vec<(Vec<u8>, Payload, Option<Timespec>)>> {
+        let (snd, rcv) = oneshot::channel();
+        let _ = self.work.send(Work::Snapshot(snd));
+        rcv.wait().map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "cache worker stopped")
+        })
+    }
+
+    /// Saves every entry in the cache to a snapshot file at `path`, in the format described by
+    /// `encode_snapshot`, for `Options::snapshot` or `load_from` to restore. Where it was taken
+    /// is marked in the log, if there is one. The snapshot is written to a temporary file
+    /// next to `path` and renamed over it once complete, so a crash part way through leaves any
+    /// previous snapshot intact. Like `for_each`, this copies the whole cache in memory first.
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let (snd, rcv) = oneshot::channel();
+        let _ = self.work.send(Work::Checkpoint(snd));
+        let (mark, entries) = rcv.wait().unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::Other, "cache worker stopped"))
+        })?;
+        let data = encode_snapshot(mark, &entries);
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Restores the entries saved by `save_to` at `path`, returning how many were stored. They
+    /// are stored with Sets in the order they were saved, so the least recently used are the
+    /// first to be evicted again, and the cache's capacity and limits apply as to any other Set.
+    /// Entries that have expired since the snapshot was taken are skipped. Meant to be called on
+    /// startup, before the cache is served. Fails if the cache keeps a log, which would record
+    /// the entries again, and be replayed before them: use `Options::snapshot` instead.
+    pub fn load_from<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        if self.options.log.is_some() {
+            let error = "a cache with a log restores its snapshot through Options::snapshot";
+            return Err(io::Error::new(io::ErrorKind::Other, error));
+        }
+        let now = self.options.clock.now();
+        let mut loaded = 0;
+        for (key, payload, expires_at) in read_snapshot(path)? {
+            let mut req = stored_request(Op::Set, &key, Some(payload));
+            if let Some(expires_at) = expires_at {
+                if expires_at <= now {
+                    continue;
+                }
+                let at = message::encode_u64(expires_at.sec as u64);
+                req = req.with_extension(message::EXT_EXPIRES_AT, at);
+            }
+            let (snd, rcv) = oneshot::channel();
+            self.process(req, snd);
+            let resp = rcv.wait().map_err(|_| {
+                io::Error::new(io::ErrorKind::Other, "cache worker stopped")
+            })?;
+            if resp.code() == Code::Ok {
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        let _ = self.work.send(Work::Stop);
+    }
+}
+
+fn new_store<S: Storage>(storage: S, options: &Options) -> Store<S> {
+    let mut store = Store::with_storage(storage);
+    store.functions = options.functions.clone();
+    store.max_bytes = options.max_bytes;
+    store.max_key_len = options.max_key_len;
+    store.max_value_len = options.max_value_len;
+    store.ttl_jitter = options.ttl_jitter.map(|percent| percent.min(100));
+    store.stale_grace = options.stale_grace;
+    store.quotas = options
+        .prefix_quotas
+        .iter()
+        .map(|&(ref prefix, max_keys)| {
+            PrefixQuota {
+                prefix: prefix.clone(),
+                max_keys: max_keys,
+                keys: 0,
+            }
+        })
+        .collect();
+    store.clock = options.clock.clone();
+    store.started = store.clock.now();
+    store.sweep_interval = options.sweep_interval;
+    store.last_sweep = store.started;
+    store.hub = options.notifications.clone();
+    store.max_replica_backlog = options.replication;
+    store.cluster = options.cluster.map(Slots::new);
+    store.hot_keys = options.hot_keys.map(|rate| HotKeys::new(rate, store.started));
+    store
+}
+
+/// The worker: handles the work taken from `queue` in turn, sweeping the store in between, until
+/// the `Cache` sending it is dropped.
+fn run<S: Storage>(
+    mut store: Store<S>,
+    queue: mpsc::Receiver<Work>,
+    panic_hook: PanicHook,
+    observer: Option<Observer>,
+    mut loads: Option<Loads>,
+) {
+    let tick = ::std::time::Duration::from_millis(SWEEP_TICK_MS);
+    loop {
+        match queue.recv_timeout(tick) {
+            Ok(Work::Snapshot(snd)) => {
+                let _ = snd.send(store.live_entries());
+            }
+            Ok(Work::Checkpoint(snd)) => {
+                let _ = snd.send(store.checkpoint());
+            }
+            Ok(Work::CompactLog(snd)) => {
+                let _ = snd.send(store.compact_log());
+            }
+            Ok(Work::Replicate(snd)) => {
+                let _ = snd.send(store.replicate());
+            }
+            Ok(Work::Request(snd, msg)) => {
+                let missed = match loads {
+                    Some(_) => missed(&mut store, &msg),
+                    None => None,
+                };
+                match (missed, loads.as_mut()) {
+                    (Some(key), Some(loads)) => loads.start(key, msg, snd),
+                    _ => {
+                        // The requester may have gone, with its connection. The request has
+                        // still been served, so there's nothing more to do.
+                        let _ = snd.send(respond(&mut store, msg, &panic_hook, &observer));
+                    }
+                }
+            }
+            Ok(Work::Loaded(key, get, loaded)) => {
+                if let Some(ref mut loads) = loads {
+                    let response = match loaded {
+                        Ok(loaded) => {
+                            // What to answer if the value, or the marker, can't be stored, and
+                            // for the marker when it is: the Get did miss.
+                            let found = loaded.is_some();
+                            let fallback = match loaded {
+                                Some(ref payload) => {
+                                    message::response(Op::Get, Code::Hit, Some(payload.clone()))
+                                }
+                                None => message::response(Op::Get, Code::Miss, None),
+                            };
+                            let stored = match loaded {
+                                Some(payload) => Some((payload, loads.ttl)),
+                                None => loads.negative_ttl.map(|ttl| (not_found(), Some(ttl))),
+                            };
+                            match stored {
+                                Some((payload, ttl)) => {
+                                    let add = loads.add_request(&get, payload, ttl);
+                                    let added = respond(&mut store, add, &panic_hook, &observer);
+                                    match added.code() {
+                                        Code::Ok if found => {
+                                            respond(&mut store, get, &panic_hook, &observer)
+                                        }
+                                        Code::Conflict => {
+                                            respond(&mut store, get, &panic_hook, &observer)
+                                        }
+                                        _ => fallback,
+                                    }
+                                }
+                                None => fallback,
+                            }
+                        }
+                        Err(e) => message::server_error(Op::Get, &format!("failed to load: {}", e)),
+                    };
+                    for snd in loads.waiting.remove(&key).unwrap_or_default() {
+                        let _ = snd.send(response.clone());
+                    }
+                }
+            }
+            Ok(Work::Stop) |
+            Err(RecvTimeoutError::Disconnected) => return,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        store.sweep();
+    }
+}
+
+/// Handles `msg`, recording what it wrote in the log, streaming it to replicas and publishing
+/// the changes and the `Event`.
+fn respond<S: Storage>(
+    store: &mut Store<S>,
+    msg: Message,
+    panic_hook: &PanicHook,
+    observer: &Option<Observer>,
+) -> Message {
+    let op = msg.op();
+    let key = observer.as_ref().and_then(|_| msg.key().map(|k| k.to_vec()));
+    let written = if store.log.is_some() || store.hub.is_some() || !store.replicas.is_empty() {
+        Some(written_keys(store, &msg))
+    } else {
+        None
+    };
+    let versions = match (&store.hub, &written) {
+        (&Some(_), &Some(ref keys)) => Some(store.versions(keys)),
+        _ => None,
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| handle(store, msg)));
+    let mut response = match result {
+        Ok(Ok(msg)) => msg,
+        Ok(Err(e)) => handle_error(&e),
+        Err(cause) => {
+            let cause = panic_description(&cause);
+            panic_hook(&cause);
+            handle_panic(op, &cause)
+        }
+    };
+    if let (Some(keys), Some(versions)) = (written.as_ref(), versions) {
+        store.notify_writes(keys, &versions);
+    }
+    if let (Some(keys), Code::Ok) = (written, response.code()) {
+        let records = store.records(keys);
+        store.replicate_writes(&records);
+        if let Err(e) = store.log_writes(&records) {
+            let error = format!("failed to write to the log: {}", e);
+            error!("Worker {}.", error);
+            response = message::server_error(op, &error);
+        }
+    }
+    if let Some(ref observer) = *observer {
+        observer.publish(Event {
+            op: op,
+            key: key.unwrap_or_default(),
+            code: response.code(),
+        });
+    }
+    response
+}
+
+/// The stored key `msg` is a Get missing on, if it is one.
+fn missed<S: Storage>(store: &mut Store<S>, msg: &Message) -> Option<Vec<u8>> {
+    let key = match (msg.op(), Namespace::of(msg), msg.key()) {
+        (Op::Get, Ok(namespace), Some(key)) if redirect(store, msg).is_none() => {
+            namespace.key(key)
+        }
+        _ => return None,
+    };
+    store.expire(&key);
+    if store.entries.contains_key(&key) {
+        None
+    } else {
+        Some(key)
+    }
+}
+
+/// The keys being loaded by the worker, see `Options::loader`.
+struct Loads {
+    loader: LoadFn,
+    pool: CpuPool,
+    ttl: Option<Duration>,
+    negative_ttl: Option<Duration>,
+    /// Where the loaded values are sent back to the worker.
+    work: SyncSender<Work>,
+    /// The Gets waiting for each stored key being loaded.
+    waiting: HashMap<Vec<u8>, Vec<Sender<Message>>>,
+}
+
+impl Loads {
+    /// Answers `get`, which missed on the stored key `key`, once the key is loaded, starting to
+    /// load it unless it already is.
+    fn start(&mut self, key: Vec<u8>, get: Message, snd: Sender<Message>) {
+        if let Some(waiting) = self.waiting.get_mut(&key) {
+            waiting.push(snd);
+            return;
+        }
+        let (loader, work, stored) = (self.loader.clone(), self.work.clone(), key.clone());
+        let namespace = Namespace::of(&get).unwrap_or(Namespace(None));
+        let within = get.key().unwrap_or_default().to_vec();
+        let load = future::lazy(move || loader(namespace.0.as_ref().map(|n| &n[..]), &within));
+        let load = AssertUnwindSafe(load).catch_unwind().then(move |loaded| {
+            let loaded = loaded.unwrap_or_else(|cause| {
+                let cause = format!("loader panicked: {}", panic_description(&cause));
+                Err(io::Error::new(io::ErrorKind::Other, cause))
+            });
+            let _ = work.send(Work::Loaded(stored, get, loaded));
+            Ok::<(), ()>(())
+        });
+        self.pool.spawn(load).forget();
+        self.waiting.insert(key, vec![snd]);
+    }
+
+    /// The request storing `payload` loaded for `get` for `ttl`, unless the key was written
+    /// meanwhile.
+    fn add_request(&self, get: &Message, payload: Payload, ttl: Option<Duration>) -> Message {
+        let key = get.key().unwrap_or_default().to_vec();
+        let mut add = message::request(Op::Add, key, Some(payload));
+        if let Some(namespace) = get.extension(message::EXT_NAMESPACE) {
+            add = add.with_extension(message::EXT_NAMESPACE, namespace.to_vec());
+        }
+        if let Some(ttl) = ttl {
+            let ttl = message::encode_u64(ttl.num_seconds() as u64);
+            add = add.with_extension(message::EXT_TTL, ttl);
+        }
+        add
+    }
+}
+
+/// `type_id` of the payload of the `Op::Replicate` frames in a `Feed`, see `replicated_write`.
+pub const REPLICATION_TYPE_ID: u32 = 20;
+
+/// A replica the store streams its writes to, see `Store::replicate`.
+struct Replica {
+    sender: futures_mpsc::UnboundedSender<Message>,
+    /// The writes sent but not yet taken from the `Feed`.
+    backlog: Arc<AtomicUsize>,
+}
+
+/// The writes to a cache, as frames for a replica to pass to `Cache::apply_replicated`, see
+/// `Cache::replicate`. Fails once the cache stops streaming to the replica, as it does when
+/// the replica falls too far behind, since the replica has then missed writes.
+pub struct Feed {
+    snapshot: ::std::vec::IntoIter<Message>,
+    writes: futures_mpsc::UnboundedReceiver<Message>,
+    backlog: Arc<AtomicUsize>,
+}
+
+impl Stream for Feed {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Message>, io::Error> {
+        if let Some(write) = self.snapshot.next() {
+            return Ok(Async::Ready(Some(write)));
+        }
+        match self.writes.poll() {
+            Ok(Async::Ready(Some(write))) => {
+                self.backlog.fetch_sub(1, Ordering::SeqCst);
+                Ok(Async::Ready(Some(write)))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(None)) | Err(()) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the cache stopped streaming writes to the replica",
+            )),
+        }
+    }
+}
+
+/// The `Op::Replicate` frame streaming `record` to a replica. Its payload is `at`, when the
+/// write was made, in milliseconds since the Unix epoch as a big endian u64, followed by the
+/// record as encoded in the log, see `aof::Record::encode`. A frame without a record starts a
+/// snapshot.
+fn replicated_write(at: Timespec, record: Option<&Record>) -> Message {
+    let mut data = vec![];
+    data.put_u64::<BigEndian>(at.sec as u64 * 1000 + at.nsec as u64 / 1000000);
+    if let Some(record) = record {
+        record.encode(&mut data);
+    }
+    message::response(Op::Replicate, Code::Ok, Some(message::payload(REPLICATION_TYPE_ID, data)))
+}
+
+/// The stored keys whose state `msg` may change if it succeeds, to be recorded in the log and
+/// streamed to replicas.
+fn written_keys<S: Storage>(store: &Store<S>, msg: &Message) -> Vec<Vec<u8>> {
+    let namespace = match Namespace::of(msg) {
+        Ok(namespace) => namespace,
+        Err(_) => return vec![],
+    };
+    let key = namespace.key(msg.key().unwrap_or_default());
+    match msg.op() {
+        Op::Set | Op::Add | Op::Replace | Op::SetIfEmpty | Op::Cas | Op::CasDel | Op::Del |
+        Op::Apply | Op::Retype | Op::FieldIncr | Op::Incr | Op::Decr | Op::Touch | Op::Expire |
+        Op::Append | Op::Prepend => vec![key],
+        Op::Batch => {
+            batched_ops(msg)
+                .unwrap_or_default()
+                .iter()
+                .flat_map(|op| written_keys(store, op))
+                .collect()
+        }
+        Op::Rename => {
+            let dest = namespace.key(msg.payload().map(|p| p.data()).unwrap_or_default());
+            vec![key, dest]
+        }
+        Op::MultiDel => {
+            msg.payload()
+                .and_then(|p| message::decode_keys(p.data()).ok())
+                .unwrap_or_default()
+                .iter()
+                .map(|key| namespace.key(key))
+                .collect()
+        }
+        Op::FlushNamespace if namespace.0.is_some() => keys_in(store, &namespace),
+        Op::FlushAll => keys_in(store, &namespace),
+        Op::AssignSlots => given_up(store, msg).map(|(keys, _)| keys).unwrap_or_default(),
+        Op::Restore => {
+            msg.payload()
+                .and_then(|p| decode_dump(p.data()).ok())
+                .unwrap_or_default()
+                .iter()
+                .map(|&(ref key, _, _)| namespace.key(key))
+                .collect()
+        }
+        _ => vec![],
+    }
+}
+
+/// Marks the start of a snapshot file, followed by the version of its format.
+static SNAPSHOT_MAGIC: &'static [u8] = b"RCSNAP";
+static SNAPSHOT_VERSION: u32 = 2;
+
+/// Encodes `entries` as a snapshot: `SNAPSHOT_MAGIC`, the format version as a u32, the `mark`
+/// logged where it was taken as a u64, see `Record::Mark`, and the number of entries as a u64,
+/// then for each entry its key and payload, see `message::put_entry`,
+/// and when it expires in seconds since the Unix epoch as a u64, 0 if never. All integers are big
+/// endian.
+fn encode_snapshot(mark: u64, entries: &[(Vec<u8>, Payload, Option<Timespec>)]) -> Vec<u8> {
+    let mut buf = vec![];
+    buf.put_slice(SNAPSHOT_MAGIC);
+    buf.put_u32::<BigEndian>(SNAPSHOT_VERSION);
+    buf.put_u64::<BigEndian>(mark);
+    buf.put_u64::<BigEndian>(entries.len() as u64);
+    for &(ref key, ref payload, expires_at) in entries {
+        message::put_entry(&mut buf, key, payload);
+        buf.put_u64::<BigEndian>(expires_at.map_or(0, |at| at.sec as u64));
+    }
+    buf
+}
+
+/// The entries in the snapshot saved by `Cache::save_to` at `path`, with when each expires, least
+/// recently used first.
+pub fn read_snapshot<P: AsRef<Path>>(
+    path: P,
+) -> io::Result<Vec<(Vec<u8>, Payload, Option<Timespec>)>> {
+    read_marked_snapshot(path.as_ref()).map(|(_, entries)| entries)
+}
+
+/// Like `read_snapshot`, along with the snapshot's mark.
+fn read_marked_snapshot(
+    path: &Path,
+) -> io::Result<(u64, Vec<(Vec<u8>, Payload, Option<Timespec>)>)> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    decode_snapshot(&data)
+}
+
+/// Decodes a snapshot written by `encode_snapshot`, or by version 1, which had no mark.
+fn decode_snapshot(
+    data: &[u8],
+) -> io::Result<(u64, Vec<(Vec<u8>, Payload, Option<Timespec>)>)> {
+    let invalid = |description: &str| -> io::Error {
+        error::Error::new(error::ErrorKind::InvalidData, description).into()
+    };
+    let mut cursor = io::Cursor::new(data);
+    if data.len() < SNAPSHOT_MAGIC.len() + 4 + 8 || !data.starts_with(SNAPSHOT_MAGIC) {
+        return Err(invalid("not a snapshot"));
+    }
+    cursor.advance(SNAPSHOT_MAGIC.len());
+    let mark = match cursor.get_u32::<BigEndian>() {
+        1 => 0,
+        2 if cursor.remaining() >= 8 + 8 => cursor.get_u64::<BigEndian>(),
+        2 => return Err(invalid("truncated snapshot")),
+        _ => return Err(invalid("unsupported snapshot version")),
+    };
+    let count = cursor.get_u64::<BigEndian>();
+
+    let mut entries = vec![];
+    for _ in 0..count {
+        let (key, payload) = match message::get_entry(&mut cursor) {
+            Some(entry) if cursor.remaining() >= 8 => entry,
+            _ => return Err(invalid("truncated snapshot")),
+        };
+        let expires_at = match cursor.get_u64::<BigEndian>() {
+            0 => None,
+            at => Some(Timespec::new(at as i64, 0)),
+        };
+        entries.push((key, payload, expires_at));
+    }
+    if cursor.has_remaining() {
+        return Err(invalid("trailing bytes after snapshot"));
+    }
+    Ok((mark, entries))
+}
+
+/// Handle the request. `Message` is a `Message::Request` variant from the front end.
+/// The response message should be a `Message::Response` variant.
+fn handle<S: Storage>(store: &mut Store<S>, message: Message) -> Result<Message, error::Error> {
+    let op = message.op();
+    let token = message.extension(message::EXT_IDEMPOTENCY_TOKEN).map(|t| t.to_vec());
+    let version = match message.extension(message::EXT_VERSION) {
+        Some(version) => Some(message::decode_u64(version)?),
+        None => None,
+    };
+    let function = message.extension(message::EXT_FUNCTION).map(|f| f.to_vec());
+    let pattern = message.extension(message::EXT_PATTERN).map(|p| p.to_vec());
+    let expires_at = match message.extension(message::EXT_EXPIRES_AT) {
+        Some(at) => Some(Timespec::new(message::decode_u64(at)? as i64, 0)),
+        None => None,
+    };
+    // The jitter is only kept when the expiry is the jittered TTL's.
+    let (expires_at, jitter) = match message.extension(message::EXT_TTL) {
+        Some(ttl) => {
+            let ttl = store.jittered(checked_ttl(message::decode_u64(ttl)?)?);
+            let at = store.clock.now() + ttl;
+            match expires_at {
+                Some(expires_at) if expires_at <= at => (Some(expires_at), None),
+                _ => (Some(at), store.ttl_jitter.filter(|&percent| percent > 0)),
+            }
+        }
+        None => (expires_at, None),
+    };
+    if let Some(redirect) = redirect(store, &message) {
+        return Ok(redirect);
+    }
+    if let Some(code) = store.over_limits(&message) {
+        return Ok(message::response(op, code, None));
+    }
+    let given_up = match op {
+        Op::AssignSlots => Some(given_up(store, &message)?),
+        _ => None,
+    };
+    let batched = match op {
+        Op::Batch => Some(batched_ops(&message)?),
+        _ => None,
+    };
+    let namespace = Namespace::of(&message)?;
+    if namespace.0.is_none() && reserved_keys(&message) {
+        return Err(error::Error::new(
+            error::ErrorKind::InvalidData,
+            "keys starting with 0xFF are reserved for namespaces",
+        ));
+    }
+    if let Some(ref mut hot_keys) = store.hot_keys {
+        if hot_keys.sample() {
+            let now = store.clock.now();
+            for key in cluster::keys_of(&message) {
+                hot_keys.record(&namespace.key(&key), now);
+            }
+        }
+    }
+    let (key, payload) = message.consume_request()?;
+    let key = namespace.key(&key);
+    store.expire(&key);
+
+    let response = match op {
+        Op::Set => {
+            let key = key;
+            let payload = payload.ok_or_else(|| "no payload given to set op")?;
+            let applied = token.is_some() &&
+                store.entries.get_mut(&key).and_then(|e| e.token.as_ref()) == token.as_ref();
+
+            if applied {
+                message::response(Op::Set, Code::AlreadyApplied, None)
+            } else if store.over_quota(&key) {
+                message::response(Op::Set, Code::QuotaExceeded, None)
+            } else if !store.fits(entry_size(&key, &payload)) {
+                return Err(too_large());
+            } else {
+                let version = store.next_version();
+                let entry = Entry::new(payload, version, expires_at, store.clock.now());
+                store.insert(
+                    key,
+                    Entry {
+                        token: token,
+                        jitter: jitter,
+                        ..entry
+                    },
+                );
+                message::response(Op::Set, Code::Ok, None)
+                    .with_extension(message::EXT_VERSION, message::encode_u64(version))
+            }
+        }
+
+        // Sets the key like a Set, but an Add only if the key is absent, responding with
+        // `Code::Conflict` otherwise, and a Replace only if it is present, responding with
+        // `Code::Miss` otherwise.
+        Op::Add | Op::Replace => {
+            let payload = payload.ok_or_else(|| "no payload given to add op")?;
+            let present = store.entries.contains_key(&key);
+
+            if op == Op::Add && present {
+                message::response(op, Code::Conflict, None)
+            } else if op == Op::Replace && !present {
+                message::response(op, Code::Miss, None)
+            } else if store.over_quota(&key) {
+                message::response(op, Code::QuotaExceeded, None)
+            } else if !store.fits(entry_size(&key, &payload)) {
+                return Err(too_large());
+            } else {
+                let version = store.next_version();
+                let entry = Entry::new(payload, version, expires_at, store.clock.now());
+                store.insert(key, Entry { jitter: jitter, ..entry });
+                message::response(op, Code::Ok, None)
+                    .with_extension(message::EXT_VERSION, message::encode_u64(version))
+            }
+        }
+
+        Op::SetIfEmpty => {
+            let payload = payload.ok_or_else(|| "no payload given to set if empty op")?;
+            let empty = store.entries.get_mut(&key).map_or(true, |e| e.payload.data().is_empty());
+
+            if !empty {
+                message::response(Op::SetIfEmpty, Code::Conflict, None)
+            } else if store.over_quota(&key) {
+                message::response(Op::SetIfEmpty, Code::QuotaExceeded, None)
+            } else if !store.fits(entry_size(&key, &payload)) {
+                return Err(too_large());
+            } else {
+                let version = store.next_version();
+                let entry = Entry::new(payload, version, expires_at, store.clock.now());
+                store.insert(key, Entry { jitter: jitter, ..entry });
+                message::response(Op::SetIfEmpty, Code::Ok, None)
+                    .with_extension(message::EXT_VERSION, message::encode_u64(version))
+            }
+        }
+
+        // Asked to with `GET_STALE`, a Get missing on a key that expired within the grace
+        // window answers with the stale value, as a hit does but with `Code::Stale`. One finding
+        // a `NOT_FOUND_TYPE_ID` marker answers with it, but with `Code::NotFound`.
+        Op::Get => {
+            let now = store.clock.now();
+            let stale = payload.map_or(false, |flags| flags.type_id() & GET_STALE != 0);
+            if let Some(entry) = store.entries.get_mut(key.as_slice()) {
+                entry.read(now);
+                if entry.payload.type_id() == NOT_FOUND_TYPE_ID {
+                    answer(Op::Get, Code::NotFound, entry)
+                } else {
+                    hit(Op::Get, entry)
+                }
+            } else {
+                match store.stale(&key) {
+                    Some(entry) if stale => answer(Op::Get, Code::Stale, entry),
+                    _ => message::response(Op::Get, Code::Miss, None),
+                }
+            }
+        }
+
+        // Like a Get, without the value.
+        Op::Exists => {
+            let code = if store.entries.contains_key(&key) { Code::Hit } else { Code::Miss };
+            message::response(Op::Exists, code, None)
+        }
+
+        // See `decode_ttl`.
+        Op::Ttl => {
+            let now = store.clock.now();
+            match store.entries.peek(&key).map(|entry| entry.expires_at) {
+                Some(Some(at)) => {
+                    let ttl = message::encode_u64((at - now).num_seconds().max(0) as u64);
+                    message::response(Op::Ttl, Code::Hit, Some(message::payload(0, ttl)))
+                }
+                Some(None) => message::response(Op::Ttl, Code::Hit, None),
+                None => message::response(Op::Ttl, Code::Miss, None),
+            }
+        }
+
+        // See `decode_inspect`.
+        Op::Inspect => {
+            let now = store.clock.now();
+            match store.entries.peek(&key) {
+                Some(entry) => {
+                    let data = encode_inspect(entry, now);
+                    message::response(Op::Inspect, Code::Hit, Some(message::payload(0, data)))
+                }
+                None => message::response(Op::Inspect, Code::Miss, None),
+            }
+        }
+
+        // The request carries the version the client already has.
+        Op::GetIfNewer => {
+            let known = version.ok_or_else(|| "no version given to get if newer op")?;
+            let now = store.clock.now();
+            match store.entries.get_mut(key.as_slice()) {
+                Some(ref mut entry) if entry.version > known => {
+                    entry.read(now);
+                    hit(Op::GetIfNewer, entry)
+                }
+                Some(_) => message::response(Op::GetIfNewer, Code::NotModified, None),
+                None => message::response(Op::GetIfNewer, Code::Miss, None),
+            }
+        }
+
+        // A Set that only applies if the entry is still at the version the request carries,
+        // typically the one from an earlier Get. Otherwise responds with `Code::CasMismatch` and
+        // the current version, or `Code::Miss` if there's no entry.
+        Op::Cas => {
+            let expected = version.ok_or_else(|| "no version given to cas op")?;
+            let payload = payload.ok_or_else(|| "no payload given to cas op")?;
+            let current = store.entries.get_mut(key.as_slice()).map(|e| e.version);
+            match current {
+                Some(current) if current == expected => {
+                    if !store.fits(entry_size(&key, &payload)) {
+                        return Err(too_large());
+                    }
+                    let version = store.next_version();
+                    let entry = Entry::new(payload, version, expires_at, store.clock.now());
+                    store.insert(key, Entry { jitter: jitter, ..entry });
+                    message::response(Op::Cas, Code::Ok, None)
+                        .with_extension(message::EXT_VERSION, message::encode_u64(version))
+                }
+                Some(current) => message::response(Op::Cas, Code::CasMismatch, None)
+                    .with_extension(message::EXT_VERSION, message::encode_u64(current)),
+                None => message::response(Op::Cas, Code::Miss, None),
+            }
+        }
+
+        // The request carries the version the client expects the entry to be at, and the entry
+        // is only deleted if it still is, so a concurrent update isn't deleted unseen.
+        Op::CasDel => {
+            let expected = version.ok_or_else(|| "no version given to cas del op")?;
+            let current = store.entries.get_mut(key.as_slice()).map(|e| e.version);
+            match current {
+                Some(current) if current == expected => {
+                    store.remove(&key);
+                    message::response(Op::CasDel, Code::Ok, None)
+                }
+                Some(current) => message::response(Op::CasDel, Code::Conflict, None)
+                    .with_extension(message::EXT_VERSION, message::encode_u64(current)),
+                None => message::response(Op::CasDel, Code::Miss, None),
+            }
+        }
+
+        #[cfg(test)]
+        Op::Panic => panic!("induced panic"),
+
+        // Responds with the deleted value, or `Code::Miss` if there was nothing to delete.
+        Op::Del => {
+            match store.remove(&key) {
+                Some(entry) => message::response(Op::Del, Code::Ok, Some(entry.payload)),
+                None => message::response(Op::Del, Code::Miss, None),
+            }
+        }
+        // The destination key is carried as the payload data. The whole move happens within a
+        // single call on the worker, so no other request can observe both or neither key.
+        Op::Rename => {
+            let dest = payload.ok_or_else(|| "no destination given to rename op")?;
+            let overwrite = dest.type_id() == RENAME_OVERWRITE;
+            let dest = namespace.key(dest.data());
+
+            store.expire(&dest);
+            let size = store.entries.get_mut(&key).map(|e| entry_size(&dest, &e.payload));
+            match size {
+                None => message::response(Op::Rename, Code::Miss, None),
+                Some(_) if dest != key && !overwrite && store.entries.contains_key(&dest) => {
+                    message::response(Op::Rename, Code::Conflict, None)
+                }
+                Some(size) if !store.fits(size) => return Err(too_large()),
+                Some(_) if dest != key && store.over_quota(&dest) => {
+                    message::response(Op::Rename, Code::QuotaExceeded, None)
+                }
+                Some(_) => {
+                    if let Some(entry) = store.remove(&key) {
+                        store.insert(dest, entry);
+                    }
+                    message::response(Op::Rename, Code::Ok, None)
+                }
+            }
+        }
+
+        // The payload carries the number of keys wanted, as a u32.
+        Op::Sample => {
+            let count = payload.ok_or_else(|| "no count given to sample op")?;
+            let count = message::decode_u32(count.data())? as usize;
+            let keys = sample(store, &namespace, count.min(MAX_SAMPLE));
+            message::response(
+                Op::Sample,
+                Code::Ok,
+                Some(message::payload(
+                    message::KEY_LIST_TYPE_ID,
+                    message::encode_keys(&keys),
+                )),
+            )
+        }
+
+        // Keys are returned in ascending order, so the last key of a page is the cursor for the
+        // next. Every key present for the whole of a scan is returned exactly once.
+        Op::Scan => {
+            let count = payload.ok_or_else(|| "no count given to scan op")?;
+            // The cursor is a key within the namespace.
+            let after = if count.type_id() == SCAN_AFTER {
+                namespace.strip(&key).map(|after| after.to_vec())
+            } else {
+                None
+            };
+            let count = message::decode_u32(count.data())? as usize;
+            let keys = scan(store, &namespace, after, pattern.as_ref(), count.min(MAX_SCAN));
+            message::response(
+                Op::Scan,
+                Code::Ok,
+                Some(message::payload(
+                    message::KEY_LIST_TYPE_ID,
+                    message::encode_keys(&keys),
+                )),
+            )
+        }
+
+        // The entries are stored in one go, making room for them first where the storage can,
+        // see `restore_request`. A TTL too long refuses the whole request.
+        Op::Restore => {
+            let entries = payload.ok_or_else(|| "no entries given to restore op")?;
+            let replace = entries.type_id() == RESTORE_REPLACE;
+            let entries = decode_dump(entries.data())?;
+            if entries.len() > MAX_MULTI_KEYS {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "too many entries in restore op",
+                ));
+            }
+            let room = store.entries.capacity().saturating_sub(store.entries.len());
+            store.entries.reserve(entries.len().min(room));
+            let now = store.clock.now();
+            let entries = entries
+                .into_iter()
+                .map(|(key, payload, ttl)| match ttl {
+                    Some(ttl) => Ok((key, payload, Some(now + checked_ttl(ttl)?))),
+                    None => Ok((key, payload, None)),
+                })
+                .collect::<Result<Vec<_>, error::Error>>()?;
+            let mut restored = 0;
+            for (key, payload, expires_at) in entries {
+                let key = namespace.key(&key);
+                store.expire(&key);
+                if !store.fits(entry_size(&key, &payload)) || store.over_quota(&key) ||
+                    !replace && store.entries.contains_key(&key)
+                {
+                    continue;
+                }
+                let version = store.next_version();
+                store.insert(key, Entry::new(payload, version, expires_at, now));
+                restored += 1;
+            }
+            let payload = message::payload(0, message::encode_u64(restored));
+            message::response(Op::Restore, Code::Ok, Some(payload))
+        }
+
+        // One page of a dump, see `dump_request`: the key is the prefix of the keys dumped, and
+        // the cursor a key within the namespace, as for `Op::Scan`.
+        Op::Dump => {
+            let page = payload.ok_or_else(|| "no count given to dump op")?;
+            let (count, _, after) = decode_dump_request(&page)?;
+            let prefix = namespace.strip(&key).unwrap_or_default();
+            let after = after.as_ref().map(|after| &after[..]);
+            let entries = dump(store, &namespace, prefix, after, (count as usize).min(MAX_DUMP));
+            let payload = message::payload(DUMP_TYPE_ID, encode_dump(&entries));
+            message::response(Op::Dump, Code::Ok, Some(payload))
+        }
+
+        // The payload carries the number of keys wanted, as a u32, and its `type_id` whether they
+        // are the oldest or the newest keys, see `range`. The response payload is packed as
+        // `decode_range` expects.
+        Op::Range => {
+            let count = payload.ok_or_else(|| "no count given to range op")?;
+            let newest = count.type_id() == RANGE_NEWEST;
+            let count = message::decode_u32(count.data())? as usize;
+            let now = store.clock.now();
+            let keys: Vec<_> = range(store, &namespace, newest, count.min(MAX_RANGE))
+                .into_iter()
+                .map(|(key, inserted_at)| {
+                    (key, (now - inserted_at).num_milliseconds().max(0) as u64)
+                })
+                .collect();
+            let payload = message::payload(0, encode_range(&keys));
+            message::response(Op::Range, Code::Ok, Some(payload))
+        }
+
+        // The payload's data is the most keys to list as a u32. The response is packed as by
+        // `Op::Range`, with how often each key was requested lately in place of its age.
+        Op::HotKeys => {
+            if store.hot_keys.is_none() {
+                return Err(error::Error::new(
+                    error::ErrorKind::Other,
+                    "hot key tracking is disabled",
+                ));
+            }
+            let count = payload.ok_or_else(|| "no count given to hot keys op")?;
+            let count = message::decode_u32(count.data())? as usize;
+            let keys = hot_keys(store, &namespace, count.min(MAX_HOT_KEYS));
+            let payload = message::payload(0, encode_range(&keys));
+            message::response(Op::HotKeys, Code::Ok, Some(payload))
+        }
+
+        // The payload is a key list packed by `message::encode_keys`. The response payload has one
+        // byte per requested key, in order, which is 1 if the key was deleted and 0 if it wasn't
+        // present, and its `type_id` is the number of keys deleted.
+        Op::MultiDel => {
+            let keys = payload.ok_or_else(|| "no keys given to multi del op")?;
+            let keys = message::decode_keys(keys.data())?;
+            if keys.len() > MAX_MULTI_KEYS {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "too many keys in multi del op",
+                ));
+            }
+            let found: Vec<u8> = keys.iter()
+                .map(|key| {
+                    let key = namespace.key(key);
+                    store.expire(&key);
+                    store.remove(&key).is_some() as u8
+                })
+                .collect();
+            let deleted = found.iter().filter(|&&f| f == 1).count();
+            message::response(
+                Op::MultiDel,
+                Code::Ok,
+                Some(message::payload(deleted as u32, found)),
+            )
+        }
+
+        // The payload is how long the entry lives for from now, in seconds as a u64, up to
+        // `MAX_TTL`. Only the expiry changes, though the entry gets a new version, so that Cas
+        // and Watch see the change.
+        Op::Touch => {
+            let ttl = payload.ok_or_else(|| "no ttl given to touch op")?;
+            let ttl = checked_ttl(message::decode_u64(ttl.data())?)?;
+            let expires_at = store.clock.now() + ttl;
+            if store.entries.contains_key(&key) {
+                let version = store.next_version();
+                let entry = store.entries.get_mut(&key).unwrap();
+                entry.expires_at = Some(expires_at);
+                entry.jitter = None;
+                entry.version = version;
+                message::response(Op::Touch, Code::Ok, None)
+            } else {
+                message::response(Op::Touch, Code::Miss, None)
+            }
+        }
+
+        // Sets the expiry from the `EXT_EXPIRES_AT` and `EXT_TTL` extensions as a Set would, or
+        // removes it if neither is given. Like a Touch, the value is left alone but the version
+        // is bumped.
+        Op::Expire => {
+            if store.entries.contains_key(&key) {
+                let version = store.next_version();
+                let entry = store.entries.get_mut(&key).unwrap();
+                entry.expires_at = expires_at;
+                entry.jitter = jitter;
+                entry.version = version;
+                message::response(Op::Expire, Code::Ok, None)
+            } else {
+                message::response(Op::Expire, Code::Miss, None)
+            }
+        }
+
+        // The payload is a key list packed by `message::encode_keys`. The response payload holds
+        // the entries found, in request order, packed by `message::encode_entries`. Keys that
+        // aren't present are left out.
+        Op::MGet => {
+            let keys = payload.ok_or_else(|| "no keys given to mget op")?;
+            let keys = message::decode_keys(keys.data())?;
+            if keys.len() > MAX_MULTI_KEYS {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "too many keys in mget op",
+                ));
+            }
+            let mut found = vec![];
+            let now = store.clock.now();
+            for key in keys {
+                let stored = namespace.key(&key);
+                store.expire(&stored);
+                let payload = store.entries.get_mut(&stored).map(|entry| {
+                    entry.read(now);
+                    entry.payload.clone()
+                });
+                if let Some(payload) = payload {
+                    found.push((key, payload));
+                }
+            }
+            let data = message::encode_entries(&found);
+            message::response(
+                Op::MGet,
+                Code::Ok,
+                Some(message::payload(message::ENTRY_LIST_TYPE_ID, data)),
+            )
+        }
+
+        // See `batch_request`.
+        Op::Batch => {
+            let ops = batched.unwrap_or_default();
+            if ops.len() > MAX_MULTI_KEYS {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "too many ops in batch op",
+                ));
+            }
+            if ops.iter().any(|op| !BATCH_OPS.contains(&op.op()) || op.key().is_none()) {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "batch op holds an op that can't be batched",
+                ));
+            }
+            let mut saved = vec![];
+            for op in &ops {
+                let key = Namespace::of(op)?.key(op.key().unwrap_or_default());
+                let entry = store.entries.get_mut(&key).cloned();
+                saved.push((key, entry));
+            }
+            let mut results = vec![];
+            let mut failed = None;
+            for req in ops {
+                let op = req.op();
+                let resp = handle(store, req).unwrap_or_else(|e| {
+                    let description = e.description().to_owned().into_bytes();
+                    let description = message::payload(0, description);
+                    message::response(op, Code::Error, Some(description))
+                });
+                let code = resp.code();
+                results.push(resp);
+                match (op, code) {
+                    (_, Code::Ok) | (Op::Get, Code::Hit) | (Op::Get, Code::Miss) |
+                    (Op::Get, Code::Stale) | (Op::Get, Code::NotFound) |
+                    (Op::Del, Code::Miss) => {}
+                    _ => {
+                        failed = Some(code);
+                        break;
+                    }
+                }
+            }
+            if failed.is_some() {
+                // Duplicate keys were saved before any op, so either copy puts them back.
+                for (key, entry) in saved {
+                    store.remove(&key);
+                    if let Some(entry) = entry {
+                        store.insert(key, entry);
+                    }
+                }
+            }
+            let data = message::encode_messages(&results);
+            message::response(
+                Op::Batch,
+                failed.unwrap_or(Code::Ok),
+                Some(message::payload(message::MESSAGE_LIST_TYPE_ID, data)),
+            )
+        }
+
+        // Runs the function named by the request's `EXT_FUNCTION` extension on the current value,
+        // with the payload as its argument. The new value keeps the stored `type_id`, or takes the
+        // argument's for a new key, and any expiry is kept. Responds with the new value, or no
+        // payload if it was deleted.
+        Op::Apply => {
+            let name = function.ok_or_else(|| "no function given to apply op")?;
+            let function = String::from_utf8(name)
+                .ok()
+                .and_then(|name| store.functions.get(&name).cloned())
+                .ok_or_else(|| "unknown function given to apply op")?;
+            let (type_id, arg) = payload.map_or((0, vec![]), |p| (p.type_id(), p.data().to_vec()));
+
+            let now = store.clock.now();
+            let (type_id, expiry, inserted_at, value) = match store.entries.get_mut(&key) {
+                Some(entry) => {
+                    let value = function(Some(entry.payload.data()), &arg);
+                    let expiry = (entry.expires_at, entry.jitter);
+                    (entry.payload.type_id(), expiry, entry.inserted_at, value)
+                }
+                None => (type_id, (None, None), now, function(None, &arg)),
+            };
+            match value {
+                Some(value) => {
+                    if store.max_value_len.map_or(false, |max| value.len() > max) {
+                        return Ok(message::response(Op::Apply, Code::ValueTooLarge, None));
+                    }
+                    let payload = message::payload(type_id, value);
+                    if !store.fits(entry_size(&key, &payload)) {
+                        return Err(too_large());
+                    }
+                    if store.over_quota(&key) {
+                        return Ok(message::response(Op::Apply, Code::QuotaExceeded, None));
+                    }
+                    let version = store.next_version();
+                    let (expires_at, jitter) = expiry;
+                    let entry = Entry::new(payload.clone(), version, expires_at, inserted_at);
+                    store.insert(key, Entry { jitter: jitter, ..entry });
+                    message::response(Op::Apply, Code::Ok, Some(payload))
+                        .with_extension(message::EXT_VERSION, message::encode_u64(version))
+                }
+                None => {
+                    store.remove(&key);
+                    message::response(Op::Apply, Code::Ok, None)
+                }
+            }
+        }
+
+        // The payload's `type_id` is the new type and its data the expected current type, as a
+        // u32. Only the `type_id` changes, the value is left in place. If the current type isn't
+        // the expected one, responds with `Code::WrongType`, see `wrong_type`.
+        Op::Retype => {
+            let retype = payload.ok_or_else(|| "no type given to retype op")?;
+            let expected = message::decode_u32(retype.data())?;
+            let current = store.entries.get_mut(&key).map(|entry| entry.payload.type_id());
+            match current {
+                Some(current) if current == expected => {
+                    let version = store.next_version();
+                    let entry = store.entries.get_mut(&key).unwrap();
+                    entry.payload.set_type_id(retype.type_id());
+                    entry.version = version;
+                    message::response(Op::Retype, Code::Ok, None)
+                        .with_extension(message::EXT_VERSION, message::encode_u64(version))
+                }
+                Some(current) => wrong_type(Op::Retype, current),
+                None => message::response(Op::Retype, Code::Miss, None),
+            }
+        }
+
+        // The payload is a field descriptor, see `field_incr_request`. Responds with the new value
+        // of the field as a u64.
+        Op::FieldIncr => {
+            let descriptor = payload.ok_or_else(|| "no field given to field incr op")?;
+            if descriptor.data().len() != FIELD_DESCRIPTOR_LEN {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "malformed field descriptor",
+                ));
+            }
+            let mut cursor = io::Cursor::new(descriptor.data());
+            let offset = cursor.get_u32::<BigEndian>() as usize;
+            let width = cursor.get_u8() as usize;
+            let delta = cursor.get_i64::<BigEndian>();
+            if ![1, 2, 4, 8].contains(&width) {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "field width must be 1, 2, 4 or 8 bytes",
+                ));
+            }
+
+            let version = store.next_version();
+            match store.entries.get_mut(&key) {
+                Some(entry) => {
+                    if offset + width > entry.payload.data().len() {
+                        return Err(error::Error::new(
+                            error::ErrorKind::InvalidData,
+                            "field is out of bounds of the value",
+                        ));
+                    }
+                    // The data may be shared, with responses still being sent, so it is copied.
+                    let mut data = entry.payload.data().to_vec();
+                    let value = incr_field(&mut data, offset, width, delta);
+                    entry.payload.set_data(data.into());
+                    entry.version = version;
+                    message::response(
+                        Op::FieldIncr,
+                        Code::Ok,
+                        Some(message::payload(0, message::encode_u64(value))),
+                    ).with_extension(message::EXT_VERSION, message::encode_u64(version))
+                }
+                None => message::response(Op::FieldIncr, Code::Miss, None),
+            }
+        }
+
+        // The payload is the amount to add or subtract, as a u64 holding an i64. The value must be
+        // a `message::TYPE_I64`, or the key absent, when it starts from 0 and takes any expiry
+        // given. Responds with the new value, or `Code::WrongType` with the current type, see
+        // `wrong_type`, if the value isn't a counter.
+        Op::Incr | Op::Decr => {
+            let delta = payload.ok_or_else(|| "no amount given to incr op")?;
+            let delta = message::decode_u64(delta.data())? as i64;
+            let delta = if op == Op::Incr { Some(delta) } else { delta.checked_neg() };
+
+            let now = store.clock.now();
+            let (current, expiry, inserted_at) = match store.entries.get_mut(&key) {
+                Some(entry) => {
+                    let payload = &entry.payload;
+                    if payload.type_id() != message::TYPE_I64 || payload.data().len() != 8 {
+                        return Ok(wrong_type(op, payload.type_id()));
+                    }
+                    let current = io::Cursor::new(payload.data()).get_i64::<BigEndian>();
+                    (current, (entry.expires_at, entry.jitter), entry.inserted_at)
+                }
+                None => {
+                    if store.over_quota(&key) {
+                        return Ok(message::response(op, Code::QuotaExceeded, None));
+                    }
+                    (0, (expires_at, jitter), now)
+                }
+            };
+            let value = delta.and_then(|delta| current.checked_add(delta)).ok_or_else(|| {
+                error::Error::new(error::ErrorKind::InvalidData, "counter overflow")
+            })?;
+
+            let payload = message::payload(message::TYPE_I64, message::encode_u64(value as u64));
+            if !store.fits(entry_size(&key, &payload)) {
+                return Err(too_large());
+            }
+            let version = store.next_version();
+            let (expires_at, jitter) = expiry;
+            let entry = Entry::new(payload.clone(), version, expires_at, inserted_at);
+            store.insert(key, Entry { jitter: jitter, ..entry });
+            message::response(op, Code::Ok, Some(payload))
+                .with_extension(message::EXT_VERSION, message::encode_u64(version))
+        }
+
+        // Concatenates the payload's data onto the end of the value, or for a Prepend onto its
+        // start, keeping the value's `type_id` and expiry; the payload's `type_id` is ignored.
+        // Responds with `Code::Miss` if there is no value to extend.
+        Op::Append | Op::Prepend => {
+            let extra = payload.ok_or_else(|| "no payload given to append op")?;
+            let (payload, expiry, inserted_at) = match store.entries.get_mut(&key) {
+                Some(ref entry) if store.max_value_len.map_or(false, |max| {
+                    entry.payload.data().len() + extra.data().len() > max
+                }) => return Ok(message::response(op, Code::ValueTooLarge, None)),
+                Some(entry) => {
+                    let (current, extra) = (entry.payload.data(), extra.data());
+                    let mut data = Vec::with_capacity(current.len() + extra.len());
+                    if op == Op::Append {
+                        data.extend_from_slice(current);
+                        data.extend_from_slice(extra);
+                    } else {
+                        data.extend_from_slice(extra);
+                        data.extend_from_slice(current);
+                    }
+                    let payload = message::payload(entry.payload.type_id(), data);
+                    (payload, (entry.expires_at, entry.jitter), entry.inserted_at)
+                }
+                None => return Ok(message::response(op, Code::Miss, None)),
+            };
+            if !store.fits(entry_size(&key, &payload)) {
+                return Err(too_large());
+            }
+            let version = store.next_version();
+            let (expires_at, jitter) = expiry;
+            let entry = Entry::new(payload, version, expires_at, inserted_at);
+            store.insert(key, Entry { jitter: jitter, ..entry });
+            message::response(op, Code::Ok, None)
+                .with_extension(message::EXT_VERSION, message::encode_u64(version))
+        }
+
+        // Streaming is driven by `service::serve`, which breaks it up into `Op::Scan` requests.
+        Op::ScanStream => {
+            return Err(error::Error::new(
+                error::ErrorKind::BadMessage,
+                "scan stream must be dispatched as scan requests",
+            ))
+        }
+
+        // The handshake is part of the connection, so `service::serve` answers it.
+        Op::Hello => {
+            return Err(error::Error::new(
+                error::ErrorKind::BadMessage,
+                "hello must be answered by the server",
+            ))
+        }
+
+        // Subscriptions are kept by the connection, see `service::SubscribeService`, once the
+        // cache has agreed to them.
+        Op::Subscribe | Op::Unsubscribe => {
+            if store.hub.is_none() {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "notifications are disabled",
+                ));
+            }
+            message::response(op, Code::Ok, None)
+        }
+
+        Op::Notify => {
+            return Err(error::Error::new(
+                error::ErrorKind::BadMessage,
+                "notifications are only sent by the server",
+            ))
+        }
+
+        Op::AssignSlots => {
+            let (keys, (first, last, owner, migrate)) = given_up.unwrap();
+            let now = store.clock.now();
+            let mut sets = vec![];
+            for key in &keys {
+                let entry = match store.remove(key) {
+                    Some(entry) if migrate && !entry.expired(now) => entry,
+                    _ => continue,
+                };
+                let mut set = stored_request(Op::Set, key, Some(entry.payload));
+                if let Some(at) = entry.expires_at {
+                    let at = message::encode_u64(at.sec as u64);
+                    set = set.with_extension(message::EXT_EXPIRES_AT, at);
+                }
+                sets.push(set);
+            }
+            if let Some(ref mut slots) = store.cluster {
+                slots.assign(first, last, owner);
+            }
+            if !sets.is_empty() {
+                cluster::hand_over(owner, sets);
+            }
+            let count = message::payload(0, message::encode_u64(keys.len() as u64));
+            message::response(Op::AssignSlots, Code::Ok, Some(count))
+        }
+
+        // The writes are streamed by the connection, see `service::ReplicateService`, once the
+        // cache has agreed to it.
+        Op::Replicate => {
+            if store.max_replica_backlog.is_none() {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "replication is disabled",
+                ));
+            }
+            message::response(Op::Replicate, Code::Ok, None)
+        }
+
+        // Answered as the connection does, see `service::pong`, for callers of the cache itself.
+        Op::Ping => message::response(Op::Ping, Code::Ok, payload),
+
+        // See `Op::Watch`. Expired entries are gone by now, so they count as missing.
+        Op::Watch => {
+            let current = store.entries.get_mut(key.as_slice()).map(|e| e.version);
+            let code = match (version, current) {
+                (Some(expected), Some(current)) if expected != current => Code::Conflict,
+                (Some(expected), None) if store.removals.since(&key, expected) => Code::Conflict,
+                _ => Code::Ok,
+            };
+            let current = current.unwrap_or(store.last_version);
+            message::response(Op::Watch, code, None)
+                .with_extension(message::EXT_VERSION, message::encode_u64(current))
+        }
+
+        // Answered by the connection, see `service::ClientService`, once the cache has agreed.
+        Op::ClientList | Op::Kick => message::response(op, Code::Ok, None),
+
+        // Transactions are kept by the connection, see `service::TransactionService`.
+        Op::Multi | Op::Exec | Op::Discard => {
+            return Err(error::Error::new(
+                error::ErrorKind::BadMessage,
+                "transactions must be run by a transaction service",
+            ))
+        }
+
+        // Connections authenticate with `service::AuthService`.
+        Op::Auth => {
+            return Err(error::Error::new(
+                error::ErrorKind::BadMessage,
+                "auth must be answered by an auth service",
+            ))
+        }
+
+        // The slow log is kept by `service::SlowLogService`, which times the requests.
+        Op::SlowLog => {
+            return Err(error::Error::new(
+                error::ErrorKind::Other,
+                "the slow log is disabled",
+            ))
+        }
+
+        // See `decode_stats`. In a namespace other than the default one, only its entries are
+        // counted, and only the evictions from it; the default namespace counts the whole cache.
+        // Either way, only the namespace's own hot keys are listed.
+        Op::Stats => {
+            let (keys, evictions, used_bytes) = match namespace.0 {
+                Some(ref prefix) => {
+                    let (keys, used_bytes) = store
+                        .entries
+                        .iter()
+                        .filter(|&(key, _)| key.starts_with(prefix))
+                        .fold((0, 0), |(keys, bytes), (key, entry)| {
+                            (keys + 1, bytes + entry_size(&key[prefix.len()..], &entry.payload))
+                        });
+                    let name = namespace_of(prefix).unwrap_or_default();
+                    let evictions = store.namespace_evictions.get(name).cloned().unwrap_or(0);
+                    (keys, evictions, used_bytes)
+                }
+                None => (store.entries.len(), store.evictions, store.used_bytes),
+            };
+            let mut data = message::encode_u64(evictions);
+            data.extend(message::encode_u64(used_bytes as u64));
+            data.extend(encode_range(&hot_keys(store, &namespace, HOT_KEYS_IN_STATS)));
+            let payload = message::payload(keys as u32, data);
+            with_limits(message::response(Op::Stats, Code::Ok, Some(payload)), &store.limits())
+        }
+
+        // Deletes every key in the request's namespace, which mustn't be the default one, and
+        // responds with how many were deleted as a u64.
+        Op::FlushNamespace => {
+            if namespace.0.is_none() {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "no namespace given to flush",
+                ));
+            }
+            let flushed = flush(store, &namespace, None);
+            let payload = message::payload(0, message::encode_u64(flushed));
+            message::response(Op::FlushNamespace, Code::Ok, Some(payload))
+        }
+
+        // Deletes every key in the cache, or only those in the request's namespace if it isn't
+        // the default one. An optional payload holds a delay in seconds as a u64, after which the
+        // keys present now expire, rather than being deleted at once. Responds with how many keys
+        // were flushed as a u64. Delays longer than `MAX_TTL` are refused.
+        Op::FlushAll => {
+            let delay = match payload {
+                Some(payload) => message::decode_u64(payload.data())?,
+                None => 0,
+            };
+            if delay > MAX_TTL {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "flush delay is too long",
+                ));
+            }
+            let flushed = flush(store, &namespace, if delay > 0 { Some(delay) } else { None });
+            let payload = message::payload(0, message::encode_u64(flushed));
+            message::response(Op::FlushAll, Code::Ok, Some(payload))
+        }
+
+        Op::Info => message::response(Op::Info, Code::Ok, Some(message::payload(1, info(store)))),
+    };
+
+    Ok(response)
+}
+
+/// The stored keys in `namespace`, or every stored key for the default namespace.
+fn keys_in<S: Storage>(store: &Store<S>, namespace: &Namespace) -> Vec<Vec<u8>> {
+    let prefix = namespace.0.as_ref().map_or(&[][..], |prefix| &prefix[..]);
+    store
+        .entries
+        .iter()
+        .filter(|&(key, _)| key.starts_with(prefix))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+/// Packs keys along with a u64 each, as `decode_range` unpacks them.
+fn encode_range(keys: &[(Vec<u8>, u64)]) -> Vec<u8> {
+    let mut data = vec![];
+    for &(ref key, n) in keys {
+        data.put_u64::<BigEndian>(n);
+        data.put_u32::<BigEndian>(key.len() as u32);
+        data.put_slice(key);
+    }
+    data
+}
+
+/// Up to `count` of the keys in `namespace` requested most lately, with how often they were,
+/// most requested first. None unless the store tracks them, see `Options::hot_keys`.
+fn hot_keys<S: Storage>(
+    store: &mut Store<S>,
+    namespace: &Namespace,
+    count: usize,
+) -> Vec<(Vec<u8>, u64)> {
+    let now = store.clock.now();
+    let counts = match store.hot_keys {
+        Some(ref mut hot_keys) => hot_keys.counts(now),
+        None => return vec![],
+    };
+    counts
+        .into_iter()
+        .filter_map(|(key, n)| namespace.strip(&key).map(|key| (key.to_vec(), n)))
+        .take(count)
+        .collect()
+}
+
+/// The `Code::Redirect` response to `msg`, in cluster mode, if a key it names is in a slot
+/// another node owns.
+fn redirect<S: Storage>(store: &Store<S>, msg: &Message) -> Option<Message> {
+    let slots = store.cluster.as_ref()?;
+    cluster::keys_of(msg)
+        .iter()
+        .map(|key| cluster::slot(key))
+        .find(|&slot| !slots.is_mine(slot))
+        .map(|slot| cluster::redirect(msg.op(), slot, slots.owner(slot)))
+}
+
+/// The stored keys an `Op::AssignSlots` takes away from this node, those in slots it owns that
+/// are assigned to another node, along with the slots, their new owner and whether to hand the
+/// keys over to it.
+fn given_up<S: Storage>(
+    store: &Store<S>,
+    msg: &Message,
+) -> Result<(Vec<Vec<u8>>, (u16, u16, SocketAddr, bool)), error::Error> {
+    let slots = store.cluster.as_ref().ok_or_else(|| "cluster mode is disabled")?;
+    let payload = msg.payload().ok_or_else(|| "no slots given to assign")?;
+    let (first, last, owner) = cluster::decode_assignment(payload)?;
+    let keys = if owner == slots.me() {
+        vec![]
+    } else {
+        store
+            .entries
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| {
+                let slot = cluster::slot(split_key(key).1);
+                slot >= first && slot <= last && slots.is_mine(slot)
+            })
+            .cloned()
+            .collect()
+    };
+    let migrate = payload.type_id() & cluster::MIGRATE != 0;
+    Ok((keys, (first, last, owner, migrate)))
+}
+
+/// Flushes the keys in `namespace`, or the whole cache for the default namespace, returning how
+/// many there were. They're deleted, along with the evictions counted for them, unless there's
+/// a `delay` in seconds, in which case they expire once it has passed, or sooner if they would
+/// have anyway. The stale values kept for the namespace are dropped either way.
+fn flush<S: Storage>(store: &mut Store<S>, namespace: &Namespace, delay: Option<u64>) -> u64 {
+    let keys = keys_in(store, namespace);
+    match namespace.0 {
+        Some(ref prefix) => store.stale.retain(|key, _| !key.starts_with(prefix)),
+        None => store.stale.clear(),
+    }
+    match delay {
+        Some(delay) => {
+            let at = store.clock.now() + Duration::seconds(delay as i64);
+            for key in &keys {
+                if let Some(entry) = store.entries.get_mut(key) {
+                    if entry.expires_at.map_or(true, |expires_at| expires_at > at) {
+                        entry.expires_at = Some(at);
+                        entry.jitter = None;
+                    }
+                }
+            }
+        }
+        None => {
+            for key in &keys {
+                store.remove(key);
+            }
+            match namespace.0 {
+                Some(ref prefix) => {
+                    let name = namespace_of(prefix).unwrap_or_default();
+                    store.namespace_evictions.remove(name);
+                }
+                None => {
+                    store.evictions = 0;
+                    store.namespace_evictions.clear();
+                }
+            }
+        }
+    }
+    keys.len() as u64
+}
+
+/// Describes the server for `Op::Info`, as UTF-8 `name: value` lines: the crate version, the
+/// uptime in seconds, the protocol version, a comma separated list of the optional features
+/// this cache has enabled, and the policy it evicts by with the entries evicted so far, followed
+/// by the `max_key_len` and `max_value_len` it takes, if limited.
+fn info<S: Storage>(store: &Store<S>) -> Vec<u8> {
+    let mut features = vec![];
+    if !store.functions.is_empty() {
+        features.push("functions");
+    }
+    if store.max_bytes.is_some() {
+        features.push("max_bytes");
+    }
+    if store.cluster.is_some() {
+        features.push("cluster");
+    }
+    let uptime = (store.clock.now() - store.started).num_seconds();
+    let mut info = format!(
+        "version: {}\nuptime: {}\nprotocol: {}\nfeatures: {}",
+        env!("CARGO_PKG_VERSION"),
+        uptime,
+        codec::PROTOCOL_VERSION,
+        features.join(",")
+    );
+    info.push_str(&format!(
+        "\neviction_policy: {}\nevictions: {}",
+        store.entries.eviction_policy(),
+        store.evictions
+    ));
+    if let Some(max) = store.max_key_len {
+        info.push_str(&format!("\nmax_key_len: {}", max));
+    }
+    if let Some(max) = store.max_value_len {
+        info.push_str(&format!("\nmax_value_len: {}", max));
+    }
+    info.into_bytes()
+}
+
+/// Adds `delta` to the big endian integer in `data[offset..offset + width]`, wrapping within
+/// `width` bytes, and returns the new value.
+fn incr_field(data: &mut [u8], offset: usize, width: usize, delta: i64) -> u64 {
+    let field = &mut data[offset..offset + width];
+    let current = field.iter().fold(0u64, |n, &b| n << 8 | b as u64);
+    let value = current.wrapping_add(delta as u64);
+    for (i, b) in field.iter_mut().enumerate() {
+        *b = (value >> (8 * (width - 1 - i))) as u8;
+    }
+    if width == 8 {
+        value
+    } else {
+        value & ((1 << (8 * width)) - 1)
+    }
+}
+
+/// The `Code::WrongType` response to `op` on a value of type `current`, which its payload holds
+/// as a u32.
+fn wrong_type(op: Op, current: u32) -> Message {
+    message::response(op, Code::WrongType, Some(message::payload(0, message::encode_u32(current))))
+}
+
+/// Responds with the value of `entry`, tagged with its version.
+fn hit(op: Op, entry: &Entry) -> Message {
+    answer(op, Code::Hit, entry)
+}
+
+/// Like `hit`, with `code`.
+fn answer(op: Op, code: Code, entry: &Entry) -> Message {
+    let resp = message::response(op, code, Some(entry.payload.clone()))
+        .with_extension(message::EXT_VERSION, message::encode_u64(entry.version));
+    match entry.expires_at {
+        Some(at) => {
+            resp.with_extension(message::EXT_EXPIRES_AT, message::encode_u64(at.sec as u64))
+        }
+        None => resp,
+    }
+}
+
+/// Selects up to `count` distinct keys uniformly at random, or every key if the store holds
+/// fewer. Uses reservoir sampling: the first `count` keys fill the sample, then the i'th key
+/// replaces a random slot with probability `count / i`. This takes a single pass over the store
+/// without disturbing the LRU order, but is linear in the size of the store.
+fn sample<S: Storage>(store: &Store<S>, namespace: &Namespace, count: usize) -> Vec<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    let now = store.clock.now();
+    let mut sample = Vec::with_capacity(count.min(store.entries.len()));
+    let live = store
+        .entries
+        .iter()
+        .filter(|&(_, entry)| !entry.expired(now))
+        .filter_map(|(key, _)| namespace.strip(key));
+    for (i, key) in live.enumerate() {
+        if i < count {
+            sample.push(key.to_vec());
+        } else {
+            let j = rng.gen_range(0, i + 1);
+            if j < count {
+                sample[j] = key.to_vec();
+            }
+        }
+    }
+    sample
+}
+
+/// Creates a `Message::Response`, setting the error code and
+/// and passing the error description as the payload. Responses with an error code should
+/// enforce the invariant that the payload contain a UTF8-encoded string, so that clients
+/// can safely decode the payload for human consumption.
+///
+/// TODO: match over the error kind and translate it into an appropriate error for the front end.
+fn handle_error(err: &error::Error) -> Message {
+    message::response(
+        Op::Get,
+        Code::Error,
+        Some(message::payload(
+            0,
+            err.description().to_owned().into_bytes(),
+        )),
+    )
+}
+
+/// Finds the `count` smallest keys greater than `after`, in ascending order, keeping at most
+/// `count` keys in hand while it walks the store.
+fn scan<S: Storage>(
+    store: &Store<S>,
+    namespace: &Namespace,
+    after: Option<Vec<u8>>,
+    pattern: Option<&Vec<u8>>,
+    count: usize,
+) -> Vec<Vec<u8>> {
+    let now = store.clock.now();
+    let mut smallest = BinaryHeap::with_capacity(count + 1);
+    for (key, entry) in store.entries.iter() {
+        let key = match namespace.strip(key) {
+            Some(key) => key,
+            None => continue,
+        };
+        if after.as_ref().map_or(true, |after| key > &after[..]) && !entry.expired(now) &&
+            pattern.map_or(true, |pattern| glob_match(pattern, key))
+        {
+            smallest.push(key);
+            if smallest.len() > count {
+                smallest.pop();
+            }
+        }
+    }
+    smallest.into_sorted_vec().into_iter().map(|key| key.to_vec()).collect()
+}
+
+/// Up to `count` of the unexpired entries in `namespace` whose keys start with `prefix`, in
+/// ascending order of key following `after`, with the seconds each has left to live. Like
+/// `scan`, this doesn't mark the entries as used.
+fn dump<S: Storage>(
+    store: &Store<S>,
+    namespace: &Namespace,
+    prefix: &[u8],
+    after: Option<&[u8]>,
+    count: usize,
+) -> Vec<(Vec<u8>, Payload, Option<u64>)> {
+    let now = store.clock.now();
+    let mut smallest = BTreeMap::new();
+    for (key, entry) in store.entries.iter() {
+        let key = match namespace.strip(key) {
+            Some(key) => key,
+            None => continue,
+        };
+        if key.starts_with(prefix) && after.map_or(true, |after| key > after) &&
+            !entry.expired(now)
+        {
+            smallest.insert(key, entry);
+            if smallest.len() > count {
+                let largest = *smallest.keys().next_back().unwrap();
+                smallest.remove(largest);
+            }
+        }
+    }
+    smallest
+        .into_iter()
+        .map(|(key, entry)| {
+            // Rounded up, so that an entry about to expire doesn't come out as never expiring.
+            let ttl = entry.expires_at.map(|at| {
+                ((at - now).num_milliseconds().max(1) as u64 + 999) / 1000
+            });
+            (key.to_vec(), entry.payload.clone(), ttl)
+        })
+        .collect()
+}
+
+/// Whether `key` matches the glob `pattern`, see `message::EXT_PATTERN`. On a mismatch, backtracks
+/// to the last `*` and lets it match one more byte, so this takes at most quadratic time.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // The position of the last `*` in the pattern, and of the key where it started matching.
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some(&b'*') => {
+                star = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => {
+                match star {
+                    Some((star_p, star_k)) => {
+                        star = Some((star_p, star_k + 1));
+                        p = star_p + 1;
+                        k = star_k + 1;
+                    }
+                    None => return false,
+                }
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Finds the `count` oldest keys by insertion time, oldest first, or the `count` newest, newest
+/// first. Keys inserted at the same time are ordered by key. Like `scan`, keeps at most `count`
+/// keys in hand while it walks the store.
+fn range<S: Storage>(
+    store: &Store<S>,
+    namespace: &Namespace,
+    newest: bool,
+    count: usize,
+) -> Vec<(Vec<u8>, Timespec)> {
+    let now = store.clock.now();
+    let mut first = BinaryHeap::with_capacity(count + 1);
+    for (key, entry) in store.entries.iter().filter(|&(_, e)| !e.expired(now)) {
+        let key = match namespace.strip(key) {
+            Some(key) => key,
+            None => continue,
+        };
+        let at = entry.inserted_at;
+        let order = if newest { (-at.sec, -at.nsec) } else { (at.sec, at.nsec) };
+        first.push((order, key, at));
+        if first.len() > count {
+            first.pop();
+        }
+    }
+    first.into_sorted_vec().into_iter().map(|(_, key, at)| (key.to_vec(), at)).collect()
+}
+
+/// Responds to a request whose handling panicked with `Code::Error`.
+fn handle_panic(op: Op, cause: &str) -> Message {
+    message::server_error(op, &format!("internal error: {}", cause))
+}
+
+fn panic_description(cause: &Box<Any + Send>) -> String {
+    if let Some(s) = cause.downcast_ref::<&str>() {
+        (*s).to_owned()
+    } else if let Some(s) = cause.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, BTreeSet};
+    use clock::MockClock;
+    use test::Bencher;
+
+    fn set<S: Storage>(store: &mut Store<S>, key: &str, value: &str) {
+        let req = message::request(
+            Op::Set,
+            key.into(),
+            Some(message::payload(1, value.into())),
+        );
+        handle(store, req).unwrap();
+    }
+
+    fn rename(store: &mut Store, src: &str, dest: &str, overwrite: bool) -> Message {
+        let flag = if overwrite { RENAME_OVERWRITE } else { 0 };
+        let req = message::request(
+            Op::Rename,
+            src.into(),
+            Some(message::payload(flag, dest.into())),
+        );
+        handle(store, req).unwrap()
+    }
+
+    #[bench]
+    fn bench_get_large_value(b: &mut Bencher) {
+        let mut store = Store::new(10);
+        let value = message::payload(1, vec![0; 1024 * 1024]);
+        handle(&mut store, message::request(Op::Set, "foo".into(), Some(value))).unwrap();
+
+        // The value is shared with the response rather than copied into it.
+        b.iter(|| handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap());
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+
+        let resp = rename(&mut store, "foo", "baz", false);
+
+        assert_eq!(resp.code(), Code::Ok);
+        assert!(!store.entries.contains_key("foo".as_bytes()));
+        assert_eq!(
+            store.entries.get_mut("baz".as_bytes()).unwrap().payload,
+            message::payload(1, "bar".into())
+        );
+    }
+
+    #[test]
+    fn test_rename_over_existing_destination() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+        set(&mut store, "baz", "qux");
+
+        let resp = rename(&mut store, "foo", "baz", false);
+        assert_eq!(resp.code(), Code::Conflict);
+        assert!(store.entries.contains_key("foo".as_bytes()));
+
+        let resp = rename(&mut store, "foo", "baz", true);
+        assert_eq!(resp.code(), Code::Ok);
+        assert!(!store.entries.contains_key("foo".as_bytes()));
+        assert_eq!(
+            store.entries.get_mut("baz".as_bytes()).unwrap().payload,
+            message::payload(1, "bar".into())
+        );
+    }
+
+    #[test]
+    fn test_rename_missing_source() {
+        let mut store = Store::new(10);
+        set(&mut store, "baz", "qux");
+
+        let resp = rename(&mut store, "foo", "baz", true);
+
+        assert_eq!(resp.code(), Code::Miss);
+        assert_eq!(store.entries.len(), 1);
+    }
+
+    fn set_with_token(store: &mut Store, key: &str, value: &str, token: &str) -> Message {
+        let req = message::request(
+            Op::Set,
+            key.into(),
+            Some(message::payload(1, value.into())),
+        ).with_extension(message::EXT_IDEMPOTENCY_TOKEN, token.into());
+        handle(store, req).unwrap()
+    }
+
+    #[test]
+    fn test_idempotent_set() {
+        let mut store = Store::new(10);
+
+        let resp = set_with_token(&mut store, "foo", "bar", "t1");
+        assert_eq!(resp.code(), Code::Ok);
+
+        // A retry of the same Set is a no-op, even if the value has since changed.
+        store.entries.get_mut("foo".as_bytes()).unwrap().payload =
+            message::payload(1, "baz".into());
+        let resp = set_with_token(&mut store, "foo", "bar", "t1");
+        assert_eq!(resp.code(), Code::AlreadyApplied);
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(1, "baz".into())
+        );
+
+        let resp = set_with_token(&mut store, "foo", "qux", "t2");
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(1, "qux".into())
+        );
+    }
+
+    #[test]
+    fn test_set_without_token_clears_token() {
+        let mut store = Store::new(10);
+
+        set_with_token(&mut store, "foo", "bar", "t1");
+        set(&mut store, "foo", "baz");
+        let resp = set_with_token(&mut store, "foo", "bar", "t1");
+
+        assert_eq!(resp.code(), Code::Ok);
+    }
+
+    fn sample_keys(store: &mut Store, count: u32) -> Vec<Vec<u8>> {
+        let req = message::request(
+            Op::Sample,
+            vec![],
+            Some(message::payload(0, message::encode_u32(count))),
+        );
+        let resp = handle(store, req).unwrap();
+        message::decode_keys(resp.payload().unwrap().data()).unwrap()
+    }
+
+    #[test]
+    fn test_sample() {
+        use std::collections::HashSet;
+
+        let mut store = Store::new(100);
+        for i in 0..20 {
+            set(&mut store, &format!("key{}", i), "value");
+        }
+
+        let keys = sample_keys(&mut store, 5);
+        let distinct: HashSet<_> = keys.iter().collect();
+        assert_eq!(keys.len(), 5);
+        assert_eq!(distinct.len(), 5);
+        assert!(keys.iter().all(|key| store.entries.contains_key(key)));
+
+        let keys = sample_keys(&mut store, 50);
+        let distinct: HashSet<_> = keys.iter().collect();
+        assert_eq!(distinct.len(), 20);
+    }
+
+    #[test]
+    fn test_worker_panic() {
+        use futures::Future;
+        use futures::sync::oneshot;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let panics = Arc::new(AtomicUsize::new(0));
+        let hook_panics = panics.clone();
+        let options = Options {
+            panic_hook: Arc::new(move |_: &str| { hook_panics.fetch_add(1, Ordering::SeqCst); }),
+            ..Options::default()
+        };
+        let cache = Cache::with_options(10, options).unwrap();
+
+        let (snd, rcv) = oneshot::channel();
+        cache.process(message::request(Op::Panic, vec![], None), snd);
+        let resp = rcv.wait().unwrap();
+        assert_eq!(resp.op(), Op::Panic);
+        assert_eq!(resp.code(), Code::ServerError);
+        assert_eq!(panics.load(Ordering::SeqCst), 1);
+
+        // The worker survives the panic.
+        let (snd, rcv) = oneshot::channel();
+        cache.process(message::request(Op::Get, "foo".into(), None), snd);
+        assert_eq!(rcv.wait().unwrap().code(), Code::Miss);
+    }
+
+    #[test]
+    fn test_busy() {
+        use futures::Future;
+        use futures::sync::oneshot;
+        use std::sync::Mutex;
+
+        // Holds up the worker until told to carry on.
+        let (started_snd, started) = mpsc::channel();
+        let (resume, resume_rcv) = mpsc::channel::<()>();
+        let (started_snd, resume_rcv) = (Mutex::new(started_snd), Mutex::new(resume_rcv));
+        let block: ApplyFn = Arc::new(move |_: Option<&[u8]>, _: &[u8]| {
+            started_snd.lock().unwrap().send(()).unwrap();
+            resume_rcv.lock().unwrap().recv().unwrap();
+            None
+        });
+        let mut options = Options {
+            max_queued: 1,
+            ..Options::default()
+        };
+        options.functions.insert("block".to_owned(), block);
+        let cache = Cache::with_options(10, options).unwrap();
+
+        let req = message::request(Op::Apply, "foo".into(), Some(message::payload(0, vec![])))
+            .with_extension(message::EXT_FUNCTION, "block".into());
+        let (snd, blocked) = oneshot::channel();
+        cache.process(req, snd);
+        started.recv().unwrap();
+
+        let get = || message::request(Op::Get, "foo".into(), None);
+        let (snd, queued) = oneshot::channel();
+        cache.process(get(), snd);
+        let (snd, refused) = oneshot::channel();
+        cache.process(get(), snd);
+        assert_eq!(refused.wait().unwrap().code(), Code::Busy);
+
+        resume.send(()).unwrap();
+        assert_eq!(blocked.wait().unwrap().code(), Code::Ok);
+        assert_eq!(queued.wait().unwrap().code(), Code::Miss);
+    }
+
+    #[test]
+    fn test_loader() {
+        use futures::future;
+        use std::sync::Mutex;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Loads "db:<key>" for keys starting with "db", failing on "err", and holds up each load
+        // until told to carry on.
+        let loaded = Arc::new(AtomicUsize::new(0));
+        let (resume, resume_rcv) = mpsc::channel::<()>();
+        let resume_rcv = Mutex::new(resume_rcv);
+        let counter = loaded.clone();
+        let loader: LoadFn = Arc::new(move |namespace: Option<&[u8]>, key: &[u8]| {
+            assert_eq!(namespace, None);
+            counter.fetch_add(1, Ordering::SeqCst);
+            resume_rcv.lock().unwrap().recv().unwrap();
+            let value: LoadFuture = if key == b"err" {
+                Box::new(future::err(io::Error::new(io::ErrorKind::Other, "down")))
+            } else if key.starts_with(b"db") {
+                let value = [&b"db:"[..], key].concat();
+                Box::new(future::ok(Some(message::payload(1, value))))
+            } else {
+                Box::new(future::ok(None))
+            };
+            value
+        });
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let options = Options {
+            clock: clock.clone(),
+            loader: Some(loader),
+            load_threads: 1,
+            load_ttl: Some(Duration::seconds(10)),
+            ..Options::default()
+        };
+        let cache = Cache::with_options(10, options).unwrap();
+        let get = |key: &str| {
+            let (snd, rcv) = oneshot::channel();
+            cache.process(message::request(Op::Get, key.into(), None), snd);
+            rcv
+        };
+
+        // Both Gets wait on the one load, while other requests carry on.
+        let (first, second) = (get("db1"), get("db1"));
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, "a".into())));
+        assert_eq!(call(&cache, set).code(), Code::Ok);
+        resume.send(()).unwrap();
+        for rcv in vec![first, second] {
+            let resp = rcv.wait().unwrap();
+            assert_eq!(resp.code(), Code::Hit);
+            assert_eq!(resp.payload(), Some(&message::payload(1, "db:db1".into())));
+        }
+        assert_eq!(loaded.load(Ordering::SeqCst), 1);
+
+        // The loaded value is stored until its TTL.
+        assert_eq!(get("db1").wait().unwrap().code(), Code::Hit);
+        assert_eq!(loaded.load(Ordering::SeqCst), 1);
+        clock.advance(Duration::seconds(10));
+        resume.send(()).unwrap();
+        assert_eq!(get("db1").wait().unwrap().code(), Code::Hit);
+        assert_eq!(loaded.load(Ordering::SeqCst), 2);
+
+        resume.send(()).unwrap();
+        assert_eq!(get("none").wait().unwrap().code(), Code::Miss);
+        resume.send(()).unwrap();
+        assert_eq!(get("err").wait().unwrap().code(), Code::ServerError);
+        assert_eq!(loaded.load(Ordering::SeqCst), 4);
+
+        // With one load thread, a second key's load waits for the first's to finish.
+        let (first, second) = (get("db2"), get("db3"));
+        while loaded.load(Ordering::SeqCst) < 5 {
+            thread::yield_now();
+        }
+        thread::sleep(::std::time::Duration::from_millis(50));
+        assert_eq!(loaded.load(Ordering::SeqCst), 5);
+        resume.send(()).unwrap();
+        assert_eq!(first.wait().unwrap().code(), Code::Hit);
+        resume.send(()).unwrap();
+        assert_eq!(second.wait().unwrap().code(), Code::Hit);
+        assert_eq!(loaded.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn test_negative_caching() {
+        use futures::future;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let loaded = Arc::new(AtomicUsize::new(0));
+        let counter = loaded.clone();
+        let loader: LoadFn = Arc::new(move |_: Option<&[u8]>, _: &[u8]| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let missing: LoadFuture = Box::new(future::ok(None));
+            missing
+        });
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let options = Options {
+            clock: clock.clone(),
+            loader: Some(loader),
+            negative_ttl: Some(Duration::seconds(5)),
+            ..Options::default()
+        };
+        let cache = Cache::with_options(10, options).unwrap();
+        let get = |key: &str| call(&cache, message::request(Op::Get, key.into(), None));
+
+        // The key the loader didn't find is remembered as missing until the marker expires. The
+        // Get that loaded it missed, the ones after it find the marker.
+        assert_eq!(get("none").code(), Code::Miss);
+        assert_eq!(get("none").code(), Code::NotFound);
+        assert_eq!(loaded.load(Ordering::SeqCst), 1);
+        clock.advance(Duration::seconds(5));
+        assert_eq!(get("none").code(), Code::Miss);
+        assert_eq!(get("none").code(), Code::NotFound);
+        assert_eq!(loaded.load(Ordering::SeqCst), 2);
+
+        // Markers can be set, and are overwritten by values.
+        assert_eq!(call(&cache, not_found_request("a".into(), 60)).code(), Code::Ok);
+        let resp = get("a");
+        assert_eq!(resp.code(), Code::NotFound);
+        assert!(resp.extension(message::EXT_EXPIRES_AT).is_some());
+        let set = message::request(Op::Set, "a".into(), Some(message::payload(1, "1".into())));
+        assert_eq!(call(&cache, set).code(), Code::Ok);
+        assert_eq!(get("a").code(), Code::Hit);
+        assert_eq!(loaded.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_replicate() {
+        let mut store = Store::new(10);
+        store.max_replica_backlog = Some(1);
+        set(&mut store, "a", "1");
+        let feed = store.replicate();
+        store.replicate_writes(&[Record::Del("a".into())]);
+        // The replica would now be two writes behind, so it is dropped, ending its feed.
+        store.replicate_writes(&[Record::Set("b".into(), message::payload(1, "2".into()), None)]);
+        assert!(store.replicas.is_empty());
+        let mut writes = feed.wait();
+
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let replica = Cache::with_options(10, options).unwrap();
+        let get = |key: &str| call(&replica, message::request(Op::Get, key.into(), None));
+        let set = message::request(Op::Set, "a".into(), Some(message::payload(1, "0".into())));
+        assert_eq!(call(&replica, set).code(), Code::Error);
+
+        // The snapshot starts by flushing the replica, then holds "a".
+        for write in writes.by_ref().take(2) {
+            replica.apply_replicated(&write.unwrap()).unwrap();
+        }
+        assert_eq!(get("a").payload(), Some(&message::payload(1, "1".into())));
+        replica.apply_replicated(&writes.next().unwrap().unwrap()).unwrap();
+        assert_eq!(get("a").code(), Code::Miss);
+        assert!(writes.next().unwrap().is_err());
+
+        let get = message::request(Op::Get, "a".into(), None);
+        assert!(replica.apply_replicated(&get).is_err());
+    }
+
+    #[test]
+    fn test_cluster() {
+        let other = "10.0.0.2:12345".parse().unwrap();
+        let mut store = Store::new(10);
+        let assign = cluster::assign_request(12000, 12200, other, false);
+        assert!(handle(&mut store, assign.clone()).is_err());
+
+        store.cluster = Some(Slots::new("10.0.0.1:12345".parse().unwrap()));
+        set(&mut store, "foo", "1");
+        set(&mut store, "123456789", "2");
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, "3".into())));
+        handle(&mut store, set.with_extension(message::EXT_NAMESPACE, "n".into())).unwrap();
+
+        // "foo" is in slot 12182, in every namespace, and "123456789" in slot 12739.
+        let given_up = written_keys(&store, &assign);
+        assert_eq!(given_up.len(), 2);
+        assert!(given_up.contains(&b"foo".to_vec()));
+        let resp = handle(&mut store, assign.clone()).unwrap();
+        assert_eq!(resp.payload().unwrap().data(), &message::encode_u64(2)[..]);
+        assert_eq!(store.entries.len(), 1);
+        assert!(written_keys(&store, &assign).is_empty());
+
+        let get = |store: &mut Store, key: &str| {
+            handle(store, message::request(Op::Get, key.into(), None)).unwrap()
+        };
+        let resp = get(&mut store, "foo");
+        assert_eq!(cluster::redirected_to(&resp), Some((12182, other)));
+        assert_eq!(get(&mut store, "123456789").code(), Code::Hit);
+        let mget = message::request(
+            Op::MGet,
+            vec![],
+            Some(message::payload(0, message::encode_keys(&["123456789".into(), "foo".into()]))),
+        );
+        assert_eq!(handle(&mut store, mget).unwrap().code(), Code::Redirect);
+    }
+
+    #[test]
+    fn test_hot_keys() {
+        let mut store = Store::new(10);
+        let hot_keys = |store: &mut Store, count: u32| {
+            let count = message::payload(0, message::encode_u32(count));
+            handle(store, message::request(Op::HotKeys, vec![], Some(count)))
+        };
+        assert!(hot_keys(&mut store, 10).is_err());
+
+        store.hot_keys = Some(HotKeys::new(1, store.clock.now()));
+        set(&mut store, "a", "1");
+        for key in &["a", "b", "a"] {
+            handle(&mut store, message::request(Op::Get, key.to_string().into(), None)).unwrap();
+        }
+        let get = message::request(Op::Get, "a".into(), None);
+        handle(&mut store, get.with_extension(message::EXT_NAMESPACE, "n".into())).unwrap();
+
+        let resp = hot_keys(&mut store, 10).unwrap();
+        let keys = decode_range(resp.payload().unwrap().data()).unwrap();
+        assert_eq!(keys, vec![(b"a".to_vec(), 3), (b"b".to_vec(), 1)]);
+        let resp = hot_keys(&mut store, 1).unwrap();
+        assert_eq!(decode_range(resp.payload().unwrap().data()).unwrap().len(), 1);
+
+        let stats = handle(&mut store, message::request(Op::Stats, vec![], None)).unwrap();
+        let stats = decode_stats(stats.payload().unwrap()).unwrap();
+        assert_eq!(stats.hot_keys, keys);
+        let stats = message::request(Op::Stats, vec![], None)
+            .with_extension(message::EXT_NAMESPACE, "n".into());
+        let stats = decode_stats(handle(&mut store, stats).unwrap().payload().unwrap()).unwrap();
+        assert_eq!(stats.hot_keys, vec![(b"a".to_vec(), 1)]);
+    }
+
+    fn scan_page(store: &mut Store, after: Option<&str>, count: u32) -> Vec<Vec<u8>> {
+        let req = scan_request(after.map(|a| a.into()), count);
+        let resp = handle(store, req).unwrap();
+        message::decode_keys(resp.payload().unwrap().data()).unwrap()
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut store = Store::new(100);
+        for key in &["d", "a", "c", "e", "b"] {
+            set(&mut store, key, "value");
+        }
+
+        let page: Vec<Vec<u8>> = vec!["a".into(), "b".into()];
+        assert_eq!(scan_page(&mut store, None, 2), page);
+        let page: Vec<Vec<u8>> = vec!["c".into(), "d".into()];
+        assert_eq!(scan_page(&mut store, Some("b"), 2), page);
+        let page: Vec<Vec<u8>> = vec!["e".into()];
+        assert_eq!(scan_page(&mut store, Some("d"), 2), page);
+        assert!(scan_page(&mut store, Some("e"), 2).is_empty());
+    }
+
+    #[test]
+    fn test_scan_matching() {
+        let mut store = Store::new(100);
+        for key in &["user:2", "session:1", "user:1", "user:10", "users"] {
+            set(&mut store, key, "value");
+        }
+        let scan_matching = |store: &mut Store, after: Option<&str>, pattern: &str| {
+            let req = scan_matching_request(after.map(|a| a.into()), 2, pattern.as_bytes());
+            let resp = handle(store, req).unwrap();
+            message::decode_keys(resp.payload().unwrap().data()).unwrap()
+        };
+
+        let page: Vec<Vec<u8>> = vec!["user:1".into(), "user:10".into()];
+        assert_eq!(scan_matching(&mut store, None, "user:*"), page);
+        let page: Vec<Vec<u8>> = vec!["user:2".into()];
+        assert_eq!(scan_matching(&mut store, Some("user:10"), "user:*"), page);
+        assert!(scan_matching(&mut store, Some("user:2"), "user:*").is_empty());
+
+        let page: Vec<Vec<u8>> = vec!["user:1".into(), "user:2".into()];
+        assert_eq!(scan_matching(&mut store, None, "user:?"), page);
+    }
+
+    #[test]
+    fn test_dump() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(100);
+        store.clock = clock.clone();
+        for key in &["user:2", "session:1", "user:1", "user:3"] {
+            set(&mut store, key, "value");
+        }
+        let req = message::request(Op::Set, "user:0".into(), Some(message::payload(7, "0".into())))
+            .with_extension(message::EXT_TTL, message::encode_u64(60));
+        handle(&mut store, req).unwrap();
+        let req = message::request(Op::Set, "user:4".into(), Some(message::payload(1, vec![])))
+            .with_extension(message::EXT_TTL, message::encode_u64(1));
+        handle(&mut store, req).unwrap();
+        clock.advance(Duration::milliseconds(1500));
+        let dump_page = |store: &mut Store, after: Option<&str>| {
+            let req = dump_request(b"user:", after.map(|after| after.as_bytes()), 2, 0);
+            let resp = handle(store, req).unwrap();
+            assert_eq!(resp.payload().unwrap().type_id(), DUMP_TYPE_ID);
+            decode_dump(resp.payload().unwrap().data()).unwrap()
+        };
+
+        // What is left of the TTL is rounded up, and "user:4" has expired.
+        let page = dump_page(&mut store, None);
+        assert_eq!(page[0], ("user:0".into(), message::payload(7, "0".into()), Some(59)));
+        assert_eq!(page[1], ("user:1".into(), message::payload(1, "value".into()), None));
+        let page = dump_page(&mut store, Some("user:1"));
+        let keys: Vec<Vec<u8>> = page.into_iter().map(|(key, _, _)| key).collect();
+        assert_eq!(keys, vec![b"user:2".to_vec(), b"user:3".to_vec()]);
+        assert!(dump_page(&mut store, Some("user:3")).is_empty());
+
+        let entries = vec![(b"a".to_vec(), message::payload(3, "1".into()), Some(5))];
+        let data = encode_dump(&entries);
+        assert_eq!(decode_dump(&data).unwrap(), entries);
+        assert!(decode_dump(&data[..data.len() - 1]).is_err());
+        let (count, per_second, after) = decode_dump_request(
+            dump_request(b"", Some(b"a"), 10, 100).payload().unwrap(),
+        ).unwrap();
+        assert_eq!((count, per_second, after), (10, 100, Some(b"a".to_vec())));
+    }
+
+    #[test]
+    fn test_restore() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut from = Store::new(100);
+        from.clock = clock.clone();
+        for key in &["a", "b", "c"] {
+            set(&mut from, key, key);
+        }
+        let req = message::request(Op::Set, "d".into(), Some(message::payload(5, "d".into())))
+            .with_extension(message::EXT_TTL, message::encode_u64(60));
+        handle(&mut from, req).unwrap();
+        let resp = handle(&mut from, dump_request(b"", None, 10, 0)).unwrap();
+        let entries = decode_dump(resp.payload().unwrap().data()).unwrap();
+
+        // "a" is taken, unless replacing.
+        let mut store = Store::new(100);
+        store.clock = clock.clone();
+        store.max_key_len = Some(3);
+        store.max_value_len = Some(10);
+        set(&mut store, "a", "taken");
+        let get = |store: &mut Store, key: &str| {
+            let resp = handle(store, message::request(Op::Get, key.into(), None)).unwrap();
+            resp.payload().map(|p| p.data().to_vec())
+        };
+        let restored = |store: &mut Store, replace: bool| {
+            let resp = handle(store, restore_request(&entries, replace)).unwrap();
+            assert_eq!(written_keys(store, &restore_request(&entries, replace)).len(), 4);
+            message::decode_u64(resp.payload().unwrap().data()).unwrap()
+        };
+        assert_eq!(restored(&mut store, false), 3);
+        assert_eq!(get(&mut store, "a"), Some("taken".into()));
+        assert_eq!(get(&mut store, "b"), Some("b".into()));
+        let resp = handle(&mut store, message::request(Op::Ttl, "d".into(), None)).unwrap();
+        assert_eq!(resp.payload().unwrap().data(), &message::encode_u64(60)[..]);
+        assert_eq!(restored(&mut store, true), 4);
+        assert_eq!(get(&mut store, "a"), Some("a".into()));
+
+        // A key or value too long refuses every entry, as does a TTL too long.
+        let long = vec![("long".into(), message::payload(1, "long".into()), None)];
+        let resp = handle(&mut store, restore_request(&long, false)).unwrap();
+        assert_eq!(resp.code(), Code::KeyTooLarge);
+        let long = vec![
+            ("e".into(), message::payload(1, "e".into()), None),
+            ("f".into(), message::payload(1, vec![0; 11]), None),
+        ];
+        let resp = handle(&mut store, restore_request(&long, false)).unwrap();
+        assert_eq!(resp.code(), Code::ValueTooLarge);
+        assert_eq!(get(&mut store, "e"), None);
+        let forever = vec![
+            ("f".into(), message::payload(1, "f".into()), None),
+            ("g".into(), message::payload(1, "g".into()), Some(u64::max_value())),
+        ];
+        assert!(handle(&mut store, restore_request(&forever, false)).is_err());
+        assert_eq!(get(&mut store, "f"), None);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"", b""));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"foo*", b"foo"));
+        assert!(glob_match(b"foo*", b"foobar"));
+        assert!(!glob_match(b"foo*", b"fo"));
+        assert!(glob_match(b"*bar", b"foobar"));
+        assert!(glob_match(b"f?o", b"foo"));
+        assert!(!glob_match(b"f?o", b"fo"));
+        assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob_match(b"a*b*c", b"aXbYbZ"));
+        assert!(glob_match(b"**a", b"ba"));
+        assert!(!glob_match(b"foo", b"foobar"));
+    }
+
+    fn get_if_newer(store: &mut Store, key: &str, known: u64) -> Message {
+        let req = message::request(Op::GetIfNewer, key.into(), None)
+            .with_extension(message::EXT_VERSION, message::encode_u64(known));
+        handle(store, req).unwrap()
+    }
+
+    fn version_of(msg: &Message) -> u64 {
+        message::decode_u64(msg.extension(message::EXT_VERSION).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_get_if_newer() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+
+        let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        let version = version_of(&resp);
+
+        let resp = get_if_newer(&mut store, "foo", version);
+        assert_eq!(resp.code(), Code::NotModified);
+        assert_eq!(resp.payload(), None);
+
+        set(&mut store, "foo", "baz");
+        let resp = get_if_newer(&mut store, "foo", version);
+        assert_eq!(resp.code(), Code::Hit);
+        assert_eq!(resp.payload(), Some(&message::payload(1, "baz".into())));
+        assert!(version_of(&resp) > version);
+
+        assert_eq!(get_if_newer(&mut store, "qux", 0).code(), Code::Miss);
+    }
+
+    #[test]
+    fn test_multi_del() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+        set(&mut store, "baz", "qux");
+        set(&mut store, "keep", "me");
+
+        let keys: Vec<Vec<u8>> = vec!["foo".into(), "missing".into(), "baz".into()];
+        let req = message::request(
+            Op::MultiDel,
+            vec![],
+            Some(message::payload(0, message::encode_keys(&keys))),
+        );
+        let resp = handle(&mut store, req).unwrap();
+
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(resp.payload(), Some(&message::payload(2, vec![1, 0, 1])));
+        assert_eq!(store.entries.len(), 1);
+        assert!(store.entries.contains_key("keep".as_bytes()));
+    }
+
+    #[test]
+    fn test_multi_del_too_many_keys() {
+        let mut store = Store::new(10);
+        let keys = vec![vec![]; MAX_MULTI_KEYS + 1];
+        let req = message::request(
+            Op::MultiDel,
+            vec![],
+            Some(message::payload(0, message::encode_keys(&keys))),
+        );
+
+        assert!(handle(&mut store, req).is_err());
+    }
+
+    fn apply(store: &mut Store, key: &str, function: &str, arg: &str) -> Message {
+        let req = message::request(Op::Apply, key.into(), Some(message::payload(1, arg.into())))
+            .with_extension(message::EXT_FUNCTION, function.into());
+        handle(store, req).unwrap()
+    }
+
+    fn functions_store() -> Store {
+        let mut store = Store::new(10);
+        let append: ApplyFn = Arc::new(|value: Option<&[u8]>, arg: &[u8]| {
+            let mut value = value.unwrap_or(&[]).to_vec();
+            value.extend_from_slice(arg);
+            Some(value)
+        });
+        let delete: ApplyFn = Arc::new(|_: Option<&[u8]>, _: &[u8]| None);
+        store.functions.insert("append".to_owned(), append);
+        store.functions.insert("delete".to_owned(), delete);
+        store
+    }
+
+    #[test]
+    fn test_apply_append() {
+        let mut store = functions_store();
+        set(&mut store, "foo", "bar");
+
+        let resp = apply(&mut store, "foo", "append", "baz");
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(resp.payload(), Some(&message::payload(1, "barbaz".into())));
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(1, "barbaz".into())
+        );
+
+        apply(&mut store, "new", "append", "qux");
+        assert_eq!(
+            store.entries.get_mut("new".as_bytes()).unwrap().payload,
+            message::payload(1, "qux".into())
+        );
+    }
+
+    #[test]
+    fn test_apply_delete() {
+        let mut store = functions_store();
+        set(&mut store, "foo", "bar");
+
+        let resp = apply(&mut store, "foo", "delete", "");
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(resp.payload(), None);
+        assert!(!store.entries.contains_key("foo".as_bytes()));
+    }
+
+    #[test]
+    fn test_apply_unknown_function() {
+        let mut store = functions_store();
+        let req = message::request(Op::Apply, "foo".into(), None)
+            .with_extension(message::EXT_FUNCTION, "missing".into());
+
+        assert!(handle(&mut store, req).is_err());
+    }
+
+    fn retype(store: &mut Store, key: &str, expected: u32, type_id: u32) -> Message {
+        let req = message::request(
+            Op::Retype,
+            key.into(),
+            Some(message::payload(type_id, message::encode_u32(expected))),
+        );
+        handle(store, req).unwrap()
+    }
+
+    #[test]
+    fn test_retype() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+
+        let resp = retype(&mut store, "foo", 1, 7);
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(7, "bar".into())
+        );
+    }
+
+    #[test]
+    fn test_retype_wrong_type() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+
+        let version = store.next_version();
+        let resp = retype(&mut store, "foo", 2, 7);
+        assert_eq!(resp.code(), Code::WrongType);
+        assert_eq!(message::decode_u32(resp.payload().unwrap().data()).unwrap(), 1);
+        // Failing doesn't use up a version.
+        assert_eq!(store.next_version(), version + 1);
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(1, "bar".into())
+        );
+    }
+
+    #[test]
+    fn test_retype_missing() {
+        let mut store = Store::new(10);
+        assert_eq!(retype(&mut store, "foo", 1, 7).code(), Code::Miss);
+    }
+
+    #[test]
+    fn test_max_bytes_evicts() {
+        let mut store = Store::new(10);
+        store.max_bytes = Some(40);
+        for key in &["a", "b", "c", "d"] {
+            set(&mut store, key, "123456789");
+        }
+        assert_eq!(store.used_bytes, 40);
+
+        // Needs the space of two of the smaller entries, which go in least recently used order.
+        set(&mut store, "e", "1234567890123456789");
+        assert_eq!(store.used_bytes, 40);
+        assert!(!store.entries.contains_key("a".as_bytes()));
+        assert!(!store.entries.contains_key("b".as_bytes()));
+        assert!(store.entries.contains_key("c".as_bytes()));
+        assert!(store.entries.contains_key("e".as_bytes()));
+        assert_eq!(store.evictions, 2);
+    }
+
+    #[test]
+    fn test_stats_evictions() {
+        let mut store = Store::new(2);
+        for key in &["a", "b", "c"] {
+            set(&mut store, key, "bar");
+        }
+        // Touching "b" makes "c" the least recently used.
+        handle(&mut store, message::request(Op::Get, "b".into(), None)).unwrap();
+        set(&mut store, "d", "bar");
+        assert!(store.entries.contains_key("b".as_bytes()));
+        assert!(!store.entries.contains_key("c".as_bytes()));
+
+        let resp = handle(&mut store, message::request(Op::Stats, vec![], None)).unwrap();
+        let stats = decode_stats(resp.payload().unwrap()).unwrap();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.evictions, 2);
+        assert_eq!(stats.used_bytes, store.used_bytes as u64);
+    }
+
+    #[test]
+    fn test_max_bytes_rejects_oversized() {
+        let mut store = Store::new(10);
+        store.max_bytes = Some(40);
+        set(&mut store, "a", "123456789");
+
+        let value = vec![0; 40];
+        let req = message::request(Op::Set, "b".into(), Some(message::payload(1, value)));
+        assert!(handle(&mut store, req).is_err());
+        assert_eq!(store.used_bytes, 10);
+        assert!(store.entries.contains_key("a".as_bytes()));
+    }
+
+    #[test]
+    fn test_key_and_value_limits() {
+        let mut store = Store::new(10);
+        store.max_key_len = Some(3);
+        store.max_value_len = Some(4);
+        let code = |store: &mut Store, req| handle(store, req).unwrap().code();
+        let set = |key: &str, value: &str| {
+            message::request(Op::Set, key.into(), Some(message::payload(1, value.into())))
+        };
+
+        assert_eq!(code(&mut store, set("foo", "1234")), Code::Ok);
+        assert_eq!(code(&mut store, set("fooo", "1")), Code::KeyTooLarge);
+        assert_eq!(code(&mut store, set("bar", "12345")), Code::ValueTooLarge);
+        assert!(!store.entries.contains_key("bar".as_bytes()));
+        // Reads of long keys just miss.
+        let get = message::request(Op::Get, "fooo".into(), None);
+        assert_eq!(code(&mut store, get), Code::Miss);
+        // Nor can a value grow past the limit.
+        let append = Some(message::payload(1, "5".into()));
+        let append = message::request(Op::Append, "foo".into(), append);
+        assert_eq!(code(&mut store, append), Code::ValueTooLarge);
+        let rename = Some(message::payload(0, "baaar".into()));
+        let rename = message::request(Op::Rename, "foo".into(), rename);
+        assert_eq!(code(&mut store, rename), Code::KeyTooLarge);
+        // The namespace doesn't count.
+        let set = set("baz", "1").with_extension(message::EXT_NAMESPACE, "ns".into());
+        assert_eq!(code(&mut store, set), Code::Ok);
+        // Nor can a function's result be too long.
+        store.functions.insert("double".into(), Arc::new(|value: Option<&[u8]>, _: &[u8]| {
+            value.map(|value| [value, value].concat())
+        }));
+        let apply = || {
+            message::request(Op::Apply, "foo".into(), None)
+                .with_extension(message::EXT_FUNCTION, "double".into())
+        };
+        assert_eq!(code(&mut store, apply()), Code::ValueTooLarge);
+        let short = message::request(Op::Set, "foo".into(), Some(message::payload(1, "12".into())));
+        assert_eq!(code(&mut store, short), Code::Ok);
+        assert_eq!(code(&mut store, apply()), Code::Ok);
+
+        // The limits are advertised along with the stats.
+        let resp = handle(&mut store, message::request(Op::Stats, vec![], None)).unwrap();
+        let limits = Limits {
+            max_key_len: Some(3),
+            max_value_len: Some(4),
+        };
+        assert_eq!(decode_limits(&resp).unwrap(), limits);
+        let resp = handle(&mut Store::new(1), message::request(Op::Stats, vec![], None)).unwrap();
+        assert_eq!(decode_limits(&resp).unwrap(), Limits::default());
+
+        let resp = handle(&mut store, message::request(Op::Info, vec![], None)).unwrap();
+        let info = String::from_utf8(resp.payload().unwrap().data().to_vec()).unwrap();
+        assert!(info.ends_with("\nmax_key_len: 3\nmax_value_len: 4"));
+    }
+
+    #[test]
+    fn test_info() {
+        let clock = Arc::new(MockClock::default());
+        let mut store = Store::new(10);
+        store.clock = clock.clone();
+        store.started = clock.now();
+        store.max_bytes = Some(100);
+        clock.advance(Duration::seconds(3));
+
+        let resp = handle(&mut store, message::request(Op::Info, vec![], None)).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+        let info = String::from_utf8(resp.payload().unwrap().data().to_vec()).unwrap();
+        let lines: Vec<&str> = info.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                format!("version: {}", env!("CARGO_PKG_VERSION")),
+                "uptime: 3".to_owned(),
+                format!("protocol: {}", codec::PROTOCOL_VERSION),
+                "features: max_bytes".to_owned(),
+                "eviction_policy: lru".to_owned(),
+                "evictions: 0".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expires_at() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(10);
+        store.clock = clock.clone();
+
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())))
+            .with_extension(message::EXT_EXPIRES_AT, message::encode_u64(1010));
+        handle(&mut store, req).unwrap();
+
+        // Reads don't push the expiry back.
+        for _ in 0..9 {
+            clock.advance(Duration::seconds(1));
+            let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+            assert_eq!(resp.code(), Code::Hit);
+        }
+
+        clock.advance(Duration::seconds(1));
+        let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+        assert!(store.entries.is_empty());
+        assert_eq!(store.used_bytes, 0);
+    }
+
+    #[test]
+    fn test_exists_and_ttl() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(10);
+        store.clock = clock.clone();
+        set(&mut store, "forever", "1");
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())))
+            .with_extension(message::EXT_TTL, message::encode_u64(10));
+        handle(&mut store, req).unwrap();
+
+        let mut query = |op, key: &str| handle(&mut store, message::request(op, key.into(), None));
+        let resp = query(Op::Exists, "foo").unwrap();
+        assert_eq!((resp.code(), resp.payload()), (Code::Hit, None));
+        assert_eq!(query(Op::Exists, "nope").unwrap().code(), Code::Miss);
+
+        clock.advance(Duration::milliseconds(3500));
+        assert_eq!(decode_ttl(&query(Op::Ttl, "foo").unwrap()).unwrap(), Ttl::Seconds(6));
+        assert_eq!(decode_ttl(&query(Op::Ttl, "forever").unwrap()).unwrap(), Ttl::Forever);
+        assert_eq!(decode_ttl(&query(Op::Ttl, "nope").unwrap()).unwrap(), Ttl::Missing);
+
+        clock.advance(Duration::seconds(7));
+        assert_eq!(query(Op::Exists, "foo").unwrap().code(), Code::Miss);
+        assert_eq!(decode_ttl(&query(Op::Ttl, "foo").unwrap()).unwrap(), Ttl::Missing);
+
+        // Asking for the TTL isn't a use of the key, which is still evicted first.
+        let mut store = Store::new(2);
+        set(&mut store, "a", "1");
+        set(&mut store, "b", "1");
+        handle(&mut store, message::request(Op::Ttl, "a".into(), None)).unwrap();
+        set(&mut store, "c", "1");
+        assert!(!store.entries.contains_key(b"a"));
+    }
+
+    #[test]
+    fn test_inspect() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(10);
+        store.clock = clock.clone();
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(3, "bar".into())))
+            .with_extension(message::EXT_TTL, message::encode_u64(60));
+        handle(&mut store, req).unwrap();
+        let inspect = |store: &mut Store| {
+            let resp = handle(store, message::request(Op::Inspect, "foo".into(), None)).unwrap();
+            assert_eq!(resp.code(), Code::Hit);
+            decode_inspect(resp.payload().unwrap()).unwrap()
+        };
+
+        let mut expected = EntryInfo {
+            size: 3,
+            type_id: 3,
+            version: 1,
+            stored_at: Timespec::new(1000, 0),
+            accessed_at: None,
+            accesses: 0,
+            ttl: Some(60),
+            ttl_jitter: None,
+        };
+        assert_eq!(inspect(&mut store), expected);
+
+        // Reads count, and inspecting doesn't.
+        clock.advance(Duration::milliseconds(10250));
+        handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        expected.accessed_at = Some(Timespec::new(1010, 250000000));
+        expected.accesses = 2;
+        expected.ttl = Some(49);
+        assert_eq!(inspect(&mut store), expected);
+
+        // Writing starts over.
+        set(&mut store, "foo", "barbaz");
+        let info = inspect(&mut store);
+        assert_eq!((info.size, info.version, info.accesses, info.ttl), (6, 2, 0, None));
+        assert_eq!(info.stored_at, Timespec::new(1010, 250000000));
+
+        let resp = handle(&mut store, message::request(Op::Inspect, "nope".into(), None)).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+        assert!(decode_inspect(&message::payload(0, vec![0; 8])).is_err());
+
+        // Nor does it count as a use to the eviction policy.
+        let mut store = Store::new(2);
+        set(&mut store, "a", "1");
+        set(&mut store, "b", "1");
+        handle(&mut store, message::request(Op::Inspect, "a".into(), None)).unwrap();
+        set(&mut store, "c", "1");
+        assert!(!store.entries.contains_key(b"a"));
+        let mut store = Store::with_storage(LfuStorage::new(2));
+        set(&mut store, "a", "1");
+        set(&mut store, "b", "1");
+        handle(&mut store, message::request(Op::Inspect, "a".into(), None)).unwrap();
+        set(&mut store, "c", "1");
+        assert!(!store.entries.contains_key(b"a"));
+    }
+
+    #[test]
+    fn test_stale_while_revalidate() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(100);
+        store.clock = clock.clone();
+        store.last_sweep = clock.now();
+        store.stale_grace = Some(Duration::seconds(30));
+        store.sweep_interval = Some(Duration::seconds(1));
+        for key in &["a", "b", "c"] {
+            let payload = message::payload(1, "1".into());
+            let req = message::request(Op::Set, (*key).into(), Some(payload))
+                .with_extension(message::EXT_TTL, message::encode_u64(10));
+            handle(&mut store, req).unwrap();
+        }
+        clock.advance(Duration::seconds(20));
+        let get_stale = |store: &mut Store, key: &str| {
+            handle(store, get_stale_request(key.into())).unwrap()
+        };
+
+        // Only Gets asking for it get the stale value.
+        let get = message::request(Op::Get, "a".into(), None);
+        assert_eq!(handle(&mut store, get).unwrap().code(), Code::Miss);
+        let resp = get_stale(&mut store, "a");
+        assert_eq!(resp.code(), Code::Stale);
+        assert_eq!(resp.payload(), Some(&message::payload(1, "1".into())));
+        assert!(resp.extension(message::EXT_VERSION).is_some());
+        let exists = message::request(Op::Exists, "a".into(), None);
+        assert_eq!(handle(&mut store, exists).unwrap().code(), Code::Miss);
+
+        // Writing the key drops its stale value, and sweeping those past the window.
+        handle(&mut store, message::request(Op::Del, "b".into(), None)).unwrap();
+        assert_eq!(get_stale(&mut store, "b").code(), Code::Miss);
+        store.sweep();
+        assert_eq!(get_stale(&mut store, "c").code(), Code::Stale);
+        clock.advance(Duration::seconds(20));
+        store.sweep();
+        assert!(store.stale.entries.is_empty());
+        assert_eq!(get_stale(&mut store, "a").code(), Code::Miss);
+
+        // Flushing drops the stale values too.
+        let req = message::request(Op::Set, "d".into(), Some(message::payload(1, "1".into())))
+            .with_extension(message::EXT_TTL, message::encode_u64(10));
+        handle(&mut store, req).unwrap();
+        clock.advance(Duration::seconds(10));
+        assert_eq!(get_stale(&mut store, "d").code(), Code::Stale);
+        handle(&mut store, message::request(Op::FlushAll, vec![], None)).unwrap();
+        assert_eq!(get_stale(&mut store, "d").code(), Code::Miss);
+
+        // Only as many stale values are kept as the store holds entries, and bytes.
+        let mut store = Store::new(2);
+        store.clock = clock.clone();
+        store.stale_grace = Some(Duration::seconds(30));
+        let expire_all = |store: &mut Store, keys: &[&str]| {
+            for key in keys {
+                let payload = message::payload(1, "1".into());
+                let req = message::request(Op::Set, (*key).into(), Some(payload))
+                    .with_extension(message::EXT_TTL, message::encode_u64(1));
+                handle(store, req).unwrap();
+            }
+            clock.advance(Duration::seconds(1));
+            for key in keys {
+                store.expire(key.as_bytes());
+            }
+        };
+        expire_all(&mut store, &["a", "b"]);
+        expire_all(&mut store, &["c"]);
+        assert_eq!(get_stale(&mut store, "a").code(), Code::Miss);
+        assert_eq!(get_stale(&mut store, "b").code(), Code::Stale);
+        store.max_bytes = Some(3);
+        expire_all(&mut store, &["d"]);
+        assert_eq!((store.stale.entries.len(), store.stale.used_bytes), (1, 2));
+        assert_eq!(get_stale(&mut store, "d").code(), Code::Stale);
+    }
+
+    #[test]
+    fn test_ttl_jitter() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(100);
+        store.clock = clock.clone();
+        store.ttl_jitter = Some(10);
+        let mut expiries = BTreeSet::new();
+        for i in 0..50 {
+            let req = message::request(Op::Set, vec![i], Some(message::payload(1, vec![])))
+                .with_extension(message::EXT_TTL, message::encode_u64(100));
+            handle(&mut store, req).unwrap();
+            let at = store.entries.get_mut(&[i]).unwrap().expires_at.unwrap();
+            assert!(at >= Timespec::new(1090, 0) && at <= Timespec::new(1110, 0));
+            expiries.insert(at);
+        }
+        assert!(expiries.len() > 1);
+        let inspect = |store: &mut Store<_>, key: Vec<u8>| {
+            let resp = handle(store, message::request(Op::Inspect, key, None)).unwrap();
+            decode_inspect(resp.payload().unwrap()).unwrap()
+        };
+        assert_eq!(inspect(&mut store, vec![0]).ttl_jitter, Some(10));
+
+        // Expiry times given outright are kept, and not reported as spread.
+        let req = message::request(Op::Set, "at".into(), Some(message::payload(1, vec![])))
+            .with_extension(message::EXT_EXPIRES_AT, message::encode_u64(2000));
+        handle(&mut store, req).unwrap();
+        let info = inspect(&mut store, "at".into());
+        assert_eq!((info.ttl, info.ttl_jitter), (Some(1000), None));
+
+        // Nor are those of a Touch, while an Append keeps the spread expiry.
+        let ttl = message::payload(0, message::encode_u64(100));
+        let touch = message::request(Op::Touch, vec![1], Some(ttl));
+        assert_eq!(handle(&mut store, touch).unwrap().code(), Code::Ok);
+        assert_eq!(inspect(&mut store, vec![1]).ttl_jitter, None);
+        let req = message::request(Op::Set, "n".into(), Some(message::payload(1, vec![])))
+            .with_extension(message::EXT_TTL, message::encode_u64(100));
+        handle(&mut store, req).unwrap();
+        let extra = message::payload(0, "1".into());
+        let append = message::request(Op::Append, "n".into(), Some(extra));
+        assert_eq!(handle(&mut store, append).unwrap().code(), Code::Ok);
+        assert_eq!(inspect(&mut store, "n".into()).ttl_jitter, Some(10));
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut store = Store::new(10);
+        incr(&mut store, Op::Incr, "n", 1);
+        set(&mut store, "old", "x");
+        let value = |v: &str| Some(message::payload(1, v.into()));
+        let two = Some(message::payload(0, message::encode_u64(2)));
+        let get = |store: &mut Store, key: &str| {
+            let resp = handle(store, message::request(Op::Get, key.into(), None)).unwrap();
+            resp.payload().map(|p| p.data().to_vec())
+        };
+
+        let ops = vec![
+            message::request(Op::Set, "a".into(), value("1")),
+            message::request(Op::Incr, "n".into(), two),
+            message::request(Op::Del, "old".into(), None),
+            message::request(Op::Del, "nope".into(), None),
+        ];
+        let resp = handle(&mut store, batch_request(&ops)).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+        let results = message::decode_messages(resp.payload().unwrap().data()).unwrap();
+        let codes: Vec<_> = results.iter().map(|r| r.code()).collect();
+        assert_eq!(codes, vec![Code::Ok, Code::Ok, Code::Ok, Code::Miss]);
+        assert_eq!(get(&mut store, "a"), Some("1".into()));
+        assert_eq!(get(&mut store, "n"), Some(message::encode_u64(3)));
+        assert_eq!(get(&mut store, "old"), None);
+
+        // A failing op undoes those before it, and the rest aren't applied.
+        let ops = vec![
+            message::request(Op::Set, "a".into(), value("2")),
+            message::request(Op::Set, "b".into(), value("2")),
+            message::request(Op::Del, "n".into(), None),
+            message::request(Op::Add, "a".into(), value("3")),
+            message::request(Op::Set, "c".into(), value("2")),
+        ];
+        let resp = handle(&mut store, batch_request(&ops)).unwrap();
+        assert_eq!(resp.code(), Code::Conflict);
+        let results = message::decode_messages(resp.payload().unwrap().data()).unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(get(&mut store, "a"), Some("1".into()));
+        assert_eq!(get(&mut store, "b"), None);
+        assert_eq!(get(&mut store, "n"), Some(message::encode_u64(3)));
+        assert_eq!(get(&mut store, "c"), None);
+
+        // A Watch given a stale version fails the batch.
+        let resp = handle(&mut store, message::request(Op::Watch, "a".into(), None)).unwrap();
+        let version = resp.extension(message::EXT_VERSION).unwrap().to_vec();
+        set(&mut store, "a", "4");
+        let ops = vec![
+            message::request(Op::Watch, "a".into(), None)
+                .with_extension(message::EXT_VERSION, version),
+            message::request(Op::Set, "b".into(), value("2")),
+        ];
+        let resp = handle(&mut store, batch_request(&ops)).unwrap();
+        assert_eq!(resp.code(), Code::Conflict);
+        assert_eq!(get(&mut store, "b"), None);
+
+        // So does one watched missing, if it was created and removed since.
+        let watch = |store: &mut Store, key: &str| {
+            let resp = handle(store, message::request(Op::Watch, key.into(), None)).unwrap();
+            resp.extension(message::EXT_VERSION).unwrap().to_vec()
+        };
+        let watch_then_set = |store: &mut Store, key: &str, version: Vec<u8>| {
+            let ops = vec![
+                message::request(Op::Watch, key.into(), None)
+                    .with_extension(message::EXT_VERSION, version),
+                message::request(Op::Set, "b".into(), value("2")),
+            ];
+            handle(store, batch_request(&ops)).unwrap().code()
+        };
+        let version = watch(&mut store, "w");
+        assert_eq!(watch_then_set(&mut store, "w", version), Code::Ok);
+        let version = watch(&mut store, "w");
+        set(&mut store, "w", "1");
+        handle(&mut store, message::request(Op::Del, "w".into(), None)).unwrap();
+        assert_eq!(watch_then_set(&mut store, "w", version), Code::Conflict);
+        // Removals that were forgotten count, for every key watched before them.
+        let mut small = Store::new(2);
+        let version = watch(&mut small, "w");
+        for key in &["x", "y", "z"] {
+            set(&mut small, key, "1");
+            handle(&mut small, message::request(Op::Del, (*key).into(), None)).unwrap();
+        }
+        assert_eq!(watch_then_set(&mut small, "w", version), Code::Conflict);
+        let version = watch(&mut small, "w");
+        assert_eq!(watch_then_set(&mut small, "w", version), Code::Ok);
+        handle(&mut store, message::request(Op::Del, "b".into(), None)).unwrap();
+
+        let dest = Some(message::payload(0, "b".into()));
+        let rename = message::request(Op::Rename, "a".into(), dest);
+        assert!(handle(&mut store, batch_request(&[rename])).is_err());
+
+        // The ops are in the batch's namespace, and may not name their own.
+        let ops = vec![message::request(Op::Set, "a".into(), value("5"))];
+        let batch = batch_request(&ops).with_extension(message::EXT_NAMESPACE, "n".into());
+        assert_eq!(handle(&mut store, batch).unwrap().code(), Code::Ok);
+        assert_eq!(get(&mut store, "a"), Some("4".into()));
+        let get_in_n = message::request(Op::Get, "a".into(), None)
+            .with_extension(message::EXT_NAMESPACE, "n".into());
+        let resp = handle(&mut store, get_in_n.clone()).unwrap();
+        assert_eq!(resp.payload().map(|p| p.data().to_vec()), Some("5".into()));
+        assert!(handle(&mut store, batch_request(&[get_in_n])).is_err());
+    }
+
+    #[test]
+    fn test_expired_entries_are_not_scanned() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(10);
+        store.clock = clock.clone();
+        set(&mut store, "a", "1");
+        let req = message::request(Op::Set, "b".into(), Some(message::payload(1, "2".into())))
+            .with_extension(message::EXT_EXPIRES_AT, message::encode_u64(1001));
+        handle(&mut store, req).unwrap();
+
+        clock.advance(Duration::seconds(1));
+        assert_eq!(scan(&store, &Namespace(None), None, None, 10), vec![b"a".to_vec()]);
+        assert_eq!(sample(&store, &Namespace(None), 10), vec![b"a".to_vec()]);
+    }
+
+    #[test]
+    fn test_field_incr() {
+        let mut store = Store::new(10);
+        let value = vec![0xaa, 0x00, 0xff, 0xbb];
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(1, value)));
+        handle(&mut store, req).unwrap();
+
+        let resp = handle(&mut store, field_incr_request("foo".into(), 1, 2, 2)).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(message::decode_u64(resp.payload().unwrap().data()).unwrap(), 0x101);
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(1, vec![0xaa, 0x01, 0x01, 0xbb])
+        );
+
+        let resp = handle(&mut store, field_incr_request("foo".into(), 3, 1, -0xbc)).unwrap();
+        assert_eq!(message::decode_u64(resp.payload().unwrap().data()).unwrap(), 0xff);
+    }
+
+    #[test]
+    fn test_field_incr_out_of_bounds() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+
+        assert!(handle(&mut store, field_incr_request("foo".into(), 2, 2, 1)).is_err());
+        assert!(handle(&mut store, field_incr_request("foo".into(), 1, 3, 1)).is_err());
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(1, "bar".into())
+        );
+        let resp = handle(&mut store, field_incr_request("baz".into(), 0, 1, 1)).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+    }
+
+    fn range_of(store: &mut Store, newest: bool, count: u32) -> Vec<(Vec<u8>, u64)> {
+        let flag = if newest { RANGE_NEWEST } else { 0 };
+        let payload = message::payload(flag, message::encode_u32(count));
+        let req = message::request(Op::Range, vec![], Some(payload));
+        let resp = handle(store, req).unwrap();
+        decode_range(resp.payload().unwrap().data()).unwrap()
+    }
+
+    #[test]
+    fn test_range() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(10);
+        store.clock = clock.clone();
+        set(&mut store, "b", "1");
+        clock.advance(Duration::seconds(1));
+        set(&mut store, "a", "2");
+        clock.advance(Duration::seconds(1));
+        set(&mut store, "d", "3");
+        set(&mut store, "c", "4");
+        clock.advance(Duration::seconds(1));
+
+        assert_eq!(
+            range_of(&mut store, false, 2),
+            vec![(b"b".to_vec(), 3000), (b"a".to_vec(), 2000)]
+        );
+        assert_eq!(
+            range_of(&mut store, true, 3),
+            vec![(b"c".to_vec(), 1000), (b"d".to_vec(), 1000), (b"a".to_vec(), 2000)]
+        );
+        assert_eq!(range_of(&mut store, true, 10).len(), 4);
+    }
+
+    fn set_if_empty(store: &mut Store, key: &str, value: &str) -> Message {
+        let payload = message::payload(1, value.into());
+        handle(store, message::request(Op::SetIfEmpty, key.into(), Some(payload))).unwrap()
+    }
+
+    #[test]
+    fn test_set_if_empty_absent() {
+        let mut store = Store::new(10);
+        assert_eq!(set_if_empty(&mut store, "foo", "bar").code(), Code::Ok);
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(1, "bar".into())
+        );
+    }
+
+    #[test]
+    fn test_set_if_empty_empty() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "");
+        assert_eq!(set_if_empty(&mut store, "foo", "bar").code(), Code::Ok);
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(1, "bar".into())
+        );
+    }
+
+    #[test]
+    fn test_set_if_empty_refused() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+        assert_eq!(set_if_empty(&mut store, "foo", "baz").code(), Code::Conflict);
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(1, "bar".into())
+        );
+    }
+
+    #[test]
+    fn test_for_each() {
+        let cache = Cache::new(100).unwrap();
+        for i in 0..10 {
+            let (snd, rcv) = oneshot::channel();
+            let payload = message::payload(1, vec![i]);
+            cache.process(message::request(Op::Set, vec![i], Some(payload)), snd);
+            rcv.wait().unwrap();
+        }
+
+        let mut visited = vec![];
+        cache
+            .for_each(|key, type_id, value| {
+                assert_eq!((type_id, value), (1, key));
+                // Calling back into the cache doesn't deadlock.
+                let (snd, rcv) = oneshot::channel();
+                cache.process(message::request(Op::Get, key.to_vec(), None), snd);
+                assert_eq!(rcv.wait().unwrap().code(), Code::Hit);
+                visited.push(key.to_vec());
+            })
+            .unwrap();
+        visited.sort();
+        assert_eq!(visited, (0..10).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_snapshot_encoding() {
+        let entries = vec![
+            (b"foo".to_vec(), message::payload(1, b"bar".to_vec()), None),
+            (vec![], message::payload(7, vec![]), Some(Timespec::new(1010, 0))),
+        ];
+        let data = encode_snapshot(42, &entries);
+        assert_eq!(decode_snapshot(&data).unwrap(), (42, entries));
+
+        assert!(decode_snapshot(b"").is_err());
+        assert!(decode_snapshot(&data[..data.len() - 1]).is_err());
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(decode_snapshot(&trailing).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let options = || Options {
+            clock: clock.clone(),
+            ..Options::default()
+        };
+        let set = |cache: &Cache, key: &str, type_id: u32, ttl: Option<u64>| {
+            let payload = message::payload(type_id, key.into());
+            let mut req = message::request(Op::Set, key.into(), Some(payload));
+            if let Some(ttl) = ttl {
+                req = req.with_extension(message::EXT_TTL, message::encode_u64(ttl));
+            }
+            call(cache, req);
+        };
+        let get = |cache: &Cache, key: &str| {
+            call(cache, message::request(Op::Get, key.into(), None))
+        };
+        let name = format!("rcache-test-{}.snap", ::std::process::id());
+        let path = ::std::env::temp_dir().join(name);
+
+        let cache = Cache::with_options(100, options()).unwrap();
+        set(&cache, "foo", 1, None);
+        set(&cache, "bar", 2, Some(10));
+        set(&cache, "old", 1, Some(1));
+        cache.save_to(&path).unwrap();
+
+        // "old" expires before the snapshot is loaded.
+        clock.advance(Duration::seconds(2));
+        let restored = Cache::with_options(100, options()).unwrap();
+        assert_eq!(restored.load_from(&path).unwrap(), 2);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(get(&restored, "foo").payload(), Some(&message::payload(1, "foo".into())));
+        assert_eq!(get(&restored, "bar").payload(), Some(&message::payload(2, "bar".into())));
+        assert_eq!(get(&restored, "old").code(), Code::Miss);
+
+        // The expiry is restored with the entry.
+        clock.advance(Duration::seconds(8));
+        assert_eq!(get(&restored, "bar").code(), Code::Miss);
+    }
+
+    fn call(cache: &Cache, req: Message) -> Message {
+        let (snd, rcv) = oneshot::channel();
+        cache.process(req, snd);
+        rcv.wait().unwrap()
+    }
+
+    fn logged_cache(path: &Path, compact_after: Option<u64>) -> Cache {
+        let options = Options {
+            log: Some(LogOptions {
+                path: path.to_owned(),
+                sync: false,
+                compact_after: compact_after,
+            }),
+            ..Options::default()
+        };
+        Cache::with_options(100, options).unwrap()
+    }
+
+    #[test]
+    fn test_log_replay() {
+        let path = ::std::env::temp_dir().join(format!("rcache-log-{}", ::std::process::id()));
+        let set = |key: &str, value: &str| {
+            let payload = message::payload(1, value.into());
+            message::request(Op::Set, key.into(), Some(payload))
+        };
+        {
+            let cache = logged_cache(&path, None);
+            call(&cache, set("foo", "bar"));
+            call(&cache, set("baz", "qux"));
+            call(&cache, message::request(Op::Del, "baz".into(), None));
+            let dest = message::payload(0, "moved".into());
+            call(&cache, message::request(Op::Rename, "foo".into(), Some(dest)));
+            // Failed writes aren't logged.
+            call(&cache, message::request(Op::Del, "missing".into(), None));
+        }
+
+        let cache = logged_cache(&path, None);
+        let get = |key: &str| call(&cache, message::request(Op::Get, key.into(), None));
+        assert_eq!(get("moved").payload(), Some(&message::payload(1, "bar".into())));
+        assert_eq!(get("foo").code(), Code::Miss);
+        assert_eq!(get("baz").code(), Code::Miss);
+
+        let len = fs::metadata(&path).unwrap().len();
+        cache.compact_log().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < len);
+        let cache = logged_cache(&path, None);
+        let get = |key: &str| call(&cache, message::request(Op::Get, key.into(), None));
+        assert_eq!(get("moved").payload(), Some(&message::payload(1, "bar".into())));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_and_log() {
+        let dir = ::std::env::temp_dir();
+        let log = dir.join(format!("rcache-recover-{}", ::std::process::id()));
+        let snapshot = dir.join(format!("rcache-recover-{}.snap", ::std::process::id()));
+        let open = || {
+            let options = Options {
+                log: Some(LogOptions {
+                    path: log.clone(),
+                    sync: false,
+                    compact_after: None,
+                }),
+                snapshot: Some(snapshot.clone()),
+                ..Options::default()
+            };
+            Cache::with_options(100, options).unwrap()
+        };
+        let set = |cache: &Cache, key: &str, value: &str| {
+            let payload = message::payload(1, value.into());
+            call(cache, message::request(Op::Set, key.into(), Some(payload)));
+        };
+        let value = |cache: &Cache, key: &str| {
+            let resp = call(cache, message::request(Op::Get, key.into(), None));
+            resp.payload().map(|p| p.data().to_vec())
+        };
+
+        // Written, saved, written more, and dropped as if crashed.
+        {
+            let cache = open();
+            set(&cache, "kept", "1");
+            set(&cache, "changed", "1");
+            set(&cache, "deleted", "1");
+            cache.save_to(&snapshot).unwrap();
+            set(&cache, "changed", "2");
+            call(&cache, message::request(Op::Del, "deleted".into(), None));
+            set(&cache, "added", "1");
+        }
+
+        // Only the writes after the snapshot are replayed over it, and nothing is logged again.
+        let len = fs::metadata(&log).unwrap().len();
+        for _ in 0..2 {
+            let cache = open();
+            assert_eq!(value(&cache, "kept"), Some(b"1".to_vec()));
+            assert_eq!(value(&cache, "changed"), Some(b"2".to_vec()));
+            assert_eq!(value(&cache, "deleted"), None);
+            assert_eq!(value(&cache, "added"), Some(b"1".to_vec()));
+            assert_eq!(fs::metadata(&log).unwrap().len(), len);
+            assert!(cache.load_from(&snapshot).is_err());
+        }
+
+        // A log compacted since holds everything on its own.
+        {
+            let cache = open();
+            set(&cache, "changed", "3");
+            cache.compact_log().unwrap();
+        }
+        assert_eq!(value(&open(), "changed"), Some(b"3".to_vec()));
+
+        // Without a log, the snapshot is restored, and marked in the new log.
+        fs::remove_file(&log).unwrap();
+        {
+            let cache = open();
+            assert_eq!(value(&cache, "changed"), Some(b"1".to_vec()));
+            set(&cache, "changed", "4");
+        }
+        assert_eq!(value(&open(), "changed"), Some(b"4".to_vec()));
+        assert_eq!(value(&open(), "kept"), Some(b"1".to_vec()));
+        fs::remove_file(&log).unwrap();
+        fs::remove_file(&snapshot).unwrap();
+    }
+
+    #[test]
+    fn test_log_compacts() {
+        let path = ::std::env::temp_dir().join(format!("rcache-compact-{}", ::std::process::id()));
+        let cache = logged_cache(&path, Some(1000));
+        for _ in 0..100 {
+            let payload = message::payload(1, "bar".into());
+            call(&cache, message::request(Op::Set, "foo".into(), Some(payload)));
+        }
+        assert!(fs::metadata(&path).unwrap().len() < 1000);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_compact_without_log() {
+        assert!(Cache::new(10).unwrap().compact_log().is_err());
+    }
+
+    /// Keeps entries in key order, evicting the first key rather than the least recently used.
+    struct OrderedStorage(BTreeMap<Vec<u8>, Entry>, usize);
+
+    impl Storage for OrderedStorage {
+        fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+            self.0.get_mut(key)
+        }
+
+        fn insert(&mut self, key: Vec<u8>, entry: Entry) -> Option<Entry> {
+            self.0.insert(key, entry)
+        }
+
+        fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+            self.0.remove(key)
+        }
+
+        fn remove_lru(&mut self) -> Option<(Vec<u8>, Entry)> {
+            let first = self.0.keys().next().cloned();
+            first.and_then(|key| self.0.remove(&key).map(|entry| (key, entry)))
+        }
+
+        fn iter<'a>(&'a self) -> Box<Iterator<Item = (&'a Vec<u8>, &'a Entry)> + 'a> {
+            Box::new(self.0.iter())
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn capacity(&self) -> usize {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_custom_storage() {
+        let cache = Cache::with_storage(OrderedStorage(BTreeMap::new(), 2), Default::default())
+            .unwrap();
+        for key in &["b", "a", "c"] {
+            let payload = message::payload(1, key.as_bytes().to_vec());
+            let res = call(&cache, message::request(Op::Set, (*key).into(), Some(payload)));
+            assert_eq!(res.code(), Code::Ok);
+        }
+
+        // "a" sorts first, so it made room for "c" though "b" was set before it.
+        let get = |key: &str| call(&cache, message::request(Op::Get, key.into(), None)).code();
+        assert_eq!(get("a"), Code::Miss);
+        assert_eq!(get("b"), Code::Hit);
+        assert_eq!(get("c"), Code::Hit);
+    }
+
+    #[test]
+    fn test_lfu_eviction() {
+        let mut store = Store::with_storage(LfuStorage::new(3));
+        let get = |store: &mut Store<LfuStorage>, key: &str| {
+            handle(store, message::request(Op::Get, key.into(), None)).unwrap().code()
+        };
+        for key in &["a", "b", "c"] {
+            set(&mut store, key, "1");
+        }
+        get(&mut store, "a");
+        get(&mut store, "a");
+        get(&mut store, "c");
+
+        // "b" is used least, though "a" was stored before it.
+        set(&mut store, "d", "1");
+        assert_eq!(get(&mut store, "b"), Code::Miss);
+        set(&mut store, "e", "1");
+        assert_eq!(get(&mut store, "d"), Code::Miss);
+        assert_eq!(get(&mut store, "a"), Code::Hit);
+        assert_eq!(get(&mut store, "c"), Code::Hit);
+
+        let resp = handle(&mut store, message::request(Op::Info, vec![], None)).unwrap();
+        let info = String::from_utf8(resp.payload().unwrap().data().to_vec()).unwrap();
+        assert!(info.contains("\neviction_policy: lfu\nevictions: 2"));
+    }
+
+    #[test]
+    fn test_lfu_decay() {
+        let entry = Entry::new(message::payload(1, vec![]), 1, None, Timespec::new(0, 0));
+        // The counts are halved every 10 uses.
+        let mut storage = LfuStorage::new(1);
+        storage.insert("a".into(), entry);
+        for _ in 0..8 {
+            storage.get_mut(b"a").unwrap();
+        }
+        assert_eq!(storage.entries.rank(b"a"), Some((9, 9)));
+        storage.get_mut(b"a").unwrap();
+        assert_eq!(storage.entries.rank(b"a"), Some((5, 10)));
+        assert!(storage.contains_key(b"a"));
+        assert_eq!(storage.entries.rank(b"a"), Some((5, 10)));
+    }
+
+    #[test]
+    fn test_fifo_eviction() {
+        let options = Options {
+            eviction: EvictionPolicy::Fifo,
+            ..Options::default()
+        };
+        let cache = Cache::with_options(2, options).unwrap();
+        let get = |key: &str| call(&cache, message::request(Op::Get, key.into(), None)).code();
+        for key in &["a", "b", "c"] {
+            let payload = message::payload(1, key.as_bytes().to_vec());
+            let res = call(&cache, message::request(Op::Set, (*key).into(), Some(payload)));
+            assert_eq!(res.code(), Code::Ok);
+            // Using "a" doesn't keep it.
+            get("a");
+        }
+        assert_eq!(get("a"), Code::Miss);
+        assert_eq!(get("b"), Code::Hit);
+        assert_eq!(get("c"), Code::Hit);
+        assert_eq!(EvictionPolicy::from_name("fifo"), Some(EvictionPolicy::Fifo));
+        assert_eq!(EvictionPolicy::from_name("random"), None);
+    }
+
+    #[test]
+    fn test_fxhash() {
+        let options = Options {
+            eviction: EvictionPolicy::Lfu,
+            hasher: HashFunction::FxHash,
+            ..Options::default()
+        };
+        let cache = Cache::with_options(2, options).unwrap();
+        let get = |key: &str| call(&cache, message::request(Op::Get, key.into(), None)).code();
+        for key in &["a", "b", "c"] {
+            let payload = message::payload(1, key.as_bytes().to_vec());
+            let res = call(&cache, message::request(Op::Set, (*key).into(), Some(payload)));
+            assert_eq!(res.code(), Code::Ok);
+            get("a");
+        }
+        assert_eq!(get("a"), Code::Hit);
+        assert_eq!(get("b"), Code::Miss);
+        assert_eq!(get("c"), Code::Hit);
+        assert_eq!(HashFunction::from_name("fxhash"), Some(HashFunction::FxHash));
+        assert_eq!(HashFunction::from_name("md5"), None);
+    }
+
+    fn bench_get(b: &mut Bencher, function: HashFunction) {
+        let storage = LruStorage::with_hasher(1000, KeyHasher::new(function));
+        let mut store = Store::with_storage(storage);
+        let keys: Vec<Vec<u8>> = (0..1000).map(|i| format!("user:{}", i).into_bytes()).collect();
+        for key in &keys {
+            let value = message::payload(1, vec![0; 16]);
+            handle(&mut store, message::request(Op::Set, key.clone(), Some(value))).unwrap();
+        }
+        b.iter(|| for key in &keys {
+            handle(&mut store, message::request(Op::Get, key.clone(), None)).unwrap();
+        });
+    }
+
+    #[bench]
+    fn bench_get_siphash(b: &mut Bencher) {
+        bench_get(b, HashFunction::SipHash);
+    }
+
+    #[bench]
+    fn bench_get_fxhash(b: &mut Bencher) {
+        bench_get(b, HashFunction::FxHash);
+    }
+
+    #[test]
+    fn test_touch() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(10);
+        store.clock = clock.clone();
+        let touch = |store: &mut Store, key: &str, ttl: u64| {
+            let payload = message::payload(0, message::encode_u64(ttl));
+            handle(store, message::request(Op::Touch, key.into(), Some(payload))).unwrap()
+        };
+        let get = |store: &mut Store| {
+            handle(store, message::request(Op::Get, "foo".into(), None)).unwrap()
+        };
+
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())))
+            .with_extension(message::EXT_TTL, message::encode_u64(5));
+        handle(&mut store, req).unwrap();
+        let version = version_of(&get(&mut store));
+
+        clock.advance(Duration::seconds(4));
+        assert_eq!(touch(&mut store, "foo", 5).code(), Code::Ok);
+        clock.advance(Duration::seconds(4));
+        let resp = get(&mut store);
+        assert_eq!(resp.code(), Code::Hit);
+        assert_eq!(resp.payload().unwrap().data(), b"bar");
+        assert!(version_of(&resp) > version);
+        clock.advance(Duration::seconds(1));
+        assert_eq!(get(&mut store).code(), Code::Miss);
+
+        assert_eq!(touch(&mut store, "foo", 5).code(), Code::Miss);
+
+        // TTLs that would overflow the expiry are refused.
+        set(&mut store, "foo", "bar");
+        let payload = message::payload(0, message::encode_u64(u64::max_value()));
+        assert!(handle(&mut store, message::request(Op::Touch, "foo".into(), Some(payload)))
+            .is_err());
+    }
+
+    #[test]
+    fn test_expire() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(10);
+        store.clock = clock.clone();
+        let expire = |store: &mut Store, ext: Option<(u16, u64)>| {
+            let mut req = message::request(Op::Expire, "foo".into(), None);
+            if let Some((ext, value)) = ext {
+                req = req.with_extension(ext, message::encode_u64(value));
+            }
+            handle(store, req).unwrap()
+        };
+        let get = |store: &mut Store| {
+            handle(store, message::request(Op::Get, "foo".into(), None)).unwrap()
+        };
+
+        assert_eq!(expire(&mut store, None).code(), Code::Miss);
+        set(&mut store, "foo", "bar");
+        let version = version_of(&get(&mut store));
+        assert_eq!(expire(&mut store, Some((message::EXT_EXPIRES_AT, 1010))).code(), Code::Ok);
+        clock.advance(Duration::seconds(9));
+        let resp = get(&mut store);
+        assert_eq!(resp.code(), Code::Hit);
+        assert!(version_of(&resp) > version);
+
+        // Without an expiry, the entry lives on.
+        assert_eq!(expire(&mut store, None).code(), Code::Ok);
+        clock.advance(Duration::seconds(100));
+        assert_eq!(get(&mut store).code(), Code::Hit);
+
+        assert_eq!(expire(&mut store, Some((message::EXT_TTL, 1))).code(), Code::Ok);
+        clock.advance(Duration::seconds(1));
+        assert_eq!(get(&mut store).code(), Code::Miss);
+    }
+
+    #[test]
+    fn test_mget() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+        set(&mut store, "baz", "qux");
+        let keys = vec!["baz".into(), "missing".into(), "foo".into()];
+        let payload = message::payload(0, message::encode_keys(&keys));
+
+        let resp = handle(&mut store, message::request(Op::MGet, vec![], Some(payload))).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(
+            message::decode_entries(resp.payload().unwrap().data()).unwrap(),
+            vec![
+                ("baz".into(), message::payload(1, "qux".into())),
+                ("foo".into(), message::payload(1, "bar".into())),
+            ]
+        );
+
+        let keys = vec![vec![]; MAX_MULTI_KEYS + 1];
+        let payload = message::payload(0, message::encode_keys(&keys));
+        assert!(handle(&mut store, message::request(Op::MGet, vec![], Some(payload))).is_err());
+    }
+
+    fn incr(store: &mut Store, op: Op, key: &str, delta: i64) -> Message {
+        let payload = message::payload(0, message::encode_u64(delta as u64));
+        handle(store, message::request(op, key.into(), Some(payload))).unwrap()
+    }
+
+    fn counter(resp: &Message) -> i64 {
+        let payload = resp.payload().unwrap();
+        assert_eq!(payload.type_id(), message::TYPE_I64);
+        message::decode_u64(payload.data()).unwrap() as i64
+    }
+
+    #[test]
+    fn test_incr_decr() {
+        let mut store = Store::new(10);
+        assert_eq!(counter(&incr(&mut store, Op::Incr, "foo", 5)), 5);
+        assert_eq!(counter(&incr(&mut store, Op::Decr, "foo", 7)), -2);
+        assert_eq!(counter(&incr(&mut store, Op::Incr, "foo", -3)), -5);
+        assert_eq!(counter(&incr(&mut store, Op::Decr, "bar", 1)), -1);
+
+        let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        assert_eq!(counter(&resp), -5);
+    }
+
+    #[test]
+    fn test_incr_wrong_type() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+        let resp = incr(&mut store, Op::Incr, "foo", 1);
+        assert_eq!(resp.code(), Code::WrongType);
+        assert_eq!(message::decode_u32(resp.payload().unwrap().data()).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_incr_overflow() {
+        let mut store = Store::new(10);
+        incr(&mut store, Op::Incr, "foo", i64::max_value());
+        let req = |op: Op, delta: i64| {
+            let payload = message::payload(0, message::encode_u64(delta as u64));
+            message::request(op, "foo".into(), Some(payload))
+        };
+        assert!(handle(&mut store, req(Op::Incr, 1)).is_err());
+        assert!(handle(&mut store, req(Op::Decr, i64::min_value())).is_err());
+        let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        assert_eq!(counter(&resp), i64::max_value());
+    }
+
+    #[test]
+    fn test_add_replace() {
+        let mut store = Store::new(10);
+        let write = |store: &mut Store, op: Op, value: &str| {
+            let req = message::request(op, "foo".into(), Some(message::payload(1, value.into())));
+            handle(store, req).unwrap().code()
+        };
+        assert_eq!(write(&mut store, Op::Replace, "bar"), Code::Miss);
+        assert_eq!(write(&mut store, Op::Add, "bar"), Code::Ok);
+        assert_eq!(write(&mut store, Op::Add, "baz"), Code::Conflict);
+        assert_eq!(write(&mut store, Op::Replace, "qux"), Code::Ok);
+
+        let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        assert_eq!(resp.payload(), Some(&message::payload(1, "qux".into())));
+    }
+
+    #[test]
+    fn test_append_prepend() {
+        let mut store = Store::new(10);
+        let extend = |store: &mut Store, op: Op, value: &str| {
+            let req = message::request(op, "foo".into(), Some(message::payload(9, value.into())));
+            handle(store, req).unwrap().code()
+        };
+        assert_eq!(extend(&mut store, Op::Append, "bar"), Code::Miss);
+        assert!(store.entries.is_empty());
+
+        set(&mut store, "foo", "b");
+        assert_eq!(extend(&mut store, Op::Append, "ar"), Code::Ok);
+        assert_eq!(extend(&mut store, Op::Prepend, "foo"), Code::Ok);
+        let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        assert_eq!(resp.payload(), Some(&message::payload(1, "foobar".into())));
+        assert_eq!(store.used_bytes, "foo".len() + "foobar".len());
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let mut store = Store::new(10);
+        let in_ns = |msg: Message, namespace: &str| {
+            msg.with_extension(message::EXT_NAMESPACE, namespace.into())
+        };
+        let set_in = |store: &mut Store, namespace: &str, key: &str, value: &str| {
+            let payload = message::payload(1, value.into());
+            handle(store, in_ns(message::request(Op::Set, key.into(), Some(payload)), namespace))
+                .unwrap()
+        };
+        set(&mut store, "foo", "default");
+        set_in(&mut store, "a", "foo", "in a");
+        set_in(&mut store, "a", "bar", "in a");
+        set_in(&mut store, "b", "foo", "in b");
+
+        let get_in = |store: &mut Store, namespace: &str| {
+            let req = in_ns(message::request(Op::Get, "foo".into(), None), namespace);
+            handle(store, req).unwrap().payload().map(|p| p.data().to_vec())
+        };
+        assert_eq!(get_in(&mut store, ""), Some(b"default".to_vec()));
+        assert_eq!(get_in(&mut store, "a"), Some(b"in a".to_vec()));
+        assert_eq!(get_in(&mut store, "b"), Some(b"in b".to_vec()));
+        assert_eq!(get_in(&mut store, "c"), None);
+
+        // The default namespace can't name another's keys, and stored keys address their own.
+        let forged = message::request(Op::Get, b"\xFF\x01afoo".to_vec(), None);
+        assert!(handle(&mut store, forged).is_err());
+        let keys = Some(message::payload(0, message::encode_keys(&[b"\xFF\x01afoo".to_vec()])));
+        assert!(handle(&mut store, message::request(Op::MGet, vec![], keys)).is_err());
+        let resp = handle(&mut store, stored_request(Op::Get, b"\xFF\x01afoo", None)).unwrap();
+        assert_eq!(resp.payload().map(|p| p.data().to_vec()), Some(b"in a".to_vec()));
+        let resp = handle(&mut store, stored_request(Op::Get, b"foo", None)).unwrap();
+        assert_eq!(resp.payload().map(|p| p.data().to_vec()), Some(b"default".to_vec()));
+
+        let scan_in = |store: &mut Store, namespace: &str| {
+            let resp = handle(store, in_ns(scan_request(None, 10), namespace)).unwrap();
+            message::decode_keys(resp.payload().unwrap().data()).unwrap()
+        };
+        assert_eq!(scan_in(&mut store, ""), vec![b"foo".to_vec()]);
+        assert_eq!(scan_in(&mut store, "a"), vec![b"bar".to_vec(), b"foo".to_vec()]);
+        assert_eq!(sample(&store, &Namespace(Some(b"\xFF\x01b".to_vec())), 10).len(), 1);
+
+        let stats_in = |store: &mut Store, namespace: &str| {
+            let req = in_ns(message::request(Op::Stats, vec![], None), namespace);
+            decode_stats(handle(store, req).unwrap().payload().unwrap()).unwrap()
+        };
+        let stats = stats_in(&mut store, "a");
+        assert_eq!((stats.keys, stats.used_bytes), (2, 14));
+        assert_eq!(stats_in(&mut store, "").keys, 4);
+
+        let req = message::request(Op::FlushNamespace, vec![], None);
+        assert!(handle(&mut store, req.clone()).is_err());
+        let resp = handle(&mut store, in_ns(req, "a")).unwrap();
+        assert_eq!(resp.payload(), Some(&message::payload(0, message::encode_u64(2))));
+        assert_eq!(stats_in(&mut store, "a").keys, 0);
+        assert_eq!(get_in(&mut store, "b"), Some(b"in b".to_vec()));
+        assert_eq!(get_in(&mut store, ""), Some(b"default".to_vec()));
+    }
+
+    #[test]
+    fn test_flush_all() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(2);
+        store.clock = clock.clone();
+        set(&mut store, "foo", "bar");
+        set(&mut store, "bar", "baz");
+        set(&mut store, "baz", "qux");
+        assert_eq!(store.evictions, 1);
+
+        // A delayed flush expires the keys present once it has passed.
+        let flush_all = |delay| {
+            let payload = Some(message::payload(0, message::encode_u64(delay)));
+            message::request(Op::FlushAll, vec![], payload)
+        };
+        let resp = handle(&mut store, flush_all(10)).unwrap();
+        assert_eq!(resp.payload(), Some(&message::payload(0, message::encode_u64(2))));
+        clock.advance(Duration::seconds(9));
+        let get = || message::request(Op::Get, "bar".into(), None);
+        assert_eq!(handle(&mut store, get()).unwrap().code(), Code::Hit);
+        clock.advance(Duration::seconds(1));
+        assert_eq!(handle(&mut store, get()).unwrap().code(), Code::Miss);
+
+        assert!(handle(&mut store, flush_all(u64::max_value())).is_err());
+        let resp = handle(&mut store, flush_all(0)).unwrap();
+        assert_eq!(resp.payload(), Some(&message::payload(0, message::encode_u64(1))));
+        assert!(store.entries.is_empty());
+        assert_eq!((store.evictions, store.used_bytes), (0, 0));
+
+        // In a namespace, only its keys are flushed.
+        set(&mut store, "foo", "bar");
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(1, "a".into())));
+        handle(&mut store, req.with_extension(message::EXT_NAMESPACE, "a".into())).unwrap();
+        let req = message::request(Op::FlushAll, vec![], None)
+            .with_extension(message::EXT_NAMESPACE, "a".into());
+        handle(&mut store, req).unwrap();
+        assert_eq!(store.entries.len(), 1);
+        let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        assert_eq!(resp.code(), Code::Hit);
+    }
+
+    fn cas_del(store: &mut Store, key: &str, version: u64) -> Message {
+        let req = message::request(Op::CasDel, key.into(), None)
+            .with_extension(message::EXT_VERSION, message::encode_u64(version));
+        handle(store, req).unwrap()
+    }
+
+    #[test]
+    fn test_cas_del() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+        let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+
+        assert_eq!(cas_del(&mut store, "foo", version_of(&resp)).code(), Code::Ok);
+        assert!(store.entries.is_empty());
+    }
+
+    #[test]
+    fn test_cas_del_stale() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+        let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        let stale = version_of(&resp);
+        set(&mut store, "foo", "baz");
+
+        let resp = cas_del(&mut store, "foo", stale);
+        assert_eq!(resp.code(), Code::Conflict);
+        assert!(version_of(&resp) > stale);
+        assert!(store.entries.contains_key("foo".as_bytes()));
+    }
+
+    #[test]
+    fn test_cas_del_missing() {
+        let mut store = Store::new(10);
+        assert_eq!(cas_del(&mut store, "foo", 1).code(), Code::Miss);
+    }
+
+    fn quota_store() -> Store {
+        let mut store = Store::new(10);
+        store.quotas.push(PrefixQuota {
+            prefix: "user:".into(),
+            max_keys: 2,
+            keys: 0,
+        });
+        store
+    }
+
+    fn set_code(store: &mut Store, key: &str, value: &str) -> Code {
+        let req = message::request(Op::Set, key.into(), Some(message::payload(1, value.into())));
+        handle(store, req).unwrap().code()
+    }
+
+    #[test]
+    fn test_prefix_quota() {
+        let mut store = quota_store();
+        assert_eq!(set_code(&mut store, "user:1", "a"), Code::Ok);
+        assert_eq!(set_code(&mut store, "user:2", "b"), Code::Ok);
+        assert_eq!(set_code(&mut store, "user:3", "c"), Code::QuotaExceeded);
+        assert!(!store.entries.contains_key("user:3".as_bytes()));
+
+        // Other prefixes aren't limited.
+        assert_eq!(set_code(&mut store, "group:1", "d"), Code::Ok);
+    }
+
+    #[test]
+    fn test_prefix_quota_overwrite() {
+        let mut store = quota_store();
+        set(&mut store, "user:1", "a");
+        set(&mut store, "user:2", "b");
+
+        assert_eq!(set_code(&mut store, "user:2", "c"), Code::Ok);
+        assert_eq!(
+            store.entries.get_mut("user:2".as_bytes()).unwrap().payload,
+            message::payload(1, "c".into())
+        );
+
+        // Deleting a key frees up its place.
+        let keys = message::encode_keys(&[b"user:1".to_vec()]);
+        let req = message::request(Op::MultiDel, vec![], Some(message::payload(0, keys)));
+        handle(&mut store, req).unwrap();
+        assert_eq!(set_code(&mut store, "user:3", "d"), Code::Ok);
+    }
+
+    #[test]
+    fn test_observer() {
+        let (observer, events) = Observer::new(10, Overflow::Block);
+        let options = Options {
+            observer: Some(observer),
+            ..Options::default()
+        };
+        let cache = Cache::with_options(10, options).unwrap();
+        let requests = vec![
+            message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into()))),
+            message::request(Op::Get, "foo".into(), None),
+            message::request(Op::Del, "foo".into(), None),
+            message::request(Op::Get, "baz".into(), None),
+        ];
+        for req in requests {
+            let (snd, rcv) = oneshot::channel();
+            cache.process(req, snd);
+            rcv.wait().unwrap();
+        }
+
+        let event = |op, key: &str, code| Event {
+            op: op,
+            key: key.into(),
+            code: code,
+        };
+        let observed: Vec<_> = events.try_iter().collect();
+        assert_eq!(
+            observed,
+            vec![
+                event(Op::Set, "foo", Code::Ok),
+                event(Op::Get, "foo", Code::Hit),
+                event(Op::Del, "foo", Code::Ok),
+                event(Op::Get, "baz", Code::Miss),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_observer_drops_on_overflow() {
+        let (observer, events) = Observer::new(1, Overflow::Drop);
+        for _ in 0..3 {
+            observer.publish(Event {
+                op: Op::Get,
+                key: vec![],
+                code: Code::Miss,
+            });
+        }
+        assert_eq!(events.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_del() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+
+        let resp = handle(&mut store, message::request(Op::Del, "foo".into(), None)).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(resp.payload(), Some(&message::payload(1, "bar".into())));
+        assert!(store.entries.is_empty());
+        assert_eq!(store.used_bytes, 0);
+
+        let resp = handle(&mut store, message::request(Op::Del, "foo".into(), None)).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+        assert_eq!(resp.payload(), None);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(10);
+        store.clock = clock.clone();
+
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())))
+            .with_extension(message::EXT_TTL, message::encode_u64(10))
+            .with_extension(message::EXT_EXPIRES_AT, message::encode_u64(1020));
+        handle(&mut store, req).unwrap();
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().expires_at,
+            Some(Timespec::new(1010, 0))
+        );
+
+        clock.advance(Duration::seconds(10));
+        let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+
+        // TTLs that would overflow the expiry are refused.
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())))
+            .with_extension(message::EXT_TTL, message::encode_u64(u64::max_value()));
+        assert!(handle(&mut store, req).is_err());
+        assert!(!store.entries.contains_key("foo".as_bytes()));
+    }
+
+    #[test]
+    fn test_sweep() {
+        let clock = Arc::new(MockClock::new(Timespec::new(1000, 0)));
+        let mut store = Store::new(10);
+        store.clock = clock.clone();
+        store.last_sweep = clock.now();
+        store.sweep_interval = Some(Duration::seconds(5));
+        set(&mut store, "foo", "bar");
+        let req = message::request(Op::Set, "baz".into(), Some(message::payload(1, "qux".into())))
+            .with_extension(message::EXT_TTL, message::encode_u64(1));
+        handle(&mut store, req).unwrap();
+
+        clock.advance(Duration::seconds(2));
+        store.sweep();
+        assert_eq!(store.entries.len(), 2);
+
+        clock.advance(Duration::seconds(3));
+        store.sweep();
+        assert_eq!(store.entries.len(), 1);
+        assert!(store.entries.contains_key("foo".as_bytes()));
+        assert_eq!(store.used_bytes, 6);
+    }
+
+    fn cas(store: &mut Store, key: &str, version: u64, value: &str) -> Message {
+        let req = message::request(Op::Cas, key.into(), Some(message::payload(1, value.into())))
+            .with_extension(message::EXT_VERSION, message::encode_u64(version));
+        handle(store, req).unwrap()
+    }
+
+    #[test]
+    fn test_cas() {
+        let mut store = Store::new(10);
+        set(&mut store, "foo", "bar");
+        let resp = handle(&mut store, message::request(Op::Get, "foo".into(), None)).unwrap();
+        let version = version_of(&resp);
+
+        let resp = cas(&mut store, "foo", version, "baz");
+        assert_eq!(resp.code(), Code::Ok);
+        assert!(version_of(&resp) > version);
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(1, "baz".into())
+        );
+
+        // The version has moved on, so the same token no longer matches.
+        let resp = cas(&mut store, "foo", version, "qux");
+        assert_eq!(resp.code(), Code::CasMismatch);
+        assert!(version_of(&resp) > version);
+        assert_eq!(
+            store.entries.get_mut("foo".as_bytes()).unwrap().payload,
+            message::payload(1, "baz".into())
+        );
+
+        assert_eq!(cas(&mut store, "missing", version, "qux").code(), Code::Miss);
+    }
 }
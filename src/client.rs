@@ -1,20 +1,46 @@
 
-use futures::Future;
+use futures::{future, Future, Stream};
+use futures::sync::mpsc::{self, UnboundedReceiver};
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::Handle;
-use tokio_proto::TcpClient;
+use tokio_proto::{BindClient, TcpClient};
 use tokio_proto::multiplex::ClientService;
 use tokio_service::Service;
+use tokio_tls::{TlsConnectorExt, TlsStream};
+use native_tls::TlsConnector;
 use std::net::SocketAddr;
+use std::path::Path;
+use tokio_uds::UnixStream;
 use std::io;
 
 use proto::CacheProto;
-use message::{self, Message, Op};
+use message::{self, Message, Op, Code, Payload};
+use typed::{self, TypedPayload};
+use pubsub::{self, Notification};
+use cache;
+use codec;
+use cluster;
+use slowlog;
+use clients::{self, ClientInfo};
+use stats;
 
 /// A simple client for interacting with `rcache`, intended for debugging, testing, and benchmarking.
 /// Can be used as a template for implementing a more robust client.
+///
+/// Keys and values can be anything that converts into bytes, such as `&str` or `Vec<u8>`. Values
+/// given as bytes are sent with a `type_id` of 1, a utf-8 string by convention; use
+/// `set_payload` to send another type.
 pub struct Client {
-    inner: ClientService<TcpStream, CacheProto>,
+    inner: Transport,
+    namespace: Option<Vec<u8>>,
+    pushes: Option<UnboundedReceiver<Message>>,
+}
+
+/// The connection a `Client` sends its requests over.
+enum Transport {
+    Tcp(ClientService<TcpStream, CacheProto>),
+    Tls(ClientService<TlsStream<TcpStream>, CacheProto>),
+    Unix(ClientService<UnixStream, CacheProto>),
 }
 
 impl Client {
@@ -22,25 +48,665 @@ impl Client {
         addr: &SocketAddr,
         handle: &Handle,
     ) -> impl Future<Item = Client, Error = io::Error> {
-        TcpClient::new(CacheProto).connect(addr, handle).map(
-            |client_service| Client { inner: client_service },
-        )
+        let (pushes, receiver) = mpsc::unbounded();
+        TcpClient::new(CacheProto::with_pushes(pushes))
+            .connect(addr, handle)
+            .map(|client_service| {
+                Client {
+                    inner: Transport::Tcp(client_service),
+                    namespace: None,
+                    pushes: Some(receiver),
+                }
+            })
+    }
+
+    /// Connects to a server serving TLS, see `ServeOptions::tls`. The server's certificate must
+    /// be valid for `domain`, and trusted by `connector`, see `tls::connector`.
+    pub fn connect_tls(
+        addr: &SocketAddr,
+        domain: &str,
+        connector: &TlsConnector,
+        handle: &Handle,
+    ) -> impl Future<Item = Client, Error = io::Error> {
+        let (domain, connector, handle) = (domain.to_owned(), connector.clone(), handle.clone());
+        TcpStream::connect(addr, &handle)
+            .and_then(move |socket| {
+                connector.connect_async(&domain, socket).map_err(|e| {
+                    io::Error::new(io::ErrorKind::Other, e)
+                })
+            })
+            .map(move |stream| {
+                let (pushes, receiver) = mpsc::unbounded();
+                let proto = CacheProto::with_pushes(pushes);
+                Client {
+                    inner: Transport::Tls(proto.bind_client(&handle, stream)),
+                    namespace: None,
+                    pushes: Some(receiver),
+                }
+            })
+    }
+
+    /// Connects to a server at the UNIX domain socket at `path`, see `service::Listener::Unix`.
+    pub fn connect_unix<P: AsRef<Path>>(
+        path: P,
+        handle: &Handle,
+    ) -> impl Future<Item = Client, Error = io::Error> {
+        let handle = handle.clone();
+        future::result(UnixStream::connect(path, &handle)).map(move |stream| {
+            let (pushes, receiver) = mpsc::unbounded();
+            let proto = CacheProto::with_pushes(pushes);
+            Client {
+                inner: Transport::Unix(proto.bind_client(&handle, stream)),
+                namespace: None,
+                pushes: Some(receiver),
+            }
+        })
+    }
+
+    /// Addresses every request the client makes to the namespace `namespace`, see
+    /// `message::EXT_NAMESPACE`, rather than the default one.
+    pub fn in_namespace<N: Into<Vec<u8>>>(mut self, namespace: N) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    pub fn get<K: Into<Vec<u8>>>(&self, key: K) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::Get, key.into(), None);
+        self.call(req)
+    }
+
+    /// Like `get`, but responds with a value that expired lately, with `Code::Stale`, rather than
+    /// `Code::Miss`, see `cache::Options::stale_grace`.
+    pub fn get_stale<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(cache::get_stale_request(key.into()))
+    }
+
+    /// Deletes `key`, responding with its value, or `Code::Miss` if it wasn't present.
+    pub fn del<K: Into<Vec<u8>>>(&self, key: K) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::Del, key.into(), None);
+        self.call(req)
+    }
+
+    /// Fetches `key` only if its version is newer than `known`, otherwise the server responds with
+    /// `Code::NotModified` and no payload.
+    pub fn get_if_newer(
+        &self,
+        key: Vec<u8>,
+        known: u64,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::GetIfNewer, key, None)
+            .with_extension(message::EXT_VERSION, message::encode_u64(known));
+        self.call(req)
+    }
+
+    pub fn set<K, V>(&self, key: K, value: V) -> Box<Future<Item = Message, Error = io::Error>>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.set_payload(key, message::payload(1, value.into()))
+    }
+
+    /// Marks `key` as missing for `ttl` seconds, so that Gets for it respond with
+    /// `Code::NotFound`, see `cache::NOT_FOUND_TYPE_ID`.
+    pub fn set_not_found<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        ttl: u64,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(cache::not_found_request(key.into(), ttl))
+    }
+
+    /// Sets `key` to `value`, encoded as its `TypedPayload` implementation says, see
+    /// `typed::encode`.
+    pub fn set_typed<K, T>(
+        &self,
+        key: K,
+        value: &T,
+    ) -> Box<Future<Item = Message, Error = io::Error>>
+    where
+        K: Into<Vec<u8>>,
+        T: TypedPayload,
+    {
+        match typed::encode(value) {
+            Ok(payload) => self.set_payload(key, payload),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Gets the value of `key` set by `set_typed`, or `None` if there isn't one. Fails with
+    /// `io::ErrorKind::InvalidData` if the key holds another type.
+    pub fn get_typed<K, T>(&self, key: K) -> Box<Future<Item = Option<T>, Error = io::Error>>
+    where
+        K: Into<Vec<u8>>,
+        T: TypedPayload + 'static,
+    {
+        Box::new(self.get(key).and_then(|resp| match (resp.code(), resp.payload()) {
+            (Code::Hit, Some(payload)) => typed::decode(payload).map(Some),
+            (Code::Miss, _) | (Code::NotFound, _) => Ok(None),
+            (code, _) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("unexpected response to get: {}", code),
+            )),
+        }))
+    }
+
+    /// Sets `key` to `payload`, keeping its `type_id`.
+    pub fn set_payload<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        payload: Payload,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::Set, key.into(), Some(payload));
+        self.call(req)
+    }
+
+    /// Sets `key` unless the last Set applied to it carried the same `token`, in which case the
+    /// server responds with `Code::AlreadyApplied`. Safe to retry.
+    pub fn set_once(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        token: Vec<u8>,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::Set, key, Some(message::payload(1, value)))
+            .with_extension(message::EXT_IDEMPOTENCY_TOKEN, token);
+        self.call(req)
+    }
+
+    /// Sets `key` to `value` if it is absent or holds an empty value. Otherwise the server
+    /// responds with `Code::Conflict`.
+    pub fn set_if_empty(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::SetIfEmpty, key, Some(message::payload(1, value)));
+        self.call(req)
+    }
+
+    /// Sets `key` to `value` only if it is absent. Otherwise the server responds with
+    /// `Code::Conflict`, which makes this usable as a lock.
+    pub fn add<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let payload = message::payload(1, value.into());
+        self.call(message::request(Op::Add, key.into(), Some(payload)))
+    }
+
+    /// Sets `key` to `value` only if it is present. Otherwise the server responds with
+    /// `Code::Miss`.
+    pub fn replace<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let payload = message::payload(1, value.into());
+        self.call(message::request(Op::Replace, key.into(), Some(payload)))
+    }
+
+    /// Appends `value` to the value at `key`, keeping its `type_id`. If `key` is absent the
+    /// server responds with `Code::Miss`.
+    pub fn append<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let payload = message::payload(1, value.into());
+        self.call(message::request(Op::Append, key.into(), Some(payload)))
+    }
+
+    /// Prepends `value` to the value at `key`, like `append`.
+    pub fn prepend<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let payload = message::payload(1, value.into());
+        self.call(message::request(Op::Prepend, key.into(), Some(payload)))
+    }
+
+    /// Sets `key` to `value` for `ttl` seconds.
+    pub fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: u64,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::Set, key, Some(message::payload(1, value)))
+            .with_extension(message::EXT_TTL, message::encode_u64(ttl));
+        self.call(req)
+    }
+
+    /// Sets `key` to `value` until `expires_at`, in seconds since the Unix epoch.
+    pub fn set_expires_at(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: u64,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::Set, key, Some(message::payload(1, value)))
+            .with_extension(message::EXT_EXPIRES_AT, message::encode_u64(expires_at));
+        self.call(req)
+    }
+
+    /// Makes `key` expire `ttl` seconds from now, leaving its value alone but giving it a new
+    /// version.
+    pub fn touch<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        ttl: u64,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let payload = message::payload(0, message::encode_u64(ttl));
+        self.call(message::request(Op::Touch, key.into(), Some(payload)))
+    }
+
+    /// Makes `key` expire at `expires_at`, in seconds since the Unix epoch, or never if `None`,
+    /// leaving its value alone but giving it a new version.
+    pub fn expire<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        expires_at: Option<u64>,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let mut req = message::request(Op::Expire, key.into(), None);
+        if let Some(expires_at) = expires_at {
+            req = req.with_extension(message::EXT_EXPIRES_AT, message::encode_u64(expires_at));
+        }
+        self.call(req)
+    }
+
+    /// Checks whether `key` is present without fetching its value, responding with `Code::Hit`
+    /// or `Code::Miss`.
+    pub fn exists<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::Exists, key.into(), None))
+    }
+
+    /// Fetches how long `key` has left to live.
+    pub fn ttl<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+    ) -> Box<Future<Item = cache::Ttl, Error = io::Error>> {
+        let req = message::request(Op::Ttl, key.into(), None);
+        Box::new(self.call(req).and_then(|resp| Ok(cache::decode_ttl(&resp)?)))
+    }
+
+    /// Applies `ops`, writes built with `message::request`, all or none of them, see
+    /// `cache::batch_request`. They are in the client's namespace, see `in_namespace`.
+    pub fn batch(&self, ops: &[Message]) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(cache::batch_request(ops))
+    }
+
+    /// Stores `entries`, as the pages of a dump hold them, replacing those already under their keys
+    /// if `replace` is set, see `cache::restore_request`. The response payload holds the number
+    /// stored, as a u64.
+    pub fn restore(
+        &self,
+        entries: &[(Vec<u8>, Payload, Option<u64>)],
+        replace: bool,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(cache::restore_request(entries, replace))
+    }
+
+    /// Watches `key` for the connection's next transaction, see `service::TransactionService`.
+    pub fn watch<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::Watch, key.into(), None))
+    }
+
+    /// Starts a transaction: the connection's requests are queued until `exec` or `discard`.
+    pub fn multi(&self) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::Multi, vec![], None))
     }
 
-    pub fn get(&self, key: Vec<u8>) -> Box<Future<Item = Message, Error = io::Error>> {
-        let req = message::request(Op::Get, key, None);
+    /// Applies the transaction, responding as `service::TransactionService` describes.
+    pub fn exec(&self) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::Exec, vec![], None))
+    }
+
+    pub fn discard(&self) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::Discard, vec![], None))
+    }
+
+    /// Fetches what the server knows about `key` besides its value, or `None` if it's missing,
+    /// without counting as a read of the value.
+    pub fn inspect<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+    ) -> Box<Future<Item = Option<cache::EntryInfo>, Error = io::Error>> {
+        let req = message::request(Op::Inspect, key.into(), None);
+        Box::new(self.call(req).and_then(|resp| match (resp.code(), resp.payload()) {
+            (Code::Hit, Some(payload)) => Ok(Some(cache::decode_inspect(payload)?)),
+            (Code::Miss, _) => Ok(None),
+            (code, _) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("unexpected response to inspect: {}", code),
+            )),
+        }))
+    }
+
+    /// Runs the server-side function `function` on the value of `key`, with `arg` as its argument.
+    pub fn apply(
+        &self,
+        key: Vec<u8>,
+        function: &str,
+        arg: Vec<u8>,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::Apply, key, Some(message::payload(1, arg)))
+            .with_extension(message::EXT_FUNCTION, function.as_bytes().to_vec());
+        self.call(req)
+    }
+
+    /// Moves the value at `src` to `dest`. Unless `overwrite` is set, an existing `dest` is left
+    /// untouched and the server responds with `Code::Conflict`.
+    pub fn rename(
+        &self,
+        src: Vec<u8>,
+        dest: Vec<u8>,
+        overwrite: bool,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let flag = if overwrite { cache::RENAME_OVERWRITE } else { 0 };
+        let req = message::request(Op::Rename, src, Some(message::payload(flag, dest)));
+        self.call(req)
+    }
+
+    /// Changes the `type_id` of the value at `key` to `type_id`, if it is currently `expected`.
+    /// Otherwise the server responds with `Code::WrongType` and the current type, as a u32.
+    pub fn retype(
+        &self,
+        key: Vec<u8>,
+        expected: u32,
+        type_id: u32,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(
+            Op::Retype,
+            key,
+            Some(message::payload(type_id, message::encode_u32(expected))),
+        );
+        self.call(req)
+    }
+
+    /// Adds `delta` to the `width` byte integer at `offset` in the value of `key`. See
+    /// `cache::field_incr_request` for how the field is read.
+    pub fn field_incr(
+        &self,
+        key: Vec<u8>,
+        offset: u32,
+        width: u8,
+        delta: i64,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(cache::field_incr_request(key, offset, width, delta))
+    }
+
+    /// Adds `delta` to the counter at `key`, starting from 0 if it's absent, and responds with
+    /// the new value as a `message::TYPE_I64` payload.
+    pub fn incr<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        delta: i64,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let payload = message::payload(0, message::encode_u64(delta as u64));
+        self.call(message::request(Op::Incr, key.into(), Some(payload)))
+    }
+
+    /// Subtracts `delta` from the counter at `key`, like `incr`.
+    pub fn decr<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        delta: i64,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let payload = message::payload(0, message::encode_u64(delta as u64));
+        self.call(message::request(Op::Decr, key.into(), Some(payload)))
+    }
+
+    /// Sets `key` to `value` if it is still at `version`, as carried in the `EXT_VERSION`
+    /// extension of a Get response. If it has been written since, the server responds with
+    /// `Code::CasMismatch` and the current version.
+    pub fn cas(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: u64,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::Cas, key, Some(message::payload(1, value)))
+            .with_extension(message::EXT_VERSION, message::encode_u64(version));
+        self.call(req)
+    }
+
+    /// Deletes `key` if it is still at `version`. If it has been written since, the server
+    /// responds with `Code::Conflict` and the current version.
+    pub fn cas_del(
+        &self,
+        key: Vec<u8>,
+        version: u64,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::CasDel, key, None)
+            .with_extension(message::EXT_VERSION, message::encode_u64(version));
+        self.call(req)
+    }
+
+    /// Fetches each of `keys` in one request. See `message::decode_entries` for unpacking the
+    /// entries found from the response.
+    pub fn mget(&self, keys: &[Vec<u8>]) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(
+            Op::MGet,
+            vec![],
+            Some(message::payload(0, message::encode_keys(keys))),
+        );
         self.call(req)
     }
 
-    pub fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Box<Future<Item = Message, Error = io::Error>> {
-        let req = message::request(Op::Set, key, Some(message::payload(1, value)));
+    /// Deletes each of `keys`. The response payload has a byte per key, 1 if it was deleted and 0
+    /// if it wasn't present.
+    pub fn multi_del(&self, keys: &[Vec<u8>]) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(
+            Op::MultiDel,
+            vec![],
+            Some(message::payload(0, message::encode_keys(keys))),
+        );
+        self.call(req)
+    }
+
+    /// Fetches up to `count` randomly selected keys.
+    pub fn sample(&self, count: u32) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(
+            Op::Sample,
+            vec![],
+            Some(message::payload(0, message::encode_u32(count))),
+        );
+        self.call(req)
+    }
+
+    /// Fetches up to `count` keys in ascending order, starting after `after` if given. Pass the
+    /// last key of a page as `after` to fetch the next.
+    pub fn scan(
+        &self,
+        after: Option<Vec<u8>>,
+        count: u32,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(cache::scan_request(after, count))
+    }
+
+    /// Like `scan`, but only fetches keys matching the glob `pattern`, see `message::EXT_PATTERN`.
+    pub fn scan_matching(
+        &self,
+        after: Option<Vec<u8>>,
+        count: u32,
+        pattern: &[u8],
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(cache::scan_matching_request(after, count, pattern))
+    }
+
+    /// Authenticates the connection with `secret`, see `service::AuthService`. The server
+    /// responds with `Code::Unauthorized` if it doesn't know the secret.
+    pub fn auth<S: Into<Vec<u8>>>(
+        &self,
+        secret: S,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::Auth, vec![], Some(message::payload(0, secret.into())));
         self.call(req)
     }
 
+    /// Opens the connection's handshake, telling the server which protocol version this client
+    /// speaks and offering every feature it supports. The server responds with its own version
+    /// and the features both support, see `codec::decode_hello`, or `Code::Error` if it can't
+    /// speak the client's version.
+    pub fn hello(&self) -> Box<Future<Item = Message, Error = io::Error>> {
+        let offer = codec::hello_payload(codec::PROTOCOL_VERSION, codec::SUPPORTED_FEATURES);
+        self.call(message::request(Op::Hello, vec![], Some(offer)))
+    }
+
+    /// Fetches up to `count` of the oldest keys by insertion time, or the newest if `newest` is
+    /// set, along with their ages. See `cache::decode_range` for unpacking the response.
+    pub fn range(
+        &self,
+        newest: bool,
+        count: u32,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let flag = if newest { cache::RANGE_NEWEST } else { 0 };
+        let payload = message::payload(flag, message::encode_u32(count));
+        self.call(message::request(Op::Range, vec![], Some(payload)))
+    }
+
+    /// Fetches up to `count` of the keys requested most lately, along with how often they were,
+    /// most requested first. See `cache::decode_range` for unpacking the response.
+    pub fn hot_keys(&self, count: u32) -> Box<Future<Item = Message, Error = io::Error>> {
+        let payload = message::payload(0, message::encode_u32(count));
+        self.call(message::request(Op::HotKeys, vec![], Some(payload)))
+    }
+
+    /// Fetches the latest slow requests, emptying the server's slow log if `reset` is set. See
+    /// `slowlog::decode` for unpacking the response.
+    pub fn slow_log(&self, reset: bool) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(slowlog::request(reset))
+    }
+
+    /// Lists the clients connected to the server, see `service::ClientService`.
+    pub fn client_list(&self) -> Box<Future<Item = Vec<ClientInfo>, Error = io::Error>> {
+        let req = message::request(Op::ClientList, vec![], None);
+        Box::new(self.call(req).and_then(|resp| match (resp.code(), resp.payload()) {
+            (Code::Ok, Some(payload)) => Ok(clients::decode(payload.data())?),
+            (Code::Ok, None) => Ok(vec![]),
+            (code, _) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("unexpected response to client list: {}", code),
+            )),
+        }))
+    }
+
+    /// Closes the connection of the client `id`, as listed by `client_list`. The server responds
+    /// with `Code::Miss` if there's no such client.
+    pub fn kick(&self, id: u64) -> Box<Future<Item = Message, Error = io::Error>> {
+        let payload = message::payload(0, message::encode_u64(id));
+        self.call(message::request(Op::Kick, vec![], Some(payload)))
+    }
+
+    /// Fetches the server's stats. Served through a `StatService`, they are packed as a
+    /// `stats::ServerStats`, see `ServerStats::decode`.
     pub fn stats(&self) -> Box<Future<Item = Message, Error = io::Error>> {
         let req = message::request(Op::Stats, vec![], None);
         self.call(req)
     }
+
+    /// Fetches the server's stats as JSON, see `stats::ServerStats::to_json`. Only a server
+    /// answering through a `StatService` knows to.
+    pub fn stats_json(&self) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(stats::json_request())
+    }
+
+    /// Deletes every key in the client's namespace, see `in_namespace`, responding with how many
+    /// were deleted as a u64.
+    pub fn flush_namespace(&self) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::FlushNamespace, vec![], None))
+    }
+
+    /// Deletes every key in the client's namespace, or in the whole cache if it has none, see
+    /// `in_namespace`. With a `delay` in seconds the keys present now expire once it has passed
+    /// instead. The server responds with how many keys were flushed as a u64.
+    pub fn flush_all(&self, delay: Option<u64>) -> Box<Future<Item = Message, Error = io::Error>> {
+        let payload = delay.map(|delay| message::payload(0, message::encode_u64(delay)));
+        self.call(message::request(Op::FlushAll, vec![], payload))
+    }
+
+    /// Checks that the server is up. It responds with `Code::Ok`, without the request reaching
+    /// the cache, which also keeps the connection from being closed for being idle.
+    pub fn ping(&self) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::Ping, vec![], None))
+    }
+
+    /// Subscribes the connection to changes to the keys starting with `prefix` in the client's
+    /// namespace, see `notifications`. The server responds with `Code::Error` if it doesn't
+    /// publish notifications, see `ServeOptions::notifications`.
+    pub fn subscribe<K: Into<Vec<u8>>>(
+        &self,
+        prefix: K,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::Subscribe, prefix.into(), None))
+    }
+
+    /// Ends a subscription made by `subscribe` with the same `prefix`.
+    pub fn unsubscribe<K: Into<Vec<u8>>>(
+        &self,
+        prefix: K,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::Unsubscribe, prefix.into(), None))
+    }
+
+    /// The changes to the keys the connection subscribed to, see `subscribe`. Only the first
+    /// call of this or `replicated_writes` returns them. Notifications are dropped while the
+    /// server has too many waiting to be sent, so they tell that a key changed, but not every
+    /// change it went through.
+    pub fn notifications(&mut self) -> Option<Box<Stream<Item = Notification, Error = io::Error>>> {
+        self.pushes.take().map(pubsub::notifications)
+    }
+
+    /// Asks the server to stream its writes to the connection, see `replicated_writes`. The
+    /// server responds with `Code::Error` unless it lets replicas follow it, see
+    /// `ServeOptions::replication`.
+    pub fn replicate(&self) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::Replicate, vec![], None))
+    }
+
+    /// Assigns the slots from `first` to `last`, inclusive, to the node at `owner`, see
+    /// `cluster::assign_request`. The server responds with the number of keys it gave up.
+    pub fn assign_slots(
+        &self,
+        first: u16,
+        last: u16,
+        owner: SocketAddr,
+        migrate: bool,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(cluster::assign_request(first, last, owner, migrate))
+    }
+
+    /// The writes the server streams once asked to by `replicate`, as frames for a replica to
+    /// pass to `Cache::apply_replicated`. Only the first call of this or `notifications` returns
+    /// them. Ends once the connection closes.
+    pub fn replicated_writes(
+        &mut self,
+    ) -> Option<Box<Stream<Item = Message, Error = io::Error>>> {
+        self.pushes.take().map(|pushes| {
+            let writes = pushes
+                .filter(|msg| msg.op() == Op::Replicate)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "connection closed"));
+            Box::new(writes) as Box<Stream<Item = Message, Error = io::Error>>
+        })
+    }
+
+    /// Describes the server: its version, uptime, protocol version and enabled features.
+    pub fn info(&self) -> Box<Future<Item = Message, Error = io::Error>> {
+        let req = message::request(Op::Info, vec![], None);
+        self.call(req)
+    }
 }
 
 impl Service for Client {
@@ -50,6 +716,14 @@ impl Service for Client {
     type Future = Box<Future<Item = Message, Error = io::Error>>;
 
     fn call(&self, req: Message) -> Self::Future {
-        Box::new(self.inner.call(req))
+        let req = match self.namespace {
+            Some(ref namespace) => req.with_extension(message::EXT_NAMESPACE, namespace.clone()),
+            None => req,
+        };
+        match self.inner {
+            Transport::Tcp(ref inner) => Box::new(inner.call(req)),
+            Transport::Tls(ref inner) => Box::new(inner.call(req)),
+            Transport::Unix(ref inner) => Box::new(inner.call(req)),
+        }
     }
 }
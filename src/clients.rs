@@ -0,0 +1,252 @@
+use bytes::{Buf, BufMut, BigEndian};
+use futures::sync::oneshot;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use time::{self, Timespec};
+
+use message::Op;
+use error;
+
+/// `type_id` of the payload `service::ClientService` answers `Op::ClientList` with, as packed by
+/// `encode`.
+pub const CLIENT_LIST_TYPE_ID: u32 = 23;
+
+/// What a server knows about a client connected to it, see `Clients`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    /// Identifies the connection, for `Op::Kick`. Ids aren't reused while the server runs.
+    pub id: u64,
+    /// The client's address, `None` on a Unix socket.
+    pub addr: Option<SocketAddr>,
+    pub connected_at: Timespec,
+    /// The requests the client has sent.
+    pub ops: u64,
+    /// The op of the latest, `None` before any.
+    pub last_op: Option<Op>,
+    /// Who the client authenticated as, see `service::AuthService`, `None` until it does.
+    pub identity: Option<String>,
+}
+
+/// The clients connected to a server, kept in its `Stats`, see `Stats::clients`. The server
+/// registers connections as it accepts them, and they drop out as they close.
+#[derive(Default)]
+pub struct Clients {
+    registry: Mutex<Registry>,
+}
+
+#[derive(Default)]
+struct Registry {
+    last_id: u64,
+    clients: BTreeMap<u64, Client>,
+}
+
+struct Client {
+    info: ClientInfo,
+    /// Resolves the receiver the connection stops reading at, see `Clients::kick`.
+    kick: Option<oneshot::Sender<()>>,
+}
+
+impl Clients {
+    /// Registers a client connected from `addr`, until the returned `Registration` is dropped.
+    /// The receiver resolves if the client is kicked.
+    pub fn register(
+        clients: &Arc<Clients>,
+        addr: Option<SocketAddr>,
+    ) -> (Registration, oneshot::Receiver<()>) {
+        let (snd, rcv) = oneshot::channel();
+        let mut registry = clients.registry.lock().unwrap();
+        registry.last_id += 1;
+        let id = registry.last_id;
+        let info = ClientInfo {
+            id: id,
+            addr: addr,
+            connected_at: time::get_time(),
+            ops: 0,
+            last_op: None,
+            identity: None,
+        };
+        registry.clients.insert(id, Client {
+            info: info,
+            kick: Some(snd),
+        });
+        let registration = Registration {
+            clients: clients.clone(),
+            id: id,
+        };
+        (registration, rcv)
+    }
+
+    /// Counts a request for `op` from the client `id`.
+    pub fn record(&self, id: u64, op: Op) {
+        if let Some(client) = self.registry.lock().unwrap().clients.get_mut(&id) {
+            client.info.ops += 1;
+            client.info.last_op = Some(op);
+        }
+    }
+
+    /// Records that the client `id` authenticated as `identity`.
+    pub fn identify(&self, id: u64, identity: String) {
+        if let Some(client) = self.registry.lock().unwrap().clients.get_mut(&id) {
+            client.info.identity = Some(identity);
+        }
+    }
+
+    /// The connected clients, oldest first.
+    pub fn list(&self) -> Vec<ClientInfo> {
+        let registry = self.registry.lock().unwrap();
+        registry.clients.values().map(|client| client.info.clone()).collect()
+    }
+
+    /// Closes the connection of the client `id`: it stops reading requests, and closes once
+    /// those it read are answered. Returns whether there was such a client.
+    pub fn kick(&self, id: u64) -> bool {
+        match self.registry.lock().unwrap().clients.get_mut(&id) {
+            Some(client) => {
+                if let Some(kick) = client.kick.take() {
+                    let _ = kick.send(());
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Keeps a client in its `Clients` until dropped.
+pub struct Registration {
+    clients: Arc<Clients>,
+    id: u64,
+}
+
+impl Registration {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn clients(&self) -> &Arc<Clients> {
+        &self.clients
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.clients.registry.lock().unwrap().clients.remove(&self.id);
+    }
+}
+
+/// Packs `clients` for an `Op::ClientList` response, all integers big endian. For each client:
+/// its id, when it connected in milliseconds since the Unix epoch and its number of requests as
+/// u64s, a byte that is 1 if its last op follows as a u8, then its address and its identity in
+/// UTF-8, each preceded by its length as a u32 and empty if `None`.
+pub fn encode(clients: &[ClientInfo]) -> Vec<u8> {
+    let mut data = vec![];
+    for client in clients {
+        let at = client.connected_at.sec as u64 * 1000 + client.connected_at.nsec as u64 / 1000000;
+        data.put_u64::<BigEndian>(client.id);
+        data.put_u64::<BigEndian>(at);
+        data.put_u64::<BigEndian>(client.ops);
+        match client.last_op {
+            Some(op) => {
+                data.put_u8(1);
+                data.put_u8(op as u8);
+            }
+            None => data.put_u8(0),
+        }
+        let addr = client.addr.map(|addr| addr.to_string()).unwrap_or_default();
+        let identity = client.identity.as_ref().map_or("", |identity| &identity[..]);
+        for field in &[&addr[..], identity] {
+            data.put_u32::<BigEndian>(field.len() as u32);
+            data.put_slice(field.as_bytes());
+        }
+    }
+    data
+}
+
+/// Unpacks the clients packed by `encode`. A last op this version doesn't know reads as `None`.
+pub fn decode(data: &[u8]) -> Result<Vec<ClientInfo>, error::Error> {
+    let invalid = |description| error::Error::new(error::ErrorKind::InvalidData, description);
+    let mut clients = vec![];
+    let mut cursor = io::Cursor::new(data);
+    while cursor.remaining() > 0 {
+        if cursor.remaining() < 8 * 3 + 1 {
+            return Err(invalid("truncated client list"));
+        }
+        let id = cursor.get_u64::<BigEndian>();
+        let at = cursor.get_u64::<BigEndian>();
+        let ops = cursor.get_u64::<BigEndian>();
+        let last_op = match cursor.get_u8() {
+            1 if cursor.remaining() > 0 => Op::try_from(cursor.get_u8()).ok(),
+            1 => return Err(invalid("truncated client list")),
+            _ => None,
+        };
+        let mut fields = vec![];
+        for _ in 0..2 {
+            if cursor.remaining() < 4 {
+                return Err(invalid("truncated client list"));
+            }
+            let len = cursor.get_u32::<BigEndian>() as usize;
+            if cursor.remaining() < len {
+                return Err(invalid("truncated client list"));
+            }
+            let mut field = vec![0; len];
+            cursor.copy_to_slice(&mut field);
+            let field = String::from_utf8(field).map_err(|_| invalid("invalid client list"))?;
+            fields.push(Some(field).filter(|field| !field.is_empty()));
+        }
+        let identity = fields.pop().unwrap();
+        let addr = match fields.pop().unwrap() {
+            Some(addr) => Some(addr.parse().map_err(|_| invalid("invalid client address"))?),
+            None => None,
+        };
+        clients.push(ClientInfo {
+            id: id,
+            addr: addr,
+            connected_at: Timespec::new((at / 1000) as i64, (at % 1000) as i32 * 1000000),
+            ops: ops,
+            last_op: last_op,
+            identity: identity,
+        });
+    }
+    Ok(clients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn test_clients() {
+        let clients = Arc::new(Clients::default());
+        let (first, _) = Clients::register(&clients, Some("10.0.0.1:1234".parse().unwrap()));
+        let (second, kicked) = Clients::register(&clients, None);
+        clients.record(first.id(), Op::Get);
+        clients.record(first.id(), Op::Set);
+        clients.identify(first.id(), "admin".to_owned());
+
+        let list = clients.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!((list[0].ops, list[0].last_op), (2, Some(Op::Set)));
+        assert_eq!(list[0].identity, Some("admin".to_owned()));
+        assert_eq!((list[1].addr, list[1].last_op), (None, None));
+
+        // The connection time is kept to the millisecond.
+        let mut list = list;
+        for client in &mut list {
+            client.connected_at.nsec -= client.connected_at.nsec % 1000000;
+        }
+        assert_eq!(decode(&encode(&list)).unwrap(), list);
+        let data = encode(&list);
+        assert!(decode(&data[..data.len() - 1]).is_err());
+
+        let id = second.id();
+        assert!(clients.kick(id));
+        assert!(kicked.wait().is_ok());
+        drop(second);
+        assert_eq!(clients.list().len(), 1);
+        assert!(!clients.kick(id));
+    }
+}
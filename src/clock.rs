@@ -0,0 +1,50 @@
+use std::sync::Mutex;
+use time::{self, Duration, Timespec};
+
+/// A source of the current time. Everything in the crate that depends on time takes a `Clock`,
+/// so that tests can control time with a `MockClock` rather than sleeping.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Timespec;
+}
+
+/// The system's wall clock.
+#[derive(Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timespec {
+        time::get_time()
+    }
+}
+
+/// A clock that only moves when told to.
+pub struct MockClock {
+    now: Mutex<Timespec>,
+}
+
+impl MockClock {
+    pub fn new(now: Timespec) -> Self {
+        MockClock { now: Mutex::new(now) }
+    }
+
+    pub fn set(&self, now: Timespec) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new(Timespec::new(0, 0))
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Timespec {
+        *self.now.lock().unwrap()
+    }
+}
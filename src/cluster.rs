@@ -0,0 +1,269 @@
+use futures::{stream, Stream};
+use tokio_core::reactor::Core;
+use tokio_service::Service;
+use bytes::{Buf, BufMut, BigEndian};
+use std::io;
+use std::net::SocketAddr;
+use std::str;
+use std::thread;
+
+use cache;
+use client::Client;
+use message::{self, Code, Message, Op, Payload};
+use error;
+
+/// The hash slots keys are spread over in cluster mode, see `slot`.
+pub static SLOTS: usize = 16384;
+
+/// Payload `type_id` flag on an `Op::AssignSlots` request handing the keys in the slots a node
+/// gives up to their new owner, see `hand_over`. Without it, the node drops them.
+pub static MIGRATE: u32 = 1;
+
+/// The most keys a node hands over at once, see `hand_over`.
+static HAND_OVER_IN_FLIGHT: usize = 64;
+
+/// The slot of `key`: its CRC16 (XMODEM), as Redis Cluster computes it, modulo `SLOTS`. Only
+/// the key within its namespace is hashed, so a key has the same slot in every namespace.
+pub fn slot(key: &[u8]) -> u16 {
+    let crc = key.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| if crc & 0x8000 != 0 {
+            crc << 1 ^ 0x1021
+        } else {
+            crc << 1
+        })
+    });
+    crc % SLOTS as u16
+}
+
+/// The owner of every slot, as a node in cluster mode sees it, see `cache::Options::cluster`.
+/// A node starts out owning every slot, and gives them up as they are assigned to other nodes
+/// with `Op::AssignSlots`. Requests for keys in slots it doesn't own are answered with
+/// `Code::Redirect`, see `redirect`.
+///
+/// Each node only knows what it was told, so slots are assigned by sending the same
+/// `Op::AssignSlots` to every node, the new owner first, so that it takes the requests
+/// redirected to it.
+pub struct Slots {
+    me: SocketAddr,
+    owners: Vec<SocketAddr>,
+}
+
+impl Slots {
+    /// The slots of the node clients reach at `me`, which owns all of them.
+    pub fn new(me: SocketAddr) -> Self {
+        Slots {
+            me: me,
+            owners: vec![me; SLOTS],
+        }
+    }
+
+    /// The node's own address.
+    pub fn me(&self) -> SocketAddr {
+        self.me
+    }
+
+    pub fn owner(&self, slot: u16) -> SocketAddr {
+        self.owners[slot as usize]
+    }
+
+    /// Whether the node owns `slot`.
+    pub fn is_mine(&self, slot: u16) -> bool {
+        self.owner(slot) == self.me
+    }
+
+    /// Assigns the slots from `first` to `last`, inclusive, to `owner`.
+    pub fn assign(&mut self, first: u16, last: u16, owner: SocketAddr) {
+        for slot in first..last + 1 {
+            self.owners[slot as usize] = owner;
+        }
+    }
+}
+
+/// The keys deciding which node serves `msg`, within its namespace: its key, the destination
+/// of an `Op::Rename`, the keys named by an `Op::MGet` or `Op::MultiDel`, those of the ops in an
+/// `Op::Batch` and those of the entries in an `Op::Restore`. Requests for the server rather than
+/// a key have none, and are served by any node.
+pub fn keys_of(msg: &Message) -> Vec<Vec<u8>> {
+    let key = msg.key().unwrap_or_default().to_vec();
+    match msg.op() {
+        Op::Get | Op::GetIfNewer | Op::Set | Op::Add | Op::Replace | Op::SetIfEmpty | Op::Cas |
+        Op::CasDel | Op::Del | Op::Apply | Op::Retype | Op::FieldIncr | Op::Incr | Op::Decr |
+        Op::Touch | Op::Expire | Op::Append | Op::Prepend | Op::Exists | Op::Ttl |
+        Op::Inspect | Op::Watch => vec![key],
+        Op::Rename => {
+            let dest = msg.payload().map(|p| p.data().to_vec()).unwrap_or_default();
+            vec![key, dest]
+        }
+        Op::MGet | Op::MultiDel => {
+            msg.payload()
+                .and_then(|p| message::decode_keys(p.data()).ok())
+                .unwrap_or_default()
+        }
+        Op::Batch => {
+            let ops = msg.payload().and_then(|p| message::decode_messages(p.data()).ok());
+            ops.unwrap_or_default().iter().flat_map(keys_of).collect()
+        }
+        Op::Restore => {
+            let entries = msg.payload().and_then(|p| cache::decode_dump(p.data()).ok());
+            entries.unwrap_or_default().into_iter().map(|(key, _, _)| key).collect()
+        }
+        _ => vec![],
+    }
+}
+
+/// The `Op::AssignSlots` request assigning the slots from `first` to `last`, inclusive, to the
+/// node at `owner`, handing it the keys in them if `migrate` is set, see `MIGRATE`. Its payload
+/// is `first` and `last` as big endian u16s followed by `owner` in UTF-8. The node answers with
+/// the number of keys it gave up, as a u64.
+pub fn assign_request(first: u16, last: u16, owner: SocketAddr, migrate: bool) -> Message {
+    let mut data = vec![];
+    data.put_u16::<BigEndian>(first);
+    data.put_u16::<BigEndian>(last);
+    data.put_slice(owner.to_string().as_bytes());
+    let flags = if migrate { MIGRATE } else { 0 };
+    message::request(Op::AssignSlots, vec![], Some(message::payload(flags, data)))
+}
+
+/// Decodes the payload of an `assign_request` into the first and last slot and the owner.
+pub fn decode_assignment(payload: &Payload) -> Result<(u16, u16, SocketAddr), error::Error> {
+    let invalid = || error::Error::new(error::ErrorKind::InvalidData, "invalid slot assignment");
+    let data = payload.data();
+    if data.len() < 4 {
+        return Err(invalid());
+    }
+    let mut cursor = io::Cursor::new(&data[..4]);
+    let (first, last) = (cursor.get_u16::<BigEndian>(), cursor.get_u16::<BigEndian>());
+    if first > last || last as usize >= SLOTS {
+        return Err(invalid());
+    }
+    let owner = str::from_utf8(&data[4..]).ok().and_then(|owner| owner.parse().ok());
+    Ok((first, last, owner.ok_or_else(invalid)?))
+}
+
+/// The `Code::Redirect` response to an `op` for a key in `slot`, which `owner` serves. Its
+/// payload's `type_id` is the slot, and holds the owner's address in UTF-8.
+pub fn redirect(op: Op, slot: u16, owner: SocketAddr) -> Message {
+    let payload = message::payload(slot as u32, owner.to_string().into_bytes());
+    message::response(op, Code::Redirect, Some(payload))
+}
+
+/// The slot and node a `Code::Redirect` response names.
+pub fn redirected_to(resp: &Message) -> Option<(u16, SocketAddr)> {
+    match resp.payload() {
+        Some(payload) if resp.code() == Code::Redirect => {
+            let owner = str::from_utf8(payload.data()).ok().and_then(|owner| owner.parse().ok());
+            owner.map(|owner| (payload.type_id() as u16, owner))
+        }
+        _ => None,
+    }
+}
+
+/// Sends `requests`, the Sets storing the keys a node gave up, to their new owner at `owner`,
+/// on a thread of its own so that the node carries on meanwhile. This is best effort: keys that
+/// fail to arrive are lost, as they would be if evicted.
+pub fn hand_over(owner: SocketAddr, requests: Vec<Message>) {
+    let spawned = thread::Builder::new().name("rcache-migrate".to_owned()).spawn(move || {
+        let count = requests.len();
+        let failed = Core::new().and_then(|mut core| {
+            let client = core.run(Client::connect(&owner, &core.handle()))?;
+            let sets = stream::iter_ok(requests)
+                .map(|req| client.call(req))
+                .buffer_unordered(HAND_OVER_IN_FLIGHT)
+                .filter(|resp| resp.code() != Code::Ok)
+                .collect();
+            Ok(core.run(sets)?.len())
+        });
+        match failed {
+            Ok(0) => info!("Handed {} keys over to {}.", count, owner),
+            Ok(failed) => warn!("Failed to hand {} of {} keys to {}.", failed, count, owner),
+            Err(e) => warn!("Failed to hand {} keys over to {}: {}.", count, owner, e),
+        }
+    });
+    if let Err(e) = spawned {
+        warn!("Failed to hand keys over to {}: {}.", owner, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::{self, Cache};
+    use futures::sync::oneshot;
+    use futures::Future;
+    use service::{self, CacheService, ServeOptions};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn call(cache: &Cache, req: Message) -> Message {
+        let (snd, rcv) = oneshot::channel();
+        cache.process(req, snd);
+        rcv.wait().unwrap()
+    }
+
+    #[test]
+    fn test_slot() {
+        // The check value of CRC16 (XMODEM), and slots as Redis Cluster has them.
+        assert_eq!(slot(b"123456789"), 0x31C3 % SLOTS as u16);
+        assert_eq!(slot(b"foo"), 12182);
+        assert_eq!(slot(b""), 0);
+    }
+
+    #[test]
+    fn test_assignment() {
+        let owner = "10.0.0.2:12345".parse().unwrap();
+        let req = assign_request(10, 20, owner, true);
+        let payload = req.payload().unwrap();
+        assert_eq!(payload.type_id(), MIGRATE);
+        assert_eq!(decode_assignment(payload).unwrap(), (10, 20, owner));
+        let backwards = assign_request(20, 10, owner, false);
+        assert!(decode_assignment(backwards.payload().unwrap()).is_err());
+        let out_of_range = assign_request(0, SLOTS as u16, owner, false);
+        assert!(decode_assignment(out_of_range.payload().unwrap()).is_err());
+
+        let mut slots = Slots::new("10.0.0.1:12345".parse().unwrap());
+        slots.assign(10, 20, owner);
+        assert!(slots.is_mine(9) && slots.is_mine(21));
+        assert_eq!(slots.owner(10), owner);
+        assert_eq!(slots.owner(20), owner);
+        assert_eq!(redirected_to(&redirect(Op::Get, 15, owner)), Some((15, owner)));
+    }
+
+    #[test]
+    fn test_hand_over() {
+        let to = Arc::new(Cache::new(100).unwrap());
+        let cache = to.clone();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = service::serve_on_thread(addr, ServeOptions::default(), move || {
+            Ok(CacheService { cache: cache })
+        }).unwrap();
+        let options = cache::Options {
+            cluster: Some("10.0.0.1:12345".parse().unwrap()),
+            ..cache::Options::default()
+        };
+        let from = Cache::with_options(100, options).unwrap();
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, "1".into())));
+        let set = set.with_extension(message::EXT_NAMESPACE, "n".into())
+            .with_extension(message::EXT_TTL, message::encode_u64(60));
+        assert_eq!(call(&from, set).code(), Code::Ok);
+
+        // "foo" is in slot 12182.
+        let resp = call(&from, assign_request(12000, 12200, server.local_addr(), true));
+        assert_eq!(resp.payload().unwrap().data(), &message::encode_u64(1)[..]);
+        let get = message::request(Op::Get, "foo".into(), None)
+            .with_extension(message::EXT_NAMESPACE, "n".into());
+        assert_eq!(redirected_to(&call(&from, get.clone())), Some((12182, server.local_addr())));
+
+        // The new owner gets it in the same namespace.
+        let started = Instant::now();
+        loop {
+            let resp = call(&to, get.clone());
+            if resp.code() == Code::Hit {
+                assert_eq!(resp.payload(), Some(&message::payload(1, "1".into())));
+                break;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "foo wasn't handed over");
+            thread::sleep(Duration::from_millis(10));
+        }
+        server.shutdown().unwrap();
+    }
+}
@@ -1,15 +1,156 @@
 use tokio_io::codec::{Encoder, Decoder};
 use tokio_proto::multiplex::RequestId;
+use std::cmp;
 use std::io;
 use std::convert::TryFrom;
-use bytes::{Buf, BufMut, BigEndian, BytesMut};
-use message::{self, Message, Op, Code};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use bytes::{Buf, BufMut, BigEndian, Bytes, BytesMut};
+use message::{self, Message, Op, Code, Payload};
+use error;
+use snap;
 
 
 static HEADER_LEN: usize = 8 + 1 + 1 + 8 + 4;
+
+/// Set on the request id of a frame the server sends unprompted. See `CacheCodec`.
+pub const UNSOLICITED_FLAG: u64 = 1 << 63;
+
+/// Whether `id` marks a frame the server sent unprompted, rather than a response to a request.
+pub fn is_unsolicited(id: RequestId) -> bool {
+    id & UNSOLICITED_FLAG != 0
+}
+
+/// Extension marking the response the decoder returns in place of a frame it refused, one that
+/// failed its checksum or a request too large to take, see `refused`. It is never read off the
+/// wire: the decoder drops it from every frame, so a peer can't pass its own frames off as
+/// refused ones.
+const EXT_REFUSED: u16 = 0xFFFF;
+
+/// The response to send for `msg` if the decoder returned it in place of a frame it refused,
+/// rather than decoding it from a frame.
+pub fn refused(msg: &Message) -> Option<Message> {
+    match msg.extension(EXT_REFUSED) {
+        Some(_) => Some(msg.clone().without_extension(EXT_REFUSED)),
+        None => None,
+    }
+}
+
+fn reserved_id() -> io::Error {
+    error::Error::new(error::ErrorKind::BadMessage, "request id is reserved for unsolicited frames")
+        .into()
+}
+
+/// Version of the wire protocol below, bumped on changes to the framing. Version 2 added
+/// checksummed frames, version 3 feature negotiation in the `Op::Hello` handshake.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Oldest protocol version the server still speaks.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The type id of a negotiating `Op::Hello` payload, "RCHL", see `hello_payload`. It tells a
+/// negotiating hello apart from the bare protocol version sent by peers older than version 3.
+pub const HELLO_MAGIC: u32 = 0x5243_484C;
+
+/// Feature bit for checksummed frames.
+pub const FEATURE_CHECKSUM: u32 = 1;
+
+/// Feature bit for the compare-and-swap ops, `Op::Cas` and `Op::CasDel`.
+pub const FEATURE_CAS: u32 = 1 << 1;
+
+/// Feature bit for Snappy compressed payloads, see `CacheCodec::compress_above`.
+pub const FEATURE_COMPRESSION: u32 = 1 << 2;
+
+/// Feature bit for payloads sent in chunks, see `CacheCodec`.
+pub const FEATURE_CHUNKED: u32 = 1 << 3;
+
+/// The features this end of the protocol supports.
+pub const SUPPORTED_FEATURES: u32 = FEATURE_CHECKSUM | FEATURE_CAS | FEATURE_COMPRESSION |
+    FEATURE_CHUNKED;
+
+/// Default length above which payloads are compressed, once compression has been negotiated.
+pub static DEFAULT_COMPRESS_ABOVE: usize = 1024;
+
+/// Length from which a decoded payload shares the buffer its frame was read into, rather than
+/// being copied out of it, so that a large value can be stored and sent back out without ever
+/// being copied. Shorter ones are copied, as a small value kept by the cache would otherwise keep
+/// the whole buffer, and the frames around it, from being freed.
+pub static SHARE_PAYLOADS_FROM: usize = 4096;
+
+/// The payload of an `Op::Hello`, either way: the protocol `version` spoken and the `features`
+/// offered, as a bitset of the `FEATURE_` constants. The server answers with its own version and
+/// the features both ends support.
+pub fn hello_payload(version: u32, features: u32) -> Payload {
+    let mut data = Vec::with_capacity(8);
+    data.put_u32::<BigEndian>(version);
+    data.put_u32::<BigEndian>(features);
+    message::payload(HELLO_MAGIC, data)
+}
+
+/// Unpacks the version and features of a hello payload made by `hello_payload`. A bare version,
+/// as sent before version 3, offers no features.
+pub fn decode_hello(payload: &Payload) -> io::Result<(u32, u32)> {
+    let data = payload.data();
+    match payload.type_id() {
+        HELLO_MAGIC if data.len() == 8 => {
+            let mut cursor = io::Cursor::new(data);
+            Ok((cursor.get_u32::<BigEndian>(), cursor.get_u32::<BigEndian>()))
+        }
+        HELLO_MAGIC => {
+            Err(error::Error::new(error::ErrorKind::InvalidData, "malformed hello").into())
+        }
+        _ => Ok((message::decode_u32(data)?, 0)),
+    }
+}
+
+/// Default maximum key length accepted by the decoder, in bytes.
+pub static DEFAULT_MAX_KEY_LEN: usize = 250;
+
+/// Default maximum payload length accepted by the decoder, in bytes.
+pub static DEFAULT_MAX_PAYLOAD_LEN: usize = 1024 * 1024;
+
+/// Maximum length of a trailing extensions block accepted by the decoder, in bytes.
+pub static MAX_EXTENSIONS_LEN: usize = 64 * 1024;
+
+/// Default maximum length of a payload sent in chunks, in bytes.
+pub static DEFAULT_MAX_VALUE_LEN: usize = 64 * 1024 * 1024;
+
+/// The most payload bytes the encoder puts in each chunk, short of the payload limit.
+pub static CHUNK_LEN: usize = 256 * 1024;
+
+/// Set on the op byte when the frame carries a trailing extensions block.
+static EXTENSIONS_FLAG: u8 = 0x80;
+
+/// Length of the type and length fields preceding each extension value.
+static EXTENSION_HEADER_LEN: usize = 2 + 4;
+
+/// Set on the code byte when the frame ends with a CRC32 of everything before it.
+static CHECKSUM_FLAG: u8 = 0x80;
+
+/// Length of the checksum trailer.
+static CHECKSUM_LEN: usize = 4;
+
+/// Set on the code byte when the payload data is compressed with Snappy.
+static COMPRESSED_FLAG: u8 = 0x40;
+
+/// Set on the code byte of every chunk of a payload but the last.
+static CONTINUED_FLAG: u8 = 0x20;
+
+/// The CRC32 (IEEE) of `data`. Computed bitwise rather than from a table, frames are small.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
 /// A basic, multiplexed byte-protocol for interacting with the cache.
-/// This is my first ever binary/byte protocol and no doubt has numerous issues. At the very
-/// least, there should be a CRC check and support for CAS ops.
+/// This is my first ever binary/byte protocol and no doubt has numerous issues.
 ///
 /// +-- request id ------+- code ---------+----op --+--- payload len ---+---- key len ---
 /// |                    |                |         |                   |
@@ -22,7 +163,263 @@ static HEADER_LEN: usize = 8 + 1 + 1 + 8 + 4;
 /// |   [u8]   |   u32       |    [u8]     |
 /// |          |             |             |
 /// +----------+-------------+-------------+
-pub struct CacheCodec;
+///
+/// If the high bit of the op byte is set, the payload is followed by an extensions block: a u32
+/// length (not counting itself) and then a sequence of TLVs, each a u16 type, a u32 value length
+/// and the value bytes. Decoders that don't set the bit never see the block, so old clients keep
+/// working.
+///
+/// +-- extensions len --+-- type --+-- len --+-- value --+-- type --+ ...
+/// |                    |          |         |           |          |
+/// |  u32 (4 bytes)     |   u16    |   u32   |   [u8]    |   u16    | ...
+/// |                    |          |         |           |          |
+/// +--------------------+----------+---------+-----------+----------+ ...
+///
+/// If the high bit of the code byte is set, the frame ends with a u32 CRC32 of all the bytes
+/// before it, flag included. A frame that fails the check decodes to a `Code::Error` response
+/// for its request id in place of the message, marked so that `refused` tells it from
+/// a frame sent with that code, which `service::serve` sends straight back, so a corrupted
+/// request is reported to its client rather than served. Checksums came with protocol
+/// version 2: the encoder only adds them when `checksum` is set, or once the decoder has seen a
+/// checksummed frame from the peer, so peers speaking version 1 keep working unchanged.
+///
+/// Request ids with the high bit set, `UNSOLICITED_FLAG`, are reserved for frames the server
+/// sends of its own accord rather than in answer to a request, such as pushed invalidations. A
+/// client never uses them, so pushes can't be mistaken for responses: both the encoder and the
+/// decoder refuse requests with a reserved id.
+///
+/// If the second highest bit of the code byte is set, the payload data, after the type id, is
+/// compressed with Snappy, and the payload length is that of the compressed data. Peers agree
+/// to compression in the `Op::Hello` handshake, see `FEATURE_COMPRESSION`: once the codec has
+/// decoded a hello offering or accepting it, the encoder compresses payloads longer than
+/// `compress_above`, when that makes them smaller. The decoder decompresses payloads with the bit
+/// set, refusing data that would decompress past `max_payload_len`, and ends the connection on
+/// compressed frames sent before compression was negotiated.
+///
+/// Checksums and the compare-and-swap ops, which came before the handshake could negotiate them,
+/// are accepted from a peer that never negotiates. Once a hello has settled the features without
+/// them, the decoder ends the connection on checksummed frames, or on `Op::Cas` and `Op::CasDel`
+/// frames, respectively, see `FEATURE_CHECKSUM` and `FEATURE_CAS`.
+///
+/// A message whose payload is too long for a single frame, up to `max_value_len`, is sent in
+/// chunks once peers have agreed to it in the `Op::Hello` handshake, see `FEATURE_CHUNKED`. Each
+/// chunk is a frame of its own with the message's request id, op and code, holding the next
+/// `CHUNK_LEN` bytes of the payload, and the third highest bit of the code byte set on all but
+/// the last. Each carries a `message::EXT_CHUNK` extension with its sequence number, the first
+/// along with the payload's whole length, and only the first carries the key and the message's
+/// other extensions. The chunks of a message are sent one after the other, so the decoder
+/// reassembles them into the payload as they arrive, growing it only by the chunks received, and
+/// never has to buffer a frame longer than a chunk. It ends the connection on chunks out of
+/// sequence, on chunks sent before they were negotiated, and on chunks that would take the
+/// reassemblies under way past a shared `AssemblyLimit`, see `limit_assemblies`.
+///
+/// With `expect_hello` set, the decoder refuses a connection whose first frame isn't an
+/// `Op::Hello` request, as soon as its header has arrived. A peer speaking another protocol
+/// altogether is turned away with an error, rather than having its bytes misparsed as frames.
+///
+/// In `strict` mode, extensions whose type isn't listed in `message::KNOWN_EXTENSIONS` are
+/// dropped on decode; otherwise they are preserved on the decoded message.
+///
+/// The decoder refuses frames whose header declares a key longer than `max_key_len`, a payload
+/// longer than `max_payload_len`, or a frame longer than `max_frame_len` in all, before any of
+/// the body has been buffered, so a peer can't make it buffer without bound. A request is
+/// discarded as it arrives, and decodes to a `Code::KeyTooLarge` or `Code::ValueTooLarge`
+/// response in its place, marked as `refused`; anything else, such as a chunk or a response,
+/// ends the connection. By default the frame limit is the longest frame the other limits allow.
+/// Symmetrically, the encoder refuses to write a frame longer than `max_encoded_len`, not counting
+/// a checksum. By default that's the largest frame the decoder accepts, so a server never writes
+/// a response its peer would reject.
+/// `service::serve` replaces oversized responses with a `Code::Error` response before they reach
+/// the encoder, so the client is told rather than having its connection dropped.
+pub struct CacheCodec {
+    max_key_len: usize,
+    max_payload_len: usize,
+    max_encoded_len: usize,
+    max_frame_len: usize,
+    strict: bool,
+    checksum: bool,
+    expect_hello: bool,
+    compress_above: Option<usize>,
+    compress: bool,
+    max_value_len: usize,
+    chunked: bool,
+    /// The features settled by the last `Op::Hello` decoded, if any was.
+    features: Option<u32>,
+    assembling: Option<Assembly>,
+    assemblies: Option<Arc<AssemblyLimit>>,
+    skipping: Option<Skip>,
+}
+
+/// A refused request being discarded as it arrives: the bytes of it left to discard, and whether
+/// its extensions block and its checksum follow those.
+struct Skip {
+    left: usize,
+    extensions: bool,
+    checksum: bool,
+}
+
+/// A message whose chunks are being decoded: its request id, the message with its payload so
+/// far, the payload's whole length, the next chunk's sequence number, and its place under the
+/// codec's `AssemblyLimit`, if any.
+struct Assembly {
+    request_id: RequestId,
+    msg: Message,
+    data: BytesMut,
+    len: usize,
+    next: u32,
+    _slot: Option<AssemblySlot>,
+}
+
+/// The most chunked messages the codecs sharing it reassemble at once, such as those of a
+/// server's connections, see `CacheCodec::limit_assemblies`.
+pub struct AssemblyLimit {
+    max: usize,
+    assembling: AtomicUsize,
+}
+
+impl AssemblyLimit {
+    pub fn new(max: usize) -> Arc<Self> {
+        Arc::new(AssemblyLimit {
+            max: max,
+            assembling: AtomicUsize::new(0),
+        })
+    }
+
+    /// The chunked messages being reassembled.
+    pub fn assembling(&self) -> usize {
+        self.assembling.load(Ordering::SeqCst)
+    }
+}
+
+/// A reassembly counted against an `AssemblyLimit` until dropped.
+struct AssemblySlot(Arc<AssemblyLimit>);
+
+impl AssemblySlot {
+    fn take(limit: &Arc<AssemblyLimit>) -> Option<Self> {
+        if limit.assembling.fetch_add(1, Ordering::SeqCst) >= limit.max {
+            limit.assembling.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(AssemblySlot(limit.clone()))
+    }
+}
+
+impl Drop for AssemblySlot {
+    fn drop(&mut self) {
+        self.0.assembling.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl CacheCodec {
+    pub fn new(max_key_len: usize, max_payload_len: usize) -> Self {
+        let max_encoded_len = HEADER_LEN + max_key_len + 4 + max_payload_len + 4 +
+            MAX_EXTENSIONS_LEN;
+        CacheCodec {
+            max_key_len: max_key_len,
+            max_payload_len: max_payload_len,
+            max_encoded_len: max_encoded_len,
+            max_frame_len: max_encoded_len + CHECKSUM_LEN,
+            strict: false,
+            checksum: false,
+            expect_hello: false,
+            compress_above: None,
+            compress: false,
+            max_value_len: DEFAULT_MAX_VALUE_LEN,
+            chunked: false,
+            features: None,
+            assembling: None,
+            assemblies: None,
+            skipping: None,
+        }
+    }
+
+    /// Limit the length of frames written by the encoder.
+    pub fn limit_encoded_len(mut self, max_encoded_len: usize) -> Self {
+        self.max_encoded_len = max_encoded_len;
+        self
+    }
+
+    pub fn max_encoded_len(&self) -> usize {
+        self.max_encoded_len
+    }
+
+    /// Limit the length of frames accepted by the decoder, checksum included.
+    pub fn limit_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    /// Drop unknown extension types on decode.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Add a checksum to encoded frames.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Compress payloads longer than `threshold` bytes, once compression has been negotiated.
+    /// Without a threshold, nothing is compressed.
+    pub fn compress_above(mut self, threshold: Option<usize>) -> Self {
+        self.compress_above = threshold;
+        self
+    }
+
+    /// Limit the length of payloads sent in chunks, by either end.
+    pub fn limit_value_len(mut self, max_value_len: usize) -> Self {
+        self.max_value_len = max_value_len;
+        self
+    }
+
+    /// Count the chunked messages the decoder reassembles against `limit`, which is shared with
+    /// other codecs, ending the connection rather than start one past it.
+    pub fn limit_assemblies(mut self, limit: Arc<AssemblyLimit>) -> Self {
+        self.assemblies = Some(limit);
+        self
+    }
+
+    /// The longest message the encoder writes, in chunks, once chunking has been negotiated.
+    pub fn max_chunked_len(&self) -> usize {
+        HEADER_LEN + self.max_key_len + 4 + self.max_value_len + MAX_EXTENSIONS_LEN
+    }
+
+    /// Require the first frame decoded to be an `Op::Hello` request.
+    pub fn expect_hello(mut self, expect_hello: bool) -> Self {
+        self.expect_hello = expect_hello;
+        self
+    }
+}
+
+/// The number of bytes `msg` occupies once encoded.
+pub fn encoded_len(msg: &Message) -> usize {
+    let key_len = msg.key().map(|k| k.len()).unwrap_or(0);
+    let payload_len = msg.payload().map(|p| p.data().len()).unwrap_or(0);
+    let type_id_len = if payload_len == 0 { 0 } else { 4 };
+
+    HEADER_LEN + key_len + type_id_len + payload_len + extensions_len(msg)
+}
+
+/// The number of bytes the extensions block of `msg` occupies once encoded, including its
+/// length prefix, or 0 when there are no extensions.
+fn extensions_len(msg: &Message) -> usize {
+    if msg.extensions().is_empty() {
+        return 0;
+    }
+    4 +
+        msg.extensions()
+            .values()
+            .map(|v| EXTENSION_HEADER_LEN + v.len())
+            .sum::<usize>()
+}
+
+impl Default for CacheCodec {
+    fn default() -> Self {
+        CacheCodec::new(DEFAULT_MAX_KEY_LEN, DEFAULT_MAX_PAYLOAD_LEN)
+    }
+}
 
 impl Encoder for CacheCodec {
     type Item = (RequestId, Message);
@@ -30,21 +427,103 @@ impl Encoder for CacheCodec {
 
     fn encode(&mut self, msg: (RequestId, Message), buf: &mut BytesMut) -> io::Result<()> {
         let (request_id, msg) = msg;
+        if is_unsolicited(request_id) && msg.code() == Code::Req {
+            return Err(reserved_id());
+        }
 
+        let payload_len = msg.payload().map_or(0, |p| p.data().len());
+        let chunk = self.chunked && payload_len > self.max_payload_len;
+        if encoded_len(&msg) <= self.max_encoded_len && !chunk {
+            self.encode_frame(request_id, &msg, false, buf);
+            return Ok(());
+        }
+        if self.chunked && payload_len > 0 && encoded_len(&msg) <= self.max_chunked_len() {
+            self.encode_chunks(request_id, msg, buf);
+            return Ok(());
+        }
+        Err(error::Error::new(error::ErrorKind::InvalidData, "frame exceeds maximum length").into())
+    }
+}
+
+impl CacheCodec {
+    /// Writes `msg` in chunks, see `CacheCodec`.
+    fn encode_chunks(&mut self, request_id: RequestId, msg: Message, buf: &mut BytesMut) {
+        let (op, code) = (msg.op(), msg.code());
+        let (key, payload, extensions) = match msg {
+            Message::Request(_, key, payload, extensions) => (key, payload, extensions),
+            Message::Response(_, _, payload, extensions) => (Bytes::new(), payload, extensions),
+        };
+        let payload = payload.expect("only payloads are too long for a frame");
+        let (type_id, data) = (payload.type_id(), payload.bytes());
+        let chunk_len = CHUNK_LEN.min(self.max_payload_len).max(1);
+        let (mut start, mut sequence) = (0, 0u32);
+        while start < data.len() {
+            let end = data.len().min(start + chunk_len);
+            let mut chunk = message::encode_u32(sequence);
+            let mut extensions = if sequence == 0 {
+                chunk.put_u64::<BigEndian>(data.len() as u64);
+                extensions.clone()
+            } else {
+                message::Extensions::new()
+            };
+            extensions.insert(message::EXT_CHUNK, chunk);
+            let key = if sequence == 0 { key.clone() } else { Bytes::new() };
+            let payload = Some(Payload::new(type_id, data.slice(start, end)));
+            let chunk = match code {
+                Code::Req => Message::Request(op, key, payload, extensions),
+                code => Message::Response(op, code, payload, extensions),
+            };
+            self.encode_frame(request_id, &chunk, end < data.len(), buf);
+            start = end;
+            sequence += 1;
+        }
+    }
+
+    /// Writes `msg` in a single frame, marked as continued in the next if `continued` is set.
+    fn encode_frame(
+        &mut self,
+        request_id: RequestId,
+        msg: &Message,
+        continued: bool,
+        buf: &mut BytesMut,
+    ) {
         let key = msg.key().unwrap_or_else(|| &[]);
         let payload = msg.payload().map(|p| p.data()).unwrap_or_else(|| &[]);
         let type_id = msg.type_id().unwrap_or(0 as u32);
-
-        let type_id_len = if payload.is_empty() { 0 } else { 4 };
+        let compressed = match self.compress_above {
+            Some(threshold) if self.compress && payload.len() > threshold => {
+                compress(payload).filter(|compressed| compressed.len() < payload.len())
+            }
+            _ => None,
+        };
+        let payload = compressed.as_ref().map(|c| &c[..]).unwrap_or(payload);
 
         let payload_len = payload.len();
+        let extensions_len = extensions_len(msg);
+
+        buf.reserve(encoded_len(msg) + CHECKSUM_LEN);
+        let start = buf.len();
 
-        let min_size = HEADER_LEN + key.len() + payload_len + type_id_len;
-        buf.reserve(min_size);
+        let op = if extensions_len > 0 {
+            msg.op() as u8 | EXTENSIONS_FLAG
+        } else {
+            msg.op() as u8
+        };
+
+        let mut code = msg.code() as u8;
+        if self.checksum {
+            code |= CHECKSUM_FLAG;
+        }
+        if compressed.is_some() {
+            code |= COMPRESSED_FLAG;
+        }
+        if continued {
+            code |= CONTINUED_FLAG;
+        }
 
         buf.put_u64::<BigEndian>(request_id as u64);
-        buf.put_u8(msg.code() as u8);
-        buf.put_u8(msg.op() as u8);
+        buf.put_u8(code);
+        buf.put_u8(op);
         buf.put_u64::<BigEndian>(payload_len as u64);
         buf.put_u32::<BigEndian>(key.len() as u32);
         buf.put_slice(key);
@@ -54,7 +533,70 @@ impl Encoder for CacheCodec {
             buf.put_slice(payload);
         }
 
-        Ok(())
+        if extensions_len > 0 {
+            buf.put_u32::<BigEndian>((extensions_len - 4) as u32);
+            for (ext_type, value) in msg.extensions() {
+                buf.put_u16::<BigEndian>(*ext_type);
+                buf.put_u32::<BigEndian>(value.len() as u32);
+                buf.put_slice(value);
+            }
+        }
+
+        if self.checksum {
+            let crc = crc32(&buf[start..]);
+            buf.put_u32::<BigEndian>(crc);
+        }
+    }
+
+    /// Decodes every complete frame in `buf`, up to `max` of them.
+    pub fn decode_all(
+        &mut self,
+        buf: &mut BytesMut,
+        max: usize,
+    ) -> io::Result<Vec<(RequestId, Message)>> {
+        let mut frames = vec![];
+        while frames.len() < max {
+            match self.decode(buf)? {
+                Some(frame) => frames.push(frame),
+                None => break,
+            }
+        }
+        Ok(frames)
+    }
+}
+
+/// Decodes frames in batches of every complete frame that has been read, up to `max_batch`, so
+/// that a pipelining client's requests can be dispatched together. Encodes like `CacheCodec`.
+pub struct BatchCodec {
+    codec: CacheCodec,
+    max_batch: usize,
+}
+
+impl BatchCodec {
+    pub fn new(codec: CacheCodec, max_batch: usize) -> Self {
+        BatchCodec {
+            codec: codec,
+            max_batch: max_batch.max(1),
+        }
+    }
+}
+
+impl Decoder for BatchCodec {
+    type Item = Vec<(RequestId, Message)>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        let frames = self.codec.decode_all(buf, self.max_batch)?;
+        Ok(if frames.is_empty() { None } else { Some(frames) })
+    }
+}
+
+impl Encoder for BatchCodec {
+    type Item = (RequestId, Message);
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: (RequestId, Message), buf: &mut BytesMut) -> io::Result<()> {
+        self.codec.encode(msg, buf)
     }
 }
 
@@ -63,19 +605,175 @@ impl Decoder for CacheCodec {
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(RequestId, Message)>, io::Error> {
+        while let Some((request_id, msg, continued)) = self.decode_frame(buf)? {
+            if let Some(msg) = self.reassemble(request_id, msg, continued)? {
+                return Ok(Some((request_id, msg)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl CacheCodec {
+    /// Adds the chunk `msg` to the message being reassembled, returning the message once it is
+    /// whole. Messages not sent in chunks are returned as they are.
+    fn reassemble(
+        &mut self,
+        request_id: RequestId,
+        msg: Message,
+        continued: bool,
+    ) -> io::Result<Option<Message>> {
+        let chunk = match msg.extension(message::EXT_CHUNK) {
+            Some(_) if !self.chunked => return Err(not_negotiated("chunks were not negotiated")),
+            Some(chunk) if chunk.len() >= 4 => chunk.to_vec(),
+            Some(_) => return Err(bad_chunk()),
+            None if continued || self.assembling.is_some() => return Err(bad_chunk()),
+            None => return Ok(Some(msg)),
+        };
+        let mut cursor = io::Cursor::new(chunk);
+        let sequence = cursor.get_u32::<BigEndian>();
+        let data = msg.payload().map(|p| p.bytes()).unwrap_or_default();
+        let mut assembly = match self.assembling.take() {
+            None if sequence == 0 && cursor.remaining() == 8 => {
+                let len = cursor.get_u64::<BigEndian>() as usize;
+                if len > self.max_value_len {
+                    let error = "value exceeds maximum length";
+                    return Err(error::Error::new(error::ErrorKind::InvalidData, error).into());
+                }
+                let slot = match self.assemblies {
+                    Some(ref limit) => match AssemblySlot::take(limit) {
+                        Some(slot) => Some(slot),
+                        None => {
+                            let error = "too many chunked messages being reassembled";
+                            return Err(error::Error::new(error::ErrorKind::Other, error).into());
+                        }
+                    },
+                    None => None,
+                };
+                Assembly {
+                    request_id: request_id,
+                    msg: msg.without_extension(message::EXT_CHUNK),
+                    data: BytesMut::new(),
+                    len: len,
+                    next: 0,
+                    _slot: slot,
+                }
+            }
+            Some(assembly) => assembly,
+            None => return Err(bad_chunk()),
+        };
+        if assembly.request_id != request_id || assembly.next != sequence ||
+            assembly.data.len() + data.len() > assembly.len
+        {
+            return Err(bad_chunk());
+        }
+        assembly.data.extend_from_slice(&data);
+        assembly.next += 1;
+        if continued {
+            self.assembling = Some(assembly);
+            return Ok(None);
+        }
+        if assembly.data.len() != assembly.len {
+            return Err(bad_chunk());
+        }
+        let type_id = assembly.msg.type_id().unwrap_or(0);
+        let payload = Some(Payload::new(type_id, assembly.data.freeze()));
+        Ok(Some(match assembly.msg {
+            Message::Request(op, key, _, extensions) => {
+                Message::Request(op, key, payload, extensions)
+            }
+            Message::Response(op, code, _, extensions) => {
+                Message::Response(op, code, payload, extensions)
+            }
+        }))
+    }
+
+    /// Decodes the next frame in `buf`, along with whether it is continued by the next one.
+    fn decode_frame(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> io::Result<Option<(RequestId, Message, bool)>> {
+        if !self.skip(buf)? {
+            return Ok(None);
+        }
+
         // Check that at least the header is complete
         if buf.len() < HEADER_LEN {
             return Ok(None);
         }
 
+        if self.expect_hello {
+            if buf[8] & !CHECKSUM_FLAG != 0 || buf[9] & !EXTENSIONS_FLAG != Op::Hello as u8 {
+                return Err(
+                    error::Error::new(error::ErrorKind::BadMessage, "expected a hello").into(),
+                );
+            }
+            self.expect_hello = false;
+        }
+
         // TODO: Only instantiate the cursor once?
         let payload_len = io::Cursor::new(&buf.as_ref()[10..18]).get_u64::<BigEndian>() as usize;
         let key_len = io::Cursor::new(&buf.as_ref()[18..22]).get_u32::<BigEndian>() as usize;
 
         // If we have a payload, then we have a type_id to include in the total message length.
         let type_id_len = if payload_len == 0 { 0 } else { 4 };
+        let mut msg_len = HEADER_LEN + payload_len + key_len + type_id_len;
+        let has_extensions = buf[9] & EXTENSIONS_FLAG != 0;
+
+        // Refuse oversized frames up front, rather than waiting for the body to arrive.
+        if key_len > self.max_key_len {
+            let error = "key exceeds maximum length";
+            let error = error::Error::new(error::ErrorKind::InvalidData, error);
+            return self.refuse(buf, Code::KeyTooLarge, (msg_len, has_extensions), error.into());
+        }
+        if payload_len > self.max_payload_len {
+            let error = "payload exceeds maximum length";
+            let error = error::Error::new(error::ErrorKind::InvalidData, error);
+            return self.refuse(buf, Code::ValueTooLarge, (msg_len, has_extensions), error.into());
+        }
+        if msg_len > self.max_frame_len {
+            let error = frame_too_long();
+            return self.refuse(buf, Code::ValueTooLarge, (msg_len, has_extensions), error);
+        }
 
-        let msg_len = HEADER_LEN + payload_len + key_len + type_id_len;
+        // If the extensions flag is set, the block's length prefix follows the payload.
+        if has_extensions {
+            if buf.len() < msg_len + 4 {
+                return Ok(None);
+            }
+            let extensions_len = io::Cursor::new(&buf.as_ref()[msg_len..msg_len + 4])
+                .get_u32::<BigEndian>() as usize;
+            if extensions_len > MAX_EXTENSIONS_LEN {
+                return Err(
+                    error::Error::new(
+                        error::ErrorKind::InvalidData,
+                        "extensions exceed maximum length",
+                    ).into(),
+                );
+            }
+            msg_len += 4 + extensions_len;
+        }
+
+        let has_checksum = buf[8] & CHECKSUM_FLAG != 0;
+        let is_compressed = buf[8] & COMPRESSED_FLAG != 0;
+        let continued = buf[8] & CONTINUED_FLAG != 0;
+        let op = buf[9] & !EXTENSIONS_FLAG;
+        if is_compressed && !self.compress {
+            return Err(not_negotiated("compression was not negotiated"));
+        }
+        if has_checksum && !self.allows(FEATURE_CHECKSUM) {
+            return Err(not_negotiated("checksums were not negotiated"));
+        }
+        if (op == Op::Cas as u8 || op == Op::CasDel as u8) && !self.allows(FEATURE_CAS) {
+            return Err(not_negotiated("compare-and-swap was not negotiated"));
+        }
+        let body_len = msg_len;
+        if has_checksum {
+            msg_len += CHECKSUM_LEN;
+        }
+        if msg_len > self.max_frame_len {
+            return self.refuse(buf, Code::ValueTooLarge, (body_len, false), frame_too_long());
+        }
 
         // Buffer not ready.
         if (buf.len()) < msg_len {
@@ -83,15 +781,33 @@ impl Decoder for CacheCodec {
         }
 
         // Split off the complete message.
-        let msg = buf.split_to(msg_len);
+        let msg = buf.split_to(msg_len).freeze();
 
         // Instantiate the cursor.
         let mut cursor = io::Cursor::new(msg);
 
         // Read the first 3 fields.
         let request_id = cursor.get_u64::<BigEndian>();
-        let code = cursor.get_u8();
-        let op = cursor.get_u8();
+        let code = cursor.get_u8() & !(CHECKSUM_FLAG | COMPRESSED_FLAG | CONTINUED_FLAG);
+        let op = cursor.get_u8() & !EXTENSIONS_FLAG;
+        if is_unsolicited(request_id) && code == 0 {
+            return Err(reserved_id());
+        }
+
+        if has_checksum {
+            // Answer for the peer from here on in kind.
+            self.checksum = true;
+            let frame = cursor.get_ref();
+            let body_len = frame.len() - CHECKSUM_LEN;
+            let expected = io::Cursor::new(&frame[body_len..]).get_u32::<BigEndian>();
+            if crc32(&frame[..body_len]) != expected {
+                let op = Op::try_from(op).unwrap_or(Op::Get);
+                let error = "frame checksum mismatch".to_owned().into_bytes();
+                let resp = message::response(op, Code::Error, Some(message::payload(0, error)))
+                    .with_extension(EXT_REFUSED, vec![]);
+                return Ok(Some((request_id as RequestId, resp, false)));
+            }
+        }
 
         // Skip the payload_len and key_len as they've been read already.
         cursor.advance(12);
@@ -104,26 +820,178 @@ impl Decoder for CacheCodec {
         // Read the payload.
         let payload = if payload_len > 0 {
             let type_id = cursor.get_u32::<BigEndian>();
-            Some(message::payload(type_id, cursor.collect()))
+            let start = cursor.position() as usize;
+            cursor.advance(payload_len);
+            let data = &cursor.get_ref()[start..start + payload_len];
+            let data = if is_compressed {
+                decompress(data, self.max_payload_len)?.into()
+            } else if payload_len >= SHARE_PAYLOADS_FROM {
+                cursor.get_ref().slice(start, start + payload_len)
+            } else {
+                Bytes::from(data)
+            };
+            Some(Payload::new(type_id, data))
         } else {
             None
         };
 
-        let msg = if code == 0 {
-            message::request(Op::try_from(op)?, key.to_vec(), payload)
+        let mut msg = if code == 0 {
+            message::request(Op::try_from(op)?, key, payload)
         } else {
             message::response(Op::try_from(op)?, Code::try_from(code)?, payload)
         };
 
-        Ok(Some((request_id as RequestId, msg)))
+        // Read the extensions, checking that each TLV fits within the declared block.
+        if has_extensions {
+            let mut remaining = cursor.get_u32::<BigEndian>() as usize;
+            while remaining > 0 {
+                if remaining < EXTENSION_HEADER_LEN {
+                    return Err(
+                        error::Error::new(error::ErrorKind::InvalidData, "truncated extension")
+                            .into(),
+                    );
+                }
+                let ext_type = cursor.get_u16::<BigEndian>();
+                let len = cursor.get_u32::<BigEndian>() as usize;
+                if len > remaining - EXTENSION_HEADER_LEN {
+                    return Err(
+                        error::Error::new(error::ErrorKind::InvalidData, "truncated extension")
+                            .into(),
+                    );
+                }
+                let mut value = Vec::with_capacity(len);
+                value.resize(len, 0);
+                cursor.copy_to_slice(&mut value);
+                remaining -= EXTENSION_HEADER_LEN + len;
+
+                if ext_type == EXT_REFUSED {
+                    continue;
+                }
+                if !self.strict || message::KNOWN_EXTENSIONS.contains(&ext_type) {
+                    msg = msg.with_extension(ext_type, value);
+                }
+            }
+        }
+
+        // Either end of the handshake settles the features used from here on.
+        if msg.op() == Op::Hello {
+            if let Some(Ok((_, features))) = msg.payload().map(decode_hello) {
+                self.compress = features & FEATURE_COMPRESSION != 0;
+                self.chunked = features & FEATURE_CHUNKED != 0;
+                self.features = Some(features & SUPPORTED_FEATURES);
+            }
+        }
+
+        Ok(Some((request_id as RequestId, msg, continued)))
+    }
+
+    /// Answers the request whose header starts `buf` with a `code` response right away, and
+    /// starts discarding it, `len` bytes long before its extensions block and its checksum, if
+    /// any. Fails with `error` if it isn't a request that can be answered so.
+    fn refuse(
+        &mut self,
+        buf: &mut BytesMut,
+        code: Code,
+        (len, extensions): (usize, bool),
+        error: io::Error,
+    ) -> io::Result<Option<(RequestId, Message, bool)>> {
+        let request_id = io::Cursor::new(&buf.as_ref()[..8]).get_u64::<BigEndian>();
+        let op = Op::try_from(buf[9] & !EXTENSIONS_FLAG);
+        let request = buf[8] & !(CHECKSUM_FLAG | COMPRESSED_FLAG) == 0;
+        match op {
+            Ok(op) if request && !is_unsolicited(request_id) && self.assembling.is_none() => {
+                self.skipping = Some(Skip {
+                    left: len,
+                    extensions: extensions,
+                    checksum: buf[8] & CHECKSUM_FLAG != 0,
+                });
+                self.skip(buf)?;
+                let response = message::response(op, code, None);
+                Ok(Some((request_id, response.with_extension(EXT_REFUSED, vec![]), false)))
+            }
+            _ => Err(error),
+        }
+    }
+
+    /// Discards what has arrived of the frame being refused, if any, returning whether it has
+    /// all been.
+    fn skip(&mut self, buf: &mut BytesMut) -> io::Result<bool> {
+        if let Some(ref mut skip) = self.skipping {
+            loop {
+                let skipped = cmp::min(skip.left, buf.len());
+                buf.advance(skipped);
+                skip.left -= skipped;
+                if skip.left > 0 {
+                    return Ok(false);
+                }
+                if skip.extensions {
+                    if buf.len() < 4 {
+                        return Ok(false);
+                    }
+                    let len = io::Cursor::new(&buf.split_to(4)[..]).get_u32::<BigEndian>();
+                    if len as usize > MAX_EXTENSIONS_LEN {
+                        let error = "extensions exceed maximum length";
+                        return Err(error::Error::new(error::ErrorKind::InvalidData, error).into());
+                    }
+                    skip.left = len as usize;
+                    skip.extensions = false;
+                } else if skip.checksum {
+                    skip.left = CHECKSUM_LEN;
+                    skip.checksum = false;
+                } else {
+                    break;
+                }
+            }
+        }
+        self.skipping = None;
+        Ok(true)
+    }
+
+    /// Whether the peer may use `feature`, one of those that came before the handshake: unless
+    /// a hello settled the features without it.
+    fn allows(&self, feature: u32) -> bool {
+        self.features.map_or(true, |features| features & feature != 0)
     }
 }
 
+fn not_negotiated(description: &str) -> io::Error {
+    error::Error::new(error::ErrorKind::BadMessage, description).into()
+}
+
+fn bad_chunk() -> io::Error {
+    error::Error::new(error::ErrorKind::BadMessage, "chunk out of sequence").into()
+}
+
+fn frame_too_long() -> io::Error {
+    error::Error::new(error::ErrorKind::InvalidData, "frame exceeds maximum length").into()
+}
+
+fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    snap::raw::Encoder::new().compress_vec(data).ok()
+}
+
+/// Decompresses `data`, refusing it if it would decompress to more than `max_len` bytes, before
+/// allocating for it.
+fn decompress(data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let invalid = || error::Error::new(error::ErrorKind::InvalidData, "invalid compressed payload");
+    let len = snap::raw::decompress_len(data).map_err(|_| invalid())?;
+    if len > max_len {
+        return Err(
+            error::Error::new(error::ErrorKind::InvalidData, "payload exceeds maximum length")
+                .into(),
+        );
+    }
+    snap::raw::Decoder::new().decompress_vec(data).map_err(|_| invalid().into())
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use cache;
     use message::Op;
+    use slowlog;
+    use stats;
     use test::Bencher;
 
     #[test]
@@ -146,7 +1014,7 @@ mod tests {
         );
         let req_id = 123 as RequestId;
         let mut buf = BytesMut::new();
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
 
         codec.encode((req_id, msg.clone()), &mut buf).unwrap();
         let (decoded_req, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
@@ -164,7 +1032,7 @@ mod tests {
         );
         let req_id = 123 as RequestId;
         let mut buf = BytesMut::new();
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
 
         codec.encode((req_id, msg.clone()), &mut buf).unwrap();
         let (decoded_req, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
@@ -178,7 +1046,7 @@ mod tests {
         let msg = message::request(Op::Get, "foo".into(), None);
         let req_id = 123 as RequestId;
         let mut buf = BytesMut::new();
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
 
         codec.encode((req_id, msg.clone()), &mut buf).unwrap();
         let (decoded_req, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
@@ -189,12 +1057,12 @@ mod tests {
 
     #[test]
     fn test_response_no_payload() {
-        let msg = Message::Response(Op::Set, Code::Ok, None);
+        let msg = message::response(Op::Set, Code::Ok, None);
 
 
         let req_id = 123 as RequestId;
         let mut buf = BytesMut::new();
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
 
         codec.encode((req_id, msg.clone()), &mut buf).unwrap();
         let (decoded_req, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
@@ -203,6 +1071,32 @@ mod tests {
         assert_eq!(decoded_message, msg);
     }
 
+    #[test]
+    fn test_flags_round_trip() {
+        // Payloads without data lose their `type_id` on the wire, so flags come with a byte.
+        let mut codec = CacheCodec::default();
+        let mut buf = BytesMut::new();
+        let get = cache::get_stale_request("foo".into());
+        codec.encode((1, get.clone()), &mut buf).unwrap();
+        let (_, decoded) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded, get);
+        assert_eq!(decoded.type_id(), Some(cache::GET_STALE));
+
+        let marker = cache::not_found_request("foo".into(), 60);
+        codec.encode((2, marker.clone()), &mut buf).unwrap();
+        let (_, decoded) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded, marker);
+        assert_eq!(decoded.type_id(), Some(cache::NOT_FOUND_TYPE_ID));
+
+        codec.encode((3, slowlog::request(true)), &mut buf).unwrap();
+        let (_, decoded) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.type_id(), Some(slowlog::SLOW_LOG_RESET));
+
+        codec.encode((4, stats::json_request()), &mut buf).unwrap();
+        let (_, decoded) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.type_id(), Some(stats::STATS_JSON_TYPE_ID));
+    }
+
     #[bench]
     #[allow(unused_must_use)]
     fn bench_encoding(b: &mut Bencher) {
@@ -211,7 +1105,7 @@ mod tests {
             Code::Ok,
             Some(message::payload(3, "123124125".into())),
         );
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
         let req_id = 123 as RequestId;
 
         b.iter(|| {
@@ -227,11 +1121,628 @@ mod tests {
             Code::Ok,
             Some(message::payload(3, "123124125".into())),
         );
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
         let req_id = 123 as RequestId;
         let mut buf = BytesMut::new();
         codec.encode((req_id, msg.clone()), &mut buf).unwrap();
 
         b.iter(|| codec.decode(&mut buf.clone()));
     }
+
+    fn sized_request(key_len: usize, payload_len: usize) -> Message {
+        message::request(
+            Op::Set,
+            vec![b'k'; key_len],
+            Some(message::payload(1, vec![b'v'; payload_len])),
+        )
+    }
+
+    #[test]
+    fn test_decode_at_max_sizes() {
+        let mut codec = CacheCodec::new(16, 64);
+        let msg = sized_request(16, 64);
+        let mut buf = BytesMut::new();
+
+        codec.encode((1, msg.clone()), &mut buf).unwrap();
+        let (_, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(decoded_message, msg);
+    }
+
+    #[test]
+    fn test_decode_key_over_max() {
+        let mut codec = CacheCodec::new(16, 64);
+        let msg = sized_request(17, 64);
+        let mut buf = BytesMut::new();
+
+        codec.encode((1, msg), &mut buf).unwrap();
+
+        assert_eq!(refusal(&mut codec, &mut buf), (1, Code::KeyTooLarge));
+    }
+
+    #[test]
+    fn test_decode_payload_over_max() {
+        let mut codec = CacheCodec::new(16, 64);
+        let msg = sized_request(16, 65);
+        let mut buf = BytesMut::new();
+
+        codec.encode((1, msg), &mut buf).unwrap();
+
+        assert_eq!(refusal(&mut codec, &mut buf), (1, Code::ValueTooLarge));
+    }
+
+    /// Decodes the response `codec` refused the next request in `buf` with, and its id.
+    fn refusal(codec: &mut CacheCodec, buf: &mut BytesMut) -> (RequestId, Code) {
+        let (id, msg) = codec.decode(buf).unwrap().unwrap();
+        (id, refused(&msg).unwrap().code())
+    }
+
+    #[test]
+    fn test_decode_over_max_skipped() {
+        // Discarded as it arrives, even a byte at a time, with the frames after it decoded.
+        let mut codec = CacheCodec::new(16, 64);
+        let mut checksummed = CacheCodec::new(16, 64).checksum(true);
+        checksummed.features = Some(FEATURE_CHECKSUM);
+        let mut buf = BytesMut::new();
+        let too_large = sized_request(17, 64).with_extension(message::EXT_TTL, vec![0; 8]);
+        let get = message::request(Op::Get, "foo".into(), None);
+        codec.encode((1, too_large.clone()), &mut buf).unwrap();
+        codec.encode((2, get.clone()), &mut buf).unwrap();
+        checksummed.encode((3, too_large), &mut buf).unwrap();
+        checksummed.encode((4, get.clone()), &mut buf).unwrap();
+        codec.features = Some(FEATURE_CHECKSUM);
+
+        let mut decoded = vec![];
+        let mut input = BytesMut::new();
+        for byte in buf.iter() {
+            input.extend_from_slice(&[*byte]);
+            while let Some(frame) = codec.decode(&mut input).unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded.len(), 4);
+        for &(i, id) in &[(0, 1), (2, 3)] {
+            assert_eq!(decoded[i].0, id);
+            let resp = refused(&decoded[i].1).unwrap();
+            assert_eq!((resp.op(), resp.code()), (Op::Set, Code::KeyTooLarge));
+        }
+        assert_eq!(decoded[1], (2, get.clone()));
+        assert_eq!(decoded[3], (4, get));
+
+        // Anything but a request can't be answered, so it still ends the connection.
+        let mut buf = BytesMut::new();
+        let resp = message::response(Op::Get, Code::Ok, Some(message::payload(1, vec![0; 65])));
+        CacheCodec::default().encode((1, resp), &mut buf).unwrap();
+        assert!(CacheCodec::new(16, 64).decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_decode_over_max_rejected_from_header() {
+        // The guard must engage as soon as the header is in, without waiting for the body.
+        let mut codec = CacheCodec::new(16, 64);
+        let msg = sized_request(16, 65);
+        let mut buf = BytesMut::new();
+
+        codec.encode((1, msg), &mut buf).unwrap();
+        buf.truncate(HEADER_LEN);
+
+        assert_eq!(refusal(&mut codec, &mut buf), (1, Code::ValueTooLarge));
+    }
+
+    #[bench]
+    fn bench_decoding_max_sizes(b: &mut Bencher) {
+        let mut codec = CacheCodec::default();
+        let msg = sized_request(DEFAULT_MAX_KEY_LEN, DEFAULT_MAX_PAYLOAD_LEN);
+        let mut buf = BytesMut::new();
+        codec.encode((123, msg), &mut buf).unwrap();
+
+        b.iter(|| codec.decode(&mut buf.clone()));
+    }
+
+    #[bench]
+    fn bench_decoding_large_payload(b: &mut Bencher) {
+        let mut codec = CacheCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode((123, sized_request(16, 1024 * 1024)), &mut buf).unwrap();
+
+        // Only cloning the buffer copies the payload.
+        b.iter(|| codec.decode(&mut buf.clone()));
+    }
+
+    #[test]
+    fn test_shared_payloads() {
+        let mut codec = CacheCodec::default();
+        for &len in &[SHARE_PAYLOADS_FROM - 1, SHARE_PAYLOADS_FROM] {
+            let mut buf = BytesMut::new();
+            codec.encode((123, sized_request(3, len)), &mut buf).unwrap();
+            let data_at = buf[HEADER_LEN + 3 + 4..].as_ptr();
+            let (_, msg) = codec.decode(&mut buf).unwrap().unwrap();
+            let shared = msg.payload().unwrap().data().as_ptr() == data_at;
+            assert_eq!(shared, len >= SHARE_PAYLOADS_FROM);
+            assert_eq!(msg, sized_request(3, len));
+        }
+    }
+
+    #[test]
+    fn test_extensions() {
+        let msg = message::request(
+            Op::Get,
+            "foo".into(),
+            Some(message::payload(3, "123124125".into())),
+        ).with_extension(7, "seven".into())
+            .with_extension(300, vec![]);
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default();
+
+        codec.encode((123, msg.clone()), &mut buf).unwrap();
+        assert_eq!(buf.len(), encoded_len(&msg));
+        let (_, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(decoded_message, msg);
+        assert_eq!(decoded_message.extension(7), Some("seven".as_bytes()));
+        assert_eq!(decoded_message.extension(300), Some(&[][..]));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_no_extensions() {
+        let msg = message::response(Op::Get, Code::Miss, None);
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default();
+
+        codec.encode((123, msg.clone()), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN);
+        assert_eq!(buf[9] & EXTENSIONS_FLAG, 0);
+        let (_, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(decoded_message, msg);
+        assert!(decoded_message.extensions().is_empty());
+    }
+
+    #[test]
+    fn test_strict_drops_unknown_extensions() {
+        let msg = message::request(Op::Get, "foo".into(), None).with_extension(99, "foo".into());
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default().strict(true);
+
+        codec.encode((123, msg), &mut buf).unwrap();
+        let (_, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
+
+        assert!(decoded_message.extensions().is_empty());
+    }
+
+    #[test]
+    fn test_partial_extensions() {
+        let msg = message::request(Op::Get, "foo".into(), None).with_extension(7, "seven".into());
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default();
+
+        codec.encode((123, msg), &mut buf).unwrap();
+        let mut partial = buf.clone();
+        partial.truncate(buf.len() - 1);
+
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_encode_over_max() {
+        let msg = message::response(Op::Get, Code::Hit, Some(message::payload(1, vec![0; 65])));
+        let mut codec = CacheCodec::default().limit_encoded_len(HEADER_LEN + 4 + 64);
+        let mut buf = BytesMut::new();
+
+        assert!(codec.encode((1, msg), &mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_encode_at_max() {
+        let msg = message::response(Op::Get, Code::Hit, Some(message::payload(1, vec![0; 64])));
+        let mut codec = CacheCodec::default().limit_encoded_len(HEADER_LEN + 4 + 64);
+        let mut buf = BytesMut::new();
+
+        codec.encode((1, msg.clone()), &mut buf).unwrap();
+        let (_, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(decoded_message, msg);
+    }
+
+    #[test]
+    fn test_batch_decode() {
+        let mut buf = BytesMut::new();
+        let mut codec = BatchCodec::new(CacheCodec::default(), 2);
+        for req_id in 0..3 {
+            let msg = message::request(Op::Get, "foo".into(), None);
+            codec.encode((req_id, msg), &mut buf).unwrap();
+        }
+        let len = buf.len();
+        let mut partial = buf.split_to(len - 1);
+
+        let ids = |batch: Vec<(RequestId, Message)>| -> Vec<RequestId> {
+            batch.into_iter().map(|(req_id, _)| req_id).collect()
+        };
+        assert_eq!(ids(codec.decode(&mut partial).unwrap().unwrap()), vec![0, 1]);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.extend_from_slice(&buf);
+        assert_eq!(ids(codec.decode(&mut partial).unwrap().unwrap()), vec![2]);
+    }
+
+    /// Feeds the first `len` bytes of `frame` to a decoder, returning whether it decoded a frame.
+    fn decodes_from(frame: &BytesMut, len: usize) -> bool {
+        let mut buf = BytesMut::from(&frame[..len]);
+        let decoded = CacheCodec::default().decode(&mut buf).unwrap();
+        if decoded.is_none() {
+            // Nothing may be consumed until the whole frame is there.
+            assert_eq!(buf.len(), len);
+        }
+        decoded.is_some()
+    }
+
+    #[test]
+    fn test_decode_boundaries() {
+        let msg = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())));
+        let mut frame = BytesMut::new();
+        CacheCodec::default().encode((1, msg), &mut frame).unwrap();
+        let msg_len = frame.len();
+
+        assert!(!decodes_from(&frame, HEADER_LEN - 1));
+        assert!(!decodes_from(&frame, HEADER_LEN));
+        assert!(!decodes_from(&frame, msg_len - 1));
+        assert!(decodes_from(&frame, msg_len));
+    }
+
+    #[test]
+    fn test_decode_boundaries_header_only() {
+        let msg = message::response(Op::Set, Code::Ok, None);
+        let mut frame = BytesMut::new();
+        CacheCodec::default().encode((1, msg), &mut frame).unwrap();
+        assert_eq!(frame.len(), HEADER_LEN);
+
+        assert!(!decodes_from(&frame, HEADER_LEN - 1));
+        assert!(decodes_from(&frame, HEADER_LEN));
+    }
+
+    #[test]
+    fn test_decode_boundaries_extensions() {
+        let msg = message::request(Op::Get, "foo".into(), None)
+            .with_extension(message::EXT_IDEMPOTENCY_TOKEN, "token".into());
+        let mut frame = BytesMut::new();
+        CacheCodec::default().encode((1, msg), &mut frame).unwrap();
+        let msg_len = frame.len();
+        let body_len = HEADER_LEN + 3;
+
+        assert!(!decodes_from(&frame, body_len));
+        assert!(!decodes_from(&frame, body_len + 4));
+        assert!(!decodes_from(&frame, msg_len - 1));
+        assert!(decodes_from(&frame, msg_len));
+    }
+
+    #[test]
+    fn test_unsolicited_ids() {
+        let mut codec = CacheCodec::default();
+        let id = UNSOLICITED_FLAG | 1;
+
+        let push = message::response(Op::Del, Code::Ok, None);
+        let mut buf = BytesMut::new();
+        codec.encode((id, push.clone()), &mut buf).unwrap();
+        let (decoded_id, decoded) = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(is_unsolicited(decoded_id));
+        assert_eq!(decoded, push);
+
+        let req = message::request(Op::Get, "foo".into(), None);
+        assert!(codec.encode((id, req.clone()), &mut buf).is_err());
+
+        // A request with a reserved id is refused on decode too.
+        codec.encode((1, req), &mut buf).unwrap();
+        buf[0] |= 0x80;
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_cas_round_trip() {
+        let mut codec = CacheCodec::default();
+        let mut buf = BytesMut::new();
+        let req = message::request(Op::Cas, "foo".into(), Some(message::payload(1, "bar".into())))
+            .with_extension(message::EXT_VERSION, message::encode_u64(7));
+        codec.encode((1, req.clone()), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (1, req));
+
+        let resp = message::response(Op::Cas, Code::CasMismatch, None)
+            .with_extension(message::EXT_VERSION, message::encode_u64(8));
+        codec.encode((1, resp.clone()), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (1, resp));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_checksum_round_trip() {
+        let msg = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())))
+            .with_extension(message::EXT_TTL, message::encode_u64(10));
+        let mut codec = CacheCodec::default().checksum(true);
+        let mut buf = BytesMut::new();
+
+        codec.encode((1, msg.clone()), &mut buf).unwrap();
+        assert_eq!(buf.len(), encoded_len(&msg) + CHECKSUM_LEN);
+        assert!(!decodes_from(&buf, buf.len() - 1));
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (1, msg));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let msg = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())));
+        let mut buf = BytesMut::new();
+        CacheCodec::default().checksum(true).encode((7, msg), &mut buf).unwrap();
+        let last_payload_byte = buf.len() - CHECKSUM_LEN - 1;
+        buf[last_payload_byte] ^= 1;
+
+        let (req_id, resp) = CacheCodec::default().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(req_id, 7);
+        assert_eq!(resp.op(), Op::Set);
+        assert_eq!(resp.code(), Code::Error);
+        assert_eq!(refused(&resp).unwrap().extensions().len(), 0);
+        assert!(buf.is_empty());
+
+        // Frames sent with the error code, or the marker, aren't taken for failed ones.
+        let forged = message::response(Op::Set, Code::Error, None)
+            .with_extension(EXT_REFUSED, vec![]);
+        CacheCodec::default().checksum(true).encode((8, forged), &mut buf).unwrap();
+        let (_, resp) = CacheCodec::default().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(resp.code(), Code::Error);
+        assert_eq!(refused(&resp), None);
+    }
+
+    #[test]
+    fn test_checksum_follows_peer() {
+        let mut codec = CacheCodec::default();
+        let mut buf = BytesMut::new();
+        let resp = message::response(Op::Get, Code::Miss, None);
+
+        // An unchecksummed peer gets unchecksummed frames.
+        let req = message::request(Op::Get, "foo".into(), None);
+        CacheCodec::default().encode((1, req.clone()), &mut buf).unwrap();
+        codec.decode(&mut buf).unwrap().unwrap();
+        codec.encode((1, resp.clone()), &mut buf).unwrap();
+        assert_eq!(buf.split_off(0).len(), HEADER_LEN);
+
+        // Once the peer checksums its frames, so does the codec.
+        CacheCodec::default().checksum(true).encode((2, req), &mut buf).unwrap();
+        codec.decode(&mut buf).unwrap().unwrap();
+        codec.encode((2, resp), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + CHECKSUM_LEN);
+        assert_ne!(buf[8] & CHECKSUM_FLAG, 0);
+    }
+
+    #[test]
+    fn test_expect_hello() {
+        let mut codec = CacheCodec::default().expect_hello(true);
+        let mut buf = BytesMut::from(&b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), io::ErrorKind::Other);
+
+        let mut codec = CacheCodec::default().expect_hello(true);
+        let mut buf = BytesMut::new();
+        let hello = hello_payload(PROTOCOL_VERSION, SUPPORTED_FEATURES);
+        let req = message::request(Op::Hello, vec![], Some(hello.clone()));
+        CacheCodec::default().encode((1, req), &mut buf).unwrap();
+        let req = message::request(Op::Get, "foo".into(), None);
+        CacheCodec::default().encode((2, req), &mut buf).unwrap();
+        let (_, decoded) = codec.decode(&mut buf).unwrap().unwrap();
+        let offered = decode_hello(decoded.payload().unwrap()).unwrap();
+        assert_eq!(offered, (PROTOCOL_VERSION, SUPPORTED_FEATURES));
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().1.op(), Op::Get);
+
+        // A bare version offers no features.
+        let bare = message::payload(0, message::encode_u32(2));
+        assert_eq!(decode_hello(&bare).unwrap(), (2, 0));
+    }
+
+    #[test]
+    fn test_chunked() {
+        let mut client = CacheCodec::new(250, 1000);
+        let mut server = CacheCodec::new(250, 1000).limit_value_len(3500);
+        let mut buf = BytesMut::new();
+        let value: Vec<u8> = (0..3500).map(|i| i as u8).collect();
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, value)))
+            .with_extension(message::EXT_TTL, message::encode_u64(60));
+
+        // Before the handshake it goes in a frame too long for the peer, and is refused.
+        client.encode((1, set.clone()), &mut buf).unwrap();
+        assert_eq!(refusal(&mut CacheCodec::new(250, 1000), &mut buf), (1, Code::ValueTooLarge));
+        buf.clear();
+
+        // Nor are chunks accepted before it.
+        let mut eager = CacheCodec::new(250, 1000);
+        eager.chunked = true;
+        eager.encode((1, set.clone()), &mut buf).unwrap();
+        assert!(CacheCodec::new(250, 1000).limit_value_len(3500).decode(&mut buf).is_err());
+        buf.clear();
+
+        let offer = hello_payload(PROTOCOL_VERSION, FEATURE_CHUNKED);
+        client.encode((2, message::request(Op::Hello, vec![], Some(offer.clone()))), &mut buf)
+            .unwrap();
+        server.decode(&mut buf).unwrap().unwrap();
+        server.encode((2, message::response(Op::Hello, Code::Ok, Some(offer))), &mut buf)
+            .unwrap();
+        client.decode(&mut buf).unwrap().unwrap();
+
+        // Sent in four chunks, reassembled as they arrive, a byte at a time, into a payload that
+        // only grows by the chunks received.
+        client.encode((3, set.clone()), &mut buf).unwrap();
+        assert_ne!(buf[8] & CONTINUED_FLAG, 0);
+        let mut received = BytesMut::new();
+        let mut decoded = None;
+        for &byte in buf.iter() {
+            assert!(decoded.is_none());
+            received.extend_from_slice(&[byte]);
+            decoded = server.decode(&mut received).unwrap();
+            if let Some(ref assembly) = server.assembling {
+                assert!(assembly.next > 1 || assembly.data.capacity() < 3500);
+            }
+        }
+        assert_eq!(decoded, Some((3, set.clone())));
+        assert!(received.is_empty());
+        buf.clear();
+
+        // Only so long a value is accepted.
+        let long = message::response(Op::Get, Code::Hit, Some(message::payload(1, vec![1; 3501])));
+        client.encode((4, long), &mut buf).unwrap();
+        assert!(server.decode(&mut buf).is_err());
+        buf.clear();
+
+        // Chunks out of sequence end the connection.
+        let mut server = server.limit_value_len(4000);
+        let get = message::response(Op::Get, Code::Hit, Some(message::payload(1, vec![1; 2500])));
+        client.encode((5, get), &mut buf).unwrap();
+        let mut received = BytesMut::new();
+        while server.assembling.is_none() {
+            received.extend_from_slice(&buf.split_to(1));
+            assert_eq!(server.decode(&mut received).unwrap(), None);
+        }
+        client.encode((6, message::request(Op::Ping, vec![], None)), &mut received).unwrap();
+        assert!(server.decode(&mut received).is_err());
+        buf.clear();
+
+        // Codecs sharing a limit only reassemble so many messages at once.
+        let limit = AssemblyLimit::new(1);
+        let mut servers: Vec<_> = (0..2)
+            .map(|_| {
+                let mut server = CacheCodec::new(250, 1000)
+                    .limit_value_len(3500)
+                    .limit_assemblies(limit.clone());
+                server.chunked = true;
+                server
+            })
+            .collect();
+        client.encode((7, set.clone()), &mut buf).unwrap();
+        let (mut first_chunk, mut received) = (0, BytesMut::new());
+        while servers[0].assembling.is_none() {
+            received.extend_from_slice(&buf[first_chunk..first_chunk + 1]);
+            first_chunk += 1;
+            assert_eq!(servers[0].decode(&mut received).unwrap(), None);
+        }
+        assert_eq!(limit.assembling(), 1);
+        let mut second = BytesMut::from(&buf[..first_chunk]);
+        assert!(servers[1].decode(&mut second).is_err());
+        assert_eq!(limit.assembling(), 1);
+        let mut rest = BytesMut::from(&buf[first_chunk..]);
+        assert_eq!(servers[0].decode(&mut rest).unwrap(), Some((7, set)));
+        assert_eq!(limit.assembling(), 0);
+    }
+
+    #[test]
+    fn test_compression() {
+        let mut client = CacheCodec::default().compress_above(Some(64));
+        let mut server = CacheCodec::default().compress_above(Some(64));
+        let mut buf = BytesMut::new();
+        let value = vec![b'a'; 1000];
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, value)));
+
+        // Nothing is compressed before the handshake.
+        client.encode((1, set.clone()), &mut buf).unwrap();
+        assert_eq!(buf[8] & COMPRESSED_FLAG, 0);
+        assert_eq!(server.decode(&mut buf).unwrap().unwrap().1, set);
+
+        let offer = hello_payload(PROTOCOL_VERSION, FEATURE_COMPRESSION);
+        client.encode((2, message::request(Op::Hello, vec![], Some(offer.clone()))), &mut buf)
+            .unwrap();
+        server.decode(&mut buf).unwrap().unwrap();
+        server.encode((2, message::response(Op::Hello, Code::Ok, Some(offer))), &mut buf)
+            .unwrap();
+        client.decode(&mut buf).unwrap().unwrap();
+
+        // Both ends now compress large payloads, and only those.
+        client.encode((3, set.clone()), &mut buf).unwrap();
+        assert_ne!(buf[8] & COMPRESSED_FLAG, 0);
+        assert!(buf.len() < encoded_len(&set));
+        assert_eq!(server.decode(&mut buf).unwrap().unwrap().1, set);
+
+        let small = message::response(Op::Get, Code::Hit, Some(message::payload(1, vec![1; 10])));
+        server.encode((4, small.clone()), &mut buf).unwrap();
+        assert_eq!(buf[8] & COMPRESSED_FLAG, 0);
+        assert_eq!(client.decode(&mut buf).unwrap().unwrap().1, small);
+    }
+
+    #[test]
+    fn test_unnegotiated_features() {
+        let mut buf = BytesMut::new();
+        let cas = message::request(Op::Cas, "foo".into(), Some(message::payload(1, "bar".into())))
+            .with_extension(message::EXT_VERSION, message::encode_u64(1));
+        let get = message::request(Op::Get, "foo".into(), None);
+
+        // Without a handshake, the features that came before it are accepted.
+        let mut server = CacheCodec::default();
+        CacheCodec::default().checksum(true).encode((1, cas.clone()), &mut buf).unwrap();
+        assert_eq!(server.decode(&mut buf).unwrap(), Some((1, cas.clone())));
+
+        // Unlike compression.
+        let mut compressed = BytesMut::new();
+        CacheCodec::default().encode((2, get.clone()), &mut compressed).unwrap();
+        compressed[8] |= COMPRESSED_FLAG;
+        assert!(CacheCodec::default().decode(&mut compressed).is_err());
+
+        // Once a hello settled the features without them, they end the connection.
+        let negotiated = || {
+            let offer = hello_payload(PROTOCOL_VERSION, FEATURE_CHUNKED);
+            let mut buf = BytesMut::new();
+            let hello = message::request(Op::Hello, vec![], Some(offer));
+            CacheCodec::default().encode((3, hello), &mut buf).unwrap();
+            let mut server = CacheCodec::default();
+            server.decode(&mut buf).unwrap().unwrap();
+            server
+        };
+        CacheCodec::default().encode((4, cas), &mut buf).unwrap();
+        assert!(negotiated().decode(&mut buf).is_err());
+        buf.clear();
+        CacheCodec::default().checksum(true).encode((5, get.clone()), &mut buf).unwrap();
+        assert!(negotiated().decode(&mut buf).is_err());
+        buf.clear();
+        CacheCodec::default().encode((6, get.clone()), &mut buf).unwrap();
+        assert_eq!(negotiated().decode(&mut buf).unwrap(), Some((6, get)));
+    }
+
+    #[test]
+    fn test_compressed_too_long() {
+        let mut codec = CacheCodec::new(16, 64);
+        codec.compress = true;
+        let mut buf = BytesMut::new();
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(1, vec![0; 10])));
+        CacheCodec::default().encode((1, req), &mut buf).unwrap();
+
+        // Swap in a compressed payload that would decompress past the limit.
+        let compressed = compress(&[0; 128]).unwrap();
+        assert!(compressed.len() <= 64);
+        let len = buf.len();
+        buf.truncate(len - 10);
+        buf.extend_from_slice(&compressed);
+        buf[8] |= COMPRESSED_FLAG;
+        let payload_len = compressed.len() as u64;
+        buf[10..18].copy_from_slice(&message::encode_u64(payload_len));
+        assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_frame_too_long() {
+        let mut buf = BytesMut::new();
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(1, vec![0; 64])));
+        CacheCodec::default().encode((1, req), &mut buf).unwrap();
+        let header = buf.split_to(HEADER_LEN);
+
+        // Refused from the header alone, whichever limit it breaks.
+        let mut codec = CacheCodec::default().limit_frame_len(HEADER_LEN + 3 + 4 + 63);
+        assert_eq!(refusal(&mut codec, &mut header.clone()), (1, Code::ValueTooLarge));
+        let mut codec = CacheCodec::default().limit_frame_len(HEADER_LEN + 3 + 4 + 64);
+        assert_eq!(codec.decode(&mut header.clone()).unwrap(), None);
+
+        // A checksum counts towards the limit.
+        let mut buf = BytesMut::new();
+        let req = message::request(Op::Get, "foo".into(), None);
+        CacheCodec::default().checksum(true).encode((1, req), &mut buf).unwrap();
+        let mut codec = CacheCodec::default().limit_frame_len(HEADER_LEN + 3);
+        assert_eq!(refusal(&mut codec, &mut buf), (1, Code::ValueTooLarge));
+        assert!(buf.is_empty());
+    }
 }
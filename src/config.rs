@@ -0,0 +1,1036 @@
+use futures::{future, Future};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle};
+use tokio_service::{NewService, Service};
+use time::Duration;
+use toml::{self, Value};
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use cache::{self, Cache, EvictionPolicy, HashFunction, LogOptions};
+use codec;
+use message::{Message, Op};
+use logging;
+use log::LevelFilter;
+use std::convert::TryFrom;
+use memcache;
+use metrics;
+use replica;
+use warmup;
+use hotkeys;
+use resp;
+use pubsub::Hub;
+use service::{self, CacheService, Listener, LogService, ServeOptions, SlowLogService};
+use service::{CoalesceService, RateLimit, RateLimitService, StatService, TransactionService};
+use slowlog::{self, SlowLog};
+use clock::Clock;
+use stats::Stats;
+use tls;
+
+/// The entries a server holds when its configuration doesn't say.
+pub static DEFAULT_CAPACITY: usize = 2000000;
+
+/// How a server started with `run` is set up. Built with `ServerConfig::new` and the setters
+/// below, or loaded from a TOML file such as:
+///
+/// ```toml
+/// addr = "127.0.0.1:12345"
+/// capacity = 100000
+/// max_bytes = 104857600
+/// max_key_len = 250
+/// max_value_len = 1048576
+/// ttl_jitter = 10           # percent, to spread out the expiry of keys set together
+/// stale_grace = 30          # seconds expired values are kept for Gets asking for them
+/// eviction = "lru"          # or "lfu" or "fifo"
+/// hasher = "siphash"        # or "fxhash", only with trusted clients
+/// sweep_interval = 10      # seconds, 0 to never sweep
+/// metrics_addr = "127.0.0.1:9100"
+/// memcache_addr = "127.0.0.1:11211"
+/// resp_addr = "127.0.0.1:6379"
+/// unix_socket = "/run/rcache/rcache.sock"
+/// unix_socket_mode = "770"  # octal
+/// max_frame_len = 2097152
+/// max_in_flight = 16
+/// idle_timeout = 300       # seconds
+/// keepalive = 60           # seconds
+/// notifications = false
+/// replicas = false
+/// replica_of = "10.0.0.1:12345"
+/// cluster_addr = "10.0.0.1:12345"
+/// hot_keys = false
+///
+/// [persistence]
+/// log = "/var/lib/rcache/aof"
+/// log_sync = false
+/// log_compact_after = 67108864
+/// snapshot = "/var/lib/rcache/snapshot"
+///
+/// [warmup]                  # either from a snapshot or from a peer
+/// peer = "10.0.0.2:12345"
+/// prefix = "user:"          # only the keys starting with it, from a peer
+///
+/// [middleware]
+/// log = false
+/// stats = true
+/// slow_log = 10000         # microseconds
+/// slow_log_print = false
+/// rate_limit = 1000        # requests per second and connection
+/// rate_limit_burst = 2000
+/// coalesce_gets = false    # share one read between concurrent Gets for the same key
+///
+/// [logging]
+/// level = "info"
+/// ops = ["Set", "Del"]     # the ops the log middleware logs, all by default
+/// max_payload_len = 64
+///
+/// [tls]
+/// pkcs12 = "/etc/rcache/identity.p12"
+/// password = "secret"
+/// ```
+///
+/// Only `addr` is required. A loaded configuration can still be overridden with the setters, as
+/// the command line does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    addr: SocketAddr,
+    capacity: usize,
+    max_bytes: Option<usize>,
+    max_key_len: Option<usize>,
+    max_value_len: Option<usize>,
+    ttl_jitter: Option<u32>,
+    stale_grace: Option<Duration>,
+    eviction: EvictionPolicy,
+    hasher: HashFunction,
+    sweep_interval: Option<Duration>,
+    log_path: Option<PathBuf>,
+    log_sync: bool,
+    log_compact_after: Option<u64>,
+    snapshot_path: Option<PathBuf>,
+    warmup: Option<warmup::Source>,
+    metrics_addr: Option<SocketAddr>,
+    memcache_addr: Option<SocketAddr>,
+    resp_addr: Option<SocketAddr>,
+    unix_socket: Option<PathBuf>,
+    unix_socket_mode: Option<u32>,
+    max_frame_len: Option<usize>,
+    max_in_flight: Option<usize>,
+    idle_timeout: Option<StdDuration>,
+    keepalive: Option<StdDuration>,
+    notifications: bool,
+    replicas: bool,
+    replica_of: Option<SocketAddr>,
+    cluster_addr: Option<SocketAddr>,
+    hot_keys: bool,
+    log_requests: bool,
+    stats: bool,
+    slow_log: Option<Duration>,
+    slow_log_print: bool,
+    rate_limit: Option<RateLimit>,
+    coalesce_gets: bool,
+    log_level: LevelFilter,
+    log_ops: Option<Vec<Op>>,
+    log_max_payload_len: Option<usize>,
+    tls: Option<(PathBuf, String)>,
+}
+
+impl ServerConfig {
+    /// A configuration serving at `addr`, with the defaults of `cache::Options`, up to
+    /// `DEFAULT_CAPACITY` entries, no persistence, and only the stats middleware.
+    pub fn new(addr: SocketAddr) -> Self {
+        ServerConfig {
+            addr: addr,
+            capacity: DEFAULT_CAPACITY,
+            max_bytes: None,
+            max_key_len: None,
+            max_value_len: None,
+            ttl_jitter: None,
+            stale_grace: None,
+            eviction: EvictionPolicy::Lru,
+            hasher: HashFunction::SipHash,
+            sweep_interval: cache::Options::default().sweep_interval,
+            log_path: None,
+            log_sync: false,
+            log_compact_after: None,
+            snapshot_path: None,
+            warmup: None,
+            metrics_addr: None,
+            memcache_addr: None,
+            resp_addr: None,
+            unix_socket: None,
+            unix_socket_mode: None,
+            max_frame_len: None,
+            max_in_flight: None,
+            idle_timeout: None,
+            keepalive: None,
+            notifications: false,
+            replicas: false,
+            replica_of: None,
+            cluster_addr: None,
+            hot_keys: false,
+            log_requests: false,
+            stats: true,
+            slow_log: None,
+            slow_log_print: false,
+            rate_limit: None,
+            coalesce_gets: false,
+            log_level: LevelFilter::Info,
+            log_ops: None,
+            log_max_payload_len: None,
+            tls: None,
+        }
+    }
+
+    /// Loads the configuration in the TOML file at `path`, see `from_toml`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut s = String::new();
+        File::open(path)?.read_to_string(&mut s)?;
+        ServerConfig::from_toml(&s)
+    }
+
+    /// Parses a configuration in the layout shown above. Unknown settings are rejected, rather
+    /// than ignored, so that a misspelt one doesn't go unnoticed.
+    pub fn from_toml(s: &str) -> io::Result<Self> {
+        let value = s.parse::<Value>().map_err(|e| invalid(&format!("invalid TOML: {}", e)))?;
+        let table = value.as_table().ok_or_else(|| invalid("expected a table"))?;
+        let mut config = match table.get("addr") {
+            Some(addr) => ServerConfig::new(parse_addr("addr", addr)?),
+            None => return Err(invalid("addr is required")),
+        };
+
+        for (key, value) in table {
+            match key.as_str() {
+                "addr" => {}
+                "capacity" => config.capacity = integer(key, value)? as usize,
+                "max_bytes" => config.max_bytes = Some(integer(key, value)? as usize),
+                "max_key_len" => config.max_key_len = Some(integer(key, value)? as usize),
+                "max_value_len" => config.max_value_len = Some(integer(key, value)? as usize),
+                "ttl_jitter" => {
+                    config.ttl_jitter = match integer(key, value)? {
+                        percent if percent > 100 => {
+                            return Err(invalid("ttl_jitter is a percentage, at most 100"));
+                        }
+                        percent => Some(percent as u32),
+                    };
+                }
+                "stale_grace" => {
+                    config.stale_grace = match integer(key, value)? {
+                        0 => None,
+                        secs => Some(Duration::seconds(secs)),
+                    }
+                }
+                "eviction" => {
+                    let policy = string(key, value)?;
+                    config.eviction = EvictionPolicy::from_name(policy).ok_or_else(|| {
+                        invalid(&format!("unknown eviction policy {}", policy))
+                    })?;
+                }
+                "hasher" => {
+                    let hasher = string(key, value)?;
+                    config.hasher = HashFunction::from_name(hasher).ok_or_else(|| {
+                        invalid(&format!("unknown hash function {}", hasher))
+                    })?;
+                }
+                "sweep_interval" => {
+                    config.sweep_interval = match integer(key, value)? {
+                        0 => None,
+                        secs => Some(Duration::seconds(secs)),
+                    }
+                }
+                "metrics_addr" => config.metrics_addr = Some(parse_addr(key, value)?),
+                "memcache_addr" => config.memcache_addr = Some(parse_addr(key, value)?),
+                "resp_addr" => config.resp_addr = Some(parse_addr(key, value)?),
+                "unix_socket" => config.unix_socket = Some(string(key, value)?.into()),
+                "unix_socket_mode" => {
+                    let mode = string(key, value)?;
+                    config.unix_socket_mode = Some(u32::from_str_radix(mode, 8).map_err(|_| {
+                        invalid(&format!("{} must be an octal mode, such as \"770\"", key))
+                    })?);
+                }
+                "max_frame_len" => config.max_frame_len = Some(integer(key, value)? as usize),
+                "max_in_flight" => config.max_in_flight = Some(integer(key, value)? as usize),
+                "idle_timeout" => {
+                    config.idle_timeout = Some(StdDuration::from_secs(integer(key, value)? as u64))
+                }
+                "keepalive" => {
+                    config.keepalive = Some(StdDuration::from_secs(integer(key, value)? as u64))
+                }
+                "notifications" => config.notifications = boolean(key, value)?,
+                "replicas" => config.replicas = boolean(key, value)?,
+                "replica_of" => config.replica_of = Some(parse_addr(key, value)?),
+                "cluster_addr" => config.cluster_addr = Some(parse_addr(key, value)?),
+                "hot_keys" => config.hot_keys = boolean(key, value)?,
+                "persistence" => for (key, value) in section(key, value)? {
+                    match key.as_str() {
+                        "log" => config.log_path = Some(string(key, value)?.into()),
+                        "log_sync" => config.log_sync = boolean(key, value)?,
+                        "log_compact_after" => {
+                            config.log_compact_after = Some(integer(key, value)? as u64)
+                        }
+                        "snapshot" => config.snapshot_path = Some(string(key, value)?.into()),
+                        _ => return Err(unknown("persistence", key)),
+                    }
+                },
+                "warmup" => {
+                    let (mut snapshot, mut peer, mut prefix) = (None, None, None);
+                    for (key, value) in section(key, value)? {
+                        match key.as_str() {
+                            "snapshot" => snapshot = Some(PathBuf::from(string(key, value)?)),
+                            "peer" => peer = Some(parse_addr(key, value)?),
+                            "prefix" => prefix = Some(string(key, value)?.as_bytes().to_vec()),
+                            _ => return Err(unknown("warmup", key)),
+                        }
+                    }
+                    config.warmup = Some(match (snapshot, peer, prefix) {
+                        (Some(path), None, None) => warmup::Source::Snapshot(path),
+                        (None, Some(addr), prefix) => {
+                            warmup::Source::Peer(addr, prefix.unwrap_or_default())
+                        }
+                        _ => {
+                            return Err(invalid(
+                                "warmup needs either a snapshot or a peer, and a prefix only \
+                                 with a peer",
+                            ))
+                        }
+                    });
+                }
+                "middleware" => {
+                    let mut burst = None;
+                    for (key, value) in section(key, value)? {
+                        match key.as_str() {
+                            "log" => config.log_requests = boolean(key, value)?,
+                            "stats" => config.stats = boolean(key, value)?,
+                            "slow_log" => {
+                                let micros = integer(key, value)?;
+                                config.slow_log = Some(Duration::microseconds(micros));
+                            }
+                            "slow_log_print" => config.slow_log_print = boolean(key, value)?,
+                            "rate_limit" => {
+                                let per_second = integer(key, value)? as u32;
+                                config.rate_limit = Some(RateLimit::new(per_second));
+                            }
+                            "rate_limit_burst" => burst = Some(integer(key, value)? as u32),
+                            "coalesce_gets" => config.coalesce_gets = boolean(key, value)?,
+                            _ => return Err(unknown("middleware", key)),
+                        }
+                    }
+                    config.rate_limit = match (config.rate_limit, burst) {
+                        (Some(limit), Some(burst)) => Some(limit.burst(burst)),
+                        (None, Some(_)) => {
+                            return Err(invalid("middleware.rate_limit_burst needs a rate_limit"))
+                        }
+                        (limit, None) => limit,
+                    };
+                }
+                "logging" => for (key, value) in section(key, value)? {
+                    match key.as_str() {
+                        "level" => {
+                            let level = string(key, value)?;
+                            config.log_level = level.parse().map_err(|_| {
+                                invalid(&format!("unknown log level {}", level))
+                            })?;
+                        }
+                        "ops" => {
+                            let ops = value.as_array().ok_or_else(|| {
+                                invalid(&format!("{} must be an array", key))
+                            })?;
+                            let ops = ops.iter().map(|op| parse_op(string(key, op)?));
+                            config.log_ops = Some(ops.collect::<io::Result<_>>()?);
+                        }
+                        "max_payload_len" => {
+                            config.log_max_payload_len = Some(integer(key, value)? as usize)
+                        }
+                        _ => return Err(unknown("logging", key)),
+                    }
+                },
+                "tls" => {
+                    let tls = section(key, value)?;
+                    let mut pkcs12 = None;
+                    let mut password = String::new();
+                    for (key, value) in tls {
+                        match key.as_str() {
+                            "pkcs12" => pkcs12 = Some(PathBuf::from(string(key, value)?)),
+                            "password" => password = string(key, value)?.to_owned(),
+                            _ => return Err(unknown("tls", key)),
+                        }
+                    }
+                    let pkcs12 = pkcs12.ok_or_else(|| invalid("tls.pkcs12 is required"))?;
+                    config.tls = Some((pkcs12, password));
+                }
+                _ => return Err(unknown("", key)),
+            }
+        }
+        Ok(config)
+    }
+
+    /// The address to serve at.
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// The most entries the cache holds.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// See `cache::Options::max_bytes`.
+    pub fn max_bytes(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// See `cache::Options::max_key_len`.
+    pub fn max_key_len(mut self, max_key_len: Option<usize>) -> Self {
+        self.max_key_len = max_key_len;
+        self
+    }
+
+    /// See `cache::Options::max_value_len`.
+    pub fn max_value_len(mut self, max_value_len: Option<usize>) -> Self {
+        self.max_value_len = max_value_len;
+        self
+    }
+
+    /// See `cache::Options::ttl_jitter`.
+    pub fn ttl_jitter(mut self, percent: Option<u32>) -> Self {
+        self.ttl_jitter = percent;
+        self
+    }
+
+    /// See `cache::Options::stale_grace`.
+    pub fn stale_grace(mut self, grace: Option<Duration>) -> Self {
+        self.stale_grace = grace;
+        self
+    }
+
+    /// See `cache::Options::eviction`.
+    pub fn eviction(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction = policy;
+        self
+    }
+
+    /// See `cache::Options::hasher`.
+    pub fn hasher(mut self, hasher: HashFunction) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    /// See `cache::Options::sweep_interval`.
+    pub fn sweep_interval(mut self, interval: Option<Duration>) -> Self {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// Keep an append-only log of writes at `path`, see `cache::LogOptions`.
+    pub fn log<P: Into<PathBuf>>(mut self, path: Option<P>, sync: bool) -> Self {
+        self.log_path = path.map(Into::into);
+        self.log_sync = sync;
+        self
+    }
+
+    /// See `cache::LogOptions::compact_after`.
+    pub fn log_compact_after(mut self, bytes: Option<u64>) -> Self {
+        self.log_compact_after = bytes;
+        self
+    }
+
+    /// Restore the snapshot at `path` on startup, if there is one, see `cache::Options::snapshot`.
+    pub fn snapshot<P: Into<PathBuf>>(mut self, path: Option<P>) -> Self {
+        self.snapshot_path = path.map(Into::into);
+        self
+    }
+
+    /// Warm the cache up from `source` on startup, see `warmup::warm_up`.
+    pub fn warmup(mut self, source: Option<warmup::Source>) -> Self {
+        self.warmup = source;
+        self
+    }
+
+    /// Serve Prometheus metrics at `addr`, see `metrics::serve_metrics`. Needs the stats
+    /// middleware.
+    pub fn metrics_addr(mut self, addr: Option<SocketAddr>) -> Self {
+        self.metrics_addr = addr;
+        self
+    }
+
+    /// Also serve the memcached text protocol at `addr`, see `memcache::serve_memcache`. Its
+    /// requests go through the stats middleware, if enabled, but not through the others.
+    pub fn memcache_addr(mut self, addr: Option<SocketAddr>) -> Self {
+        self.memcache_addr = addr;
+        self
+    }
+
+    /// Also serve the Redis protocol at `addr`, see `resp::serve_resp`. Like the memcached
+    /// protocol's, its requests only go through the stats middleware.
+    pub fn resp_addr(mut self, addr: Option<SocketAddr>) -> Self {
+        self.resp_addr = addr;
+        self
+    }
+
+    /// Also serve at the UNIX domain socket at `path`, see `service::bind_unix`, with the same
+    /// middleware as at `addr`. Only the users the permission bits `mode` allows may connect, or
+    /// those the process's umask does without it.
+    pub fn unix_socket<P: Into<PathBuf>>(mut self, path: Option<P>, mode: Option<u32>) -> Self {
+        self.unix_socket = path.map(Into::into);
+        self.unix_socket_mode = mode;
+        self
+    }
+
+    /// See `ServeOptions::max_frame_len`.
+    pub fn max_frame_len(mut self, max_frame_len: Option<usize>) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    /// See `ServeOptions::max_in_flight`, whose default applies without a limit.
+    pub fn max_in_flight(mut self, max_in_flight: Option<usize>) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// See `ServeOptions::idle_timeout`.
+    pub fn idle_timeout(mut self, timeout: Option<StdDuration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// See `ServeOptions::keepalive`.
+    pub fn keepalive(mut self, keepalive: Option<StdDuration>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Let clients subscribe to changes to keys, see `ServeOptions::notifications`.
+    pub fn notifications(mut self, enabled: bool) -> Self {
+        self.notifications = enabled;
+        self
+    }
+
+    /// Let replicas follow the server, see `ServeOptions::replication`, holding up to
+    /// `cache::DEFAULT_REPLICA_BACKLOG` writes for each.
+    pub fn replicas(mut self, enabled: bool) -> Self {
+        self.replicas = enabled;
+        self
+    }
+
+    /// Serve as a read-only replica of the server at `addr`, see `replica::follow`.
+    pub fn replica_of(mut self, addr: Option<SocketAddr>) -> Self {
+        self.replica_of = addr;
+        self
+    }
+
+    /// Serve in cluster mode as the node clients reach at `addr`, see `cache::Options::cluster`.
+    pub fn cluster_addr(mut self, addr: Option<SocketAddr>) -> Self {
+        self.cluster_addr = addr;
+        self
+    }
+
+    /// Track the keys requested most, see `cache::Options::hot_keys`, counting one in
+    /// `hotkeys::DEFAULT_SAMPLE_RATE` requests.
+    pub fn hot_keys(mut self, enabled: bool) -> Self {
+        self.hot_keys = enabled;
+        self
+    }
+
+    /// The least severe records logged, see `logging::init`.
+    pub fn log_level(mut self, level: LevelFilter) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    /// Have the `LogService` only log the requests for `ops`, rather than all of them.
+    pub fn log_ops(mut self, ops: Option<Vec<Op>>) -> Self {
+        self.log_ops = ops;
+        self
+    }
+
+    /// Have the `LogService` log only the first `len` bytes of payloads.
+    pub fn log_max_payload_len(mut self, len: Option<usize>) -> Self {
+        self.log_max_payload_len = len;
+        self
+    }
+
+    /// Put a `LogService` in front of the cache, logging every request.
+    pub fn log_requests(mut self, enabled: bool) -> Self {
+        self.log_requests = enabled;
+        self
+    }
+
+    /// Put a `SlowLogService` in front of the cache, recording the requests it takes longer than
+    /// `threshold` to answer, and logging them too if `print` is set.
+    pub fn slow_log(mut self, threshold: Option<Duration>, print: bool) -> Self {
+        self.slow_log = threshold;
+        self.slow_log_print = print;
+        self
+    }
+
+    /// Put a `RateLimitService` in front of the cache, limiting each connection to `limit`.
+    pub fn rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.rate_limit = limit;
+        self
+    }
+
+    /// Put a `CoalesceService` in front of the cache, having concurrent Gets for the same key
+    /// share one read. It sits behind the transactions, so queued Gets are never shared.
+    pub fn coalesce_gets(mut self, enabled: bool) -> Self {
+        self.coalesce_gets = enabled;
+        self
+    }
+
+    /// Put a `StatService` in front of the cache, answering `Op::Stats` for the server.
+    pub fn stats(mut self, enabled: bool) -> Self {
+        self.stats = enabled;
+        self
+    }
+
+    /// Serve TLS, with the identity in the PKCS #12 archive at `path`, see `tls::acceptor`.
+    pub fn tls<P: Into<PathBuf>>(mut self, path: Option<P>, password: &str) -> Self {
+        self.tls = path.map(|path| (path.into(), password.to_owned()));
+        self
+    }
+}
+
+/// Starts a server as configured by `config` on the current thread, and serves until it fails.
+/// The cache is fronted by the enabled middleware, outermost first: `LogService`,
+/// `StatService`, `RateLimitService`, `SlowLogService`, `TransactionService`, `CoalesceService`
+/// and `CacheService`. Records are logged
+/// through a `logging::ThreadLogger`, unless the process has set a logger of its own.
+pub fn run(config: ServerConfig) -> io::Result<()> {
+    logging::init(config.log_level)?;
+    if config.metrics_addr.is_some() && !config.stats {
+        return Err(invalid("metrics need the stats middleware"));
+    }
+    let stats = if config.stats {
+        Some(Arc::new(Stats::default()))
+    } else {
+        None
+    };
+
+    let panic_stats = stats.clone();
+    let log_sync = config.log_sync;
+    let compact_after = config.log_compact_after;
+    let hub = if config.notifications {
+        Some(Hub::new())
+    } else {
+        None
+    };
+    let options = cache::Options {
+        panic_hook: Arc::new(move |cause: &str| {
+            error!("Worker panicked: {}.", cause);
+            if let Some(ref stats) = panic_stats {
+                stats.incr_worker_panics();
+            }
+        }),
+        max_bytes: config.max_bytes,
+        max_key_len: config.max_key_len,
+        max_value_len: config.max_value_len,
+        ttl_jitter: config.ttl_jitter,
+        stale_grace: config.stale_grace,
+        eviction: config.eviction,
+        hasher: config.hasher,
+        sweep_interval: config.sweep_interval,
+        log: config.log_path.clone().map(|path| {
+            LogOptions {
+                path: path,
+                sync: log_sync,
+                compact_after: compact_after,
+            }
+        }),
+        notifications: hub.clone(),
+        replication: if config.replicas {
+            Some(cache::DEFAULT_REPLICA_BACKLOG)
+        } else {
+            None
+        },
+        snapshot: config.snapshot_path.clone(),
+        read_only: config.replica_of.is_some(),
+        cluster: config.cluster_addr,
+        hot_keys: if config.hot_keys {
+            Some(hotkeys::DEFAULT_SAMPLE_RATE)
+        } else {
+            None
+        },
+        ..cache::Options::default()
+    };
+    let cache = Cache::with_options(config.capacity, options)?;
+    let cache = Arc::new(cache);
+    let _follower = match config.replica_of {
+        Some(primary) => Some(replica::follow(primary, &cache, stats.clone())?),
+        None => None,
+    };
+    if let Some(source) = config.warmup.clone() {
+        warmup::warm_up(&cache, source, stats.clone())?;
+    }
+
+    let serve_options = ServeOptions {
+        stats: stats.clone(),
+        max_frame_len: config.max_frame_len,
+        // The frames have to fit what the cache takes. A payload can hold several values, so
+        // the codec's default stays the least it is held to.
+        max_key_len: config.max_key_len,
+        max_payload_len: config
+            .max_value_len
+            .map(|len| cmp::max(len, codec::DEFAULT_MAX_PAYLOAD_LEN)),
+        max_in_flight: config.max_in_flight.unwrap_or(ServeOptions::default().max_in_flight),
+        idle_timeout: config.idle_timeout,
+        keepalive: config.keepalive,
+        notifications: hub,
+        replication: if config.replicas {
+            Some(cache.clone())
+        } else {
+            None
+        },
+        tls: match config.tls {
+            Some((ref path, ref password)) => Some(tls::acceptor(path, password)?),
+            None => None,
+        },
+        limits: cache.limits(),
+        ..ServeOptions::default()
+    };
+
+    // Everything is served on this one reactor, the metrics alongside the cache.
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    if let (Some(addr), Some(stats)) = (config.metrics_addr, stats.clone()) {
+        metrics::serve_metrics(&addr, stats, cache.clone(), &handle)?;
+    }
+    if let Some(addr) = config.memcache_addr {
+        let service = CacheService { cache: cache.clone() };
+        match stats.clone() {
+            Some(stats) => {
+                let service = StatService {
+                    inner: service,
+                    stats: stats,
+                    clock: cache.clock().clone(),
+                };
+                memcache::serve_memcache(&addr, service, &handle)?
+            }
+            None => memcache::serve_memcache(&addr, service, &handle)?,
+        }
+    }
+    if let Some(addr) = config.resp_addr {
+        let service = CacheService { cache: cache.clone() };
+        match stats.clone() {
+            Some(stats) => {
+                let service = StatService {
+                    inner: service,
+                    stats: stats,
+                    clock: cache.clock().clone(),
+                };
+                resp::serve_resp(&addr, service, &handle)?
+            }
+            None => resp::serve_resp(&addr, service, &handle)?,
+        }
+    }
+    let mut listeners = vec![Listener::from(TcpListener::bind(&config.addr, &handle)?)];
+    if let Some(ref path) = config.unix_socket {
+        let listener = service::bind_unix(path, config.unix_socket_mode, &handle)?;
+        listeners.push(listener.into());
+    }
+    let clock = cache.clock().clone();
+    let slow_log = config.slow_log.map(|threshold| {
+        let slow_log = SlowLog::new(threshold, slowlog::DEFAULT_SLOW_LOG_LEN)
+            .print(config.slow_log_print);
+        Arc::new(slow_log)
+    });
+    let servers = listeners.into_iter().map(|listener| {
+        let service = CacheService { cache: cache.clone() };
+        let (slow_log, clock, options) = (slow_log.clone(), clock.clone(), serve_options.clone());
+        if config.coalesce_gets {
+            let service = TransactionService::new(CoalesceService::new(service));
+            serve_slow_logged(listener, service, slow_log, &config, clock, options, handle.clone())
+        } else {
+            let service = TransactionService::new(service);
+            serve_slow_logged(listener, service, slow_log, &config, clock, options, handle.clone())
+        }
+    });
+    let servers: Vec<_> = servers.collect();
+    core.run(future::join_all(servers)).map(|_| ())
+}
+
+/// Serves `service` behind the `SlowLogService` recording to `slow_log`, if any, and the
+/// middleware in front of it, see `serve_limited`.
+fn serve_slow_logged<T>(
+    listener: Listener,
+    service: T,
+    slow_log: Option<Arc<SlowLog>>,
+    config: &ServerConfig,
+    clock: Arc<Clock>,
+    options: ServeOptions,
+    handle: Handle,
+) -> Box<Future<Item = (), Error = io::Error>>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    let stats = options.stats.clone();
+    match slow_log {
+        Some(slow_log) => {
+            let service = SlowLogService {
+                inner: service,
+                slow_log: slow_log,
+                clock: clock.clone(),
+            };
+            serve_limited(listener, service, config, stats, clock, options, handle)
+        }
+        None => serve_limited(listener, service, config, stats, clock, options, handle),
+    }
+}
+
+/// Serves `service` behind the `RateLimitService` `config` asks for, and the middleware in front
+/// of it, see `serve_with`.
+fn serve_limited<T>(
+    listener: Listener,
+    service: T,
+    config: &ServerConfig,
+    stats: Option<Arc<Stats>>,
+    clock: Arc<Clock>,
+    options: ServeOptions,
+    handle: Handle,
+) -> Box<Future<Item = (), Error = io::Error>>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    match config.rate_limit {
+        Some(limit) => {
+            let service = RateLimitService::new(service, limit, clock.clone());
+            serve_with(listener, service, config, stats, clock, options, handle)
+        }
+        None => serve_with(listener, service, config, stats, clock, options, handle),
+    }
+}
+
+/// Serves `service` behind the middleware `config` asks for.
+fn serve_with<T>(
+    listener: Listener,
+    service: T,
+    config: &ServerConfig,
+    stats: Option<Arc<Stats>>,
+    clock: Arc<Clock>,
+    options: ServeOptions,
+    handle: Handle,
+) -> Box<Future<Item = (), Error = io::Error>>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    match (config.log_requests, stats) {
+        (false, None) => serve(listener, service, options, handle),
+        (true, None) => serve(listener, logged(service, config), options, handle),
+        (log_requests, Some(stats)) => {
+            let service = StatService {
+                inner: service,
+                stats: stats,
+                clock: clock,
+            };
+            if log_requests {
+                serve(listener, logged(service, config), options, handle)
+            } else {
+                serve(listener, service, options, handle)
+            }
+        }
+    }
+}
+
+/// `service` behind the `LogService` `config` asks for.
+fn logged<T>(service: T, config: &ServerConfig) -> LogService<T> {
+    let service = LogService::new(service).max_payload_len(config.log_max_payload_len);
+    match config.log_ops {
+        Some(ref ops) => service.ops(ops),
+        None => service,
+    }
+}
+
+fn serve<T>(
+    listener: Listener,
+    s: T,
+    options: ServeOptions,
+    handle: Handle,
+) -> Box<Future<Item = (), Error = io::Error>>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    service::server(listener, s, options, future::empty::<(), ()>(), handle)
+}
+
+/// The op named `name`, as `Op`'s `Display` has it.
+fn parse_op(name: &str) -> io::Result<Op> {
+    (0..u8::max_value())
+        .filter_map(|code| Op::try_from(code).ok())
+        .find(|op| op.to_string() == name)
+        .ok_or_else(|| invalid(&format!("unknown op {}", name)))
+}
+
+fn invalid(description: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, description)
+}
+
+fn unknown(section: &str, key: &str) -> io::Error {
+    if section.is_empty() {
+        invalid(&format!("unknown setting {}", key))
+    } else {
+        invalid(&format!("unknown setting {}.{}", section, key))
+    }
+}
+
+fn section<'a>(key: &str, value: &'a Value) -> io::Result<&'a toml::value::Table> {
+    value.as_table().ok_or_else(|| invalid(&format!("{} must be a table", key)))
+}
+
+fn string<'a>(key: &str, value: &'a Value) -> io::Result<&'a str> {
+    value.as_str().ok_or_else(|| invalid(&format!("{} must be a string", key)))
+}
+
+fn boolean(key: &str, value: &Value) -> io::Result<bool> {
+    value.as_bool().ok_or_else(|| invalid(&format!("{} must be a boolean", key)))
+}
+
+fn integer(key: &str, value: &Value) -> io::Result<i64> {
+    match value.as_integer() {
+        Some(n) if n >= 0 => Ok(n),
+        _ => Err(invalid(&format!("{} must be a non-negative integer", key))),
+    }
+}
+
+fn parse_addr(key: &str, value: &Value) -> io::Result<SocketAddr> {
+    string(key, value)?.parse().map_err(|_| invalid(&format!("{} must be a socket address", key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_toml() {
+        let config = ServerConfig::from_toml(
+            r#"
+            addr = "127.0.0.1:12345"
+            capacity = 100
+            max_key_len = 250
+            max_value_len = 1024
+            ttl_jitter = 10
+            stale_grace = 30
+            eviction = "lfu"
+            hasher = "fxhash"
+            sweep_interval = 0
+            metrics_addr = "127.0.0.1:9100"
+            memcache_addr = "127.0.0.1:11211"
+            resp_addr = "127.0.0.1:6379"
+            unix_socket = "/tmp/rcache.sock"
+            unix_socket_mode = "770"
+            max_frame_len = 4096
+            max_in_flight = 8
+            idle_timeout = 30
+            notifications = true
+            replicas = true
+            replica_of = "127.0.0.1:12346"
+            cluster_addr = "127.0.0.1:12345"
+            hot_keys = true
+
+            [persistence]
+            log = "/tmp/rcache.aof"
+            log_sync = true
+
+            [warmup]
+            peer = "127.0.0.1:12347"
+            prefix = "user:"
+
+            [middleware]
+            log = true
+            slow_log = 5000
+            rate_limit = 100
+            rate_limit_burst = 200
+            coalesce_gets = true
+
+            [logging]
+            level = "debug"
+            ops = ["Set", "Del"]
+            max_payload_len = 16
+            "#,
+        ).unwrap();
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let expected = ServerConfig::new(addr)
+            .capacity(100)
+            .max_key_len(Some(250))
+            .max_value_len(Some(1024))
+            .ttl_jitter(Some(10))
+            .stale_grace(Some(Duration::seconds(30)))
+            .eviction(EvictionPolicy::Lfu)
+            .hasher(HashFunction::FxHash)
+            .sweep_interval(None)
+            .metrics_addr(Some("127.0.0.1:9100".parse().unwrap()))
+            .memcache_addr(Some("127.0.0.1:11211".parse().unwrap()))
+            .resp_addr(Some("127.0.0.1:6379".parse().unwrap()))
+            .unix_socket(Some("/tmp/rcache.sock"), Some(0o770))
+            .max_frame_len(Some(4096))
+            .max_in_flight(Some(8))
+            .idle_timeout(Some(StdDuration::from_secs(30)))
+            .notifications(true)
+            .replicas(true)
+            .replica_of(Some("127.0.0.1:12346".parse().unwrap()))
+            .cluster_addr(Some(addr))
+            .hot_keys(true)
+            .log(Some("/tmp/rcache.aof"), true)
+            .warmup(Some(warmup::Source::Peer("127.0.0.1:12347".parse().unwrap(), "user:".into())))
+            .log_requests(true)
+            .slow_log(Some(Duration::milliseconds(5)), false)
+            .rate_limit(Some(RateLimit::new(100).burst(200)))
+            .coalesce_gets(true)
+            .log_level(LevelFilter::Debug)
+            .log_ops(Some(vec![Op::Set, Op::Del]))
+            .log_max_payload_len(Some(16));
+        assert_eq!(config, expected);
+
+        // Setters override what was loaded.
+        let config = config.capacity(5).log(None::<PathBuf>, false);
+        assert_eq!(config.capacity, 5);
+        assert_eq!(config.log_path, None);
+    }
+
+    #[test]
+    fn test_from_toml_errors() {
+        let kind = |s: &str| ServerConfig::from_toml(s).err().map(|e| e.kind());
+        let invalid = Some(io::ErrorKind::InvalidData);
+        assert_eq!(kind("capacity = 10"), invalid);
+        assert_eq!(kind("addr = \"localhost\""), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\ncapacity = -1"), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\ncapasity = 10"), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\nunix_socket_mode = \"rwx\""), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\neviction = \"random\""), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\nhasher = \"md5\""), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\nttl_jitter = 150"), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\n[logging]\nops = [\"Sett\"]"), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\n[logging]\nlevel = \"loud\""), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\n[middleware]\nlogs = true"), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\n[middleware]\nrate_limit_burst = 2"), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\n[tls]\npassword = \"x\""), invalid);
+        assert_eq!(kind("addr = \"127.0.0.1:1\"\n[warmup]\nprefix = \"a\""), invalid);
+        let snapshot_with_prefix = "addr = \"127.0.0.1:1\"\n[warmup]\nsnapshot = \"a\"\n\
+                                    prefix = \"a\"";
+        assert_eq!(kind(snapshot_with_prefix), invalid);
+        assert!(ServerConfig::from_toml("addr = \"127.0.0.1:1\"\n[tls]\npkcs12 = \"a\"").is_ok());
+    }
+
+    #[test]
+    fn test_run_errors() {
+        let config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        let metrics_addr = Some("127.0.0.1:0".parse().unwrap());
+        let no_stats = config.clone().stats(false).metrics_addr(metrics_addr);
+        assert_eq!(run(no_stats).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        let missing = config.tls(Some("/nonexistent/rcache.p12"), "");
+        assert_eq!(run(missing).err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+    }
+}
@@ -0,0 +1,153 @@
+use rand::{self, Rng};
+use std::collections::HashMap;
+use time::{Duration, Timespec};
+
+/// How long the window `HotKeys` counts requests over is, in seconds.
+pub static WINDOW_SECS: i64 = 60;
+
+/// The most keys `HotKeys` counts in a window. Once as many are counted, a newly requested key
+/// takes the place of the least requested one.
+pub static TRACKED_KEYS: usize = 1024;
+
+/// The sample rate for servers whose configuration doesn't say, see `cache::Options::hot_keys`.
+pub static DEFAULT_SAMPLE_RATE: u32 = 10;
+
+/// Counts the requests for each key over a sliding window of `WINDOW_SECS`, to find the keys
+/// requested most. Only one in `sample_rate` requests is counted, and the counts are scaled
+/// back up, so that tracking costs little on a busy cache.
+///
+/// The window is made of the current period and the one before it. A key's count is its count
+/// in the current period, plus its count in the previous one weighted by how much of that
+/// period still falls in the window.
+///
+/// The most requested keys are found with the Space-Saving algorithm: once `TRACKED_KEYS` are
+/// counted, a new key replaces the one counted least, and inherits its count. The keys requested
+/// more than that are always counted, but a count may overestimate by up to the count it
+/// inherited.
+pub struct HotKeys {
+    sample_rate: u32,
+    current: HashMap<Vec<u8>, u64>,
+    previous: HashMap<Vec<u8>, u64>,
+    period_start: Timespec,
+}
+
+impl HotKeys {
+    /// Counts one in `sample_rate` requests, or every request if it is 0 or 1.
+    pub fn new(sample_rate: u32, now: Timespec) -> Self {
+        HotKeys {
+            sample_rate: sample_rate.max(1),
+            current: HashMap::new(),
+            previous: HashMap::new(),
+            period_start: now,
+        }
+    }
+
+    /// Whether to count the next request, picked at random.
+    pub fn sample(&self) -> bool {
+        self.sample_rate == 1 || rand::thread_rng().gen_range(0, self.sample_rate) == 0
+    }
+
+    /// Counts a sampled request for `key`.
+    pub fn record(&mut self, key: &[u8], now: Timespec) {
+        self.advance(now);
+        if let Some(count) = self.current.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        let mut count = 1;
+        if self.current.len() >= TRACKED_KEYS {
+            let least = self.current
+                .iter()
+                .min_by_key(|&(_, &count)| count)
+                .map(|(key, &count)| (key.clone(), count));
+            if let Some((least, least_count)) = least {
+                self.current.remove(&least);
+                count += least_count;
+            }
+        }
+        self.current.insert(key.to_vec(), count);
+    }
+
+    /// The counted keys with their estimated requests over the window, most requested first.
+    pub fn counts(&mut self, now: Timespec) -> Vec<(Vec<u8>, u64)> {
+        self.advance(now);
+        let window = Duration::seconds(WINDOW_SECS).num_milliseconds() as u64;
+        let elapsed = (now - self.period_start).num_milliseconds().max(0) as u64;
+        let mut counts: HashMap<&[u8], u64> = HashMap::new();
+        for (key, &count) in &self.previous {
+            let weighted = count * window.saturating_sub(elapsed) / window;
+            if weighted > 0 {
+                counts.insert(key, weighted);
+            }
+        }
+        for (key, &count) in &self.current {
+            *counts.entry(key).or_insert(0) += count;
+        }
+        let rate = self.sample_rate as u64;
+        let mut counts: Vec<_> = counts
+            .into_iter()
+            .map(|(key, count)| (key.to_vec(), count * rate))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// Starts a new period once the current one is over.
+    fn advance(&mut self, now: Timespec) {
+        let period = Duration::seconds(WINDOW_SECS);
+        if now - self.period_start < period {
+            return;
+        }
+        if now - self.period_start < period * 2 {
+            self.previous = self.current.drain().collect();
+            self.period_start = self.period_start + period;
+        } else {
+            self.previous.clear();
+            self.current.clear();
+            self.period_start = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let start = Timespec::new(1000, 0);
+        let mut hot = HotKeys::new(1, start);
+        for _ in 0..10 {
+            hot.record(b"a", start);
+        }
+        hot.record(b"b", start);
+        hot.record(b"b", start);
+        assert_eq!(hot.counts(start), vec![(b"a".to_vec(), 10), (b"b".to_vec(), 2)]);
+
+        // Halfway through the next period, half of the previous one is still in the window.
+        let later = start + Duration::seconds(WINDOW_SECS + WINDOW_SECS / 2);
+        for _ in 0..6 {
+            hot.record(b"b", later);
+        }
+        assert_eq!(hot.counts(later), vec![(b"b".to_vec(), 7), (b"a".to_vec(), 5)]);
+
+        let much_later = start + Duration::seconds(WINDOW_SECS * 5);
+        assert!(hot.counts(much_later).is_empty());
+    }
+
+    #[test]
+    fn test_tracked_keys() {
+        let now = Timespec::new(1000, 0);
+        let mut hot = HotKeys::new(2, now);
+        for _ in 0..10 {
+            hot.record(b"hot", now);
+        }
+        for i in 0..TRACKED_KEYS * 2 {
+            hot.record(format!("cold:{}", i).as_bytes(), now);
+        }
+        let counts = hot.counts(now);
+        assert_eq!(counts.len(), TRACKED_KEYS);
+        // Counts are scaled by the sample rate.
+        assert_eq!(counts[0], (b"hot".to_vec(), 20));
+    }
+}
@@ -11,19 +11,25 @@
 //! achieve with Rust.
 //!
 //! TODOs include better benchmarks, more featureful clients (for rust and the command line), support
-//! for a more complete set of commands, and improving the binary protocol to at least include CRC checks.
+//! for a more complete set of commands, and improving the binary protocol.
 //!
 //! ## Features
 //!
 //! - Based on `tokio`
 //! - The TCP frontend speaks a multiplexed-binary protocol, detailed (poorly) in src/codec.rs.
-//! - Currently supports GET, SET, and DEL commands. CAS is conspicuously absent, but will be along eventually.
+//! - Connections can be encrypted with TLS, see `service::ServeOptions::tls` and `tls`.
+//! - Metrics can be scraped by Prometheus, see `metrics`.
+//! - Currently supports GET, SET, DEL and CAS commands, among others.
 //! - Storage is backed by an LRU cached based on a Linked Hash Map (provided by the lru-cache crate),
 //! all operations are threaded through a single worker, which has unsynchronized access to the store.
+//! LFU and FIFO eviction can be chosen instead, see `cache::Options::eviction`, and other backends
+//! can be plugged in by implementing `cache::Storage`. Keys are hashed with SipHash, or with the
+//! faster FxHash when clients are trusted, see `cache::Options::hasher`.
 //!
 //! ## Usage
 //!
-//! Start a server: `cargo run -- 127.0.0.1:12345 server`
+//! Start a server: `cargo run -- 127.0.0.1:12345 server`, optionally with `--config` naming a
+//! TOML file as described in `config::ServerConfig`.
 //!
 //! Set a key: `cargo run -- 127.0.0.1:12345 client SET foo bar`
 //!
@@ -44,14 +50,29 @@ extern crate time;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate mio_uds;
+extern crate tokio_uds;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_proto;
 extern crate tokio_service;
-extern crate deque;
 extern crate bytes;
 extern crate rand;
 extern crate lru_cache;
+extern crate linked_hash_map;
+extern crate fxhash;
+extern crate native_tls;
+extern crate tokio_tls;
+extern crate toml;
+extern crate snap;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+extern crate bincode;
+#[macro_use]
+extern crate log;
+#[cfg(test)]
+#[macro_use]
+extern crate serde_derive;
 extern crate test;
 
 pub mod client;
@@ -59,7 +80,24 @@ pub mod message;
 pub mod cache;
 pub mod stats;
 pub mod service;
+pub mod codec;
+pub mod clock;
+pub mod metrics;
+pub mod tls;
+pub mod config;
+pub mod typed;
+pub mod pubsub;
+pub mod memcache;
+pub mod resp;
+pub mod replica;
+pub mod warmup;
+pub mod sharding;
+pub mod cluster;
+pub mod hotkeys;
+pub mod slowlog;
+pub mod clients;
+pub mod logging;
 
-mod codec;
 mod proto;
 mod error;
+mod aof;
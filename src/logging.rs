@@ -0,0 +1,128 @@
+use log::{self, LevelFilter, Log, Metadata, Record};
+use time;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+
+/// The most records a `ThreadLogger` holds while its thread writes them out. Records logged
+/// while as many are waiting are dropped, rather than making the caller wait.
+pub static LOG_BUFFER: usize = 4096;
+
+/// A logger for the `log` facade formatting each record as a line of its own, with the time,
+/// level and target, and handing it to a thread of its own to write, so that logging never
+/// blocks the event loop on a slow stdout. The number of records dropped for want of room in
+/// `LOG_BUFFER` is logged once there is room again.
+pub struct ThreadLogger {
+    level: LevelFilter,
+    lines: Mutex<SyncSender<String>>,
+    dropped: AtomicUsize,
+}
+
+impl ThreadLogger {
+    /// A logger writing the records at `level` or above to `out`.
+    pub fn new<W: Write + Send + 'static>(level: LevelFilter, mut out: W) -> io::Result<Self> {
+        let (snd, rcv) = mpsc::sync_channel::<String>(LOG_BUFFER);
+        thread::Builder::new().name("rcache-log".to_owned()).spawn(move || {
+            for line in rcv {
+                let _ = out.write_all(line.as_bytes()).and_then(|()| out.flush());
+            }
+        })?;
+        Ok(ThreadLogger {
+            level: level,
+            lines: Mutex::new(snd),
+            dropped: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl Log for ThreadLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = time::now_utc();
+        let mut line = format!(
+            "{} {:<5} {}: {}\n",
+            now.rfc3339(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        let dropped = self.dropped.swap(0, Ordering::SeqCst);
+        if dropped > 0 {
+            let warning = format!("{} WARN  {}: ", now.rfc3339(), module_path!());
+            line = format!("{}dropped {} records\n{}", warning, dropped, line);
+        }
+        match self.lines.lock().unwrap().try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(dropped + 1, Ordering::SeqCst);
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Logs the records at `level` or above to stdout through a `ThreadLogger`, unless a logger was
+/// already set, in which case that one is kept.
+pub fn init(level: LevelFilter) -> io::Result<()> {
+    let logger = ThreadLogger::new(level, io::stdout())?;
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// Bytes written by the logger's thread, for the test to look at.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_thread_logger() {
+        let out = Shared::default();
+        let logger = ThreadLogger::new(LevelFilter::Info, out.clone()).unwrap();
+        let record = |level, message| {
+            logger.log(&Record::builder()
+                .level(level)
+                .target("rcache::service")
+                .args(format_args!("{}", message))
+                .build());
+        };
+        record(Level::Debug, "hidden");
+        record(Level::Warn, "shown");
+
+        let started = Instant::now();
+        while out.0.lock().unwrap().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5), "nothing was logged");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let logged = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert!(logged.ends_with(" WARN  rcache::service: shown\n"), "{}", logged);
+        assert_eq!(logged.lines().count(), 1);
+    }
+}
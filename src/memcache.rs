@@ -0,0 +1,551 @@
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use tokio_io::AsyncRead;
+use tokio_io::codec::{Decoder, Encoder};
+use tokio_service::{NewService, Service};
+use bytes::BytesMut;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str;
+
+use cache;
+use message::{self, Code, Message, Op};
+use service;
+use stats::{self, ServerStats};
+
+/// The longest command line accepted, without a terminating newline, before the connection is
+/// closed.
+pub static MAX_LINE_LEN: usize = 2048;
+
+/// The largest value a storage command may carry. A larger one closes the connection.
+pub static MAX_VALUE_LEN: usize = 1 << 20;
+
+/// The longest key memcached allows.
+static MAX_KEY_LEN: usize = 250;
+
+/// Expiration times up to this many seconds are relative to now, later ones are Unix times, as
+/// in memcached.
+static MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+/// A command of the memcached text protocol, as decoded by `MemcacheCodec`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Get(Vec<Vec<u8>>),
+    /// `set`, `add` or `replace`, as `op`.
+    Store {
+        op: Op,
+        key: Vec<u8>,
+        flags: u32,
+        exptime: i64,
+        data: Vec<u8>,
+        noreply: bool,
+    },
+    Delete { key: Vec<u8>, noreply: bool },
+    /// `incr`, or `decr` if `decr` is set.
+    Incr {
+        key: Vec<u8>,
+        delta: u64,
+        decr: bool,
+        noreply: bool,
+    },
+    Stats,
+    Version,
+    Quit,
+    /// A command that couldn't be understood, answered with the reply it holds.
+    Invalid(String),
+}
+
+/// Frames the memcached text protocol: decodes commands, and encodes the replies to them as
+/// they are to be written.
+#[derive(Debug, Default)]
+pub struct MemcacheCodec;
+
+impl Decoder for MemcacheCodec {
+    type Item = Command;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Command>, io::Error> {
+        let line_len = match buf.iter().position(|&b| b == b'\n') {
+            Some(newline) => newline + 1,
+            None if buf.len() > MAX_LINE_LEN => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"))
+            }
+            None => return Ok(None),
+        };
+        let parsed = {
+            let line = trim_newline(&buf[..line_len]);
+            let words: Vec<&[u8]> = line.split(|&b| b == b' ').filter(|w| !w.is_empty()).collect();
+            match words.split_first() {
+                Some((&b"set", args)) => parse_store(Op::Set, args),
+                Some((&b"add", args)) => parse_store(Op::Add, args),
+                Some((&b"replace", args)) => parse_store(Op::Replace, args),
+                _ => Err(parse_command(&words)),
+            }
+        };
+
+        // A storage command is followed by its data block, which is decoded along with it.
+        let (command, data_len) = match parsed {
+            Ok((command, data_len)) => (command, data_len),
+            Err(command) => {
+                buf.split_to(line_len);
+                return Ok(Some(command));
+            }
+        };
+        if data_len > MAX_VALUE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "value too large"));
+        }
+        if buf.len() < line_len + data_len + 2 {
+            return Ok(None);
+        }
+        let block = buf.split_to(line_len + data_len + 2);
+        if &block[line_len + data_len..] != b"\r\n" {
+            return Ok(Some(Command::Invalid("CLIENT_ERROR bad data chunk".to_owned())));
+        }
+        Ok(Some(match command {
+            Command::Store { op, key, flags, exptime, noreply, .. } => {
+                Command::Store {
+                    op: op,
+                    key: key,
+                    flags: flags,
+                    exptime: exptime,
+                    data: block[line_len..line_len + data_len].to_vec(),
+                    noreply: noreply,
+                }
+            }
+            command => command,
+        }))
+    }
+}
+
+impl Encoder for MemcacheCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn encode(&mut self, reply: Vec<u8>, buf: &mut BytesMut) -> io::Result<()> {
+        buf.extend_from_slice(&reply);
+        Ok(())
+    }
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = &line[..line.len() - 1];
+    if line.ends_with(b"\r") {
+        &line[..line.len() - 1]
+    } else {
+        line
+    }
+}
+
+/// Parses the arguments of a storage command, `<key> <flags> <exptime> <bytes> [noreply]`,
+/// returning the command without its data, and the length of the data, or the command to
+/// answer instead if they are invalid.
+fn parse_store(op: Op, args: &[&[u8]]) -> Result<(Command, usize), Command> {
+    let noreply = args.len() == 5 && args[4] == b"noreply";
+    if args.len() != 4 && !noreply {
+        return Err(Command::Invalid("ERROR".to_owned()));
+    }
+    let key = parse_key(args[0]).map_err(Command::Invalid)?;
+    let format = || Command::Invalid("CLIENT_ERROR bad command line format".to_owned());
+    let flags = parse(args[1]).ok_or_else(&format)?;
+    let exptime = parse(args[2]).ok_or_else(&format)?;
+    let data_len = parse(args[3]).ok_or_else(&format)?;
+    let command = Command::Store {
+        op: op,
+        key: key,
+        flags: flags,
+        exptime: exptime,
+        data: vec![],
+        noreply: noreply,
+    };
+    Ok((command, data_len))
+}
+
+/// Parses a command without a data block.
+fn parse_command(words: &[&[u8]]) -> Command {
+    let parsed = match words.split_first() {
+        Some((&b"get", keys)) | Some((&b"gets", keys)) if !keys.is_empty() => {
+            keys.iter().map(|key| parse_key(key)).collect::<Result<_, _>>().map(Command::Get)
+        }
+        Some((&b"delete", args)) if !args.is_empty() && args.len() <= 3 => {
+            // A legacy hold time of 0 may precede noreply.
+            let noreply = args.last() == Some(&&b"noreply"[..]);
+            match (args.len() - noreply as usize, args.get(1)) {
+                (1, _) | (2, Some(&b"0")) => {
+                    parse_key(args[0]).map(|key| Command::Delete { key: key, noreply: noreply })
+                }
+                _ => Err("CLIENT_ERROR bad command line format".to_owned()),
+            }
+        }
+        Some((&b"incr", args)) | Some((&b"decr", args)) if args.len() == 2 || args.len() == 3 => {
+            let noreply = args.len() == 3 && args[2] == b"noreply";
+            match (parse_key(args[0]), parse(args[1])) {
+                (Ok(key), Some(delta)) if args.len() == 2 || noreply => {
+                    Ok(Command::Incr {
+                        key: key,
+                        delta: delta,
+                        decr: words[0] == b"decr",
+                        noreply: noreply,
+                    })
+                }
+                (Err(reply), _) => Err(reply),
+                _ => Err("CLIENT_ERROR invalid numeric delta argument".to_owned()),
+            }
+        }
+        Some((&b"stats", args)) if args.is_empty() => Ok(Command::Stats),
+        Some((&b"version", args)) if args.is_empty() => Ok(Command::Version),
+        Some((&b"quit", args)) if args.is_empty() => Ok(Command::Quit),
+        _ => Err("ERROR".to_owned()),
+    };
+    parsed.unwrap_or_else(Command::Invalid)
+}
+
+fn parse_key(key: &[u8]) -> Result<Vec<u8>, String> {
+    if key.len() > MAX_KEY_LEN || key.iter().any(|&b| b < 0x21 || b == 0x7F) {
+        return Err("CLIENT_ERROR bad key".to_owned());
+    }
+    Ok(key.to_vec())
+}
+
+fn parse<T: str::FromStr>(word: &[u8]) -> Option<T> {
+    str::from_utf8(word).ok().and_then(|word| word.parse().ok())
+}
+
+/// Serves the memcached text protocol at `addr`, on the reactor behind `handle`, translating
+/// each command into requests to a service from `new_service`, such as a `CacheService`, so that
+/// memcached clients can use the cache. The commands understood are `get` and `gets`, `set`,
+/// `add`, `replace`, `delete`, `incr`, `decr`, `stats`, `version` and `quit`. A value's flags
+/// are stored as its payload's `type_id`, and `gets` doesn't return cas values.
+pub fn serve_memcache<T>(addr: &SocketAddr, new_service: T, handle: &Handle) -> io::Result<()>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    let listener = TcpListener::bind(addr, handle)?;
+    let server = memcache_server(listener, new_service, handle.clone());
+    handle.spawn(server.map_err(|e| {
+        error!("Memcache server error: {}.", e);
+    }));
+    Ok(())
+}
+
+/// Answers the commands on each connection accepted by `listener` in turn, until it quits or
+/// closes.
+fn memcache_server<T>(
+    listener: TcpListener,
+    new_service: T,
+    handle: Handle,
+) -> Box<Future<Item = (), Error = io::Error>>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    Box::new(listener.incoming().for_each(move |(socket, peer)| {
+        let service = Rc::new(service::new_service_for(&new_service, peer)?);
+        let (replies, commands) = socket.framed(MemcacheCodec).split();
+        let connection = commands
+            .take_while(|command| Ok(*command != Command::Quit))
+            .and_then(move |command| execute(&service, command))
+            .forward(replies);
+        handle.spawn(connection.then(|result| {
+            if let Err(e) = result {
+                warn!("Memcache connection error: {}.", e);
+            }
+            Ok(())
+        }));
+        Ok(())
+    }))
+}
+
+/// Carries out `command` with requests to `service`, resolving to the reply to write, which is
+/// empty if the command asked for none.
+pub fn execute<S>(
+    service: &Rc<S>,
+    command: Command,
+) -> Box<Future<Item = Vec<u8>, Error = io::Error>>
+where
+    S: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+    S::Future: 'static,
+{
+    match command {
+        Command::Get(keys) => {
+            let service = service.clone();
+            let gets = keys.into_iter().map(move |key| {
+                service.call(message::request(Op::Get, key.clone(), None)).map(|resp| (key, resp))
+            });
+            Box::new(future::join_all(gets).map(|responses| {
+                let mut reply = vec![];
+                for (key, resp) in responses {
+                    if let (Code::Hit, Some(payload)) = (resp.code(), resp.payload()) {
+                        reply.extend_from_slice(b"VALUE ");
+                        reply.extend_from_slice(&key);
+                        let header = format!(" {} {}\r\n", payload.type_id(), payload.data().len());
+                        reply.extend_from_slice(header.as_bytes());
+                        reply.extend_from_slice(payload.data());
+                        reply.extend_from_slice(b"\r\n");
+                    }
+                }
+                reply.extend_from_slice(b"END\r\n");
+                reply
+            }))
+        }
+        Command::Store { op, key, flags, exptime, data, noreply } => {
+            let mut req = message::request(op, key, Some(message::payload(flags, data)));
+            if exptime < 0 {
+                req = req.with_extension(message::EXT_TTL, message::encode_u64(0));
+            } else if exptime > MAX_RELATIVE_EXPTIME {
+                let at = message::encode_u64(exptime as u64);
+                req = req.with_extension(message::EXT_EXPIRES_AT, at);
+            } else if exptime > 0 {
+                req = req.with_extension(message::EXT_TTL, message::encode_u64(exptime as u64));
+            }
+            reply(service.call(req), noreply, |code| match code {
+                Code::Ok => "STORED",
+                Code::Conflict | Code::Miss => "NOT_STORED",
+                _ => "",
+            })
+        }
+        Command::Delete { key, noreply } => {
+            let req = message::request(Op::Del, key, None);
+            reply(service.call(req), noreply, |code| match code {
+                Code::Ok => "DELETED",
+                Code::Miss => "NOT_FOUND",
+                _ => "",
+            })
+        }
+        Command::Incr { key, delta, decr, noreply } => {
+            let incr = incr(service.clone(), key, delta, decr);
+            Box::new(incr.map(move |reply| if noreply { vec![] } else { reply.into_bytes() }))
+        }
+        Command::Stats => {
+            let req = message::request(Op::Stats, vec![], None);
+            Box::new(service.call(req).map(|resp| stats_reply(&resp).into_bytes()))
+        }
+        Command::Version => {
+            Box::new(future::ok(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes()))
+        }
+        Command::Quit => Box::new(future::ok(vec![])),
+        Command::Invalid(reply) => Box::new(future::ok(format!("{}\r\n", reply).into_bytes())),
+    }
+}
+
+/// Replies to a command answered by `response` with the line `line` gives for its code, or a
+/// server error if it gives none.
+fn reply<F, L>(
+    response: F,
+    noreply: bool,
+    line: L,
+) -> Box<Future<Item = Vec<u8>, Error = io::Error>>
+where
+    F: Future<Item = Message, Error = io::Error> + 'static,
+    L: Fn(Code) -> &'static str + 'static,
+{
+    Box::new(response.map(move |resp| {
+        if noreply {
+            return vec![];
+        }
+        match line(resp.code()) {
+            "" => server_error(resp.code()),
+            line => format!("{}\r\n", line),
+        }.into_bytes()
+    }))
+}
+
+fn server_error(code: Code) -> String {
+    match code {
+        Code::QuotaExceeded => "SERVER_ERROR out of memory storing object\r\n".to_owned(),
+        Code::ValueTooLarge => "SERVER_ERROR object too large for cache\r\n".to_owned(),
+        Code::KeyTooLarge => "CLIENT_ERROR bad command line format\r\n".to_owned(),
+        code => format!("SERVER_ERROR {}\r\n", code),
+    }
+}
+
+/// Increments, or decrements, the decimal value of `key` by `delta`, as memcached does: the
+/// value must be a decimal number, increments wrap around at 2^64 and decrements stop at 0.
+/// The value is read and written back with an `Op::Cas`, retrying if it changed meanwhile, and
+/// loses its expiration time.
+fn incr<S>(
+    service: Rc<S>,
+    key: Vec<u8>,
+    delta: u64,
+    decr: bool,
+) -> Box<Future<Item = String, Error = io::Error>>
+where
+    S: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+    S::Future: 'static,
+{
+    Box::new(future::loop_fn((), move |()| {
+        let (service, key) = (service.clone(), key.clone());
+        service.call(message::request(Op::Get, key.clone(), None)).and_then(move |resp| {
+            let (payload, version) = match (resp.code(), resp.payload()) {
+                (Code::Hit, Some(payload)) => {
+                    let version = resp.extension(message::EXT_VERSION).map(message::decode_u64);
+                    (payload.clone(), version)
+                }
+                (Code::Miss, _) => return future::Either::A(future::ok(done("NOT_FOUND"))),
+                (code, _) => return future::Either::A(future::ok(Loop::Break(server_error(code)))),
+            };
+            let current = match (parse::<u64>(payload.data()), version) {
+                (Some(current), Some(Ok(version))) => (current, version),
+                _ => {
+                    let reply = "CLIENT_ERROR cannot increment or decrement non-numeric value";
+                    return future::Either::A(future::ok(done(reply)));
+                }
+            };
+            let value = if decr {
+                current.0.saturating_sub(delta)
+            } else {
+                current.0.wrapping_add(delta)
+            };
+            let data = value.to_string().into_bytes();
+            let payload = message::payload(payload.type_id(), data);
+            let req = message::request(Op::Cas, key, Some(payload))
+                .with_extension(message::EXT_VERSION, message::encode_u64(current.1));
+            future::Either::B(service.call(req).map(move |resp| match resp.code() {
+                Code::Ok => Loop::Break(format!("{}\r\n", value)),
+                Code::CasMismatch => Loop::Continue(()),
+                Code::Miss => done("NOT_FOUND"),
+                code => Loop::Break(server_error(code)),
+            }))
+        })
+    }))
+}
+
+fn done(line: &str) -> Loop<String, ()> {
+    Loop::Break(format!("{}\r\n", line))
+}
+
+/// The reply to `stats`, from the response to an `Op::Stats`: the cache's own stats, and those
+/// of the server if a `StatService` answered.
+fn stats_reply(resp: &Message) -> String {
+    let (cache, server) = match resp.payload() {
+        Some(payload) if payload.type_id() == stats::STATS_TYPE_ID => {
+            match ServerStats::decode(payload.data()) {
+                Ok(stats) => (stats.cache, Some((stats.uptime, stats.requests))),
+                Err(_) => (None, None),
+            }
+        }
+        Some(payload) => (cache::decode_stats(payload).ok(), None),
+        None => (None, None),
+    };
+    let mut reply = String::new();
+    if let Some((uptime, _)) = server {
+        writeln!(reply, "STAT uptime {}\r", uptime).unwrap();
+    }
+    writeln!(reply, "STAT version {}\r", env!("CARGO_PKG_VERSION")).unwrap();
+    if let Some(cache) = cache {
+        writeln!(reply, "STAT curr_items {}\r", cache.keys).unwrap();
+        writeln!(reply, "STAT bytes {}\r", cache.used_bytes).unwrap();
+        writeln!(reply, "STAT evictions {}\r", cache.evictions).unwrap();
+    }
+    if let Some((_, requests)) = server {
+        writeln!(reply, "STAT curr_connections {}\r", requests.connections).unwrap();
+        let gets = requests.requests_by_op.get(&Op::Get).cloned().unwrap_or(0);
+        let sets = requests.requests_by_op.get(&Op::Set).cloned().unwrap_or(0);
+        writeln!(reply, "STAT cmd_get {}\r", gets).unwrap();
+        writeln!(reply, "STAT cmd_set {}\r", sets).unwrap();
+        writeln!(reply, "STAT get_hits {}\r", requests.hits).unwrap();
+        writeln!(reply, "STAT get_misses {}\r", requests.misses).unwrap();
+        writeln!(reply, "STAT bytes_read {}\r", requests.bytes_in).unwrap();
+        writeln!(reply, "STAT bytes_written {}\r", requests.bytes_out).unwrap();
+    }
+    reply.push_str("END\r\n");
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use service::CacheService;
+    use std::sync::Arc;
+
+    fn decode_all(input: &[u8]) -> Vec<Command> {
+        let mut buf = BytesMut::from(input);
+        let mut commands = vec![];
+        while let Some(command) = MemcacheCodec.decode(&mut buf).unwrap() {
+            commands.push(command);
+        }
+        assert!(buf.is_empty());
+        commands
+    }
+
+    #[test]
+    fn test_decode() {
+        let commands = decode_all(
+            b"get a b\r\nset foo 3 0 5 noreply\r\nhello\r\ndelete foo\r\nincr n 2\nbogus\r\n\
+              set foo x 0 1\r\nget\r\n",
+        );
+        assert_eq!(
+            commands,
+            vec![
+                Command::Get(vec!["a".into(), "b".into()]),
+                Command::Store {
+                    op: Op::Set,
+                    key: "foo".into(),
+                    flags: 3,
+                    exptime: 0,
+                    data: "hello".into(),
+                    noreply: true,
+                },
+                Command::Delete { key: "foo".into(), noreply: false },
+                Command::Incr {
+                    key: "n".into(),
+                    delta: 2,
+                    decr: false,
+                    noreply: false,
+                },
+                Command::Invalid("ERROR".to_owned()),
+                Command::Invalid("CLIENT_ERROR bad command line format".to_owned()),
+                Command::Invalid("ERROR".to_owned()),
+            ]
+        );
+
+        // A storage command waits for its data, which must end with a newline.
+        let mut buf = BytesMut::from(&b"add foo 0 0 5\r\nhel"[..]);
+        assert_eq!(MemcacheCodec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"loXX");
+        assert_eq!(
+            MemcacheCodec.decode(&mut buf).unwrap(),
+            Some(Command::Invalid("CLIENT_ERROR bad data chunk".to_owned()))
+        );
+        let mut buf = BytesMut::from(vec![b'a'; MAX_LINE_LEN + 1]);
+        assert!(MemcacheCodec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_execute() {
+        let service = Rc::new(CacheService { cache: Arc::new(cache::Cache::new(10).unwrap()) });
+        let run = |input: &[u8]| -> String {
+            let replies: Vec<_> = decode_all(input)
+                .into_iter()
+                .map(|command| execute(&service, command).wait().unwrap())
+                .collect();
+            String::from_utf8(replies.concat()).unwrap()
+        };
+
+        assert_eq!(run(b"set foo 5 0 3\r\nbar\r\n"), "STORED\r\n");
+        assert_eq!(run(b"add foo 0 0 1\r\nx\r\n"), "NOT_STORED\r\n");
+        assert_eq!(run(b"replace nope 0 0 1\r\nx\r\n"), "NOT_STORED\r\n");
+        assert_eq!(
+            run(b"get foo nope foo\r\n"),
+            "VALUE foo 5 3\r\nbar\r\nVALUE foo 5 3\r\nbar\r\nEND\r\n"
+        );
+        assert_eq!(run(b"set n 0 0 2 noreply\r\n10\r\nincr n 5\r\n"), "15\r\n");
+        assert_eq!(run(b"decr n 20\r\nget n\r\n"), "0\r\nVALUE n 0 1\r\n0\r\nEND\r\n");
+        assert_eq!(
+            run(b"incr foo 1\r\n"),
+            "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+        );
+        assert_eq!(run(b"incr nope 1\r\n"), "NOT_FOUND\r\n");
+        assert_eq!(run(b"delete foo\r\ndelete foo\r\n"), "DELETED\r\nNOT_FOUND\r\n");
+
+        // A negative expiration time expires the value at once.
+        assert_eq!(run(b"set gone 0 -1 1\r\nx\r\nget gone\r\n"), "STORED\r\nEND\r\n");
+        assert!(run(b"stats\r\n").contains("STAT curr_items 1\r\n"));
+    }
+}
@@ -1,26 +1,109 @@
 use std::convert::TryFrom;
+use std::collections::BTreeMap;
+use std::io;
+use bytes::{Buf, BufMut, BigEndian, Bytes};
 use error;
 use std::fmt;
 
+/// Optional protocol extensions carried alongside a message, keyed by extension type.
+/// See `codec::CacheCodec` for the framing.
+pub type Extensions = BTreeMap<u16, Vec<u8>>;
+
+/// Extension carrying an opaque idempotency token on `Op::Set`. If it matches the token of the
+/// last Set applied to the key, the Set is skipped and answered with `Code::AlreadyApplied`.
+pub const EXT_IDEMPOTENCY_TOKEN: u16 = 1;
+
+/// Extension carrying an entry's version as a u64. Versions increase with every write, so a
+/// larger version is a newer value. Responses to reads and writes carry the entry's current
+/// version; `Op::GetIfNewer` requests carry the version the client already has, and `Op::Cas` and
+/// `Op::CasDel` requests the version they expect to replace.
+pub const EXT_VERSION: u16 = 2;
+
+/// Extension naming, in UTF-8, the registered function an `Op::Apply` request runs.
+pub const EXT_FUNCTION: u16 = 3;
+
+/// Extension carrying, on `Op::Set` or `Op::Expire`, the absolute time at which the entry expires,
+/// as a u64 count of seconds since the Unix epoch. The entry expires then however often it is read.
+/// Hits answering `Op::Get` and `Op::GetIfNewer` carry it too, for entries that expire.
+pub const EXT_EXPIRES_AT: u16 = 4;
+
+/// Extension carrying, on `Op::Set` or `Op::Expire`, how long the entry lives for, as a u64 count
+/// of seconds from when the request is applied. Given along with `EXT_EXPIRES_AT`, the entry
+/// expires at the earlier of the two. Requests with a TTL longer than `cache::MAX_TTL` are refused.
+pub const EXT_TTL: u16 = 5;
+
+/// Extension carrying, on `Op::Scan` or `Op::ScanStream`, a glob pattern the keys returned must
+/// match. `*` matches any run of bytes, `?` any single byte, and every other byte itself, so a
+/// prefix scan is the prefix followed by `*`.
+pub const EXT_PATTERN: u16 = 6;
+
+/// Extension naming the namespace a request addresses, up to 255 bytes. Keys in different
+/// namespaces never collide, and a request only sees the keys of its own namespace. Without the
+/// extension, or with an empty name, a request addresses the default namespace, where keys
+/// starting with 0xFF are reserved and refused.
+pub const EXT_NAMESPACE: u16 = 7;
+
+/// Extension carrying the sequence number of a chunk of a payload sent in chunks, as a u32,
+/// followed on the first chunk by the length of the whole payload as a u64, see
+/// `codec::CacheCodec`. The codec adds and removes it, so it is never seen on a whole message.
+pub const EXT_CHUNK: u16 = 8;
+
+/// Extension on the responses to `Op::Hello` and `Op::Stats` carrying the longest key the cache
+/// takes, as a u64, if limited, see `cache::decode_limits`.
+pub const EXT_MAX_KEY_LEN: u16 = 9;
+
+/// Extension on the responses to `Op::Hello` and `Op::Stats` carrying the longest value the
+/// cache takes, as a u64, if limited, see `cache::decode_limits`.
+pub const EXT_MAX_VALUE_LEN: u16 = 10;
+
+/// Extension types understood by this version of the server. In strict mode the codec drops any
+/// other extension type on decode.
+pub static KNOWN_EXTENSIONS: &'static [u16] = &[
+    EXT_IDEMPOTENCY_TOKEN,
+    EXT_VERSION,
+    EXT_FUNCTION,
+    EXT_EXPIRES_AT,
+    EXT_TTL,
+    EXT_PATTERN,
+    EXT_NAMESPACE,
+    EXT_CHUNK,
+    EXT_MAX_KEY_LEN,
+    EXT_MAX_VALUE_LEN,
+];
+
+/// The `type_id` of a payload holding a big endian i64, as kept by `Op::Incr` and `Op::Decr`.
+/// By convention 1 is a UTF-8 string.
+pub const TYPE_I64: u32 = 2;
+
 /// `Message`
+///
+/// Keys and payloads are held as `Bytes`, so that those decoded from a frame can be stored by the
+/// cache and sent back out without being copied, and cloning a message is cheap however large
+/// its payload.
 #[derive(Debug, PartialEq, Clone)]
 pub enum Message {
-    Request(Op, Vec<u8>, Option<Payload>),
-    Response(Op, Code, Option<Payload>),
+    Request(Op, Bytes, Option<Payload>, Extensions),
+    Response(Op, Code, Option<Payload>, Extensions),
 }
 
+/// A request for `key`. The key is moved into the message, not copied, as is a payload's data.
 pub fn request(op: Op, key: Vec<u8>, payload: Option<Payload>) -> Message {
-    Message::Request(op, key, payload)
+    Message::Request(op, key.into(), payload, Extensions::new())
 }
 
 pub fn response(op: Op, code: Code, payload: Option<Payload>) -> Message {
-    Message::Response(op, code, payload)
+    Message::Response(op, code, payload, Extensions::new())
+}
+
+/// A `Code::ServerError` response to `op`, describing what went wrong.
+pub fn server_error(op: Op, description: &str) -> Message {
+    response(op, Code::ServerError, Some(payload(0, description.as_bytes().to_vec())))
 }
 
 impl Message {
     pub fn key(&self) -> Option<&[u8]> {
         match *self {
-            Message::Request(_, ref key, _) => Some(key.as_slice()),
+            Message::Request(_, ref key, ..) => Some(&key[..]),
             Message::Response(..) => None,
         }
     }
@@ -40,21 +123,54 @@ impl Message {
     }
     pub fn type_id(&self) -> Option<u32> {
         match *self {
-            Message::Request(_, _, ref payload) => payload.as_ref().map(|p| p.type_id),
-            Message::Response(_, _, ref payload) => payload.as_ref().map(|p| p.type_id),
+            Message::Request(_, _, ref payload, _) => payload.as_ref().map(|p| p.type_id),
+            Message::Response(_, _, ref payload, _) => payload.as_ref().map(|p| p.type_id),
         }
     }
 
     pub fn payload(&self) -> Option<&Payload> {
         match *self {
-            Message::Request(_, _, ref payload) |
-            Message::Response(_, _, ref payload) => payload.as_ref(),
+            Message::Request(_, _, ref payload, _) |
+            Message::Response(_, _, ref payload, _) => payload.as_ref(),
         }
     }
 
-    pub fn consume_request(self) -> Result<(Vec<u8>, Option<Payload>), error::Error> {
+    pub fn extensions(&self) -> &Extensions {
+        match *self {
+            Message::Request(_, _, _, ref extensions) |
+            Message::Response(_, _, _, ref extensions) => extensions,
+        }
+    }
+
+    pub fn extension(&self, ext_type: u16) -> Option<&[u8]> {
+        self.extensions().get(&ext_type).map(|v| v.as_slice())
+    }
+
+    /// Attach an extension to the message, replacing any existing extension of the same type.
+    pub fn with_extension(mut self, ext_type: u16, value: Vec<u8>) -> Self {
+        match self {
+            Message::Request(_, _, _, ref mut extensions) |
+            Message::Response(_, _, _, ref mut extensions) => {
+                extensions.insert(ext_type, value);
+            }
+        }
+        self
+    }
+
+    /// Remove the extension of type `ext_type` from the message, if it has one.
+    pub fn without_extension(mut self, ext_type: u16) -> Self {
         match self {
-            Message::Request(_, key, payload) => Ok((key, payload)),
+            Message::Request(_, _, _, ref mut extensions) |
+            Message::Response(_, _, _, ref mut extensions) => {
+                extensions.remove(&ext_type);
+            }
+        }
+        self
+    }
+
+    pub fn consume_request(self) -> Result<(Bytes, Option<Payload>), error::Error> {
+        match self {
+            Message::Request(_, key, payload, _) => Ok((key, payload)),
             Message::Response(..) => Err(error::Error::new(
                 error::ErrorKind::BadMessage,
                 "expected a request, got a response",
@@ -63,7 +179,7 @@ impl Message {
     }
     pub fn consume_response(self) -> Result<(Op, Code, Option<Payload>), error::Error> {
         match self {
-            Message::Response(op, code, payload) => Ok((op, code, payload)),
+            Message::Response(op, code, payload, _) => Ok((op, code, payload)),
             Message::Request(..) => Err(error::Error::new(
                 error::ErrorKind::BadMessage,
                 "expected a request, got a response",
@@ -75,13 +191,13 @@ impl Message {
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Message::Request(ref op, ref key, ref payload) => {
+            Message::Request(ref op, ref key, ref payload, _) => {
                 match *payload {
                     Some(ref payload) => write!(f, "Request[Op={}, Key={:?}] {}", op, key, payload.clone()),
                     None => write!(f, "Request[Op={}, Key={:?}]", op, key),
                 }
             }
-            Message::Response(ref op, ref code, ref payload) => {
+            Message::Response(ref op, ref code, ref payload, _) => {
                 match *payload {
                     Some(ref payload) => write!(f, "Response[Op={}, Code={}] {:?}", op, code, payload.clone()),
                     None => write!(f, "Request[Op={}, Code={}]", op, code),
@@ -95,23 +211,43 @@ impl fmt::Display for Message {
 #[derive(Debug, PartialEq, Clone)]
 pub struct Payload {
     type_id: u32,
-    data: Vec<u8>,
+    data: Bytes,
 }
 
 impl Payload {
+    /// A payload sharing `data`, such as a slice of the frame it was decoded from.
+    pub fn new(type_id: u32, data: Bytes) -> Self {
+        Payload {
+            type_id: type_id,
+            data: data,
+        }
+    }
     pub fn data(&self) -> &[u8] {
-        self.data.as_slice()
+        &self.data
+    }
+    /// The data, shared rather than copied.
+    pub fn bytes(&self) -> Bytes {
+        self.data.clone()
     }
     pub fn type_id(&self) -> u32 {
         self.type_id
     }
+    pub fn set_data(&mut self, data: Bytes) {
+        self.data = data;
+    }
+    pub fn set_type_id(&mut self, type_id: u32) {
+        self.type_id = type_id;
+    }
 }
 
 pub fn payload(type_id: u32, data: Vec<u8>) -> Payload {
-    Payload {
-        type_id: type_id,
-        data: data,
-    }
+    Payload::new(type_id, data.into())
+}
+
+/// A payload carrying nothing but `flags`, in its `type_id`. It holds a single zero byte, as a
+/// payload without data is sent without its `type_id`, see `codec::CacheCodec`.
+pub fn flags(flags: u32) -> Payload {
+    payload(flags, vec![0])
 }
 
 impl fmt::Display for Payload {
@@ -120,13 +256,321 @@ impl fmt::Display for Payload {
     }
 }
 
+/// `type_id` of a payload holding a list of keys, as packed by `encode_keys`.
+pub const KEY_LIST_TYPE_ID: u32 = 16;
+
+/// Packs `keys` as a sequence of u32 length prefixed byte strings.
+pub fn encode_keys(keys: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(keys.iter().map(|k| 4 + k.len()).sum());
+    for key in keys {
+        data.put_u32::<BigEndian>(key.len() as u32);
+        data.put_slice(key);
+    }
+    data
+}
+
+/// Appends an entry of `key` and its value to `buf`: the key as a u32 length prefixed byte
+/// string, then the value's `type_id` as a u32 and its data as a u64 length prefixed byte string,
+/// all integers big endian. Entry lists, snapshots and the append log all lay out entries so.
+pub fn put_entry(buf: &mut Vec<u8>, key: &[u8], payload: &Payload) {
+    buf.reserve(4 + key.len() + 4 + 8 + payload.data().len());
+    buf.put_u32::<BigEndian>(key.len() as u32);
+    buf.put_slice(key);
+    buf.put_u32::<BigEndian>(payload.type_id());
+    buf.put_u64::<BigEndian>(payload.data().len() as u64);
+    buf.put_slice(payload.data());
+}
+
+/// Takes the entry put by `put_entry` at the cursor, or `None` if the data ends part way
+/// through it.
+pub fn get_entry(cursor: &mut io::Cursor<&[u8]>) -> Option<(Vec<u8>, Payload)> {
+    if cursor.remaining() < 4 {
+        return None;
+    }
+    let len = cursor.get_u32::<BigEndian>() as usize;
+    if cursor.remaining() < len || cursor.remaining() - len < 4 + 8 {
+        return None;
+    }
+    let mut key = vec![0; len];
+    cursor.copy_to_slice(&mut key);
+    let type_id = cursor.get_u32::<BigEndian>();
+    let len = cursor.get_u64::<BigEndian>() as usize;
+    if cursor.remaining() < len {
+        return None;
+    }
+    let mut value = vec![0; len];
+    cursor.copy_to_slice(&mut value);
+    Some((key, payload(type_id, value)))
+}
+
+/// `type_id` of a payload holding a list of entries, as packed by `encode_entries`.
+pub const ENTRY_LIST_TYPE_ID: u32 = 17;
+
+/// Packs `entries` of a key and its value as a sequence of them, see `put_entry`.
+pub fn encode_entries(entries: &[(Vec<u8>, Payload)]) -> Vec<u8> {
+    let mut data = vec![];
+    for &(ref key, ref payload) in entries {
+        put_entry(&mut data, key, payload);
+    }
+    data
+}
+
+/// Unpacks a list of entries packed by `encode_entries`.
+pub fn decode_entries(data: &[u8]) -> Result<Vec<(Vec<u8>, Payload)>, error::Error> {
+    let mut entries = vec![];
+    let mut cursor = io::Cursor::new(data);
+    while cursor.remaining() > 0 {
+        match get_entry(&mut cursor) {
+            Some(entry) => entries.push(entry),
+            None => {
+                return Err(error::Error::new(
+                    error::ErrorKind::InvalidData,
+                    "truncated entry list",
+                ))
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// `type_id` of a payload holding a list of messages, as packed by `encode_messages`.
+pub const MESSAGE_LIST_TYPE_ID: u32 = 22;
+
+/// Packs `messages`, all integers big endian. For each message: its op and its code as u8s,
+/// `Code::Req` for a request, its key as a u32 length prefixed byte string, empty for a
+/// response, the number of its extensions as a u16, followed by each one's type as a u16 and
+/// its value as a u32 length prefixed byte string, and a byte that is 1 if a payload follows, as
+/// its `type_id` as a u32 and its data as a u64 length prefixed byte string.
+pub fn encode_messages(messages: &[Message]) -> Vec<u8> {
+    let mut data = vec![];
+    for msg in messages {
+        data.put_u8(msg.op() as u8);
+        data.put_u8(msg.code() as u8);
+        let key = msg.key().unwrap_or_default();
+        data.put_u32::<BigEndian>(key.len() as u32);
+        data.put_slice(key);
+        data.put_u16::<BigEndian>(msg.extensions().len() as u16);
+        for (&ext_type, value) in msg.extensions() {
+            data.put_u16::<BigEndian>(ext_type);
+            data.put_u32::<BigEndian>(value.len() as u32);
+            data.put_slice(value);
+        }
+        match msg.payload() {
+            Some(payload) => {
+                data.put_u8(1);
+                data.put_u32::<BigEndian>(payload.type_id());
+                data.put_u64::<BigEndian>(payload.data().len() as u64);
+                data.put_slice(payload.data());
+            }
+            None => data.put_u8(0),
+        }
+    }
+    data
+}
+
+/// Unpacks a list of messages packed by `encode_messages`.
+pub fn decode_messages(data: &[u8]) -> Result<Vec<Message>, error::Error> {
+    let truncated = || error::Error::new(error::ErrorKind::InvalidData, "truncated message list");
+    let mut messages = vec![];
+    let mut cursor = io::Cursor::new(data);
+    let bytes = |cursor: &mut io::Cursor<&[u8]>, len: usize| {
+        if cursor.remaining() < len {
+            return Err(truncated());
+        }
+        let mut bytes = vec![0; len];
+        cursor.copy_to_slice(&mut bytes);
+        Ok(bytes)
+    };
+    while cursor.remaining() > 0 {
+        if cursor.remaining() < 1 + 1 + 4 {
+            return Err(truncated());
+        }
+        let op = Op::try_from(cursor.get_u8())?;
+        let code = Code::try_from(cursor.get_u8())?;
+        let len = cursor.get_u32::<BigEndian>() as usize;
+        let key = bytes(&mut cursor, len)?;
+        if cursor.remaining() < 2 {
+            return Err(truncated());
+        }
+        let mut extensions = Extensions::new();
+        for _ in 0..cursor.get_u16::<BigEndian>() {
+            if cursor.remaining() < 2 + 4 {
+                return Err(truncated());
+            }
+            let ext_type = cursor.get_u16::<BigEndian>();
+            let len = cursor.get_u32::<BigEndian>() as usize;
+            extensions.insert(ext_type, bytes(&mut cursor, len)?);
+        }
+        if cursor.remaining() < 1 {
+            return Err(truncated());
+        }
+        let payload = if cursor.get_u8() == 1 {
+            if cursor.remaining() < 4 + 8 {
+                return Err(truncated());
+            }
+            let type_id = cursor.get_u32::<BigEndian>();
+            let len = cursor.get_u64::<BigEndian>() as usize;
+            Some(payload(type_id, bytes(&mut cursor, len)?))
+        } else {
+            None
+        };
+        messages.push(match code {
+            Code::Req => Message::Request(op, key.into(), payload, extensions),
+            code => Message::Response(op, code, payload, extensions),
+        });
+    }
+    Ok(messages)
+}
+
+/// Unpacks a list of keys packed by `encode_keys`.
+pub fn decode_keys(data: &[u8]) -> Result<Vec<Vec<u8>>, error::Error> {
+    let mut keys = vec![];
+    let mut cursor = io::Cursor::new(data);
+    while cursor.remaining() > 0 {
+        if cursor.remaining() < 4 {
+            return Err(error::Error::new(error::ErrorKind::InvalidData, "truncated key list"));
+        }
+        let len = cursor.get_u32::<BigEndian>() as usize;
+        if cursor.remaining() < len {
+            return Err(error::Error::new(error::ErrorKind::InvalidData, "truncated key list"));
+        }
+        let mut key = vec![0; len];
+        cursor.copy_to_slice(&mut key);
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+pub fn encode_u32(n: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4);
+    data.put_u32::<BigEndian>(n);
+    data
+}
+
+pub fn decode_u32(data: &[u8]) -> Result<u32, error::Error> {
+    if data.len() != 4 {
+        return Err(error::Error::new(error::ErrorKind::InvalidData, "expected a u32"));
+    }
+    Ok(io::Cursor::new(data).get_u32::<BigEndian>())
+}
+
+pub fn encode_u64(n: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8);
+    data.put_u64::<BigEndian>(n);
+    data
+}
+
+pub fn decode_u64(data: &[u8]) -> Result<u64, error::Error> {
+    if data.len() != 8 {
+        return Err(error::Error::new(error::ErrorKind::InvalidData, "expected a u64"));
+    }
+    Ok(io::Cursor::new(data).get_u64::<BigEndian>())
+}
+
 /// `Op`
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
 pub enum Op {
     Set = 0,
     Get = 1,
     Del = 2,
     Stats = 3,
+    Rename = 4,
+    Sample = 5,
+    Scan = 6,
+    ScanStream = 7,
+    GetIfNewer = 8,
+    MultiDel = 9,
+    Apply = 10,
+    Retype = 11,
+    Info = 12,
+    FieldIncr = 13,
+    Hello = 14,
+    Range = 15,
+    SetIfEmpty = 16,
+    CasDel = 17,
+    Cas = 18,
+    Incr = 19,
+    Decr = 20,
+    MGet = 21,
+    Touch = 22,
+    Expire = 23,
+    Auth = 24,
+    Append = 25,
+    Prepend = 26,
+    Add = 27,
+    Replace = 28,
+    FlushNamespace = 29,
+    FlushAll = 30,
+    Ping = 31,
+    Subscribe = 32,
+    Unsubscribe = 33,
+    /// Pushed by the server to a subscribed connection, see `pubsub::Notification`.
+    Notify = 34,
+    /// Asks the server to stream the cache's writes to the connection, see
+    /// `service::ReplicateService`. The writes are pushed as `Op::Replicate` frames.
+    Replicate = 35,
+    /// Assigns hash slots to a node in cluster mode, see `cluster::assign_request`.
+    AssignSlots = 36,
+    /// Lists the keys requested most lately, with how often, see `cache::Options::hot_keys`.
+    HotKeys = 37,
+    /// Lists the latest slow requests, see `service::SlowLogService`.
+    SlowLog = 38,
+    /// Whether a key is present, answered with `Code::Hit` or `Code::Miss` and no payload.
+    Exists = 39,
+    /// How long a key has left to live, see `cache::decode_ttl`. Doesn't count as using the key.
+    Ttl = 40,
+    /// What the cache knows about a key besides its value, see `cache::decode_inspect`. Doesn't
+    /// count as reading the value.
+    Inspect = 41,
+    /// Applies several writes at once, all or none of them, see `cache::batch_request`.
+    Batch = 42,
+    /// Answers with the version of a key's entry, as an `EXT_VERSION` extension, or the cache's
+    /// latest version if there is none, which the key is then known to be missing as of. Given a
+    /// version, it is also answered with `Code::Conflict` unless the entry is still at it, or, if
+    /// missing, it wasn't removed since, see `service::TransactionService`.
+    Watch = 43,
+    /// Starts a transaction, see `service::TransactionService`.
+    Multi = 44,
+    /// Applies a transaction.
+    Exec = 45,
+    /// Drops a transaction.
+    Discard = 46,
+    /// Lists the clients connected to the server, see `service::ClientService`.
+    ClientList = 47,
+    /// Closes a client's connection, see `service::ClientService`.
+    Kick = 48,
+    /// Streams the entries of the keyspace, see `service::dump_stream` and `cache::dump_request`.
+    Dump = 49,
+    /// Stores a batch of entries, as dumped by `Op::Dump`, see `cache::restore_request`.
+    Restore = 50,
+    /// Panics the worker, to exercise its panic handling. The last op code there can be, since
+    /// the top bit of the op byte flags the extensions, see `codec`.
+    #[cfg(test)]
+    Panic = 127,
+}
+
+impl Op {
+    /// Whether the op can change what the cache holds. Ops added later count as writes until
+    /// they are listed here.
+    pub fn is_write(self) -> bool {
+        match self {
+            Op::Get | Op::Stats | Op::Sample | Op::Scan | Op::ScanStream | Op::GetIfNewer |
+            Op::Info | Op::Hello | Op::Range | Op::MGet | Op::Auth | Op::Ping | Op::Subscribe |
+            Op::Unsubscribe | Op::Notify | Op::Replicate | Op::HotKeys | Op::SlowLog |
+            Op::Exists | Op::Ttl | Op::Inspect | Op::Watch | Op::Multi | Op::Discard |
+            Op::ClientList | Op::Dump => false,
+            _ => true,
+        }
+    }
+
+    /// Whether the op needs `service::Role::Admin` when connections authenticate.
+    pub fn is_admin(self) -> bool {
+        match self {
+            Op::FlushAll | Op::Replicate | Op::AssignSlots | Op::SlowLog | Op::ClientList |
+            Op::Kick | Op::Dump | Op::Restore => true,
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Op {
@@ -136,6 +580,55 @@ impl fmt::Display for Op {
             Op::Get => "Get",
             Op::Del => "Del",
             Op::Stats => "Stats",
+            Op::Rename => "Rename",
+            Op::Sample => "Sample",
+            Op::Scan => "Scan",
+            Op::ScanStream => "ScanStream",
+            Op::GetIfNewer => "GetIfNewer",
+            Op::MultiDel => "MultiDel",
+            Op::Apply => "Apply",
+            Op::Retype => "Retype",
+            Op::Info => "Info",
+            Op::FieldIncr => "FieldIncr",
+            Op::Hello => "Hello",
+            Op::Range => "Range",
+            Op::SetIfEmpty => "SetIfEmpty",
+            Op::CasDel => "CasDel",
+            Op::Cas => "Cas",
+            Op::Incr => "Incr",
+            Op::Decr => "Decr",
+            Op::MGet => "MGet",
+            Op::Touch => "Touch",
+            Op::Expire => "Expire",
+            Op::Auth => "Auth",
+            Op::Append => "Append",
+            Op::Prepend => "Prepend",
+            Op::Add => "Add",
+            Op::Replace => "Replace",
+            Op::FlushNamespace => "FlushNamespace",
+            Op::FlushAll => "FlushAll",
+            Op::Ping => "Ping",
+            Op::Subscribe => "Subscribe",
+            Op::Unsubscribe => "Unsubscribe",
+            Op::Notify => "Notify",
+            Op::Replicate => "Replicate",
+            Op::AssignSlots => "AssignSlots",
+            Op::HotKeys => "HotKeys",
+            Op::SlowLog => "SlowLog",
+            Op::Exists => "Exists",
+            Op::Ttl => "Ttl",
+            Op::Inspect => "Inspect",
+            Op::Batch => "Batch",
+            Op::Watch => "Watch",
+            Op::Multi => "Multi",
+            Op::Exec => "Exec",
+            Op::Discard => "Discard",
+            Op::ClientList => "ClientList",
+            Op::Kick => "Kick",
+            Op::Dump => "Dump",
+            Op::Restore => "Restore",
+            #[cfg(test)]
+            Op::Panic => "Panic",
         };
 
         write!(f, "{}", s)
@@ -151,6 +644,53 @@ impl TryFrom<u8> for Op {
             1 => Ok(Op::Get),
             2 => Ok(Op::Del),
             3 => Ok(Op::Stats),
+            4 => Ok(Op::Rename),
+            5 => Ok(Op::Sample),
+            6 => Ok(Op::Scan),
+            7 => Ok(Op::ScanStream),
+            8 => Ok(Op::GetIfNewer),
+            9 => Ok(Op::MultiDel),
+            10 => Ok(Op::Apply),
+            11 => Ok(Op::Retype),
+            12 => Ok(Op::Info),
+            13 => Ok(Op::FieldIncr),
+            14 => Ok(Op::Hello),
+            15 => Ok(Op::Range),
+            16 => Ok(Op::SetIfEmpty),
+            17 => Ok(Op::CasDel),
+            18 => Ok(Op::Cas),
+            19 => Ok(Op::Incr),
+            20 => Ok(Op::Decr),
+            21 => Ok(Op::MGet),
+            22 => Ok(Op::Touch),
+            23 => Ok(Op::Expire),
+            24 => Ok(Op::Auth),
+            25 => Ok(Op::Append),
+            26 => Ok(Op::Prepend),
+            27 => Ok(Op::Add),
+            28 => Ok(Op::Replace),
+            29 => Ok(Op::FlushNamespace),
+            30 => Ok(Op::FlushAll),
+            31 => Ok(Op::Ping),
+            32 => Ok(Op::Subscribe),
+            33 => Ok(Op::Unsubscribe),
+            34 => Ok(Op::Notify),
+            35 => Ok(Op::Replicate),
+            36 => Ok(Op::AssignSlots),
+            37 => Ok(Op::HotKeys),
+            38 => Ok(Op::SlowLog),
+            39 => Ok(Op::Exists),
+            40 => Ok(Op::Ttl),
+            41 => Ok(Op::Inspect),
+            42 => Ok(Op::Batch),
+            43 => Ok(Op::Watch),
+            44 => Ok(Op::Multi),
+            45 => Ok(Op::Exec),
+            46 => Ok(Op::Discard),
+            47 => Ok(Op::ClientList),
+            48 => Ok(Op::Kick),
+            49 => Ok(Op::Dump),
+            50 => Ok(Op::Restore),
             _ => Err(error::Error::new(
                 error::ErrorKind::UnknownOp,
                 "got an unknown op code",
@@ -167,6 +707,43 @@ pub enum Code {
     Miss = 2,
     Error = 3,
     Hit = 4,
+    Conflict = 5,
+    AlreadyApplied = 6,
+    End = 7,
+    NotModified = 8,
+    WrongType = 9,
+    QuotaExceeded = 10,
+    CasMismatch = 11,
+    Unauthorized = 12,
+    /// The server failed to answer the request, through no fault of the request: the worker
+    /// panicked, the log couldn't be written, or the worker is gone. Unlike `Error`, the same
+    /// request may well succeed if retried.
+    ServerError = 13,
+    /// The server is too busy to take the request, which it hasn't attempted, see
+    /// `cache::Options::max_queued`. It may be retried once the load has eased.
+    Busy = 14,
+    /// The key belongs to another node of the cluster, whose address the payload holds, see
+    /// `cluster::redirect`. The request should be sent there instead.
+    Redirect = 15,
+    /// The connection authenticated, but its `service::Acl` doesn't allow the request's op or
+    /// keys.
+    Forbidden = 16,
+    /// The connection sent requests faster than its `service::RateLimit` allows, and the request
+    /// wasn't attempted. It may be retried once the connection has slowed down.
+    Throttled = 17,
+    /// A write named a key longer than the cache's `cache::Options::max_key_len`.
+    KeyTooLarge = 18,
+    /// A write would have stored a value longer than the cache's `cache::Options::max_value_len`.
+    ValueTooLarge = 19,
+    /// The request was queued by the connection's transaction, to be applied by its `Op::Exec`,
+    /// see `service::TransactionService`.
+    Queued = 20,
+    /// An `Op::Get` asking for a stale value found one that expired less than the cache's
+    /// `cache::Options::stale_grace` ago, and answered with it, see `cache::GET_STALE`.
+    Stale = 21,
+    /// An `Op::Get` found the marker of a key known to be missing, see
+    /// `cache::NOT_FOUND_TYPE_ID`.
+    NotFound = 22,
 }
 
 impl fmt::Display for Code {
@@ -177,6 +754,24 @@ impl fmt::Display for Code {
             Code::Miss => "Miss",
             Code::Error => "Error",
             Code::Hit => "Hit",
+            Code::Conflict => "Conflict",
+            Code::AlreadyApplied => "AlreadyApplied",
+            Code::End => "End",
+            Code::NotModified => "NotModified",
+            Code::WrongType => "WrongType",
+            Code::QuotaExceeded => "QuotaExceeded",
+            Code::CasMismatch => "CasMismatch",
+            Code::Unauthorized => "Unauthorized",
+            Code::ServerError => "ServerError",
+            Code::Busy => "Busy",
+            Code::Redirect => "Redirect",
+            Code::Forbidden => "Forbidden",
+            Code::Throttled => "Throttled",
+            Code::KeyTooLarge => "KeyTooLarge",
+            Code::ValueTooLarge => "ValueTooLarge",
+            Code::Queued => "Queued",
+            Code::Stale => "Stale",
+            Code::NotFound => "NotFound",
         };
         write!(f, "{}", s)
     }
@@ -192,6 +787,24 @@ impl TryFrom<u8> for Code {
             2 => Ok(Code::Miss),
             3 => Ok(Code::Error),
             4 => Ok(Code::Hit),
+            5 => Ok(Code::Conflict),
+            6 => Ok(Code::AlreadyApplied),
+            7 => Ok(Code::End),
+            8 => Ok(Code::NotModified),
+            9 => Ok(Code::WrongType),
+            10 => Ok(Code::QuotaExceeded),
+            11 => Ok(Code::CasMismatch),
+            12 => Ok(Code::Unauthorized),
+            13 => Ok(Code::ServerError),
+            14 => Ok(Code::Busy),
+            15 => Ok(Code::Redirect),
+            16 => Ok(Code::Forbidden),
+            17 => Ok(Code::Throttled),
+            18 => Ok(Code::KeyTooLarge),
+            19 => Ok(Code::ValueTooLarge),
+            20 => Ok(Code::Queued),
+            21 => Ok(Code::Stale),
+            22 => Ok(Code::NotFound),
             _ => Err(error::Error::new(
                 error::ErrorKind::InvalidData,
                 "unknown code",
@@ -199,3 +812,46 @@ impl TryFrom<u8> for Code {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let keys = vec!["foo".into(), vec![], "barbaz".into()];
+        let data = encode_keys(&keys);
+
+        assert_eq!(decode_keys(&data).unwrap(), keys);
+        assert!(decode_keys(&data[..data.len() - 1]).is_err());
+        assert!(decode_keys(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_entries() {
+        let entries = vec![
+            ("foo".into(), payload(1, "bar".into())),
+            (vec![], payload(0, vec![])),
+        ];
+        let data = encode_entries(&entries);
+
+        assert_eq!(decode_entries(&data).unwrap(), entries);
+        assert!(decode_entries(&data[..data.len() - 1]).is_err());
+        assert!(decode_entries(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_messages() {
+        let messages = vec![
+            request(Op::Set, "foo".into(), Some(payload(1, "bar".into())))
+                .with_extension(EXT_TTL, encode_u64(60)),
+            request(Op::Del, vec![], None),
+            response(Op::Incr, Code::Ok, Some(payload(0, encode_u64(2)))),
+        ];
+        let data = encode_messages(&messages);
+
+        assert_eq!(decode_messages(&data).unwrap(), messages);
+        assert!(decode_messages(&data[..data.len() - 1]).is_err());
+        assert!(decode_messages(&[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,304 @@
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use futures::sync::oneshot;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+use tokio_io::io::{read_until, shutdown, write_all};
+use std::error::Error;
+use std::fmt::Write;
+use std::io::{self, BufReader, Read};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use cache::{self, Cache, CacheStats};
+use message::{self, Op};
+use stats::{self, Stats, StatsSnapshot};
+
+/// Renders `stats`, along with the cache's own, in Prometheus' text exposition format.
+pub fn render(stats: &StatsSnapshot, cache: &CacheStats) -> String {
+    let mut out = String::new();
+    describe(&mut out, "requests_total", "counter", "Requests answered, by op.");
+    for (op, count) in &stats.requests_by_op {
+        writeln!(out, "rcache_requests_total{{op=\"{}\"}} {}", op, count).unwrap();
+    }
+
+    let help = "How long requests took to answer.";
+    describe(&mut out, "request_duration_seconds", "histogram", help);
+    let mut below = 0;
+    for (i, count) in stats.latency_buckets.iter().enumerate() {
+        below += *count;
+        let le = match stats::LATENCY_BUCKETS.get(i) {
+            Some(&bound) => (bound as f64 / 1e6).to_string(),
+            None => "+Inf".to_owned(),
+        };
+        writeln!(out, "rcache_request_duration_seconds_bucket{{le=\"{}\"}} {}", le, below)
+            .unwrap();
+    }
+    let seconds = stats.total_request_time as f64 / 1e6;
+    writeln!(out, "rcache_request_duration_seconds_sum {}", seconds).unwrap();
+    writeln!(out, "rcache_request_duration_seconds_count {}", stats.total_requests).unwrap();
+
+    let counters = [
+        ("hits_total", "counter", "Reads that found their key.", stats.hits as u64),
+        ("misses_total", "counter", "Reads that didn't find their key.", stats.misses as u64),
+        (
+            "negative_hits_total",
+            "counter",
+            "Reads that found their key marked as missing.",
+            stats.negative_hits as u64,
+        ),
+        ("keys", "gauge", "Keys in the cache.", cache.keys as u64),
+        ("used_bytes", "gauge", "Bytes taken by the cache's keys and values.", cache.used_bytes),
+        ("evictions_total", "counter", "Entries evicted to make room.", cache.evictions),
+        (
+            "worker_panics_total",
+            "counter",
+            "Panics in the cache's worker.",
+            stats.worker_panics as u64,
+        ),
+        (
+            "connection_errors_total",
+            "counter",
+            "Connections that failed.",
+            stats.connection_errors as u64,
+        ),
+        ("connections", "gauge", "Connections open.", stats.connections as u64),
+    ];
+    for &(name, kind, help, value) in &counters {
+        describe(&mut out, name, kind, help);
+        writeln!(out, "rcache_{} {}", name, value).unwrap();
+    }
+    if let Some(lag) = stats.replication_lag {
+        let help = "How far the replica was behind its primary as of the last write it applied.";
+        describe(&mut out, "replication_lag_seconds", "gauge", help);
+        writeln!(out, "rcache_replication_lag_seconds {}", lag as f64 / 1e3).unwrap();
+    }
+    if let Some(warmup) = stats.warmup {
+        describe(&mut out, "warmup_loaded", "gauge", "Entries stored by warming the cache up.");
+        writeln!(out, "rcache_warmup_loaded {}", warmup.loaded).unwrap();
+        let help = "Entries warming the cache up failed to store.";
+        describe(&mut out, "warmup_failed", "gauge", help);
+        writeln!(out, "rcache_warmup_failed {}", warmup.failed).unwrap();
+        describe(&mut out, "warmup_done", "gauge", "Whether the cache is done warming up.");
+        writeln!(out, "rcache_warmup_done {}", warmup.done as u8).unwrap();
+    }
+    out
+}
+
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP rcache_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE rcache_{} {}", name, kind).unwrap();
+}
+
+/// Serves the metrics of `cache` over HTTP at `addr`, under `/metrics`, for Prometheus to scrape,
+/// on the reactor behind `handle`, which is usually the one serving the cache, see
+/// `service::server`. `stats` are those kept by the `StatService` in front of the cache.
+pub fn serve_metrics(
+    addr: &SocketAddr,
+    stats: Arc<Stats>,
+    cache: Arc<Cache>,
+    handle: &Handle,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr, handle)?;
+    handle.spawn(metrics_server(listener, stats, cache, handle.clone()).map_err(|e| {
+        error!("Metrics server error: {}.", e);
+    }));
+    Ok(())
+}
+
+/// Answers each connection accepted by `listener` with the metrics, or a 404 if it asks for
+/// another path, and closes it.
+fn metrics_server(
+    listener: TcpListener,
+    stats: Arc<Stats>,
+    cache: Arc<Cache>,
+    handle: Handle,
+) -> Box<Future<Item = (), Error = io::Error>> {
+    Box::new(listener.incoming().for_each(move |(socket, _)| {
+        let (stats, cache) = (stats.clone(), cache.clone());
+        let exchange = read_request_line(socket).and_then(move |(socket, line)| {
+            let response: Box<Future<Item = Vec<u8>, Error = io::Error>> =
+                if line.starts_with(b"GET /metrics ") {
+                    Box::new(cache_stats(&cache).map(move |cache| {
+                        http_response("200 OK", render(&stats.snapshot(), &cache))
+                    }))
+                } else {
+                    Box::new(future::ok(http_response("404 Not Found", "not found\n".into())))
+                };
+            response
+                .and_then(|response| write_all(socket, response))
+                .and_then(|(socket, _)| shutdown(socket))
+        });
+        handle.spawn(exchange.then(|result| {
+            if let Err(e) = result {
+                warn!("Metrics connection error: {}.", e);
+            }
+            Ok(())
+        }));
+        Ok(())
+    }))
+}
+
+/// The longest request line the metrics server reads.
+const MAX_REQUEST_LINE: usize = 8 * 1024;
+
+/// The longest request head, headers included, the metrics server reads.
+const MAX_REQUEST_HEAD: u64 = 64 * 1024;
+
+/// Reads an HTTP request's head, up to the empty line ending it, and returns its first line.
+/// Fails if the head is longer than `MAX_REQUEST_HEAD`, or its first line `MAX_REQUEST_LINE`.
+fn read_request_line(
+    socket: TcpStream,
+) -> Box<Future<Item = (TcpStream, Vec<u8>), Error = io::Error>> {
+    let reader = BufReader::new(socket).take(MAX_REQUEST_HEAD);
+    Box::new(future::loop_fn((reader, None), |(reader, first)| {
+        read_until(reader, b'\n', vec![]).and_then(move |(reader, line)| {
+            if reader.limit() == 0 && !line.ends_with(b"\n") {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request too long"));
+            }
+            if line.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated request"));
+            }
+            if first.is_none() && line.len() > MAX_REQUEST_LINE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request line too long"));
+            }
+            Ok(match first {
+                None => Loop::Continue((reader, Some(line))),
+                Some(first) => {
+                    if line == b"\r\n" || line == b"\n" {
+                        Loop::Break((reader.into_inner().into_inner(), first))
+                    } else {
+                        Loop::Continue((reader, Some(first)))
+                    }
+                }
+            })
+        })
+    }))
+}
+
+fn cache_stats(cache: &Cache) -> Box<Future<Item = CacheStats, Error = io::Error>> {
+    let (snd, rcv) = oneshot::channel();
+    cache.process(message::request(Op::Stats, vec![], None), snd);
+    Box::new(
+        rcv.map_err(|e| io::Error::new(io::ErrorKind::Other, e.description()))
+            .and_then(|resp| match resp.payload().map(cache::decode_stats) {
+                Some(Ok(stats)) => Ok(stats),
+                _ => Err(io::Error::new(io::ErrorKind::Other, "cache didn't answer with stats")),
+            }),
+    )
+}
+
+fn http_response(status: &str, body: String) -> Vec<u8> {
+    format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    ).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_render() {
+        let stats = Stats::default();
+        stats.record_request(Op::Get, message::Code::Hit, 90);
+        stats.record_request(Op::Get, message::Code::Miss, 300);
+        let cache = CacheStats {
+            keys: 3,
+            evictions: 1,
+            used_bytes: 42,
+            hot_keys: vec![],
+        };
+
+        let out = render(&stats.snapshot(), &cache);
+        assert!(out.contains("# TYPE rcache_requests_total counter\n"));
+        assert!(out.contains("rcache_requests_total{op=\"Get\"} 2\n"));
+        // Buckets count every request at or below their bound.
+        assert!(out.contains("rcache_request_duration_seconds_bucket{le=\"0.0001\"} 1\n"));
+        assert!(out.contains("rcache_request_duration_seconds_bucket{le=\"0.0005\"} 2\n"));
+        assert!(out.contains("rcache_request_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("rcache_request_duration_seconds_count 2\n"));
+        assert!(out.contains("rcache_hits_total 1\n"));
+        assert!(out.contains("rcache_keys 3\n"));
+        assert!(out.contains("rcache_used_bytes 42\n"));
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut socket = net::TcpStream::connect(addr).unwrap();
+        write!(socket, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve_metrics() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let cache = Arc::new(Cache::new(10).unwrap());
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, vec![])));
+        let (snd, rcv) = oneshot::channel();
+        cache.process(set, snd);
+        rcv.wait().unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = Arc::new(Stats::default());
+        handle.spawn(metrics_server(listener, stats, cache, handle.clone()).map_err(|_| ()));
+
+        // The cache is answered on this thread, so the requests are made from another.
+        let (snd, rcv) = oneshot::channel();
+        thread::spawn(move || snd.send((get(addr, "/metrics"), get(addr, "/"))).unwrap());
+        let (metrics, not_found) = core.run(rcv).unwrap();
+
+        assert!(metrics.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(metrics.contains("\r\n\r\n# HELP"));
+        assert!(metrics.contains("rcache_keys 1\n"));
+        assert!(not_found.starts_with("HTTP/1.0 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_request_too_long() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cache = Arc::new(Cache::new(10).unwrap());
+        let stats = Arc::new(Stats::default());
+        handle.spawn(metrics_server(listener, stats, cache, handle.clone()).map_err(|_| ()));
+
+        // Both a long request line and endless headers get the connection closed unanswered.
+        let request = move |first: String, header: String, headers: u64| {
+            let mut socket = net::TcpStream::connect(addr).unwrap();
+            let mut response = String::new();
+            let mut written = socket.write_all(first.as_bytes()).is_ok();
+            for _ in 0..headers {
+                written = written && socket.write_all(header.as_bytes()).is_ok();
+            }
+            if written {
+                let _ = socket.read_to_string(&mut response);
+            }
+            response
+        };
+        let (snd, rcv) = oneshot::channel();
+        thread::spawn(move || {
+            let path = "a".repeat(MAX_REQUEST_LINE);
+            let long_line = request(format!("GET /{} HTTP/1.0\r\n\r\n", path), String::new(), 0);
+            let header = format!("X-Padding: {}\r\n", "a".repeat(1000));
+            let first = "GET /metrics HTTP/1.0\r\n".to_owned();
+            let long_head = request(first, header, MAX_REQUEST_HEAD / 1000 + 1);
+            snd.send((long_line, long_head)).unwrap()
+        });
+        let (long_line, long_head) = core.run(rcv).unwrap();
+        assert_eq!(long_line, "");
+        assert_eq!(long_head, "");
+    }
+}
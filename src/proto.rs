@@ -1,22 +1,90 @@
-use codec::CacheCodec;
+use codec::{self, CacheCodec};
 use message::Message;
+use futures::{Async, Poll, Sink, StartSend, Stream};
+use futures::sync::mpsc::UnboundedSender;
 use tokio_io::codec::Framed;
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_proto::multiplex::{ClientProto, ServerProto};
+use tokio_proto::multiplex::{ClientProto, RequestId, ServerProto};
 use std::io;
 
-/// `CacheProto`
-pub struct CacheProto;
+/// `CacheProto`. A client passes the frames the server pushes to it, see
+/// `codec::UNSOLICITED_FLAG`, to `pushes` if it has one, and otherwise drops them, rather than
+/// mistaking them for responses.
+#[derive(Default)]
+pub struct CacheProto {
+    pushes: Option<UnboundedSender<Message>>,
+}
+
+impl CacheProto {
+    pub fn with_pushes(pushes: UnboundedSender<Message>) -> Self {
+        CacheProto { pushes: Some(pushes) }
+    }
+}
 
 impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for CacheProto {
     type Request = Message;
     type Response = Message;
 
-    type Transport = Framed<T, CacheCodec>;
+    type Transport = SplitPushes<Framed<T, CacheCodec>>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(CacheCodec))
+        let framed = io.framed(
+            CacheCodec::default()
+                .checksum(true)
+                .compress_above(Some(codec::DEFAULT_COMPRESS_ABOVE)),
+        );
+        Ok(SplitPushes {
+            inner: framed,
+            pushes: self.pushes.clone(),
+        })
+    }
+}
+
+/// A client transport diverting the frames pushed by the server to `pushes`, see `CacheProto`.
+pub struct SplitPushes<T> {
+    inner: T,
+    pushes: Option<UnboundedSender<Message>>,
+}
+
+impl<T> Stream for SplitPushes<T>
+where
+    T: Stream<Item = (RequestId, Message), Error = io::Error>,
+{
+    type Item = (RequestId, Message);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        loop {
+            match self.inner.poll()? {
+                Async::Ready(Some((id, msg))) if codec::is_unsolicited(id) => {
+                    if let Some(ref pushes) = self.pushes {
+                        let _ = pushes.unbounded_send(msg);
+                    }
+                }
+                polled => return Ok(polled),
+            }
+        }
+    }
+}
+
+impl<T> Sink for SplitPushes<T>
+where
+    T: Sink<SinkItem = (RequestId, Message), SinkError = io::Error>,
+{
+    type SinkItem = (RequestId, Message);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.inner.close()
     }
 }
 
@@ -28,6 +96,6 @@ impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for CacheProto {
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(CacheCodec))
+        Ok(io.framed(CacheCodec::default()))
     }
 }
@@ -0,0 +1,234 @@
+use futures::Stream;
+use futures::sync::mpsc;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use error;
+use message::{self, Code, Message, Op};
+
+/// `type_id` of the payload of an `Op::Notify` frame, as packed by `Notification::to_message`.
+pub const NOTIFICATION_TYPE_ID: u32 = 19;
+
+/// How a key changed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Set = 1,
+    Deleted = 2,
+    Expired = 3,
+    Evicted = 4,
+}
+
+impl Change {
+    fn from_u8(i: u8) -> Option<Self> {
+        match i {
+            1 => Some(Change::Set),
+            2 => Some(Change::Deleted),
+            3 => Some(Change::Expired),
+            4 => Some(Change::Evicted),
+            _ => None,
+        }
+    }
+}
+
+/// A change to a key a connection subscribed to, see `Hub`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub change: Change,
+    /// The key within the subscriber's namespace.
+    pub key: Vec<u8>,
+}
+
+impl Notification {
+    /// The `Op::Notify` frame the server pushes, whose payload is the change as a u8 followed by
+    /// the key.
+    pub fn to_message(&self) -> Message {
+        let mut data = Vec::with_capacity(1 + self.key.len());
+        data.push(self.change as u8);
+        data.extend_from_slice(&self.key);
+        message::response(Op::Notify, Code::Ok, Some(message::payload(NOTIFICATION_TYPE_ID, data)))
+    }
+
+    /// Decodes a frame built by `to_message`.
+    pub fn from_message(msg: &Message) -> Result<Self, error::Error> {
+        let invalid = || error::Error::new(error::ErrorKind::InvalidData, "invalid notification");
+        let data = match msg.payload() {
+            Some(payload) if msg.op() == Op::Notify &&
+                payload.type_id() == NOTIFICATION_TYPE_ID => payload.data(),
+            _ => return Err(invalid()),
+        };
+        match data.split_first() {
+            Some((&change, key)) => {
+                Ok(Notification {
+                    change: Change::from_u8(change).ok_or_else(invalid)?,
+                    key: key.to_vec(),
+                })
+            }
+            None => Err(invalid()),
+        }
+    }
+}
+
+struct Subscriber {
+    connection: usize,
+    namespace: Option<Vec<u8>>,
+    prefix: Vec<u8>,
+    sender: mpsc::Sender<Notification>,
+}
+
+/// Where a `Cache` publishes the changes to its keys, for the connections subscribed to them,
+/// see `cache::Options::notifications` and `ServeOptions::notifications`. Clones share their
+/// subscribers.
+#[derive(Clone, Default)]
+pub struct Hub {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    /// The number of subscribers, so that publishing doesn't lock while there are none.
+    count: Arc<AtomicUsize>,
+    next_connection: Arc<AtomicUsize>,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Hub::default()
+    }
+
+    /// Starts subscriptions for a new connection, returning them along with where the
+    /// connection's notifications arrive. Up to `bound` notifications wait to be sent to the
+    /// connection; once as many are waiting, those that follow are dropped, so that a slow
+    /// subscriber never holds up the cache.
+    pub fn connect(&self, bound: usize) -> (Subscriptions, mpsc::Receiver<Notification>) {
+        let (sender, receiver) = mpsc::channel(bound);
+        let subscriptions = Subscriptions {
+            hub: self.clone(),
+            connection: self.next_connection.fetch_add(1, Ordering::SeqCst),
+            sender: sender,
+        };
+        (subscriptions, receiver)
+    }
+
+    /// Notifies the subscribers to `key` in `namespace`, `None` being the default one, that it
+    /// changed.
+    pub fn publish(&self, change: Change, namespace: Option<&[u8]>, key: &[u8]) {
+        if self.count.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for subscriber in subscribers.iter_mut() {
+            if subscriber.namespace.as_ref().map(|n| &n[..]) == namespace &&
+                key.starts_with(&subscriber.prefix)
+            {
+                let notification = Notification {
+                    change: change,
+                    key: key.to_vec(),
+                };
+                let _ = subscriber.sender.try_send(notification);
+            }
+        }
+    }
+
+    fn retain<F: FnMut(&Subscriber) -> bool>(&self, f: F) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(f);
+        self.count.store(subscribers.len(), Ordering::SeqCst);
+    }
+}
+
+/// The subscriptions of one connection, see `Hub::connect`. They end when dropped.
+pub struct Subscriptions {
+    hub: Hub,
+    connection: usize,
+    sender: mpsc::Sender<Notification>,
+}
+
+impl Subscriptions {
+    /// Subscribes to the keys in `namespace` starting with `prefix`. Subscribing twice to the
+    /// same keys has no more effect than subscribing once.
+    pub fn subscribe(&self, namespace: Option<Vec<u8>>, prefix: Vec<u8>) {
+        self.unsubscribe(namespace.as_ref().map(|n| &n[..]), &prefix);
+        let mut subscribers = self.hub.subscribers.lock().unwrap();
+        subscribers.push(Subscriber {
+            connection: self.connection,
+            namespace: namespace,
+            prefix: prefix,
+            sender: self.sender.clone(),
+        });
+        self.hub.count.store(subscribers.len(), Ordering::SeqCst);
+    }
+
+    /// Ends the subscription to the keys in `namespace` starting with `prefix`, if there is one.
+    pub fn unsubscribe(&self, namespace: Option<&[u8]>, prefix: &[u8]) {
+        let connection = self.connection;
+        self.hub.retain(|s| {
+            s.connection != connection || s.namespace.as_ref().map(|n| &n[..]) != namespace ||
+                s.prefix != prefix
+        });
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        let connection = self.connection;
+        self.hub.retain(|s| s.connection != connection);
+    }
+}
+
+/// Decodes the notifications among the frames a server pushed, see `Client::notifications`.
+pub fn notifications(
+    pushes: mpsc::UnboundedReceiver<Message>,
+) -> Box<Stream<Item = Notification, Error = io::Error>> {
+    Box::new(
+        pushes
+            .filter(|msg| msg.op() == Op::Notify)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "connection closed"))
+            .and_then(|msg| Notification::from_message(&msg).map_err(io::Error::from)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notification_message() {
+        let notification = Notification {
+            change: Change::Expired,
+            key: "foo".into(),
+        };
+        let msg = notification.to_message();
+        assert_eq!(Notification::from_message(&msg).unwrap(), notification);
+        let msg = message::response(Op::Notify, Code::Ok, None);
+        assert!(Notification::from_message(&msg).is_err());
+    }
+
+    #[test]
+    fn test_hub() {
+        let hub = Hub::new();
+        let (subscriptions, receiver) = hub.connect(10);
+        subscriptions.subscribe(None, "foo".into());
+        subscriptions.subscribe(Some("ns".into()), vec![]);
+
+        hub.publish(Change::Set, None, b"foobar");
+        hub.publish(Change::Set, None, b"bar");
+        hub.publish(Change::Deleted, Some(b"ns"), b"bar");
+        hub.publish(Change::Set, Some(b"other"), b"foo");
+        subscriptions.unsubscribe(None, b"foo");
+        hub.publish(Change::Set, None, b"foo");
+        drop(subscriptions);
+        assert_eq!(hub.count.load(Ordering::SeqCst), 0);
+
+        let received: Vec<_> = receiver.wait().map(|n| n.unwrap()).collect();
+        assert_eq!(
+            received,
+            vec![
+                Notification {
+                    change: Change::Set,
+                    key: "foobar".into(),
+                },
+                Notification {
+                    change: Change::Deleted,
+                    key: "bar".into(),
+                },
+            ]
+        );
+    }
+}
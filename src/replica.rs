@@ -0,0 +1,191 @@
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use futures::sync::oneshot;
+use tokio_core::reactor::{Core, Handle, Timeout};
+use std::cmp;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use cache::Cache;
+use client::Client;
+use message::Code;
+use stats::Stats;
+
+/// How long a replica waits to reconnect once its connection to the primary fails.
+static RECONNECT_DELAY_MS: u64 = 1000;
+
+/// A replica following its primary on a thread of its own, see `follow`. Dropping it stops the
+/// replica without waiting for the thread.
+pub struct Follower {
+    stop: oneshot::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Follower {
+    /// Stops following the primary, and waits for the thread to exit.
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+/// Makes `cache` a replica of the server at `primary`, which must let replicas follow it, see
+/// `ServeOptions::replication`. On a thread of its own, the replica connects to the primary,
+/// asks it to stream its writes with `Op::Replicate`, and applies them with
+/// `Cache::apply_replicated`: first a snapshot of the primary, replacing whatever `cache` held,
+/// then every write the primary makes. Whenever the connection fails, the replica reconnects
+/// and takes a fresh snapshot. It stops once `cache` is dropped.
+///
+/// `cache` should be `cache::Options::read_only`, so that it only changes with the primary.
+/// How far behind the replica is goes in `stats`, see `StatsSnapshot::replication_lag`, which
+/// relies on the clocks of both servers agreeing.
+pub fn follow(
+    primary: SocketAddr,
+    cache: &Arc<Cache>,
+    stats: Option<Arc<Stats>>,
+) -> io::Result<Follower> {
+    let cache = Arc::downgrade(cache);
+    let (stop_snd, stop_rcv) = oneshot::channel();
+    let thread = thread::Builder::new().name("rcache-replica".to_owned()).spawn(move || {
+        let mut core = match Core::new() {
+            Ok(core) => core,
+            Err(e) => {
+                error!("Failed to start the replica: {}.", e);
+                return;
+            }
+        };
+        let handle = core.handle();
+        let following = future::loop_fn((), move |()| {
+            let (cache, handle) = (cache.clone(), handle.clone());
+            sync(primary, cache.clone(), stats.clone(), &handle).then(move |result| {
+                if cache.upgrade().is_none() {
+                    return future::Either::A(future::ok(Loop::Break(())));
+                }
+                match result {
+                    Ok(()) => warn!("Lost the connection to the primary at {}.", primary),
+                    Err(e) => warn!("Failed to replicate from {}: {}.", primary, e),
+                }
+                let delay = Duration::from_millis(RECONNECT_DELAY_MS);
+                let retry = future::result(Timeout::new(delay, &handle)).flatten();
+                future::Either::B(retry.map(|_| Loop::Continue(())))
+            })
+        });
+        let _ = core.run(following.select2(stop_rcv));
+    })?;
+    Ok(Follower {
+        stop: stop_snd,
+        thread: thread,
+    })
+}
+
+/// Takes a snapshot of the primary at `primary` into `cache`, and applies the primary's writes
+/// to it until the connection closes or fails.
+fn sync(
+    primary: SocketAddr,
+    cache: Weak<Cache>,
+    stats: Option<Arc<Stats>>,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = io::Error>> {
+    Box::new(Client::connect(&primary, handle).and_then(move |mut client| {
+        let writes = client.replicated_writes().expect("a new client has its pushes");
+        let replicate = client.replicate().and_then(|resp| {
+            if resp.code() == Code::Ok {
+                return Ok(());
+            }
+            let reason = resp.payload().map(|p| String::from_utf8_lossy(p.data()).into_owned());
+            let reason = format!("the primary refused: {}", reason.unwrap_or_default());
+            Err(io::Error::new(io::ErrorKind::Other, reason))
+        });
+        replicate.and_then(move |()| {
+            writes.for_each(move |write| {
+                // The connection stays open for as long as the client does.
+                let _ = &client;
+                let cache = cache.upgrade().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::Other, "the cache was dropped")
+                })?;
+                let written_at = cache.apply_replicated(&write)?;
+                if let Some(ref stats) = stats {
+                    let lag = (cache.clock().now() - written_at).num_milliseconds();
+                    stats.set_replication_lag(cmp::max(lag, 0) as usize);
+                }
+                Ok(())
+            })
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache;
+    use message::{self, Message, Op};
+    use service::{self, CacheService, ServeOptions};
+    use std::time::Instant;
+
+    fn call(cache: &Cache, req: Message) -> Message {
+        let (snd, rcv) = oneshot::channel();
+        cache.process(req, snd);
+        rcv.wait().unwrap()
+    }
+
+    fn set(cache: &Cache, key: &str, value: &str) {
+        let req = message::request(Op::Set, key.into(), Some(message::payload(1, value.into())));
+        assert_eq!(call(cache, req).code(), Code::Ok);
+    }
+
+    /// Waits for `key` to hold `value` on `cache`, or to be missing without one.
+    fn wait_for(cache: &Cache, key: &str, value: Option<&str>) {
+        let started = Instant::now();
+        loop {
+            let resp = call(cache, message::request(Op::Get, key.into(), None));
+            let found = resp.payload().map(|p| p.data().to_vec());
+            if found == value.map(|v| v.as_bytes().to_vec()) {
+                return;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "{} wasn't replicated", key);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_follow() {
+        let options = cache::Options {
+            replication: Some(cache::DEFAULT_REPLICA_BACKLOG),
+            ..cache::Options::default()
+        };
+        let primary = Arc::new(Cache::with_options(10, options).unwrap());
+        let serve_options = ServeOptions {
+            replication: Some(primary.clone()),
+            ..ServeOptions::default()
+        };
+        let cache = primary.clone();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = service::serve_on_thread(addr, serve_options, move || {
+            Ok(CacheService { cache: cache })
+        }).unwrap();
+        set(&primary, "a", "1");
+
+        let options = cache::Options {
+            read_only: true,
+            ..cache::Options::default()
+        };
+        let replica = Arc::new(Cache::with_options(10, options).unwrap());
+        let stats = Arc::new(Stats::default());
+        let follower = follow(server.local_addr(), &replica, Some(stats.clone())).unwrap();
+
+        // The snapshot, then the writes made since.
+        wait_for(&replica, "a", Some("1"));
+        set(&primary, "b", "2");
+        let del = message::request(Op::Del, "a".into(), None);
+        assert_eq!(call(&primary, del).code(), Code::Ok);
+        wait_for(&replica, "b", Some("2"));
+        wait_for(&replica, "a", None);
+        assert!(stats.snapshot().replication_lag.is_some());
+
+        follower.stop();
+        server.shutdown().unwrap();
+    }
+}
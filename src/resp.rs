@@ -0,0 +1,604 @@
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use tokio_io::AsyncRead;
+use tokio_io::codec::{Decoder, Encoder};
+use tokio_service::{NewService, Service};
+use bytes::BytesMut;
+use std::cell::Cell;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str;
+
+use cache::{self, Ttl};
+use message::{self, Code, Message, Op};
+use service;
+
+/// The longest line accepted, an inline command or the header of a bulk string, before the
+/// connection is closed.
+pub static MAX_LINE_LEN: usize = 64 * 1024;
+
+/// The largest bulk string, such as a value, a command may carry. A larger one closes the
+/// connection, as does one taking the command past `RespCodec::limit_frame_len`.
+pub static MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// The longest command accepted by default, in bytes, see `RespCodec::limit_frame_len`.
+pub static DEFAULT_MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// The most arguments a command may have.
+static MAX_ARGS: usize = 1024 * 1024;
+
+/// A reply in the Redis serialization protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Status(String),
+    /// An error, starting with its kind, such as `ERR`.
+    Error(String),
+    Integer(i64),
+    /// A bulk string, or the null bulk string.
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn error(description: &str) -> Self {
+        Reply::Error(format!("ERR {}", description))
+    }
+
+    fn ok() -> Self {
+        Reply::Status("OK".to_owned())
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Reply::Status(ref status) => {
+                buf.extend_from_slice(format!("+{}\r\n", status).as_bytes())
+            }
+            Reply::Error(ref error) => buf.extend_from_slice(format!("-{}\r\n", error).as_bytes()),
+            Reply::Integer(i) => buf.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Reply::Bulk(None) => buf.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(ref data)) => {
+                buf.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                buf.extend_from_slice(data);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(ref replies) => {
+                buf.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                for reply in replies {
+                    reply.encode(buf);
+                }
+            }
+        }
+    }
+}
+
+/// Frames the Redis serialization protocol: decodes commands, sent either as arrays of bulk
+/// strings or inline, as the arguments they consist of, and encodes `Reply`s.
+///
+/// A command sent as an array is taken off the buffer a bulk string at a time, as each arrives
+/// whole, so that one arriving over many reads isn't parsed again from its start on each. A
+/// command longer than `limit_frame_len` closes the connection as soon as a header says so,
+/// before the bulk string it announces is buffered.
+#[derive(Debug)]
+pub struct RespCodec {
+    max_frame_len: usize,
+    array: Option<Array>,
+}
+
+/// A command sent as an array whose bulk strings are being decoded: those decoded so far, how
+/// many are still to come, the length of the next one once its header has been read, and the
+/// bytes of the command taken off the buffer so far.
+#[derive(Debug)]
+struct Array {
+    args: Vec<Vec<u8>>,
+    remaining: usize,
+    bulk_len: Option<usize>,
+    len: usize,
+}
+
+impl RespCodec {
+    /// Limit the length of the commands decoded, in bytes.
+    pub fn limit_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+}
+
+impl Default for RespCodec {
+    fn default() -> Self {
+        RespCodec {
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            array: None,
+        }
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Vec<Vec<u8>>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<Vec<u8>>>, io::Error> {
+        if self.array.is_none() {
+            match buf.first() {
+                None => return Ok(None),
+                Some(&b'*') => {
+                    let (count, next) = match header(buf, 0, b'*', MAX_ARGS)? {
+                        Some(header) => header,
+                        None => return Ok(None),
+                    };
+                    buf.split_to(next);
+                    self.array = Some(Array {
+                        args: Vec::with_capacity(count.min(64)),
+                        remaining: count,
+                        bulk_len: None,
+                        len: next,
+                    });
+                }
+                Some(_) => {
+                    let decoded = line(buf, 0)?.map(|(line, len)| {
+                        let args = line.split(|&b| b == b' ')
+                            .filter(|arg| !arg.is_empty())
+                            .map(|arg| arg.to_vec())
+                            .collect();
+                        (args, len)
+                    });
+                    return Ok(decoded.map(|(args, len)| {
+                        buf.split_to(len);
+                        args
+                    }));
+                }
+            }
+        }
+        let whole = match self.array {
+            Some(ref mut array) => decode_bulk_strings(array, buf, self.max_frame_len)?,
+            None => false,
+        };
+        Ok(if whole { self.array.take().map(|array| array.args) } else { None })
+    }
+}
+
+impl Encoder for RespCodec {
+    type Item = Reply;
+    type Error = io::Error;
+
+    fn encode(&mut self, reply: Reply, buf: &mut BytesMut) -> io::Result<()> {
+        let mut encoded = vec![];
+        reply.encode(&mut encoded);
+        buf.extend_from_slice(&encoded);
+        Ok(())
+    }
+}
+
+fn protocol_error(description: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("protocol error: {}", description))
+}
+
+/// The line starting at `start`, without its newline, along with where the next one starts.
+fn line(buf: &[u8], start: usize) -> io::Result<Option<(&[u8], usize)>> {
+    match buf[start..].iter().position(|&b| b == b'\n') {
+        Some(newline) => {
+            let line = &buf[start..start + newline];
+            let line = if line.ends_with(b"\r") { &line[..line.len() - 1] } else { line };
+            Ok(Some((line, start + newline + 1)))
+        }
+        None if buf.len() - start > MAX_LINE_LEN => Err(protocol_error("line too long")),
+        None => Ok(None),
+    }
+}
+
+/// Reads the integer following `prefix` on the line starting at `start`.
+fn header(buf: &[u8], start: usize, prefix: u8, max: usize) -> io::Result<Option<(usize, usize)>> {
+    let (line, next) = match line(buf, start)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&prefix) {
+        return Err(protocol_error(&format!("expected '{}'", prefix as char)));
+    }
+    let n = str::from_utf8(&line[1..]).ok().and_then(|n| n.parse::<usize>().ok());
+    match n {
+        Some(n) if n <= max => Ok(Some((n, next))),
+        _ => Err(protocol_error("invalid length")),
+    }
+}
+
+/// Takes the bulk strings of `array` that have arrived whole off `buf`, returning whether the
+/// command is now whole.
+fn decode_bulk_strings(
+    array: &mut Array,
+    buf: &mut BytesMut,
+    max_frame_len: usize,
+) -> io::Result<bool> {
+    while array.remaining > 0 {
+        let len = match array.bulk_len {
+            Some(len) => len,
+            None => {
+                let (len, start) = match header(buf, 0, b'$', MAX_BULK_LEN)? {
+                    Some(header) => header,
+                    None => return Ok(false),
+                };
+                if array.len + start + len + 2 > max_frame_len {
+                    return Err(protocol_error("command too long"));
+                }
+                buf.split_to(start);
+                array.len += start;
+                array.bulk_len = Some(len);
+                len
+            }
+        };
+        if buf.len() < len + 2 {
+            return Ok(false);
+        }
+        if &buf[len..len + 2] != b"\r\n" {
+            return Err(protocol_error("bulk string not terminated"));
+        }
+        array.args.push(buf.split_to(len).to_vec());
+        buf.split_to(2);
+        array.len += len + 2;
+        array.bulk_len = None;
+        array.remaining -= 1;
+    }
+    Ok(true)
+}
+
+/// Serves the Redis serialization protocol at `addr`, on the reactor behind `handle`,
+/// translating each command into requests to a service from `new_service`, such as a
+/// `CacheService`, so that Redis clients can use the cache. The commands understood are `GET`,
+/// `SET` with `EX`, `PX`, `NX` and `XX`, `DEL`, `EXISTS`, `TTL`, `INCR`, `DECR`, `INCRBY`,
+/// `DECRBY`, `PING`, `COMMAND` and `QUIT`. Values are stored with a `type_id` of 1.
+pub fn serve_resp<T>(addr: &SocketAddr, new_service: T, handle: &Handle) -> io::Result<()>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    let listener = TcpListener::bind(addr, handle)?;
+    let server = resp_server(listener, new_service, handle.clone());
+    handle.spawn(server.map_err(|e| {
+        error!("RESP server error: {}.", e);
+    }));
+    Ok(())
+}
+
+/// Answers the commands on each connection accepted by `listener` in turn, until it quits or
+/// closes.
+fn resp_server<T>(
+    listener: TcpListener,
+    new_service: T,
+    handle: Handle,
+) -> Box<Future<Item = (), Error = io::Error>>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    Box::new(listener.incoming().for_each(move |(socket, peer)| {
+        let service = Rc::new(service::new_service_for(&new_service, peer)?);
+        let (replies, commands) = socket.framed(RespCodec::default()).split();
+        // Set by a QUIT, which is still answered, to stop reading commands after it.
+        let quit = Rc::new(Cell::new(false));
+        let quitting = quit.clone();
+        let connection = commands
+            .take_while(move |_| Ok(!quit.get()))
+            .and_then(move |args| {
+                if args.first().map_or(false, |name| name.eq_ignore_ascii_case(b"quit")) {
+                    quitting.set(true);
+                }
+                execute(&service, args)
+            })
+            .forward(replies);
+        handle.spawn(connection.then(|result| {
+            if let Err(e) = result {
+                warn!("RESP connection error: {}.", e);
+            }
+            Ok(())
+        }));
+        Ok(())
+    }))
+}
+
+/// Carries out the command made of `args` with requests to `service`, resolving to the reply.
+pub fn execute<S>(
+    service: &Rc<S>,
+    args: Vec<Vec<u8>>,
+) -> Box<Future<Item = Reply, Error = io::Error>>
+where
+    S: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+    S::Future: 'static,
+{
+    let name = match args.first() {
+        Some(name) => String::from_utf8_lossy(name).to_ascii_lowercase(),
+        None => return Box::new(future::ok(Reply::error("empty command"))),
+    };
+    let mut args = args.into_iter().skip(1);
+    let arity = args.len();
+    let wrong_arity = || {
+        let description = format!("wrong number of arguments for '{}' command", name);
+        Box::new(future::ok(Reply::error(&description)))
+    };
+
+    match name.as_str() {
+        "ping" if arity <= 1 => {
+            let reply = match args.next() {
+                Some(message) => Reply::Bulk(Some(message)),
+                None => Reply::Status("PONG".to_owned()),
+            };
+            Box::new(future::ok(reply))
+        }
+        "quit" => Box::new(future::ok(Reply::ok())),
+        // Asked by redis-cli on connecting, which does without the answer.
+        "command" => Box::new(future::ok(Reply::Array(vec![]))),
+        "get" if arity == 1 => {
+            let req = message::request(Op::Get, args.next().unwrap(), None);
+            Box::new(service.call(req).map(|resp| match (resp.code(), resp.payload()) {
+                (Code::Hit, Some(payload)) => Reply::Bulk(Some(payload.data().to_vec())),
+                (Code::Miss, _) | (Code::NotFound, _) => Reply::Bulk(None),
+                (code, _) => server_error(code),
+            }))
+        }
+        "set" if arity >= 2 => {
+            let (key, value) = (args.next().unwrap(), args.next().unwrap());
+            let options: Vec<_> =
+                args.map(|arg| String::from_utf8_lossy(&arg).into_owned()).collect();
+            match set_request(key, value, &options) {
+                Ok(req) => {
+                    Box::new(service.call(req).map(|resp| match resp.code() {
+                        Code::Ok => Reply::ok(),
+                        // NX or XX wasn't met.
+                        Code::Conflict | Code::Miss => Reply::Bulk(None),
+                        code => server_error(code),
+                    }))
+                }
+                Err(reply) => Box::new(future::ok(reply)),
+            }
+        }
+        "del" if arity >= 1 => {
+            let dels: Vec<_> =
+                args.map(|key| service.call(message::request(Op::Del, key, None))).collect();
+            Box::new(future::join_all(dels).map(|responses| {
+                let deleted = responses.iter().filter(|resp| resp.code() == Code::Ok).count();
+                Reply::Integer(deleted as i64)
+            }))
+        }
+        "exists" if arity >= 1 => {
+            let checks: Vec<_> =
+                args.map(|key| service.call(message::request(Op::Exists, key, None))).collect();
+            Box::new(future::join_all(checks).map(|responses| {
+                let found = responses.iter().filter(|resp| resp.code() == Code::Hit).count();
+                Reply::Integer(found as i64)
+            }))
+        }
+        "ttl" if arity == 1 => {
+            let req = message::request(Op::Ttl, args.next().unwrap(), None);
+            Box::new(service.call(req).map(|resp| match cache::decode_ttl(&resp) {
+                Ok(Ttl::Seconds(ttl)) => Reply::Integer(ttl as i64),
+                Ok(Ttl::Forever) => Reply::Integer(-1),
+                Ok(Ttl::Missing) => Reply::Integer(-2),
+                Err(_) => server_error(resp.code()),
+            }))
+        }
+        "incr" | "decr" if arity == 1 => {
+            let delta = if name == "incr" { 1 } else { -1 };
+            incr_by(service.clone(), args.next().unwrap(), delta)
+        }
+        "incrby" | "decrby" if arity == 2 => {
+            let key = args.next().unwrap();
+            let delta = parse::<i64>(&args.next().unwrap()).and_then(|delta| {
+                if name == "incrby" { Some(delta) } else { delta.checked_neg() }
+            });
+            match delta {
+                Some(delta) => incr_by(service.clone(), key, delta),
+                None => Box::new(future::ok(not_an_integer())),
+            }
+        }
+        "ping" | "get" | "set" | "del" | "exists" | "ttl" | "incr" | "decr" | "incrby" |
+        "decrby" => wrong_arity(),
+        _ => Box::new(future::ok(Reply::error(&format!("unknown command '{}'", name)))),
+    }
+}
+
+/// The request for a `SET` of `key` to `value` with `options`.
+fn set_request(key: Vec<u8>, value: Vec<u8>, options: &[String]) -> Result<Message, Reply> {
+    let mut op = Op::Set;
+    let mut ttl = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_str() {
+            "nx" if op == Op::Set => op = Op::Add,
+            "xx" if op == Op::Set => op = Op::Replace,
+            // Expirations are kept to the second, so milliseconds are rounded up.
+            unit @ "ex" | unit @ "px" if ttl.is_none() => {
+                let n = options.next().and_then(|n| n.parse::<u64>().ok());
+                ttl = match (n, unit) {
+                    (Some(n), "ex") if n > 0 => Some(n),
+                    (Some(n), "px") if n > 0 => Some((n + 999) / 1000),
+                    _ => return Err(Reply::error("invalid expire time in 'set' command")),
+                };
+            }
+            _ => return Err(Reply::error("syntax error")),
+        }
+    }
+    let req = message::request(op, key, Some(message::payload(1, value)));
+    Ok(match ttl {
+        Some(ttl) => req.with_extension(message::EXT_TTL, message::encode_u64(ttl)),
+        None => req,
+    })
+}
+
+/// Adds `delta` to the value of `key`, as `INCRBY` does: the value must be a decimal integer,
+/// a missing key counts as 0, and a result that overflows is refused. The value is read and
+/// written back with an `Op::Cas` keeping its expiration time, or created with an `Op::Add`,
+/// retrying if it changed meanwhile.
+fn incr_by<S>(
+    service: Rc<S>,
+    key: Vec<u8>,
+    delta: i64,
+) -> Box<Future<Item = Reply, Error = io::Error>>
+where
+    S: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+    S::Future: 'static,
+{
+    Box::new(future::loop_fn((), move |()| {
+        let (service, key) = (service.clone(), key.clone());
+        service.call(message::request(Op::Get, key.clone(), None)).and_then(move |resp| {
+            // The version and expiration time of the value, unless there is none.
+            let (current, cas) = match (resp.code(), resp.payload()) {
+                (Code::Hit, Some(payload)) => {
+                    let version = resp.extension(message::EXT_VERSION).map(message::decode_u64);
+                    match (parse::<i64>(payload.data()), version) {
+                        (Some(current), Some(Ok(version))) => {
+                            let expires_at = resp.extension(message::EXT_EXPIRES_AT);
+                            (current, Some((version, expires_at.map(|at| at.to_vec()))))
+                        }
+                        _ => return future::Either::A(future::ok(Loop::Break(not_an_integer()))),
+                    }
+                }
+                (Code::Miss, _) => (0, None),
+                (code, _) => return future::Either::A(future::ok(Loop::Break(server_error(code)))),
+            };
+            let value = match current.checked_add(delta) {
+                Some(value) => value,
+                None => {
+                    let reply = Reply::error("increment or decrement would overflow");
+                    return future::Either::A(future::ok(Loop::Break(reply)));
+                }
+            };
+            let payload = Some(message::payload(1, value.to_string().into_bytes()));
+            let req = match cas {
+                Some((version, expires_at)) => {
+                    let req = message::request(Op::Cas, key, payload)
+                        .with_extension(message::EXT_VERSION, message::encode_u64(version));
+                    match expires_at {
+                        Some(at) => req.with_extension(message::EXT_EXPIRES_AT, at),
+                        None => req,
+                    }
+                }
+                None => message::request(Op::Add, key, payload),
+            };
+            future::Either::B(service.call(req).map(move |resp| match resp.code() {
+                Code::Ok => Loop::Break(Reply::Integer(value)),
+                Code::CasMismatch | Code::Conflict | Code::Miss => Loop::Continue(()),
+                code => Loop::Break(server_error(code)),
+            }))
+        })
+    }))
+}
+
+fn not_an_integer() -> Reply {
+    Reply::error("value is not an integer or out of range")
+}
+
+fn server_error(code: Code) -> Reply {
+    Reply::error(&format!("server answered {}", code))
+}
+
+fn parse<T: str::FromStr>(word: &[u8]) -> Option<T> {
+    str::from_utf8(word).ok().and_then(|word| word.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use service::CacheService;
+    use std::sync::Arc;
+
+    fn args(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut codec = RespCodec::default();
+        let input = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\nPING  hi\r\n*1\r\n$4\r\nPI";
+        let mut buf = BytesMut::from(&input[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(args(&["GET", "foo"])));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(args(&["PING", "hi"])));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"NG\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(args(&["PING"])));
+        assert!(buf.is_empty());
+
+        // A command arriving a byte at a time is taken off the buffer as its parts arrive.
+        let input = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$5\r\nvalue\r\n";
+        let mut decoded = None;
+        for &byte in input.iter() {
+            assert_eq!(decoded, None);
+            buf.extend_from_slice(&[byte]);
+            decoded = codec.decode(&mut buf).unwrap();
+            assert!(buf.len() <= 7);
+        }
+        assert_eq!(decoded, Some(args(&["SET", "foo", "value"])));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"*1\r\n$3\r\nGETXX"[..]);
+        assert!(RespCodec::default().decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"*1\r\n:3\r\n"[..]);
+        assert!(RespCodec::default().decode(&mut buf).is_err());
+
+        // Commands are only so long, refused from the header announcing too much.
+        let mut codec = RespCodec::default().limit_frame_len(22);
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(args(&["GET", "foo"])));
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$4\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let reply = Reply::Array(vec![
+            Reply::ok(),
+            Reply::error("no"),
+            Reply::Integer(-2),
+            Reply::Bulk(Some("hi".into())),
+            Reply::Bulk(None),
+        ]);
+        let mut buf = BytesMut::new();
+        RespCodec::default().encode(reply, &mut buf).unwrap();
+        assert_eq!(&buf[..], &b"*5\r\n+OK\r\n-ERR no\r\n:-2\r\n$2\r\nhi\r\n$-1\r\n"[..]);
+    }
+
+    #[test]
+    fn test_execute() {
+        let service = Rc::new(CacheService { cache: Arc::new(cache::Cache::new(10).unwrap()) });
+        let run = |command: &[&str]| execute(&service, args(command)).wait().unwrap();
+        let bulk = |value: &str| Reply::Bulk(Some(value.into()));
+
+        assert_eq!(run(&["PING"]), Reply::Status("PONG".to_owned()));
+        assert_eq!(run(&["set", "foo", "bar"]), Reply::ok());
+        assert_eq!(run(&["GET", "foo"]), bulk("bar"));
+        assert_eq!(run(&["SET", "foo", "baz", "NX"]), Reply::Bulk(None));
+        assert_eq!(run(&["SET", "nope", "baz", "XX"]), Reply::Bulk(None));
+        assert_eq!(run(&["TTL", "foo"]), Reply::Integer(-1));
+        assert_eq!(run(&["TTL", "nope"]), Reply::Integer(-2));
+        assert_eq!(run(&["EXISTS", "foo", "nope", "foo"]), Reply::Integer(2));
+        assert_eq!(run(&["SET", "foo", "bar", "EX", "100"]), Reply::ok());
+        match run(&["TTL", "foo"]) {
+            Reply::Integer(ttl) => assert!(ttl > 90 && ttl <= 100),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+
+        assert_eq!(run(&["INCR", "n"]), Reply::Integer(1));
+        assert_eq!(run(&["INCRBY", "n", "41"]), Reply::Integer(42));
+        assert_eq!(run(&["DECRBY", "n", "50"]), Reply::Integer(-8));
+        assert_eq!(run(&["GET", "n"]), bulk("-8"));
+        assert_eq!(run(&["INCR", "foo"]), not_an_integer());
+        // Incrementing keeps the expiration time.
+        assert_eq!(run(&["SET", "m", "1", "PX", "100001"]), Reply::ok());
+        assert_eq!(run(&["INCR", "m"]), Reply::Integer(2));
+        match run(&["TTL", "m"]) {
+            Reply::Integer(ttl) => assert!(ttl > 90 && ttl <= 101),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+
+        assert_eq!(run(&["DEL", "foo", "n", "nope"]), Reply::Integer(2));
+        assert_eq!(run(&["GET", "foo"]), Reply::Bulk(None));
+        assert_eq!(
+            run(&["SET", "foo", "bar", "EX", "0"]),
+            Reply::error("invalid expire time in 'set' command")
+        );
+        assert_eq!(run(&["GET"]), Reply::error("wrong number of arguments for 'get' command"));
+        assert_eq!(run(&["FLUSHDB"]), Reply::error("unknown command 'flushdb'"));
+    }
+}
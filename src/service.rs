@@ -1,29 +1,192 @@
-use futures::{Future, Stream, Sink};
+use futures::{Future, Stream, Sink, StartSend, AsyncSink, Async, Poll};
+use futures::{future, stream};
+use futures::unsync::mpsc as unsync_mpsc;
+use futures::unsync::oneshot as unsync_oneshot;
 
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core, Handle, Timeout};
 use tokio_core::net::TcpListener;
+use tokio_uds::UnixListener;
 
-use tokio_io::AsyncRead;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tls::TlsAcceptorExt;
+use native_tls::TlsAcceptor;
 
 use tokio_service::{Service, NewService};
+use tokio_proto::multiplex::RequestId;
 
+use std::fmt;
+use std::fs;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::ffi::OsString;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use message::{self, Message, Op, Code};
-use cache;
-use codec::CacheCodec;
-use std::sync::Arc;
+use cache::{self, Cache, Feed};
+use codec::{self, AssemblyLimit, CacheCodec, BatchCodec};
+use std::sync::{Arc, Mutex};
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::cmp;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use log::Level;
 use std::error::Error;
 use futures::sync::oneshot;
-use stats::Stats;
-use time;
+use stats::{self, Stats, ServerStats};
+use pubsub::{Hub, Subscriptions};
+use clock::Clock;
+use slowlog::{self, SlowLog, SlowRequest};
+use clients::{self, Clients, Registration};
+use time::Timespec;
+use cluster;
+
+/// Options for `serve_with_options`.
+#[derive(Clone)]
+pub struct ServeOptions {
+    /// The most response bytes a connection will hold waiting to be written to the socket. Once
+    /// reached, no more requests are read from the connection until the backlog drains. A single
+    /// response larger than this is still written, once nothing else is pending.
+    pub max_pending_bytes: usize,
+    /// Where to count the open connections, and those that fail, for example because a write to
+    /// the socket errors. Failures are logged either way.
+    pub stats: Option<Arc<Stats>>,
+    /// The most requests decoded from a single read and dispatched together. With more than one,
+    /// all the complete requests a pipelining client has sent are passed to the service before
+    /// waiting on any of their responses. Responses are still written in request order, and are
+    /// flushed to the socket together once the whole batch has been answered.
+    pub max_batch: usize,
+    /// The most batches of requests, see `max_batch`, a connection has passed to the service
+    /// and not yet had answered. Once reached, no more requests are read from the connection
+    /// until the oldest batch has been answered, so that a client pipelining requests can't
+    /// have the server hold any number of them. Responses are still written in request order.
+    pub max_in_flight: usize,
+    /// How long a new connection has to complete its handshake, by sending an `Op::Hello` with a
+    /// protocol version the server speaks, and authenticating if `require_auth` is set, before
+    /// it is closed. Requests sent before the handshake are still answered. Once the handshake
+    /// is done, `idle_timeout` takes over. Without a timeout, no handshake is required.
+    pub handshake_timeout: Option<Duration>,
+    /// Count an `Op::Auth` answered with `Code::Ok` as part of the handshake, see
+    /// `handshake_timeout`, for servers whose service is behind an `AuthService`. Otherwise the
+    /// `Op::Hello` alone completes it.
+    pub require_auth: bool,
+    /// The longest request frame accepted, see `CacheCodec::limit_frame_len`. A longer request
+    /// is answered with `Code::ValueTooLarge`. Without a limit, the codec's default applies.
+    pub max_frame_len: Option<usize>,
+    /// The longest key a request frame may carry, see `CacheCodec::new`. A request with a longer
+    /// one is answered with `Code::KeyTooLarge`. Without a limit, `codec::DEFAULT_MAX_KEY_LEN`
+    /// applies.
+    pub max_key_len: Option<usize>,
+    /// The longest payload a request frame may carry, see `CacheCodec::new`. A request with a
+    /// longer one is answered with `Code::ValueTooLarge`. Without a limit,
+    /// `codec::DEFAULT_MAX_PAYLOAD_LEN` applies.
+    pub max_payload_len: Option<usize>,
+    /// The most chunked requests the server's connections reassemble at once, see
+    /// `CacheCodec::limit_assemblies`. A connection that would start one past it is closed.
+    pub max_assemblies: usize,
+    /// Compress response payloads longer than this, on connections that negotiated compression
+    /// in their handshake, see `CacheCodec::compress_above`.
+    pub compress_above: Option<usize>,
+    /// Close connections whose first request isn't an `Op::Hello`, see `CacheCodec::expect_hello`,
+    /// so that a client speaking another protocol is turned away at once.
+    pub require_hello: bool,
+    /// How long an established connection may go without sending a request before it is closed.
+    /// Clients that are otherwise quiet can keep their connection open with `Op::Ping`.
+    pub idle_timeout: Option<Duration>,
+    /// Enables TCP keepalive on accepted connections, probing the peer once a connection has
+    /// been quiet for this long, so that connections to peers that have gone away are closed.
+    pub keepalive: Option<Duration>,
+    /// The most connections open at once from a single IP address. Connections past the limit
+    /// are closed as soon as they are accepted, without affecting other addresses.
+    pub max_connections_per_ip: Option<usize>,
+    /// How long a server that is shutting down waits for its connections to answer the requests
+    /// they have already read, see `serve_with_shutdown`. Without a timeout, it waits for as long
+    /// as that takes.
+    pub drain_timeout: Option<Duration>,
+    /// Wraps every accepted connection in TLS, see `tls::acceptor`. A connection whose TLS
+    /// handshake fails is closed and counted as failed, and so is one that doesn't complete it
+    /// within `handshake_timeout`, or without one, `idle_timeout`.
+    pub tls: Option<TlsAcceptor>,
+    /// Answer `Op::Subscribe` and `Op::Unsubscribe` with subscriptions to the changes the cache
+    /// publishes to this hub, see `cache::Options::notifications` and `SubscribeService`. Without
+    /// a hub, they are refused.
+    pub notifications: Option<Hub>,
+    /// Answer `Op::Replicate` by streaming the writes to this cache, which should be the one
+    /// behind the service, see `ReplicateService`. It needs `cache::Options::replication`.
+    pub replication: Option<Arc<Cache>>,
+    /// The longest keys and values the cache behind the service takes, see `Cache::limits`,
+    /// which the response to `Op::Hello` advertises, see `cache::with_limits`.
+    pub limits: cache::Limits,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        ServeOptions {
+            max_pending_bytes: 4 * 1024 * 1024,
+            stats: None,
+            max_batch: 1,
+            max_in_flight: 1,
+            handshake_timeout: None,
+            require_auth: false,
+            max_frame_len: None,
+            max_key_len: None,
+            max_payload_len: None,
+            max_assemblies: 64,
+            compress_above: Some(codec::DEFAULT_COMPRESS_ABOVE),
+            require_hello: false,
+            idle_timeout: None,
+            keepalive: None,
+            max_connections_per_ip: None,
+            drain_timeout: Some(Duration::from_secs(5)),
+            tls: None,
+            notifications: None,
+            replication: None,
+            limits: cache::Limits::default(),
+        }
+    }
+}
 
 /// Takes a `NewService<Request=Message, Response=Message>` and servces it at `addr`.
 pub fn serve<T>(addr: SocketAddr, s: T) -> io::Result<()>
 where
     T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    serve_with_options(addr, s, ServeOptions::default())
+}
+
+/// Like `serve`, configured by `options`.
+pub fn serve_with_options<T>(addr: SocketAddr, s: T, options: ServeOptions) -> io::Result<()>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    serve_with_shutdown(addr, s, options, future::empty::<(), ()>())
+}
+
+/// Like `serve_with_options`, but returns once `shutdown` resolves, or fails. The server then
+/// stops accepting connections and stops reading requests from the open ones, and returns once
+/// they have answered the requests already read, or `ServeOptions::drain_timeout` has passed.
+/// Connections still open by then are closed.
+pub fn serve_with_shutdown<T, F>(
+    addr: SocketAddr,
+    s: T,
+    options: ServeOptions,
+    shutdown: F,
+) -> io::Result<()>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
     <T::Instance as Service>::Future: 'static,
+    F: Future + 'static,
 {
     // The primary event loop
     let mut core = Core::new()?;
@@ -32,68 +195,570 @@ where
     // Bind to the socket
     let listener = TcpListener::bind(&addr, &handle)?;
 
-    let connections = listener.incoming();
-    // Iterate over the the stream of connections.
-    let server = connections.for_each(move |(socket, _peer_addr)| {
-        // Split the connection into a Sink and a Stream.
-        let (writer, reader) = socket.framed(CacheCodec).split();
-        let service = s.new_service().unwrap();
-
-        // Map the service function onto each element in the stream.
-        let responses = reader.and_then(move |(req_id, msg)| {
-            service.call(msg).map(move |resp| (req_id, resp))
+    core.run(server(listener, s, options, shutdown, handle))
+}
+
+/// A server running on its own thread, see `serve_on_thread`. Dropping the handle shuts the
+/// server down without waiting for it.
+pub struct ServerHandle {
+    addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    thread: thread::JoinHandle<io::Result<()>>,
+}
+
+impl ServerHandle {
+    /// The address the server is bound to, which is useful when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops the server as `serve_with_shutdown` does, and waits for its thread to exit.
+    pub fn shutdown(self) -> io::Result<()> {
+        let _ = self.shutdown.send(());
+        self.thread.join().unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::Other, "server thread panicked"))
+        })
+    }
+}
+
+/// Like `serve_with_options`, but runs the reactor on a thread of its own and returns once the
+/// server is listening. As the service generally isn't `Send`, it is built on the server's thread
+/// by `new_service`.
+pub fn serve_on_thread<F, T>(
+    addr: SocketAddr,
+    options: ServeOptions,
+    new_service: F,
+) -> io::Result<ServerHandle>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    let (addr_snd, addr_rcv) = mpsc::channel();
+    let (shutdown_snd, shutdown_rcv) = oneshot::channel();
+
+    let thread = thread::spawn(move || {
+        let started = Core::new().and_then(|core| {
+            let listener = TcpListener::bind(&addr, &core.handle())?;
+            let addr = listener.local_addr()?;
+            Ok((core, listener, addr, new_service()?))
         });
+        let (mut core, listener, addr, s) = match started {
+            Ok(started) => started,
+            Err(e) => {
+                let _ = addr_snd.send(Err(e));
+                return Ok(());
+            }
+        };
+        let _ = addr_snd.send(Ok(addr));
+
+        let handle = core.handle();
+        core.run(server(listener, s, options, shutdown_rcv, handle))
+    });
+
+    let addr = addr_rcv.recv().unwrap_or_else(|_| {
+        Err(io::Error::new(io::ErrorKind::Other, "server thread exited"))
+    })?;
+    Ok(ServerHandle {
+        addr: addr,
+        shutdown: shutdown_snd,
+        thread: thread,
+    })
+}
+
+/// A socket a server accepts connections on, see `server`.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Serves local clients over a UNIX domain socket, whose file's permissions control who may
+    /// connect. Unlike TCP connections, these aren't wrapped in `ServeOptions::tls`, and aren't
+    /// limited by `ServeOptions::max_connections_per_ip` nor kept alive by
+    /// `ServeOptions::keepalive`. Their services see no `connecting_peer`.
+    Unix(UnixSocket),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl From<UnixSocket> for Listener {
+    fn from(socket: UnixSocket) -> Self {
+        Listener::Unix(socket)
+    }
+}
+
+/// A listener bound by `bind_unix`, whose socket file is removed once it is dropped, as a server
+/// does when it shuts down.
+pub struct UnixSocket {
+    listener: UnixListener,
+    file: SocketFile,
+}
+
+/// Removes the socket file at its path when dropped.
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            warn!("Failed to remove the socket {}: {}.", self.0.display(), e);
+        }
+    }
+}
+
+/// Binds a `Listener::Unix` to the socket file at `path`, replacing the file left behind by a
+/// server that didn't shut down cleanly, and lets only the users `mode` allows connect, as the
+/// permission bits of the file, such as `0o770`. The socket is bound in a directory only the
+/// server's user may enter, and moved to `path` once it has its permissions, so that it never
+/// accepts connections `mode` doesn't allow.
+pub fn bind_unix<P: AsRef<Path>>(
+    path: P,
+    mode: Option<u32>,
+    handle: &Handle,
+) -> io::Result<UnixSocket> {
+    let path = path.as_ref();
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "not a socket"));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = match mode {
+        None => UnixListener::bind(path, handle)?,
+        Some(mode) => {
+            let name = path.file_name()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+            let mut private = OsString::from(".");
+            private.push(name);
+            private.push(format!(".{}", process::id()));
+            let dir = path.with_file_name(private);
+            fs::DirBuilder::new().mode(0o700).create(&dir)?;
+            let bound = dir.join(name);
+            let listener = UnixListener::bind(&bound, handle).and_then(|listener| {
+                fs::set_permissions(&bound, fs::Permissions::from_mode(mode))?;
+                fs::rename(&bound, path)?;
+                Ok(listener)
+            });
+            let _ = fs::remove_file(&bound);
+            let _ = fs::remove_dir(&dir);
+            listener?
+        }
+    };
+    Ok(UnixSocket {
+        listener: listener,
+        file: SocketFile(path.to_owned()),
+    })
+}
+
+/// Serves connections accepted by `listener` on the reactor behind `handle`, until `shutdown`
+/// resolves and the connections have drained, see `serve_with_shutdown`. Unlike the `serve`
+/// functions, this leaves running the reactor to the caller, who can serve more on it, such as
+/// `metrics::serve_metrics`.
+pub fn server<L, T, F>(
+    listener: L,
+    s: T,
+    options: ServeOptions,
+    shutdown: F,
+    handle: Handle,
+) -> Box<Future<Item = (), Error = io::Error>>
+where
+    L: Into<Listener>,
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+    F: Future + 'static,
+{
+    let open = Rc::new(RefCell::new(HashMap::new()));
+    let drain_timeout = options.drain_timeout;
+    let options = Rc::new(options);
+    let assemblies = AssemblyLimit::new(options.max_assemblies);
+    // Connections stop reading once `drain` resolves, and each holds a `done` sender until it
+    // closes, so the receiver ends once they all have.
+    let (drain_snd, drain_rcv) = oneshot::channel::<()>();
+    let drain = drain_rcv.shared();
+    let (done_snd, done_rcv) = unsync_mpsc::channel::<()>(0);
+    let spawn_handle = handle.clone();
 
-        // Finally, write out all of the responses.
-        let server = writer.send_all(responses).then(|_| Ok(()));
-        handle.spawn(server);
+    // The stream of connections, each served by the future it yields.
+    let counted = options.stats.clone();
+    let connections: Box<Stream<Item = Option<Box<Future<Item = (), Error = ()>>>, Error = _>> =
+        match listener.into() {
+            Listener::Tcp(listener) => {
+                let handle = spawn_handle.clone();
+                Box::new(listener.incoming().and_then(move |(socket, peer_addr)| {
+                    let max = options.max_connections_per_ip;
+                    let slot = match ConnectionSlot::take(&open, peer_addr.ip(), max) {
+                        Some(slot) => slot,
+                        None => {
+                            let ip = peer_addr.ip();
+                            warn!("Refusing connection from {}: too many connections.", ip);
+                            return Ok(None);
+                        }
+                    };
+                    if options.keepalive.is_some() {
+                        if let Err(e) = socket.set_keepalive(options.keepalive) {
+                            warn!("Failed to enable keepalive for {}: {}.", peer_addr, e);
+                        }
+                    }
+                    let service = new_service_for(&s, peer_addr)?;
+                    let client = options.stats.as_ref().map(|stats| {
+                        Clients::register(stats.clients(), Some(peer_addr))
+                    });
+                    let (drain, assemblies) = (drain.clone(), assemblies.clone());
+                    let connection = match options.tls {
+                        None => serve_socket(
+                            socket,
+                            service,
+                            client,
+                            &options,
+                            assemblies,
+                            drain,
+                            &handle,
+                        )?,
+                        Some(ref acceptor) => {
+                            let handle = handle.clone();
+                            let (options, stats) = (options.clone(), options.stats.clone());
+                            let accept = acceptor.accept_async(socket)
+                                .map(Some)
+                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+                            // The TLS handshake comes first, and has as long as the connection's.
+                            let timeout = options.handshake_timeout.or(options.idle_timeout);
+                            let handshake = match timeout {
+                                Some(timeout) => {
+                                    let timeout = Timeout::new(timeout, &handle)?.map(|()| None);
+                                    future::Either::A(accept.select(timeout)
+                                        .map(|(stream, _)| stream)
+                                        .map_err(|(e, _)| e))
+                                }
+                                None => future::Either::B(accept),
+                            };
+                            let handshake = handshake.and_then(|stream| {
+                                stream.ok_or_else(|| {
+                                    io::Error::new(io::ErrorKind::TimedOut, "timed out")
+                                })
+                            }).map_err(move |e| {
+                                warn!("TLS handshake with {} failed: {}.", peer_addr, e);
+                                if let Some(stats) = stats {
+                                    stats.incr_connection_errors();
+                                }
+                            });
+                            Box::new(handshake.and_then(move |stream| {
+                                serve_socket(
+                                    stream,
+                                    service,
+                                    client,
+                                    &options,
+                                    assemblies,
+                                    drain,
+                                    &handle,
+                                ).map_err(|e| {
+                                    warn!("Connection error: {}.", e);
+                                })
+                            }).flatten())
+                        }
+                    };
+                    Ok(Some(Box::new(connection.then(move |result| {
+                        drop(slot);
+                        result
+                    })) as Box<Future<Item = (), Error = ()>>))
+                }))
+            }
+            Listener::Unix(UnixSocket { listener, file }) => {
+                let handle = spawn_handle.clone();
+                Box::new(listener.incoming().and_then(move |(socket, _)| {
+                    // Keeps the socket file until the server stops accepting connections.
+                    let _ = &file;
+                    let service = s.new_service()?;
+                    let client = options.stats.as_ref().map(|stats| {
+                        Clients::register(stats.clients(), None)
+                    });
+                    let (drain, assemblies) = (drain.clone(), assemblies.clone());
+                    serve_socket(socket, service, client, &options, assemblies, drain, &handle)
+                        .map(Some)
+                }))
+            }
+        };
+
+    // Iterate over the the stream of connections.
+    let accepting = connections.for_each(move |connection| {
+        if let Some(connection) = connection {
+            let done = done_snd.clone();
+            let stats = counted.clone();
+            if let Some(ref stats) = stats {
+                stats.connection_opened();
+            }
+            spawn_handle.spawn(connection.then(move |result| {
+                if let Some(stats) = stats {
+                    stats.connection_closed();
+                }
+                drop(done);
+                result
+            }));
+        }
         Ok(())
     });
 
-    core.run(server)
+    let shutdown = shutdown.then(|_| Ok(()));
+    Box::new(accepting.select(shutdown).map_err(|(e, _)| e).and_then(move |(_, accepting)| {
+        // Dropping the listener, along with its `done` sender.
+        drop(accepting);
+        let _ = drain_snd.send(());
+        let drained = done_rcv.for_each(|_| Ok(())).then(|_| Ok(()));
+        match drain_timeout {
+            Some(drain_timeout) => {
+                let timeout = Timeout::new(drain_timeout, &handle)?;
+                let drained = drained.select(timeout).map(|_| ()).map_err(|(e, _)| e);
+                Ok(Box::new(drained) as Box<Future<Item = (), Error = io::Error>>)
+            }
+            None => Ok(Box::new(drained) as Box<Future<Item = (), Error = io::Error>>),
+        }
+    }).flatten())
 }
 
-/// A service middleware that dispatches requests to `cache::Cache`.
-pub struct CacheService {
-    pub cache: Arc<cache::Cache>,
+thread_local!(static CONNECTING_PEER: Cell<Option<SocketAddr>> = Cell::new(None));
+
+/// The address of the peer whose connection a server is creating a service for, for
+/// `NewService`s wanting it, such as `LogService`'s. `None` outside of
+/// `NewService::new_service` calls made by `new_service_for`, which servers call.
+pub fn connecting_peer() -> Option<SocketAddr> {
+    CONNECTING_PEER.with(|peer| peer.get())
 }
 
-impl Service for CacheService {
+/// Creates the service for a new connection from `peer`, see `connecting_peer`.
+pub fn new_service_for<T>(new_service: &T, peer: SocketAddr) -> io::Result<T::Instance>
+where
+    T: NewService,
+{
+    CONNECTING_PEER.with(|connecting| connecting.set(Some(peer)));
+    let service = new_service.new_service();
+    CONNECTING_PEER.with(|connecting| connecting.set(None));
+    service
+}
+
+/// Frames `io` and answers the requests read from it with `service`, until `drain` resolves or
+/// the connection is kicked, behind a `ClientService` keeping `client` up to date. Its chunked
+/// requests count against the server's `assemblies`.
+fn serve_socket<I, T, D>(
+    io: I,
+    service: T,
+    client: Option<(Registration, oneshot::Receiver<()>)>,
+    options: &ServeOptions,
+    assemblies: Arc<AssemblyLimit>,
+    drain: D,
+    handle: &Handle,
+) -> io::Result<Box<Future<Item = (), Error = ()>>>
+where
+    I: AsyncRead + AsyncWrite + 'static,
+    T: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Future: 'static,
+    D: Future + 'static,
+{
+    let mut codec = CacheCodec::new(
+        options.max_key_len.unwrap_or(codec::DEFAULT_MAX_KEY_LEN),
+        options.max_payload_len.unwrap_or(codec::DEFAULT_MAX_PAYLOAD_LEN),
+    ).expect_hello(options.require_hello)
+        .compress_above(options.compress_above)
+        .limit_assemblies(assemblies);
+    if let Some(max_frame_len) = options.max_frame_len {
+        codec = codec.limit_frame_len(max_frame_len);
+    }
+    let max_encoded_len = (codec.max_encoded_len(), codec.max_chunked_len());
+
+    // Split the connection into a Sink and a Stream.
+    let (writer, reader) = io.framed(BatchCodec::new(codec, options.max_batch)).split();
+    let authenticated = Rc::new(Cell::new(!options.require_auth));
+    let reader = Deadlines::new(reader, authenticated.clone(), options, handle)?;
+    let reader = Until::new(reader, drain);
+    let (client, kicked) = match client {
+        Some((client, kicked)) => {
+            let kicked: Box<Future<Item = (), Error = ()>> = Box::new(kicked.map_err(|_| ()));
+            (Some(client), kicked)
+        }
+        None => (None, Box::new(future::empty()) as Box<Future<Item = (), Error = ()>>),
+    };
+    let reader = Until::new(reader, kicked);
+    let service = ClientService {
+        inner: service,
+        client: client,
+        authenticated: authenticated,
+    };
+
+    Ok(match options.replication {
+        Some(ref cache) => {
+            let (feed_snd, feed_rcv) = oneshot::channel();
+            let service = ReplicateService {
+                inner: service,
+                cache: cache.clone(),
+                feed: Rc::new(RefCell::new(Some(feed_snd))),
+            };
+            // Connections that never replicate drop the sender along with the service.
+            let feed = feed_rcv
+                .then(|feed| Ok(stream::iter_ok::<_, io::Error>(feed.ok()).flatten()))
+                .flatten_stream();
+            subscribed(reader, writer, service, feed, max_encoded_len, options, handle)
+        }
+        None => {
+            let pushes = stream::empty();
+            subscribed(reader, writer, service, pushes, max_encoded_len, options, handle)
+        }
+    })
+}
+
+/// Like `connection`, behind a `SubscribeService` pushing the connection's notifications along
+/// with `pushes`, if the server has `ServeOptions::notifications`.
+fn subscribed<R, W, T, P>(
+    reader: R,
+    writer: W,
+    service: T,
+    pushes: P,
+    max_encoded_len: (usize, usize),
+    options: &ServeOptions,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = ()>>
+where
+    R: Stream<Item = Vec<(RequestId, Message)>, Error = io::Error> + 'static,
+    W: Sink<SinkItem = (RequestId, Message), SinkError = io::Error> + 'static,
+    T: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Future: 'static,
+    P: Stream<Item = Message, Error = io::Error> + 'static,
+{
+    match options.notifications {
+        Some(ref hub) => {
+            let (subscriptions, notifications) = hub.connect(NOTIFICATION_BUFFER);
+            let service = SubscribeService {
+                inner: service,
+                subscriptions: Rc::new(subscriptions),
+            };
+            let notifications = notifications
+                .map(|notification| notification.to_message())
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "hub closed"));
+            let pushes = pushes.select(notifications);
+            connection(reader, writer, service, pushes, max_encoded_len, options, handle)
+        }
+        None => connection(reader, writer, service, pushes, max_encoded_len, options, handle),
+    }
+}
+
+/// The most notifications waiting to be written to a subscribed connection, see `Hub::connect`.
+static NOTIFICATION_BUFFER: usize = 1024;
+
+/// A middleware keeping the subscriptions of a connection, put in front of the rest by the
+/// server when it has `ServeOptions::notifications`. The key of an `Op::Subscribe` is the
+/// prefix of the keys to subscribe to, in the namespace the request addresses, see
+/// `message::EXT_NAMESPACE`. The connection is then pushed a `pubsub::Notification` whenever
+/// one of those keys is set, deleted, expires or is evicted, with unsolicited ids, see
+/// `codec::UNSOLICITED_FLAG`. An `Op::Unsubscribe` for the same prefix ends the subscription.
+///
+/// Both are passed on first, so that the middleware behind, such as an `AuthService`, can
+/// refuse them, and only take effect if the cache answers `Code::Ok`. A subscribed connection
+/// that doesn't send requests of its own is still closed once idle, see
+/// `ServeOptions::idle_timeout`, unless it sends `Op::Ping`s.
+pub struct SubscribeService<T> {
+    pub inner: T,
+    subscriptions: Rc<Subscriptions>,
+}
+
+impl<T> Service for SubscribeService<T>
+    where T: Service<Request = Message, Response = Message, Error = io::Error>,
+          T::Future: 'static {
     type Request = Message;
     type Response = Message;
     type Error = io::Error;
     type Future = Box<Future<Item = Message, Error = io::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        let (snd, rcv) = oneshot::channel();
-
-        self.cache.process(req, snd);
-
-        // rcv is a future that resolves when snd receives a message
-        rcv.map_err(|e| io::Error::new(io::ErrorKind::Other, e.description()))
-            .boxed()
+        let subscribe = match req.op() {
+            Op::Subscribe => true,
+            Op::Unsubscribe => false,
+            _ => return Box::new(self.inner.call(req)),
+        };
+        let namespace = match req.extension(message::EXT_NAMESPACE) {
+            Some(name) if !name.is_empty() => Some(name.to_vec()),
+            _ => None,
+        };
+        let prefix = req.key().unwrap_or_default().to_vec();
+        let subscriptions = self.subscriptions.clone();
+        Box::new(self.inner.call(req).map(move |resp| {
+            if resp.code() == Code::Ok {
+                if subscribe {
+                    subscriptions.subscribe(namespace, prefix);
+                } else {
+                    subscriptions.unsubscribe(namespace.as_ref().map(|n| &n[..]), &prefix);
+                }
+            }
+            resp
+        }))
     }
 }
 
-impl NewService for CacheService {
+/// A middleware streaming the writes to the cache to a replica, once its connection sends an
+/// `Op::Replicate`, put in front of the rest by the server when it has
+/// `ServeOptions::replication`. The writes are pushed with unsolicited ids, as a `cache::Feed`,
+/// for the replica to apply, see `replica::follow`.
+///
+/// The request is passed on first, so that the middleware behind, such as an `AuthService`,
+/// can refuse it, and the feed only starts if the cache answers `Code::Ok`. A connection can
+/// only replicate once. It is closed if the cache stops streaming to it, as it does once the
+/// replica falls too far behind, see `cache::Options::replication`.
+pub struct ReplicateService<T> {
+    pub inner: T,
+    cache: Arc<Cache>,
+    /// Where the connection takes the feed from, until it has one.
+    feed: Rc<RefCell<Option<oneshot::Sender<Feed>>>>,
+}
+
+impl<T> Service for ReplicateService<T>
+    where T: Service<Request = Message, Response = Message, Error = io::Error>,
+          T::Future: 'static {
     type Request = Message;
     type Response = Message;
     type Error = io::Error;
-    type Instance = CacheService;
+    type Future = Box<Future<Item = Message, Error = io::Error>>;
 
-    fn new_service(&self) -> io::Result<Self::Instance> {
-        Ok(CacheService { cache: self.cache.clone() })
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if req.op() != Op::Replicate {
+            return Box::new(self.inner.call(req));
+        }
+        let (cache, feed) = (self.cache.clone(), self.feed.clone());
+        Box::new(self.inner.call(req).and_then(move |resp| {
+            if resp.code() != Code::Ok {
+                return Box::new(future::ok(resp)) as Box<Future<Item = _, Error = _>>;
+            }
+            match feed.borrow_mut().take() {
+                Some(feed) => Box::new(cache.replicate().map(move |writes| {
+                    let _ = feed.send(writes);
+                    resp
+                })),
+                None => {
+                    let error = b"the connection is already replicating".to_vec();
+                    let error = Some(message::payload(0, error));
+                    let resp = message::response(Op::Replicate, Code::Error, error);
+                    Box::new(future::ok(resp))
+                }
+            }
+        }))
     }
 }
 
-/// A simplistic stat collecting middleware that counts total number of requests and tracks
-/// average request time.
-pub struct StatService<T> {
+/// A middleware keeping a connection's entry in the server's `Clients` up to date, put in front
+/// of the rest by the server when it has `ServeOptions::stats`. It counts the connection's
+/// requests, and records who it authenticated as, named by the payload of the `Code::Ok`
+/// answering its `Op::Auth`, see `AuthService`. It answers `Op::ClientList` with the connected
+/// clients, packed by `clients::encode`, and `Op::Kick`, whose payload is the id of the client
+/// to kick as a u64, with `Code::Ok` once kicked, or `Code::Miss` if there's no such client,
+/// see `Clients::kick`. It is also put in front of the rest by every server to tell its
+/// `Deadlines` when the connection has authenticated, see `ServeOptions::require_auth`.
+///
+/// Both are passed on first, so that the middleware behind, such as an `AuthService`, can
+/// refuse them, and only take effect if the cache answers `Code::Ok`.
+pub struct ClientService<T> {
     pub inner: T,
-    pub stats: Arc<Stats>,
+    client: Option<Registration>,
+    authenticated: Rc<Cell<bool>>,
 }
 
-impl<T> Service for StatService<T>
+impl<T> Service for ClientService<T>
     where T: Service<Request = Message, Response = Message, Error = io::Error>,
           T::Future: 'static {
     type Request = Message;
@@ -101,97 +766,3330 @@ impl<T> Service for StatService<T>
     type Error = io::Error;
     type Future = Box<Future<Item = Message, Error = io::Error>>;
 
-    // TODO: Clean this up
     fn call(&self, req: Self::Request) -> Self::Future {
+        let client = self.client.as_ref().map(|client| (client.clients().clone(), client.id()));
+        if let Some((ref registry, id)) = client {
+            registry.record(id, req.op());
+        }
+        if req.op() == Op::Auth {
+            let authenticated = self.authenticated.clone();
+            return Box::new(self.inner.call(req).map(move |resp| {
+                if resp.code() == Code::Ok {
+                    authenticated.set(true);
+                }
+                if let (Code::Ok, Some(identity), Some((registry, id))) =
+                    (resp.code(), resp.payload(), client)
+                {
+                    registry.identify(id, String::from_utf8_lossy(identity.data()).into_owned());
+                }
+                resp
+            }));
+        }
+        let registry = match client {
+            Some((registry, _)) => registry,
+            None => return Box::new(self.inner.call(req)),
+        };
         match req.op() {
-            Op::Stats => {
-                let data = self.stats.get_stats();
-                Box::new(self.inner.call(req).map(|resp| match resp {
-                    message::Message::Response(_, _, Some(payload)) => {
-                        let len = payload.type_id();
-                        let s = format!("keys: {} ", len) + data.as_ref();
-                        message::response(Op::Stats, Code::Ok, Some(
-                            message::payload(1, s.into_bytes())))
+            Op::ClientList => Box::new(self.inner.call(req).map(move |resp| {
+                if resp.code() != Code::Ok {
+                    return resp;
+                }
+                let data = clients::encode(&registry.list());
+                let payload = message::payload(clients::CLIENT_LIST_TYPE_ID, data);
+                message::response(Op::ClientList, Code::Ok, Some(payload))
+            })),
+            Op::Kick => {
+                let kicked = req.payload().map(|payload| message::decode_u64(payload.data()));
+                Box::new(self.inner.call(req).map(move |resp| {
+                    match (resp.code(), kicked) {
+                        (Code::Ok, Some(Ok(kicked))) if registry.kick(kicked) => resp,
+                        (Code::Ok, Some(Ok(_))) => message::response(Op::Kick, Code::Miss, None),
+                        (Code::Ok, _) => {
+                            let error = b"no client id given to kick op".to_vec();
+                            let error = Some(message::payload(0, error));
+                            message::response(Op::Kick, Code::Error, error)
+                        }
+                        _ => resp,
                     }
-                    _ => message::response(Op::Stats, Code::Ok,
-                                           Some(message::payload(1, data.into_bytes())))
                 }))
             }
-            _ => {
-                let stats = self.stats.clone();
-                let start_time = time::now();
-                Box::new(self.inner.call(req).and_then(move|resp|{
-                    stats.incr_total_requests();
-                    stats.add_request_time((time::now() - start_time)
-                    .num_microseconds().unwrap() as usize);
-                    Ok(resp)
-                }))
+            _ => Box::new(self.inner.call(req)),
+        }
+    }
+}
+
+/// Ends a stream once `until` resolves or fails.
+struct Until<S, F> {
+    inner: S,
+    until: F,
+}
+
+impl<S, F> Until<S, F> {
+    fn new(inner: S, until: F) -> Self {
+        Until {
+            inner: inner,
+            until: until,
+        }
+    }
+}
+
+impl<S: Stream, F: Future> Stream for Until<S, F> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        match self.until.poll() {
+            Ok(Async::NotReady) => self.inner.poll(),
+            _ => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+/// Counts a connection from `ip` among those open from it, until dropped.
+struct ConnectionSlot {
+    open: Rc<RefCell<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl ConnectionSlot {
+    /// Takes a slot for a new connection from `ip`, unless `max` are already open.
+    fn take(
+        open: &Rc<RefCell<HashMap<IpAddr, usize>>>,
+        ip: IpAddr,
+        max: Option<usize>,
+    ) -> Option<Self> {
+        let mut counts = open.borrow_mut();
+        let count = counts.entry(ip).or_insert(0);
+        if max.map_or(false, |max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot {
+            open: open.clone(),
+            ip: ip,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.open.borrow_mut();
+        let last = match counts.get_mut(&self.ip) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
             }
+            None => false,
+        };
+        if last {
+            counts.remove(&self.ip);
         }
     }
 }
 
-impl<T> NewService for StatService<T>
+/// Answers the requests read from `reader` with `service`, writing the responses to `writer`,
+/// along with the messages from `pushes`, which are tagged with unsolicited ids. Responses longer
+/// than the first of `max_encoded_len` are replaced with errors, or than the second once chunks
+/// have been agreed to, see `cap_response`. The returned future resolves when the connection
+/// closes; if it closes because reading or writing failed, the error is logged and counted
+/// rather than dropped.
+fn connection<R, W, T, P>(
+    reader: R,
+    writer: W,
+    service: T,
+    pushes: P,
+    max_encoded_len: (usize, usize),
+    options: &ServeOptions,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = ()>>
 where
-    T: NewService<
-        Request = Message,
-        Response = Message,
-        Error = io::Error,
-    >,
-    <T::Instance as Service>::Future: 'static,
+    R: Stream<Item = Vec<(RequestId, Message)>, Error = io::Error> + 'static,
+    W: Sink<SinkItem = (RequestId, Message), SinkError = io::Error> + 'static,
+    T: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Future: 'static,
+    P: Stream<Item = Message, Error = io::Error> + 'static,
 {
-    type Request = Message;
-    type Response = Message;
+    let writer = FlushOnBatch::new(ByteBudget::new(writer, options.max_pending_bytes));
+    let stats = options.stats.clone();
+
+    // Map the service function onto each element in the stream, marking the end of each batch.
+    let responses = dispatch(reader, service, options.max_in_flight, handle)
+        .map(|batch| {
+            let frames = batch.map(Outgoing::Frame);
+            frames.chain(stream::once(Ok(Outgoing::Flush)))
+        })
+        .flatten();
+    // Responses too long for a frame can be sent in chunks once the handshake has agreed to it,
+    // as the codec has by the time the Hello is answered.
+    let (max_frame_len, max_chunked_len) = max_encoded_len;
+    let mut max_encoded_len = max_frame_len;
+    let limits = options.limits;
+    let responses = WithPushes::new(responses, pushes).map(move |outgoing| match outgoing {
+        Outgoing::Frame((req_id, mut resp)) => {
+            if resp.op() == Op::Hello && resp.code() == Code::Ok {
+                let features = resp.payload().map(codec::decode_hello);
+                max_encoded_len = match features {
+                    Some(Ok((_, features))) if features & codec::FEATURE_CHUNKED != 0 => {
+                        max_chunked_len
+                    }
+                    _ => max_frame_len,
+                };
+                resp = cache::with_limits(resp, &limits);
+            }
+            Outgoing::Frame((req_id, cap_response(resp, max_encoded_len)))
+        }
+        Outgoing::Flush => Outgoing::Flush,
+    });
+
+    // Finally, write out all of the responses.
+    Box::new(writer.send_all(responses).then(move |result| {
+        if let Err(e) = result {
+            warn!("Connection error: {}.", e);
+            if let Some(stats) = stats {
+                stats.incr_connection_errors();
+            }
+        }
+        Ok(())
+    }))
+}
+
+/// What a connection writes: a frame, or the end of a batch of frames, after which the writer is
+/// flushed.
+enum Outgoing {
+    Frame((RequestId, Message)),
+    Flush,
+}
+
+/// Interleaves messages the server sends unprompted with a connection's responses, tagging each
+/// with the next id in the range reserved by `codec::UNSOLICITED_FLAG`. A pending push is sent
+/// ahead of any ready response, and flushed on its own. Ends with the responses, as the
+/// connection is done by then.
+struct WithPushes<R, P> {
+    responses: R,
+    pushes: Option<P>,
+    next_id: u64,
+    flush: bool,
+}
+
+impl<R, P> WithPushes<R, P> {
+    fn new(responses: R, pushes: P) -> Self {
+        WithPushes {
+            responses: responses,
+            pushes: Some(pushes),
+            next_id: 0,
+            flush: false,
+        }
+    }
+}
+
+impl<R, P> Stream for WithPushes<R, P>
+where
+    R: Stream<Item = Outgoing, Error = io::Error>,
+    P: Stream<Item = Message, Error = io::Error>,
+{
+    type Item = Outgoing;
     type Error = io::Error;
-    type Instance = StatService<T::Instance>;
 
-    fn new_service(&self) -> io::Result<Self::Instance> {
-        let inner = self.inner.new_service()?;
-        Ok(StatService {
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        if self.flush {
+            self.flush = false;
+            return Ok(Async::Ready(Some(Outgoing::Flush)));
+        }
+        let push = match self.pushes {
+            Some(ref mut pushes) => pushes.poll()?,
+            None => Async::NotReady,
+        };
+        match push {
+            Async::Ready(Some(msg)) => {
+                let id = codec::UNSOLICITED_FLAG | self.next_id;
+                self.next_id = (self.next_id + 1) & !codec::UNSOLICITED_FLAG;
+                self.flush = true;
+                return Ok(Async::Ready(Some(Outgoing::Frame((id, msg)))));
+            }
+            Async::Ready(None) => self.pushes = None,
+            Async::NotReady => (),
+        }
+        self.responses.poll()
+    }
+}
+
+/// A sink that only flushes `inner` at the end of a batch, marked by `Outgoing::Flush`, rather
+/// than whenever the connection waits on the next response. The responses to a batch of
+/// pipelined requests then go out in as few writes as `inner` allows, at the cost of holding
+/// back the first responses of a batch until its last is ready. `inner` may still flush sooner
+/// of its own accord, as `ByteBudget` does once it holds too much.
+struct FlushOnBatch<S> {
+    inner: S,
+    flush: bool,
+}
+
+impl<S> FlushOnBatch<S> {
+    fn new(inner: S) -> Self {
+        FlushOnBatch {
             inner: inner,
-            stats: self.stats.clone(),
-        })
+            flush: false,
+        }
     }
 }
 
-/// A printf logger middleware.
-pub struct LogService<T> {
-    pub inner: T,
+impl<S> Sink for FlushOnBatch<S>
+where
+    S: Sink<SinkItem = (RequestId, Message), SinkError = io::Error>,
+{
+    type SinkItem = Outgoing;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Outgoing) -> StartSend<Outgoing, io::Error> {
+        match item {
+            Outgoing::Frame(frame) => {
+                Ok(match self.inner.start_send(frame)? {
+                    AsyncSink::Ready => AsyncSink::Ready,
+                    AsyncSink::NotReady(frame) => AsyncSink::NotReady(Outgoing::Frame(frame)),
+                })
+            }
+            Outgoing::Flush => {
+                self.flush = true;
+                Ok(AsyncSink::Ready)
+            }
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if !self.flush {
+            return Ok(Async::Ready(()));
+        }
+        if self.inner.poll_complete()?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        self.flush = false;
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.inner.close()
+    }
 }
 
-impl<T> Service for LogService<T>
-    where T: Service<Request = Message, Response = Message, Error = io::Error>,
-          T::Future: 'static {
-    type Request = Message;
-    type Response = Message;
-    type Error = io::Error;
-    type Future = Box<Future<Item = Message, Error = io::Error>>;
+/// A sink that holds back once `budget` bytes of encoded frames have been sent to `inner` without
+/// being flushed, until a flush completes. Responses are only produced as the sink takes them, so
+/// this pauses reading requests from a connection whose peer isn't reading its responses.
+struct ByteBudget<S> {
+    inner: S,
+    budget: usize,
+    buffered: usize,
+}
 
-    fn call(&self, req: Self::Request) -> Self::Future {
-        println!("{}", req);
-        Box::new(self.inner.call(req).and_then(|resp| {
-            println!("{}", resp);
-            Ok(resp)
-        }))
+impl<S> ByteBudget<S> {
+    fn new(inner: S, budget: usize) -> Self {
+        ByteBudget {
+            inner: inner,
+            budget: budget,
+            buffered: 0,
+        }
     }
 }
 
-impl<T> NewService for LogService<T>
+impl<S> Sink for ByteBudget<S>
 where
-    T: NewService<
-        Request = Message,
-        Response = Message,
-        Error = io::Error,
-    >,
-    <T::Instance as Service>::Future: 'static,
+    S: Sink<SinkItem = (RequestId, Message), SinkError = io::Error>,
 {
-    type Request = Message;
-    type Response = Message;
-    type Error = io::Error;
-    type Instance = LogService<T::Instance>;
+    type SinkItem = (RequestId, Message);
+    type SinkError = io::Error;
 
-    fn new_service(&self) -> io::Result<Self::Instance> {
-        let inner = self.inner.new_service()?;
-        Ok(LogService { inner: inner })
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        let len = codec::encoded_len(&item.1);
+        if self.buffered > 0 && self.buffered + len > self.budget {
+            if self.poll_complete()?.is_not_ready() {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+        match self.inner.start_send(item)? {
+            AsyncSink::Ready => {
+                self.buffered += len;
+                Ok(AsyncSink::Ready)
+            }
+            not_ready => Ok(not_ready),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        let ready = self.inner.poll_complete()?;
+        if ready.is_ready() {
+            self.buffered = 0;
+        }
+        Ok(ready)
+    }
+}
+
+/// Ends a stream of requests once its connection has gone too long without completing the
+/// handshake, or once established, without sending a request. See `ServeOptions`. The handshake
+/// is done once an `Op::Hello` was read and `authenticated` is set, by the `ClientService`
+/// answering the connection's `Op::Auth` if the server requires one.
+struct Deadlines<S> {
+    inner: S,
+    greeted: bool,
+    authenticated: Rc<Cell<bool>>,
+    established: bool,
+    idle_timeout: Option<Duration>,
+    timeout: Option<Timeout>,
+}
+
+impl<S> Deadlines<S> {
+    fn new(
+        inner: S,
+        authenticated: Rc<Cell<bool>>,
+        options: &ServeOptions,
+        handle: &Handle,
+    ) -> io::Result<Self> {
+        let timeout = match options.handshake_timeout.or(options.idle_timeout) {
+            Some(timeout) => Some(Timeout::new(timeout, handle)?),
+            None => None,
+        };
+        Ok(Deadlines {
+            inner: inner,
+            greeted: false,
+            authenticated: authenticated,
+            established: options.handshake_timeout.is_none(),
+            idle_timeout: options.idle_timeout,
+            timeout: timeout,
+        })
+    }
+
+    /// Marks the connection established if its handshake is done, returning whether it just was.
+    fn establish(&mut self) -> bool {
+        let done = self.greeted && self.authenticated.get();
+        let established = done && !self.established;
+        self.established |= done;
+        established
+    }
+
+    /// Moves the deadline on from a request just read, or from the handshake if it was just
+    /// done, by the idle timeout.
+    fn restart(&mut self, was_established: bool) {
+        match (self.idle_timeout, was_established) {
+            (Some(idle_timeout), _) => {
+                let at = Instant::now() + idle_timeout;
+                if let Some(ref mut timeout) = self.timeout {
+                    timeout.reset(at);
+                }
+            }
+            // The handshake was the last deadline.
+            (None, false) => self.timeout = None,
+            (None, true) => (),
+        }
+    }
+}
+
+impl<S> Stream for Deadlines<S>
+where
+    S: Stream<Item = Vec<(RequestId, Message)>, Error = io::Error>,
+{
+    type Item = Vec<(RequestId, Message)>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        match self.inner.poll()? {
+            Async::Ready(Some(batch)) => {
+                self.greeted |= batch.iter().any(|&(_, ref msg)| hello(msg).code() == Code::Ok);
+                let just_established = self.establish();
+                if self.established {
+                    self.restart(!just_established);
+                }
+                Ok(Async::Ready(Some(batch)))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => {
+                let expired = match self.timeout {
+                    Some(ref mut timeout) => timeout.poll()?.is_ready(),
+                    None => false,
+                };
+                // The Auth completing the handshake may have been answered since the last batch.
+                if expired && self.establish() {
+                    self.restart(false);
+                    return self.poll();
+                }
+                Ok(if expired {
+                    Async::Ready(None)
+                } else {
+                    Async::NotReady
+                })
+            }
+        }
+    }
+}
+
+/// Answers an `Op::Hello`, whose payload is the protocol version the client speaks and the
+/// features it offers, see `codec::hello_payload`, with the server's protocol version and the
+/// offered features it supports, or `Code::Error` if the server doesn't speak the client's
+/// version. A client that sent a bare version is answered with a bare version. `connection`
+/// adds the cache's limits to a successful answer, see `ServeOptions::limits`.
+fn hello(req: &Message) -> Message {
+    let offer = req.payload().map(codec::decode_hello);
+    if req.op() != Op::Hello {
+        return message::response(req.op(), Code::Error, None);
+    }
+    match offer {
+        Some(Ok((v, features)))
+            if v >= codec::MIN_PROTOCOL_VERSION && v <= codec::PROTOCOL_VERSION => {
+            let payload = if req.type_id() == Some(codec::HELLO_MAGIC) {
+                codec::hello_payload(codec::PROTOCOL_VERSION, features & codec::SUPPORTED_FEATURES)
+            } else {
+                message::payload(0, message::encode_u32(codec::PROTOCOL_VERSION))
+            };
+            message::response(Op::Hello, Code::Ok, Some(payload))
+        }
+        _ => {
+            let error = format!(
+                "unsupported protocol version, expected {} to {}",
+                codec::MIN_PROTOCOL_VERSION,
+                codec::PROTOCOL_VERSION
+            );
+            let payload = message::payload(0, error.into_bytes());
+            message::response(Op::Hello, Code::Error, Some(payload))
+        }
+    }
+}
+
+/// Answers an `Op::Ping` with `Code::Ok` and the ping's payload, if any. Pings are answered by
+/// the connection itself, without reaching the service, so that load balancers and clients can
+/// check that the server is up, and keep an idle connection open, see `ServeOptions`.
+fn pong(req: &Message) -> Message {
+    message::response(Op::Ping, Code::Ok, req.payload().cloned())
+}
+
+/// The number of keys per frame of an `Op::ScanStream` that doesn't ask for a batch size.
+pub static DEFAULT_SCAN_BATCH: u32 = 100;
+
+/// The responses to a batch of requests.
+type Responses = Box<Stream<Item = (RequestId, Message), Error = io::Error>>;
+
+/// Calls `service` for each batch of requests, yielding a stream of the responses to each batch,
+/// tagged with the request's id, in request order. Every request in a batch is passed to the
+/// service before any of their responses are waited on, and up to `max_in_flight` batches are
+/// before the first of them has been answered. Only then is the next batch taken from `batches`,
+/// so that a client pipelining requests faster than they are answered is read from no faster.
+/// Most requests have exactly one response, but an `Op::ScanStream` and an `Op::Dump` are
+/// answered with a stream of frames, see `scan_stream` and `dump_stream`.
+fn dispatch<S, T>(
+    batches: S,
+    service: T,
+    max_in_flight: usize,
+    handle: &Handle,
+) -> Box<Stream<Item = Responses, Error = io::Error>>
+where
+    S: Stream<Item = Vec<(RequestId, Message)>, Error = io::Error> + 'static,
+    T: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Future: 'static,
+{
+    let service = Rc::new(service);
+    let handle = handle.clone();
+    let answered = batches.map(move |batch| {
+        let responses: Vec<Box<Future<Item = Responses, Error = io::Error>>> = batch
+            .into_iter()
+            .map(|(req_id, msg)| -> Box<Future<Item = Responses, Error = io::Error>> {
+                let once = move |resp| -> Responses { Box::new(stream::once(Ok((req_id, resp)))) };
+                // A refused frame is answered as such, whatever its op.
+                if let Some(resp) = codec::refused(&msg) {
+                    return Box::new(future::ok(once(resp)));
+                }
+                match msg.op() {
+                    Op::ScanStream => {
+                        Box::new(future::ok(scan_stream(service.clone(), req_id, &msg)))
+                    }
+                    Op::Dump => {
+                        Box::new(future::ok(dump_stream(service.clone(), req_id, &msg, &handle)))
+                    }
+                    Op::Hello => Box::new(future::ok(once(hello(&msg)))),
+                    Op::Ping => Box::new(future::ok(once(pong(&msg)))),
+                    _ => Box::new(service.call(msg).map(once)),
+                }
+            })
+            .collect();
+        future::join_all(responses).map(|responses| -> Responses {
+            Box::new(stream::iter_ok::<_, io::Error>(responses).flatten())
+        })
+    });
+    Box::new(answered.buffered(cmp::max(max_in_flight, 1)))
+}
+
+/// Answers an `Op::ScanStream` by walking the keyspace with `Op::Scan` requests, sending each
+/// page of keys as an `Op::ScanStream` frame with `Code::Ok`, and finishing with a `Code::End`
+/// frame carrying no payload. The payload of the request, if any, is the number of keys per
+/// frame as a u32. An `EXT_PATTERN` extension on the request is passed on to every page.
+///
+/// A page is only fetched once the previous frame has been taken by the connection's sink, so a
+/// slow reader holds back the scan rather than letting frames pile up in memory.
+fn scan_stream<T>(
+    service: Rc<T>,
+    req_id: RequestId,
+    req: &Message,
+) -> Box<Stream<Item = (RequestId, Message), Error = io::Error>>
+where
+    T: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Future: 'static,
+{
+    let count = match req.payload().map(|p| message::decode_u32(p.data())) {
+        None => DEFAULT_SCAN_BATCH,
+        Some(Ok(count)) => count,
+        Some(Err(e)) => {
+            let resp = message::response(
+                Op::ScanStream,
+                Code::Error,
+                Some(message::payload(0, e.description().to_owned().into_bytes())),
+            );
+            return Box::new(stream::once(Ok((req_id, resp))));
+        }
+    };
+
+    let pattern = req.extension(message::EXT_PATTERN).map(|p| p.to_vec());
+    let namespace = req.extension(message::EXT_NAMESPACE).map(|n| n.to_vec());
+
+    // The state is the cursor to resume after, or `None` once the last frame has been sent.
+    Box::new(stream::unfold(Some(None), move |cursor: Option<Option<Vec<u8>>>| {
+        cursor.map(|cursor| {
+            let mut req = match pattern {
+                Some(ref pattern) => cache::scan_matching_request(cursor, count, pattern),
+                None => cache::scan_request(cursor, count),
+            };
+            if let Some(ref namespace) = namespace {
+                req = req.with_extension(message::EXT_NAMESPACE, namespace.clone());
+            }
+            service.call(req).map(move |resp| {
+                let keys = match (resp.code(), resp.payload()) {
+                    (Code::Ok, Some(payload)) => message::decode_keys(payload.data()).ok(),
+                    (Code::Ok, None) => Some(vec![]),
+                    _ => None,
+                };
+                match keys {
+                    Some(ref keys) if !keys.is_empty() => {
+                        let frame = message::response(
+                            Op::ScanStream,
+                            Code::Ok,
+                            resp.payload().cloned(),
+                        );
+                        ((req_id, frame), Some(keys.last().cloned()))
+                    }
+                    Some(_) => {
+                        let frame = message::response(Op::ScanStream, Code::End, None);
+                        ((req_id, frame), None)
+                    }
+                    // Pass errors on and end the stream.
+                    None => ((req_id, resp), None),
+                }
+            })
+        })
+    }))
+}
+
+/// Answers an `Op::Dump` by fetching the entries a page at a time, passing the request on to
+/// `service` with the cursor set, see `cache::dump_request`, so that every page goes through the
+/// same checks as the request. Each page is sent as an `Op::Dump` frame with `Code::Ok`, and a
+/// `Code::End` frame carrying no payload follows the last. Without a payload on the request,
+/// pages hold `DEFAULT_SCAN_BATCH` entries and aren't held back.
+///
+/// As with `scan_stream`, a page is only fetched once the previous frame has been taken by the
+/// connection's sink. Given a rate, it is also only fetched once the entries sent so far are
+/// due, so that a dump of a large cache takes its turns at the worker between other requests
+/// rather than hogging it.
+fn dump_stream<T>(
+    service: Rc<T>,
+    req_id: RequestId,
+    req: &Message,
+    handle: &Handle,
+) -> Box<Stream<Item = (RequestId, Message), Error = io::Error>>
+where
+    T: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Future: 'static,
+{
+    let (count, per_second) = match req.payload().map(cache::decode_dump_request) {
+        None => (DEFAULT_SCAN_BATCH, 0),
+        Some(Ok((count, per_second, _))) => (count, per_second),
+        Some(Err(e)) => {
+            let resp = message::response(
+                Op::Dump,
+                Code::Error,
+                Some(message::payload(0, e.description().to_owned().into_bytes())),
+            );
+            return Box::new(stream::once(Ok((req_id, resp))));
+        }
+    };
+
+    let prefix = req.key().unwrap_or_default().to_vec();
+    let namespace = req.extension(message::EXT_NAMESPACE).map(|n| n.to_vec());
+    let handle = handle.clone();
+
+    // The state is the cursor to resume after and how long to wait before fetching the page, or
+    // `None` once the last frame has been sent.
+    let start: Option<(Option<Vec<u8>>, Option<Duration>)> = Some((None, None));
+    Box::new(stream::unfold(start, move |state| {
+        state.map(|(cursor, delay)| {
+            let mut req = cache::dump_request(&prefix, cursor.as_ref().map(|c| &c[..]), count, 0);
+            if let Some(ref namespace) = namespace {
+                req = req.with_extension(message::EXT_NAMESPACE, namespace.clone());
+            }
+            let due: Box<Future<Item = (), Error = io::Error>> = match delay {
+                Some(delay) => Box::new(future::result(Timeout::new(delay, &handle)).flatten()),
+                None => Box::new(future::ok(())),
+            };
+            let service = service.clone();
+            due.and_then(move |()| service.call(req)).map(move |resp| {
+                let entries = match (resp.code(), resp.payload()) {
+                    (Code::Ok, Some(payload)) => cache::decode_dump(payload.data()).ok(),
+                    (Code::Ok, None) => Some(vec![]),
+                    _ => None,
+                };
+                match entries {
+                    Some(ref entries) if !entries.is_empty() => {
+                        let frame = message::response(Op::Dump, Code::Ok, resp.payload().cloned());
+                        let delay = match per_second {
+                            0 => None,
+                            _ => {
+                                let millis = entries.len() as u64 * 1000 / per_second as u64;
+                                Some(Duration::from_millis(millis))
+                            }
+                        };
+                        let cursor = entries.last().map(|&(ref key, _, _)| key.clone());
+                        ((req_id, frame), Some((cursor, delay)))
+                    }
+                    Some(_) => {
+                        let frame = message::response(Op::Dump, Code::End, None);
+                        ((req_id, frame), None)
+                    }
+                    // Pass errors on and end the stream.
+                    None => ((req_id, resp), None),
+                }
+            })
+        })
+    }))
+}
+
+/// Replaces a response that the codec would refuse to encode with a `Code::Error` response,
+/// so that the client gets an answer for its request.
+fn cap_response(resp: Message, max_encoded_len: usize) -> Message {
+    if codec::encoded_len(&resp) <= max_encoded_len {
+        return resp;
+    }
+    message::response(
+        resp.op(),
+        Code::Error,
+        Some(message::payload(
+            0,
+            "response exceeds maximum length".to_owned().into_bytes(),
+        )),
+    )
+}
+
+/// A service middleware that dispatches requests to `cache::Cache`.
+pub struct CacheService {
+    pub cache: Arc<cache::Cache>,
+}
+
+impl Service for CacheService {
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let (snd, rcv) = oneshot::channel();
+        let op = req.op();
+
+        self.cache.process(req, snd);
+
+        // rcv is a future that resolves when snd receives a message. If snd is dropped unsent,
+        // the worker is gone, which is answered rather than failing the connection.
+        rcv.or_else(move |_| {
+            error!("Cache worker dropped a {} request.", op);
+            Ok(message::server_error(op, "cache worker stopped"))
+        }).boxed()
+    }
+}
+
+impl NewService for CacheService {
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Instance = CacheService;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        Ok(CacheService { cache: self.cache.clone() })
+    }
+}
+
+/// A simplistic stat collecting middleware that counts total number of requests and tracks
+/// average request time. It answers `Op::Stats` with `ServerStats`, packed by
+/// `ServerStats::encode`, or as JSON if the request's payload has `stats::STATS_JSON_TYPE_ID`.
+pub struct StatService<T> {
+    pub inner: T,
+    pub stats: Arc<Stats>,
+    pub clock: Arc<Clock>,
+}
+
+impl<T> Service for StatService<T>
+    where T: Service<Request = Message, Response = Message, Error = io::Error>,
+          T::Future: 'static {
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+    // TODO: Clean this up
+    fn call(&self, req: Self::Request) -> Self::Future {
+        match req.op() {
+            Op::Stats => {
+                let (uptime, requests) = (self.stats.uptime(), self.stats.snapshot());
+                let json = req.type_id() == Some(stats::STATS_JSON_TYPE_ID);
+                Box::new(self.inner.call(req).map(move |resp| {
+                    let stats = ServerStats {
+                        uptime: uptime,
+                        requests: requests,
+                        cache: resp.payload().and_then(|p| cache::decode_stats(p).ok()),
+                        limits: cache::decode_limits(&resp).unwrap_or_default(),
+                    };
+                    let payload = if json {
+                        message::payload(stats::STATS_JSON_TYPE_ID, stats.to_json())
+                    } else {
+                        message::payload(stats::STATS_TYPE_ID, stats.encode())
+                    };
+                    message::response(Op::Stats, Code::Ok, Some(payload))
+                }))
+            }
+            op => {
+                let stats = self.stats.clone();
+                let clock = self.clock.clone();
+                let start_time = clock.now();
+                let bytes_in = codec::encoded_len(&req);
+                Box::new(self.inner.call(req).and_then(move|resp|{
+                    stats.record_request(op, resp.code(), (clock.now() - start_time)
+                    .num_microseconds().unwrap() as usize);
+                    stats.record_transfer(bytes_in, codec::encoded_len(&resp));
+                    Ok(resp)
+                }))
+            }
+        }
+    }
+}
+
+impl<T> NewService for StatService<T>
+where
+    T: NewService<
+        Request = Message,
+        Response = Message,
+        Error = io::Error,
+    >,
+    <T::Instance as Service>::Future: 'static,
+{
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Instance = StatService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = self.inner.new_service()?;
+        Ok(StatService {
+            inner: inner,
+            stats: self.stats.clone(),
+            clock: self.clock.clone(),
+        })
+    }
+}
+
+/// A middleware recording the requests the service it wraps is slow to answer in a `SlowLog`,
+/// and answering `Op::SlowLog` with them, packed by `slowlog::encode`. A request whose payload
+/// has `slowlog::SLOW_LOG_RESET` set also empties the log.
+pub struct SlowLogService<T> {
+    pub inner: T,
+    pub slow_log: Arc<SlowLog>,
+    pub clock: Arc<Clock>,
+}
+
+impl<T> Service for SlowLogService<T>
+    where T: Service<Request = Message, Response = Message, Error = io::Error>,
+          T::Future: 'static {
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if req.op() == Op::SlowLog {
+            let reset = req.payload().map_or(false, |p| p.type_id() & slowlog::SLOW_LOG_RESET != 0);
+            let requests = if reset {
+                self.slow_log.reset()
+            } else {
+                self.slow_log.requests()
+            };
+            let payload = message::payload(0, slowlog::encode(&requests));
+            return Box::new(future::ok(message::response(Op::SlowLog, Code::Ok, Some(payload))));
+        }
+        let (slow_log, clock) = (self.slow_log.clone(), self.clock.clone());
+        let at = clock.now();
+        let (op, key) = (req.op(), req.key().unwrap_or_default().to_vec());
+        let payload_len = req.payload().map_or(0, |p| p.data().len());
+        Box::new(self.inner.call(req).map(move |resp| {
+            slow_log.record(SlowRequest {
+                op: op,
+                key: key,
+                payload_len: payload_len,
+                at: at,
+                micros: (clock.now() - at).num_microseconds().unwrap_or(i64::max_value()) as u64,
+            });
+            resp
+        }))
+    }
+}
+
+impl<T> NewService for SlowLogService<T>
+where
+    T: NewService<
+        Request = Message,
+        Response = Message,
+        Error = io::Error,
+    >,
+    <T::Instance as Service>::Future: 'static,
+{
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Instance = SlowLogService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = self.inner.new_service()?;
+        Ok(SlowLogService {
+            inner: inner,
+            slow_log: self.slow_log.clone(),
+            clock: self.clock.clone(),
+        })
+    }
+}
+
+/// A middleware logging every request and its response through the `log` facade, at `level`,
+/// or at `Level::Warn` for responses with `Code::ServerError`. Each record says which
+/// connection it is for, by the peer's address, see `connecting_peer`, and a number the
+/// middleware gives the connection, and which request on the connection, by the order it was
+/// received in, followed by the op, key, code and payload of the message.
+///
+/// Logging can be limited to some ops with `ops`, and payloads cut short with
+/// `max_payload_len`. Where the records go is up to the logger, such as a
+/// `logging::ThreadLogger`, which doesn't block the event loop writing them out.
+pub struct LogService<T> {
+    pub inner: T,
+    level: Level,
+    ops: Option<Arc<BTreeSet<Op>>>,
+    max_payload_len: Option<usize>,
+    peer: Option<SocketAddr>,
+    connection: usize,
+    requests: Cell<u64>,
+}
+
+/// Numbers the connections served through a `LogService`.
+static NEXT_LOGGED_CONNECTION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+impl<T> LogService<T> {
+    /// Logs every request at `Level::Info`, with its whole payload.
+    pub fn new(inner: T) -> Self {
+        LogService {
+            inner: inner,
+            level: Level::Info,
+            ops: None,
+            max_payload_len: None,
+            peer: None,
+            connection: 0,
+            requests: Cell::new(0),
+        }
+    }
+
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Only log the requests for these ops, and their responses.
+    pub fn ops(mut self, ops: &[Op]) -> Self {
+        self.ops = Some(Arc::new(ops.iter().cloned().collect()));
+        self
+    }
+
+    /// Log only the first `len` bytes of payloads.
+    pub fn max_payload_len(mut self, len: Option<usize>) -> Self {
+        self.max_payload_len = len;
+        self
+    }
+}
+
+impl<T> Service for LogService<T>
+    where T: Service<Request = Message, Response = Message, Error = io::Error>,
+          T::Future: 'static {
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let id = self.requests.get() + 1;
+        self.requests.set(id);
+        let logged = self.ops.as_ref().map_or(true, |ops| ops.contains(&req.op()));
+        if !logged || !log_enabled!(cmp::min(self.level, Level::Warn)) {
+            return Box::new(self.inner.call(req));
+        }
+        let context = match self.peer {
+            Some(peer) => format!("peer={} conn={} req={}", peer, self.connection, id),
+            None => format!("conn={} req={}", self.connection, id),
+        };
+        let (level, max_payload_len) = (self.level, self.max_payload_len);
+        log!(level, "{} {}", context, describe(&req, max_payload_len));
+        Box::new(self.inner.call(req).map(move |resp| {
+            let level = match resp.code() {
+                Code::ServerError => cmp::min(level, Level::Warn),
+                _ => level,
+            };
+            log!(level, "{} {}", context, describe(&resp, max_payload_len));
+            resp
+        }))
+    }
+}
+
+impl<T> NewService for LogService<T>
+where
+    T: NewService<
+        Request = Message,
+        Response = Message,
+        Error = io::Error,
+    >,
+    <T::Instance as Service>::Future: 'static,
+{
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Instance = LogService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = self.inner.new_service()?;
+        Ok(LogService {
+            inner: inner,
+            level: self.level,
+            ops: self.ops.clone(),
+            max_payload_len: self.max_payload_len,
+            peer: connecting_peer(),
+            connection: NEXT_LOGGED_CONNECTION.fetch_add(1, Ordering::SeqCst) + 1,
+            requests: Cell::new(0),
+        })
+    }
+}
+
+/// `msg` as `LogService` logs it: `key=value` pairs for its op, key and code, and for its
+/// payload's `type_id`, length and data, cut short after `max_payload_len` bytes. The key and
+/// data are quoted, with what isn't printable escaped.
+fn describe(msg: &Message, max_payload_len: Option<usize>) -> String {
+    let mut out = format!("op={}", msg.op());
+    match msg.key() {
+        Some(key) => out.push_str(&format!(" key={:?}", String::from_utf8_lossy(key))),
+        None => out.push_str(&format!(" code={}", msg.code())),
+    }
+    if let Some(payload) = msg.payload() {
+        let data = payload.data();
+        let shown = &data[..max_payload_len.map_or(data.len(), |max| data.len().min(max))];
+        out.push_str(&format!(
+            " type_id={} len={} payload={:?}",
+            payload.type_id(),
+            data.len(),
+            String::from_utf8_lossy(shown)
+        ));
+        if shown.len() < data.len() {
+            out.push_str("...");
+        }
+    }
+    out
+}
+
+/// What a connection authenticated with a given secret may do, see `AuthService`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// Only ops that don't write, see `Op::is_write`.
+    ReadOnly,
+    /// Every op but the admin ones, see `Op::is_admin`.
+    ReadWrite,
+    /// Every op, including those that act on the whole server, such as flushing or dumping it.
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Role::ReadOnly => "read-only",
+            Role::ReadWrite => "read-write",
+            Role::Admin => "admin",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// What a connection authenticated with a given secret may do, see `AuthService::with_acls`:
+/// what its `Role` allows, further limited to some ops and to keys under some prefixes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acl {
+    /// Who the connections authenticated with the ACL's secret are, as `ClientService` lists
+    /// them. Without a name, they are listed by their role.
+    pub name: Option<String>,
+    pub role: Role,
+    /// The ops allowed, or every op the role allows if `None`.
+    pub ops: Option<BTreeSet<Op>>,
+    /// The prefixes of the keys allowed, within the ACL's `namespace`, or every key if `None`.
+    /// Every key a request names must have one of them, see `cluster::keys_of`. Requests naming
+    /// no key are only allowed for `Op::Ping` and `Op::Info`: `Op::Stats` tells of keys and
+    /// clients beyond the prefixes.
+    pub prefixes: Option<Vec<Vec<u8>>>,
+    /// The namespace the requests must address, see `message::EXT_NAMESPACE`, or any if `None`,
+    /// unless there are `prefixes`, which then only apply in the default namespace.
+    pub namespace: Option<Vec<u8>>,
+}
+
+impl Acl {
+    /// Allows everything `role` allows.
+    pub fn new(role: Role) -> Self {
+        Acl {
+            name: None,
+            role: role,
+            ops: None,
+            prefixes: None,
+            namespace: None,
+        }
+    }
+
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn ops(mut self, ops: &[Op]) -> Self {
+        self.ops = Some(ops.iter().cloned().collect());
+        self
+    }
+
+    pub fn prefixes(mut self, prefixes: Vec<Vec<u8>>) -> Self {
+        self.prefixes = Some(prefixes);
+        self
+    }
+
+    pub fn namespace<N: Into<Vec<u8>>>(mut self, namespace: N) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Whether the ACL allows `req`, leaving its role aside.
+    pub fn allows(&self, req: &Message) -> bool {
+        if !self.ops.as_ref().map_or(true, |ops| ops.contains(&req.op())) {
+            return false;
+        }
+        let namespace = req.extension(message::EXT_NAMESPACE).unwrap_or_default();
+        let in_namespace = match self.namespace {
+            Some(ref allowed) => namespace == &allowed[..],
+            None => self.prefixes.is_none() || namespace.is_empty(),
+        };
+        if !in_namespace {
+            return false;
+        }
+        let prefixes = match self.prefixes {
+            Some(ref prefixes) => prefixes,
+            None => return true,
+        };
+        let keys = cluster::keys_of(req);
+        if keys.is_empty() {
+            return match req.op() {
+                Op::Ping | Op::Info => true,
+                _ => false,
+            };
+        }
+        keys.iter().all(|key| prefixes.iter().any(|prefix| key.starts_with(prefix)))
+    }
+}
+
+impl From<Role> for Acl {
+    fn from(role: Role) -> Self {
+        Acl::new(role)
+    }
+}
+
+/// A middleware requiring each connection to authenticate before its requests are passed on.
+/// A connection authenticates by sending an `Op::Auth` whose payload is one of the secrets in
+/// `credentials`, and is then allowed what the secret's `Role` allows. The Auth is answered with
+/// `Code::Ok` and who the connection is, see `Acl::name`, in UTF-8. Requests it isn't allowed,
+/// including everything before a successful Auth, are answered with `Code::Unauthorized`, as is
+/// an Auth with an unknown secret. Only `Op::Hello` is answered regardless.
+///
+/// With `with_acls`, each secret's `Acl` may limit it further: requests its role allows but its
+/// ACL doesn't are answered with `Code::Forbidden`.
+pub struct AuthService<T> {
+    pub inner: T,
+    credentials: Arc<HashMap<Vec<u8>, Arc<Acl>>>,
+    acl: RefCell<Option<Arc<Acl>>>,
+}
+
+impl<T> AuthService<T> {
+    pub fn new(inner: T, credentials: HashMap<Vec<u8>, Role>) -> Self {
+        let acls = credentials.into_iter().map(|(secret, role)| (secret, Acl::new(role)));
+        AuthService::with_acls(inner, acls.collect())
+    }
+
+    /// Allows each secret in `credentials` what its `Acl` allows.
+    pub fn with_acls(inner: T, credentials: HashMap<Vec<u8>, Acl>) -> Self {
+        let credentials = credentials.into_iter().map(|(secret, acl)| (secret, Arc::new(acl)));
+        AuthService {
+            inner: inner,
+            credentials: Arc::new(credentials.collect()),
+            acl: RefCell::new(None),
+        }
+    }
+}
+
+impl<T> Service for AuthService<T>
+    where T: Service<Request = Message, Response = Message, Error = io::Error>,
+          T::Future: 'static {
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if req.op() == Op::Auth {
+            let secret = req.payload().map_or(&[][..], |payload| payload.data());
+            let resp = match self.credentials.get(secret) {
+                Some(acl) => {
+                    *self.acl.borrow_mut() = Some(acl.clone());
+                    let identity = acl.name.clone().unwrap_or_else(|| acl.role.to_string());
+                    let identity = message::payload(0, identity.into_bytes());
+                    message::response(Op::Auth, Code::Ok, Some(identity))
+                }
+                None => message::response(Op::Auth, Code::Unauthorized, None),
+            };
+            return Box::new(future::ok(resp));
+        }
+
+        let code = match *self.acl.borrow() {
+            Some(ref acl) => {
+                let allowed = match acl.role {
+                    Role::Admin => true,
+                    Role::ReadWrite => !req.op().is_admin(),
+                    Role::ReadOnly => !req.op().is_write(),
+                };
+                if !allowed {
+                    Code::Unauthorized
+                } else if !acl.allows(&req) {
+                    Code::Forbidden
+                } else {
+                    Code::Ok
+                }
+            }
+            None => Code::Unauthorized,
+        };
+        match code {
+            Code::Ok => Box::new(self.inner.call(req)),
+            code => Box::new(future::ok(message::response(req.op(), code, None))),
+        }
+    }
+}
+
+impl<T> NewService for AuthService<T>
+where
+    T: NewService<
+        Request = Message,
+        Response = Message,
+        Error = io::Error,
+    >,
+    <T::Instance as Service>::Future: 'static,
+{
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Instance = AuthService<T::Instance>;
+
+    /// Every connection starts out unauthenticated.
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = self.inner.new_service()?;
+        Ok(AuthService {
+            inner: inner,
+            credentials: self.credentials.clone(),
+            acl: RefCell::new(None),
+        })
+    }
+}
+
+/// How fast a `RateLimitService` lets requests through: `per_second` on average, and up to
+/// `burst` at once after a lull.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Up to `per_second` requests a second, with bursts of as many.
+    pub fn new(per_second: u32) -> Self {
+        RateLimit {
+            per_second: per_second,
+            burst: per_second,
+        }
+    }
+
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+}
+
+/// The requests a connection, or an identity, may still send at once: a token bucket holding up
+/// to `RateLimit::burst` tokens, refilled at `RateLimit::per_second`, that each request takes one
+/// from.
+struct Bucket {
+    tokens: f64,
+    at: Timespec,
+}
+
+impl Bucket {
+    fn full(limit: RateLimit, now: Timespec) -> Self {
+        Bucket {
+            tokens: limit.burst as f64,
+            at: now,
+        }
+    }
+
+    /// Takes a token, if there is one by `now`.
+    fn take(&mut self, limit: RateLimit, now: Timespec) -> bool {
+        let elapsed = (now - self.at).num_microseconds().unwrap_or(i64::max_value()).max(0);
+        let refill = elapsed as f64 / 1e6 * limit.per_second as f64;
+        self.tokens = (self.tokens + refill).min(limit.burst as f64);
+        self.at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+type Buckets = Arc<Mutex<HashMap<Vec<u8>, Arc<Mutex<Bucket>>>>>;
+
+/// A middleware limiting how fast each connection sends requests to the service it wraps, so
+/// that one noisy client can't starve the others. Requests over the `RateLimit` are answered
+/// with `Code::Throttled` without being passed on.
+///
+/// With `per_identity` set, the connections authenticating with the same secret share their
+/// limit from then on, so that a client can't get around it by opening more connections. It has
+/// to wrap the `AuthService` to see them authenticate.
+pub struct RateLimitService<T> {
+    pub inner: T,
+    limit: RateLimit,
+    clock: Arc<Clock>,
+    bucket: Rc<RefCell<Arc<Mutex<Bucket>>>>,
+    identities: Option<Buckets>,
+}
+
+impl<T> RateLimitService<T> {
+    pub fn new(inner: T, limit: RateLimit, clock: Arc<Clock>) -> Self {
+        let bucket = Bucket::full(limit, clock.now());
+        RateLimitService {
+            inner: inner,
+            limit: limit,
+            clock: clock,
+            bucket: Rc::new(RefCell::new(Arc::new(Mutex::new(bucket)))),
+            identities: None,
+        }
+    }
+
+    /// Share the limit between the connections authenticated as the same identity.
+    pub fn per_identity(mut self, enabled: bool) -> Self {
+        self.identities = if enabled {
+            Some(Arc::new(Mutex::new(HashMap::new())))
+        } else {
+            None
+        };
+        self
+    }
+}
+
+impl<T> Service for RateLimitService<T>
+    where T: Service<Request = Message, Response = Message, Error = io::Error>,
+          T::Future: 'static {
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let now = self.clock.now();
+        if !self.bucket.borrow().lock().unwrap().take(self.limit, now) {
+            return Box::new(future::ok(message::response(req.op(), Code::Throttled, None)));
+        }
+        match self.identities {
+            Some(ref identities) if req.op() == Op::Auth => {
+                let secret = req.payload().map(|p| p.data().to_vec()).unwrap_or_default();
+                let (identities, bucket, limit) =
+                    (identities.clone(), self.bucket.clone(), self.limit);
+                Box::new(self.inner.call(req).map(move |resp| {
+                    if resp.code() == Code::Ok {
+                        let mut identities = identities.lock().unwrap();
+                        let shared = identities.entry(secret).or_insert_with(|| {
+                            Arc::new(Mutex::new(Bucket::full(limit, now)))
+                        });
+                        *bucket.borrow_mut() = shared.clone();
+                    }
+                    resp
+                }))
+            }
+            _ => Box::new(self.inner.call(req)),
+        }
+    }
+}
+
+impl<T> NewService for RateLimitService<T>
+where
+    T: NewService<
+        Request = Message,
+        Response = Message,
+        Error = io::Error,
+    >,
+    <T::Instance as Service>::Future: 'static,
+{
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Instance = RateLimitService<T::Instance>;
+
+    /// Every connection starts out with a full bucket of its own.
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = self.inner.new_service()?;
+        let bucket = Bucket::full(self.limit, self.clock.now());
+        Ok(RateLimitService {
+            inner: inner,
+            limit: self.limit,
+            clock: self.clock.clone(),
+            bucket: Rc::new(RefCell::new(Arc::new(Mutex::new(bucket)))),
+            identities: self.identities.clone(),
+        })
+    }
+}
+
+type Waiters = Arc<Mutex<Option<Vec<oneshot::Sender<Message>>>>>;
+
+/// The flights of Gets for a key, by the `type_id` and data of the Gets' payload, their flags
+/// such as `cache::GET_STALE`, and by their extensions, such as their namespace.
+type Flights = HashMap<(Option<(u32, Vec<u8>)>, message::Extensions), Waiters>;
+
+/// A middleware that coalesces identical concurrent `Op::Get` requests. The first Get for a key
+/// is passed through to the inner service; Gets for the same key, with the same flags and
+/// extensions, arriving while it is in flight wait for its response instead of reading the store
+/// again. The single response is cloned out to every waiter, and because each waiter resolves its
+/// own call's future, responses are routed back under each caller's own request id.
+///
+/// Any other request for a key ends that key's flights early, so a Get arriving after a Set or
+/// Del never observes a value read before it.
+pub struct CoalesceService<T> {
+    pub inner: T,
+    in_flight: Arc<Mutex<HashMap<Vec<u8>, Flights>>>,
+}
+
+impl<T> CoalesceService<T> {
+    pub fn new(inner: T) -> Self {
+        CoalesceService {
+            inner: inner,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<T> Service for CoalesceService<T>
+    where T: Service<Request = Message, Response = Message, Error = io::Error>,
+          T::Future: 'static {
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let key = req.key().unwrap_or(&[]).to_vec();
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        if req.op() != Op::Get {
+            in_flight.remove(&key);
+            return Box::new(self.inner.call(req));
+        }
+
+        // Join the flight for this key if its response hasn't been fanned out yet.
+        let flight = (
+            req.payload().map(|p| (p.type_id(), p.data().to_vec())),
+            req.extensions().clone(),
+        );
+        if let Some(waiters) = in_flight.get(&key).and_then(|flights| flights.get(&flight)) {
+            if let Some(ref mut waiters) = *waiters.lock().unwrap() {
+                let (snd, rcv) = oneshot::channel();
+                waiters.push(snd);
+                return Box::new(rcv.map_err(|e| {
+                    io::Error::new(io::ErrorKind::Other, e.description())
+                }));
+            }
+        }
+
+        let waiters: Waiters = Arc::new(Mutex::new(Some(vec![])));
+        in_flight
+            .entry(key.clone())
+            .or_insert_with(HashMap::new)
+            .insert(flight.clone(), waiters.clone());
+
+        let in_flight = self.in_flight.clone();
+        Box::new(self.inner.call(req).then(move |result| {
+            {
+                let mut in_flight = in_flight.lock().unwrap();
+                let landed = match in_flight.get_mut(&key) {
+                    Some(flights) => {
+                        if flights.get(&flight).map_or(false, |w| Arc::ptr_eq(w, &waiters)) {
+                            flights.remove(&flight);
+                        }
+                        flights.is_empty()
+                    }
+                    None => false,
+                };
+                if landed {
+                    in_flight.remove(&key);
+                }
+            }
+            // Waiters are dropped on error, which fails their futures too.
+            let waiters = waiters.lock().unwrap().take().unwrap_or_default();
+            if let Ok(ref resp) = result {
+                for snd in waiters {
+                    let _ = snd.send(resp.clone());
+                }
+            }
+            result
+        }))
+    }
+}
+
+impl<T> NewService for CoalesceService<T>
+where
+    T: NewService<
+        Request = Message,
+        Response = Message,
+        Error = io::Error,
+    >,
+    <T::Instance as Service>::Future: 'static,
+{
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Instance = CoalesceService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        let inner = self.inner.new_service()?;
+        Ok(CoalesceService {
+            inner: inner,
+            in_flight: self.in_flight.clone(),
+        })
+    }
+}
+
+/// A middleware running optimistic transactions, as Redis does. A connection watches keys with
+/// `Op::Watch`, starts a transaction with `Op::Multi`, after which its requests are queued and
+/// answered with `Code::Queued`, and applies them with `Op::Exec`, as one `Op::Batch` checking
+/// the watched keys first. If one of them changed since it was watched, even if it was created
+/// and removed again meanwhile, nothing is applied and the Exec is answered with
+/// `Code::Conflict` and no payload. Otherwise it is answered as the
+/// batch is, see `cache::batch_request`, without the responses to the Watches: unlike Redis,
+/// the transaction is undone if a request in it fails. `Op::Discard` drops the transaction.
+/// Either way, the keys are no longer watched.
+///
+/// Only the ops in `cache::BATCH_OPS` can be queued. Other requests in a transaction are
+/// answered with `Code::Error`, and so is its Exec. Like any batch, the Watches and the queued
+/// requests together are limited to `cache::MAX_MULTI_KEYS`: a Watch beyond that is refused, and
+/// a request beyond it fails the transaction. The batch is applied in the Exec's namespace, and
+/// the Exec is answered with `Code::Error` if a request in it or a Watch is in another one.
+pub struct TransactionService<T> {
+    inner: Rc<T>,
+    transaction: Rc<RefCell<Transaction>>,
+}
+
+impl<T> TransactionService<T> {
+    pub fn new(inner: T) -> Self {
+        TransactionService {
+            inner: Rc::new(inner),
+            transaction: Rc::new(RefCell::new(Transaction::default())),
+        }
+    }
+}
+
+/// The state of a connection's transaction.
+#[derive(Default)]
+struct Transaction {
+    /// The Watches checking the watched keys, carrying the versions they were at, which arrive
+    /// once the Watch requests are answered. `None` for those that failed.
+    watched: Vec<unsync_oneshot::Receiver<Option<Message>>>,
+    /// The requests queued since `Op::Multi`, or `None` outside a transaction.
+    queued: Option<Vec<Message>>,
+    /// Whether a request was refused since `Op::Multi`, failing the Exec.
+    failed: bool,
+}
+
+impl<T> Service for TransactionService<T>
+    where T: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+          T::Future: 'static {
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let error = |op: Op, description: &str| -> Self::Future {
+            let payload = message::payload(0, description.to_owned().into_bytes());
+            Box::new(future::ok(message::response(op, Code::Error, Some(payload))))
+        };
+        let mut transaction = self.transaction.borrow_mut();
+        let op = req.op();
+        if transaction.queued.is_none() {
+            return match op {
+                Op::Watch if transaction.watched.len() >= cache::MAX_MULTI_KEYS => {
+                    error(op, "too many keys are watched")
+                }
+                Op::Watch => {
+                    let (snd, rcv) = unsync_oneshot::channel();
+                    transaction.watched.push(rcv);
+                    let watch = req.clone();
+                    Box::new(self.inner.call(req).then(move |resp| {
+                        let version = match resp {
+                            Ok(ref resp) if resp.code() == Code::Ok => {
+                                resp.extension(message::EXT_VERSION).map(|v| v.to_vec())
+                            }
+                            _ => None,
+                        };
+                        let watch = version.map(|v| watch.with_extension(message::EXT_VERSION, v));
+                        let _ = snd.send(watch);
+                        resp
+                    }))
+                }
+                Op::Multi => {
+                    transaction.queued = Some(vec![]);
+                    transaction.failed = false;
+                    Box::new(future::ok(message::response(Op::Multi, Code::Ok, None)))
+                }
+                Op::Exec | Op::Discard => error(op, "no transaction was started"),
+                _ => Box::new(self.inner.call(req)),
+            };
+        }
+
+        match op {
+            Op::Watch => error(op, "keys can't be watched in a transaction"),
+            Op::Multi => error(op, "a transaction was already started"),
+            Op::Discard => {
+                transaction.queued = None;
+                transaction.watched.clear();
+                Box::new(future::ok(message::response(Op::Discard, Code::Ok, None)))
+            }
+            Op::Exec => {
+                let queued = transaction.queued.take().unwrap_or_default();
+                let watched = mem::replace(&mut transaction.watched, vec![]);
+                if transaction.failed {
+                    return error(op, "the transaction was discarded after an error");
+                }
+                // The batch is in the Exec's namespace, which its ops must all be in.
+                let namespace = req.extension(message::EXT_NAMESPACE).map(|n| n.to_vec());
+                // The Watches sent just before may not be answered yet.
+                let watched = watched.into_iter().map(|watch| {
+                    watch.then(|watch| Ok::<_, io::Error>(watch.unwrap_or(None)))
+                });
+                let inner = self.inner.clone();
+                Box::new(future::join_all(watched).and_then(move |watched| {
+                    let mut ops: Vec<_> = watched.into_iter().filter_map(|watch| watch).collect();
+                    let watches = ops.len();
+                    ops.extend(queued);
+                    let namespaced = namespace.as_ref().map(|n| &n[..]);
+                    if ops.iter().any(|op| op.extension(message::EXT_NAMESPACE) != namespaced) {
+                        let spans = "the transaction spans namespaces".to_owned().into_bytes();
+                        let payload = Some(message::payload(0, spans));
+                        let resp = message::response(Op::Exec, Code::Error, payload);
+                        return future::Either::A(future::ok(resp));
+                    }
+                    let ops: Vec<_> = ops.into_iter()
+                        .map(|op| op.without_extension(message::EXT_NAMESPACE))
+                        .collect();
+                    let batch = match namespace {
+                        Some(namespace) => {
+                            cache::batch_request(&ops)
+                                .with_extension(message::EXT_NAMESPACE, namespace)
+                        }
+                        None => cache::batch_request(&ops),
+                    };
+                    future::Either::B(inner.call(batch).map(move |resp| exec(resp, watches)))
+                }))
+            }
+            _ if cache::BATCH_OPS.contains(&op) && req.key().is_some() => {
+                let watches = transaction.watched.len();
+                let full = transaction.queued.as_ref()
+                    .map_or(false, |queued| watches + queued.len() >= cache::MAX_MULTI_KEYS);
+                if full {
+                    transaction.failed = true;
+                    return error(op, "too many requests in the transaction");
+                }
+                if let Some(ref mut queued) = transaction.queued {
+                    queued.push(req);
+                }
+                Box::new(future::ok(message::response(op, Code::Queued, None)))
+            }
+            _ => {
+                transaction.failed = true;
+                error(op, "the op can't be in a transaction")
+            }
+        }
+    }
+}
+
+/// The response to an `Op::Exec` applied with `batch`, the response to its `Op::Batch`, whose
+/// first `watches` ops checked the watched keys.
+fn exec(batch: Message, watches: usize) -> Message {
+    let results = match batch.payload().map(|p| message::decode_messages(p.data())) {
+        Some(Ok(results)) => results,
+        _ => return message::response(Op::Exec, batch.code(), batch.payload().cloned()),
+    };
+    if batch.code() == Code::Conflict && results.len() <= watches {
+        return message::response(Op::Exec, Code::Conflict, None);
+    }
+    let data = message::encode_messages(&results[cmp::min(watches, results.len())..]);
+    let payload = message::payload(message::MESSAGE_LIST_TYPE_ID, data);
+    message::response(Op::Exec, batch.code(), Some(payload))
+}
+
+impl<T> NewService for TransactionService<T>
+where
+    T: NewService<
+        Request = Message,
+        Response = Message,
+        Error = io::Error,
+    >,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Instance = TransactionService<T::Instance>;
+
+    /// Every connection starts out outside a transaction, watching nothing.
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        Ok(TransactionService::new(self.inner.new_service()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use tokio_proto::multiplex::RequestId;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use clock::MockClock;
+    use test::Bencher;
+    use time::Duration;
+    use std::net::TcpStream;
+    use std::time;
+    use bytes::BytesMut;
+
+    /// A service whose responses are held until the test releases them.
+    #[derive(Default)]
+    struct Held {
+        calls: AtomicUsize,
+        pending: Mutex<Vec<oneshot::Sender<Message>>>,
+    }
+
+    impl Service for Held {
+        type Request = Message;
+        type Response = Message;
+        type Error = io::Error;
+        type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+        fn call(&self, _req: Self::Request) -> Self::Future {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let (snd, rcv) = oneshot::channel();
+            self.pending.lock().unwrap().push(snd);
+            Box::new(rcv.map_err(|e| io::Error::new(io::ErrorKind::Other, e.description())))
+        }
+    }
+
+    impl Held {
+        fn release(&self, resp: Message) {
+            for snd in self.pending.lock().unwrap().drain(..) {
+                snd.send(resp.clone()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_coalesce_gets() {
+        let service = CoalesceService::new(Held::default());
+
+        let calls: Vec<_> = (0..5 as RequestId)
+            .map(|req_id| {
+                let req = message::request(Op::Get, "foo".into(), None);
+                service.call(req).map(move |resp| (req_id, resp))
+            })
+            .collect();
+
+        assert_eq!(service.inner.calls.load(Ordering::SeqCst), 1);
+
+        let hit = message::response(Op::Get, Code::Hit, Some(message::payload(1, "bar".into())));
+        service.inner.release(hit.clone());
+
+        let responses = future::join_all(calls).wait().unwrap();
+        for (i, &(req_id, ref resp)) in responses.iter().enumerate() {
+            assert_eq!(req_id, i as RequestId);
+            assert_eq!(resp, &hit);
+        }
+
+        // The flight has landed, so the next Get reads the store again.
+        let _ = service.call(message::request(Op::Get, "foo".into(), None));
+        assert_eq!(service.inner.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_coalesce_by_namespace() {
+        let service = CoalesceService::new(Held::default());
+        let get = |namespace: &str| {
+            let req = message::request(Op::Get, "foo".into(), None);
+            service.call(req.with_extension(message::EXT_NAMESPACE, namespace.into()))
+        };
+
+        // Gets for the same key in different namespaces each read the store.
+        let a = get("a");
+        let b = get("b");
+        let also_a = get("a");
+        assert_eq!(service.inner.calls.load(Ordering::SeqCst), 2);
+
+        let hit = message::response(Op::Get, Code::Hit, Some(message::payload(1, "a".into())));
+        let pending: Vec<_> = service.inner.pending.lock().unwrap().drain(..).collect();
+        let mut pending = pending.into_iter();
+        pending.next().unwrap().send(hit.clone()).unwrap();
+        assert_eq!(a.wait().unwrap(), hit);
+        assert_eq!(also_a.wait().unwrap(), hit);
+
+        let other = message::response(Op::Get, Code::Miss, None);
+        pending.next().unwrap().send(other.clone()).unwrap();
+        assert_eq!(b.wait().unwrap(), other);
+        assert!(service.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_coalesce_by_flags() {
+        let service = CoalesceService::new(Held::default());
+
+        // A Get taking a stale value doesn't share the answer of one that doesn't.
+        let _ = service.call(message::request(Op::Get, "foo".into(), None));
+        let _ = service.call(cache::get_stale_request("foo".into()));
+        assert_eq!(service.inner.calls.load(Ordering::SeqCst), 2);
+        let _ = service.call(cache::get_stale_request("foo".into()));
+        let _ = service.call(message::request(Op::Get, "foo".into(), None));
+        assert_eq!(service.inner.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_coalesce_ends_on_write() {
+        let service = CoalesceService::new(Held::default());
+
+        let _ = service.call(message::request(Op::Get, "foo".into(), None));
+        let _ = service.call(message::request(
+            Op::Set,
+            "foo".into(),
+            Some(message::payload(1, "bar".into())),
+        ));
+        let _ = service.call(message::request(Op::Get, "foo".into(), None));
+
+        assert_eq!(service.inner.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_auth_service() {
+        let mut credentials = HashMap::new();
+        credentials.insert(b"reader".to_vec(), Role::ReadOnly);
+        credentials.insert(b"writer".to_vec(), Role::ReadWrite);
+        credentials.insert(b"admin".to_vec(), Role::Admin);
+        let service = AuthService::new(Echo, credentials);
+        let code = |req: Message| service.call(req).wait().unwrap().code();
+        let auth = |secret: &str| {
+            message::request(Op::Auth, vec![], Some(message::payload(0, secret.into())))
+        };
+        let get = || message::request(Op::Get, "foo".into(), None);
+        let set = || message::request(Op::Set, "foo".into(), Some(message::payload(1, vec![])));
+
+        assert_eq!(code(get()), Code::Unauthorized);
+        assert_eq!(code(auth("wrong")), Code::Unauthorized);
+        assert_eq!(code(get()), Code::Unauthorized);
+
+        // Echo misses everything it is passed.
+        assert_eq!(code(auth("reader")), Code::Ok);
+        assert_eq!(code(get()), Code::Miss);
+        assert_eq!(code(set()), Code::Unauthorized);
+
+        assert_eq!(code(auth("writer")), Code::Ok);
+        assert_eq!(code(set()), Code::Miss);
+
+        // A failed Auth keeps the role the connection already has.
+        assert_eq!(code(auth("wrong")), Code::Unauthorized);
+        assert_eq!(code(set()), Code::Miss);
+
+        // Flushing a namespace is a write like any other, flushing everything is for admins.
+        let flush_namespace = message::request(Op::FlushNamespace, vec![], None)
+            .with_extension(message::EXT_NAMESPACE, "a".into());
+        assert_eq!(code(flush_namespace), Code::Miss);
+        let flush = || message::request(Op::FlushAll, vec![], None);
+        assert_eq!(code(flush()), Code::Unauthorized);
+        assert_eq!(code(auth("admin")), Code::Ok);
+        assert_eq!(code(flush()), Code::Miss);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let cache = Arc::new(Cache::new(100).unwrap());
+        let service = TransactionService::new(CacheService { cache: cache.clone() });
+        let call = |req: Message| service.call(req).wait().unwrap();
+        let code = |op: Op| call(message::request(op, vec![], None)).code();
+        let set = |key: &str, value: &str| {
+            message::request(Op::Set, key.into(), Some(message::payload(1, value.into())))
+        };
+        let get = |key: &str| {
+            let resp = call(message::request(Op::Get, key.into(), None));
+            resp.payload().map(|p| p.data().to_vec())
+        };
+        let watch = |key: &str| call(message::request(Op::Watch, key.into(), None)).code();
+        let elsewhere = CacheService { cache: cache.clone() };
+
+        assert_eq!(code(Op::Exec), Code::Error);
+        assert_eq!(watch("a"), Code::Ok);
+        assert_eq!(code(Op::Multi), Code::Ok);
+        assert_eq!(call(set("a", "1")).code(), Code::Queued);
+        assert_eq!(call(message::request(Op::Get, "a".into(), None)).code(), Code::Queued);
+        assert_eq!(watch("b"), Code::Error);
+        let resp = call(message::request(Op::Exec, vec![], None));
+        assert_eq!(resp.code(), Code::Ok);
+        let results = message::decode_messages(resp.payload().unwrap().data()).unwrap();
+        let codes: Vec<_> = results.iter().map(|r| r.code()).collect();
+        assert_eq!(codes, vec![Code::Ok, Code::Hit]);
+        assert_eq!(get("a"), Some("1".into()));
+
+        // A watched key written meanwhile aborts the transaction, even one pipelined with it.
+        let watched = service.call(message::request(Op::Watch, "a".into(), None));
+        assert_eq!(code(Op::Multi), Code::Ok);
+        assert_eq!(call(set("b", "2")).code(), Code::Queued);
+        assert_eq!(watched.wait().unwrap().code(), Code::Ok);
+        elsewhere.call(set("a", "3")).wait().unwrap();
+        let resp = call(message::request(Op::Exec, vec![], None));
+        assert_eq!((resp.code(), resp.payload()), (Code::Conflict, None));
+        assert_eq!(get("b"), None);
+
+        // Exec unwatched the key.
+        elsewhere.call(set("a", "4")).wait().unwrap();
+        assert_eq!(code(Op::Multi), Code::Ok);
+        assert_eq!(call(set("b", "2")).code(), Code::Queued);
+        assert_eq!(code(Op::Exec), Code::Ok);
+        assert_eq!(get("b"), Some("2".into()));
+
+        // A request that can't be queued fails the transaction, which Exec then ends.
+        assert_eq!(code(Op::Multi), Code::Ok);
+        assert_eq!(call(set("c", "1")).code(), Code::Queued);
+        assert_eq!(code(Op::FlushAll), Code::Error);
+        assert_eq!(code(Op::Exec), Code::Error);
+        assert_eq!(get("c"), None);
+        assert_eq!(code(Op::Discard), Code::Error);
+
+        assert_eq!(code(Op::Multi), Code::Ok);
+        assert_eq!(call(set("c", "1")).code(), Code::Queued);
+        assert_eq!(code(Op::Discard), Code::Ok);
+        assert_eq!(get("c"), None);
+
+        // The transaction applies in the Exec's namespace, which its requests must share.
+        let n = |req: Message| req.with_extension(message::EXT_NAMESPACE, "n".into());
+        assert_eq!(code(Op::Multi), Code::Ok);
+        assert_eq!(call(n(set("d", "1"))).code(), Code::Queued);
+        assert_eq!(call(n(message::request(Op::Exec, vec![], None))).code(), Code::Ok);
+        assert_eq!(get("d"), None);
+        let resp = call(n(message::request(Op::Get, "d".into(), None)));
+        assert_eq!(resp.code(), Code::Hit);
+        assert_eq!(code(Op::Multi), Code::Ok);
+        assert_eq!(call(n(set("e", "1"))).code(), Code::Queued);
+        assert_eq!(code(Op::Exec), Code::Error);
+
+        // A missing key created and removed again meanwhile aborts the transaction too.
+        assert_eq!(watch("f"), Code::Ok);
+        elsewhere.call(set("f", "1")).wait().unwrap();
+        elsewhere.call(message::request(Op::Del, "f".into(), None)).wait().unwrap();
+        assert_eq!(code(Op::Multi), Code::Ok);
+        assert_eq!(call(set("g", "1")).code(), Code::Queued);
+        assert_eq!(code(Op::Exec), Code::Conflict);
+        assert_eq!(get("g"), None);
+
+        // A transaction can't queue more than a batch holds.
+        assert_eq!(watch("f"), Code::Ok);
+        assert_eq!(code(Op::Multi), Code::Ok);
+        for _ in 1..cache::MAX_MULTI_KEYS {
+            assert_eq!(call(set("h", "1")).code(), Code::Queued);
+        }
+        assert_eq!(call(set("h", "1")).code(), Code::Error);
+        assert_eq!(code(Op::Exec), Code::Error);
+        assert_eq!(get("h"), None);
+        for _ in 0..cache::MAX_MULTI_KEYS {
+            assert_eq!(watch("f"), Code::Ok);
+        }
+        assert_eq!(watch("f"), Code::Error);
+        assert_eq!(code(Op::Discard), Code::Error);
+    }
+
+    #[test]
+    fn test_acls() {
+        let mut credentials = HashMap::new();
+        let sessions = Acl::new(Role::ReadWrite)
+            .ops(&[Op::Get, Op::Set, Op::MGet, Op::Stats])
+            .prefixes(vec![b"session:".to_vec()]);
+        credentials.insert(b"sessions".to_vec(), sessions);
+        credentials.insert(b"reader".to_vec(), Role::ReadOnly.into());
+        let service = AuthService::with_acls(Echo, credentials);
+        let code = |req: Message| service.call(req).wait().unwrap().code();
+        let auth = |secret: &str| {
+            message::request(Op::Auth, vec![], Some(message::payload(0, secret.into())))
+        };
+        let get = |key: &str| message::request(Op::Get, key.into(), None);
+        let set = |key: &str| {
+            message::request(Op::Set, key.into(), Some(message::payload(1, vec![])))
+        };
+        let mget = |keys: &[&str]| {
+            let keys: Vec<Vec<u8>> = keys.iter().map(|&key| key.into()).collect();
+            let payload = message::payload(0, message::encode_keys(&keys));
+            message::request(Op::MGet, vec![], Some(payload))
+        };
+
+        assert_eq!(code(auth("sessions")), Code::Ok);
+        // Echo misses everything it is passed.
+        assert_eq!(code(get("session:1")), Code::Miss);
+        assert_eq!(code(set("session:1")), Code::Miss);
+        assert_eq!(code(get("user:1")), Code::Forbidden);
+        assert_eq!(code(mget(&["session:1", "session:2"])), Code::Miss);
+        assert_eq!(code(mget(&["session:1", "user:1"])), Code::Forbidden);
+        assert_eq!(code(message::request(Op::Stats, vec![], None)), Code::Forbidden);
+        // The prefixes only cover the keys in the ACL's namespace.
+        let elsewhere = get("session:1").with_extension(message::EXT_NAMESPACE, "n".into());
+        assert_eq!(code(elsewhere.clone()), Code::Forbidden);
+        assert_eq!(code(message::request(Op::Del, "session:1".into(), None)), Code::Forbidden);
+        // The role still applies.
+        let flush = message::request(Op::FlushAll, vec![], None);
+        assert_eq!(code(flush), Code::Unauthorized);
+
+        let acl = Acl::new(Role::Admin).prefixes(vec![b"session:".to_vec()]);
+        assert!(acl.allows(&message::request(Op::Ping, vec![], None)));
+        assert!(!acl.allows(&message::request(Op::Scan, vec![], None)));
+        let acl = acl.namespace("n");
+        assert!(acl.allows(&elsewhere));
+        assert!(!acl.allows(&get("session:1")));
+
+        assert_eq!(code(auth("reader")), Code::Ok);
+        assert_eq!(code(get("user:1")), Code::Miss);
+        assert_eq!(code(set("user:1")), Code::Unauthorized);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let clock = Arc::new(MockClock::default());
+        let service = RateLimitService::new(Echo, RateLimit::new(10).burst(2), clock.clone());
+        let code = || service.call(message::request(Op::Get, "foo".into(), None)).wait();
+        assert_eq!(code().unwrap().code(), Code::Miss);
+        assert_eq!(code().unwrap().code(), Code::Miss);
+        assert_eq!(code().unwrap().code(), Code::Throttled);
+        clock.advance(Duration::milliseconds(100));
+        assert_eq!(code().unwrap().code(), Code::Miss);
+        assert_eq!(code().unwrap().code(), Code::Throttled);
+        // A lull only saves up a burst.
+        clock.advance(Duration::seconds(10));
+        assert_eq!(code().unwrap().code(), Code::Miss);
+        assert_eq!(code().unwrap().code(), Code::Miss);
+        assert_eq!(code().unwrap().code(), Code::Throttled);
+    }
+
+    #[test]
+    fn test_rate_limit_per_identity() {
+        let mut credentials = HashMap::new();
+        credentials.insert(b"secret".to_vec(), Role::ReadWrite);
+        let cache = Arc::new(cache::Cache::new(10).unwrap());
+        let auth = AuthService::new(CacheService { cache: cache }, credentials);
+        let clock = Arc::new(MockClock::default());
+        let new_service = RateLimitService::new(auth, RateLimit::new(1).burst(3), clock)
+            .per_identity(true);
+        let (a, b, c) = (
+            new_service.new_service().unwrap(),
+            new_service.new_service().unwrap(),
+            new_service.new_service().unwrap(),
+        );
+        let code = |service: &RateLimitService<_>, req| service.call(req).wait().unwrap().code();
+        let auth = |secret: &str| {
+            message::request(Op::Auth, vec![], Some(message::payload(0, secret.into())))
+        };
+        let get = || message::request(Op::Get, "foo".into(), None);
+
+        // Each connection authenticates on its own limit, then shares the identity's.
+        assert_eq!(code(&a, auth("secret")), Code::Ok);
+        assert_eq!(code(&b, auth("secret")), Code::Ok);
+        assert_eq!(code(&a, get()), Code::Miss);
+        assert_eq!(code(&b, get()), Code::Miss);
+        assert_eq!(code(&b, get()), Code::Miss);
+        assert_eq!(code(&a, get()), Code::Throttled);
+        // Failing to authenticate doesn't.
+        assert_eq!(code(&c, auth("guess")), Code::Unauthorized);
+        assert_eq!(code(&c, auth("secret")), Code::Ok);
+        assert_eq!(code(&c, get()), Code::Throttled);
+    }
+
+    #[test]
+    fn test_cap_response() {
+        let resp = message::response(Op::Get, Code::Hit, Some(message::payload(1, vec![0; 64])));
+        let max_encoded_len = codec::encoded_len(&resp);
+
+        assert_eq!(cap_response(resp.clone(), max_encoded_len), resp);
+
+        let capped = cap_response(resp, max_encoded_len - 1);
+        assert_eq!(capped.op(), Op::Get);
+        assert_eq!(capped.code(), Code::Error);
+        assert!(codec::encoded_len(&capped) <= max_encoded_len);
+    }
+
+    #[test]
+    fn test_scan_stream() {
+        let cache = Arc::new(cache::Cache::new(1000).unwrap());
+        for i in 0..250 {
+            let (snd, rcv) = oneshot::channel();
+            let req = message::request(
+                Op::Set,
+                format!("key{:03}", i).into_bytes(),
+                Some(message::payload(1, "value".into())),
+            );
+            cache.process(req, snd);
+            rcv.wait().unwrap();
+        }
+
+        let requests = stream::iter_ok(vec![vec![
+            (
+                7,
+                message::request(
+                    Op::ScanStream,
+                    vec![],
+                    Some(message::payload(0, message::encode_u32(100))),
+                )
+            ),
+            (8, message::request(Op::Get, "key000".into(), None)),
+        ]]);
+        let core = Core::new().unwrap();
+        let frames = dispatch(requests, CacheService { cache: cache }, 1, &core.handle())
+            .flatten()
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(frames.len(), 5);
+        let mut keys = vec![];
+        for &(req_id, ref frame) in &frames[..3] {
+            assert_eq!(req_id, 7);
+            assert_eq!((frame.op(), frame.code()), (Op::ScanStream, Code::Ok));
+            keys.extend(message::decode_keys(frame.payload().unwrap().data()).unwrap());
+        }
+        let expected: Vec<Vec<u8>> = (0..250)
+            .map(|i| format!("key{:03}", i).into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
+
+        assert_eq!(frames[3].0, 7);
+        assert_eq!((frames[3].1.op(), frames[3].1.code()), (Op::ScanStream, Code::End));
+        assert_eq!(frames[4].0, 8);
+        assert_eq!(frames[4].1.code(), Code::Hit);
+    }
+
+    #[test]
+    fn test_scan_stream_pattern() {
+        let cache = Arc::new(cache::Cache::new(1000).unwrap());
+        for i in 0..50 {
+            let (snd, rcv) = oneshot::channel();
+            let key = format!("key{:02}", i).into_bytes();
+            cache.process(message::request(Op::Set, key, Some(message::payload(1, vec![]))), snd);
+            rcv.wait().unwrap();
+        }
+
+        let req = message::request(
+            Op::ScanStream,
+            vec![],
+            Some(message::payload(0, message::encode_u32(3))),
+        ).with_extension(message::EXT_PATTERN, "key?7".into());
+        let requests = stream::iter_ok(vec![vec![(7, req)]]);
+        let core = Core::new().unwrap();
+        let frames = dispatch(requests, CacheService { cache: cache }, 1, &core.handle())
+            .flatten()
+            .collect()
+            .wait()
+            .unwrap();
+
+        let keys: Vec<Vec<u8>> = frames
+            .iter()
+            .filter(|&&(_, ref frame)| frame.code() == Code::Ok)
+            .flat_map(|&(_, ref frame)| {
+                message::decode_keys(frame.payload().unwrap().data()).unwrap()
+            })
+            .collect();
+        let expected: Vec<Vec<u8>> = (0..5).map(|i| format!("key{}7", i).into_bytes()).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(frames.last().unwrap().1.code(), Code::End);
+    }
+
+    #[test]
+    fn test_dump_stream() {
+        let cache = Arc::new(cache::Cache::new(1000).unwrap());
+        for i in 0..25 {
+            let (snd, rcv) = oneshot::channel();
+            let key = format!("key{:02}", i).into_bytes();
+            let req = message::request(Op::Set, key, Some(message::payload(1, vec![i])))
+                .with_extension(message::EXT_NAMESPACE, "n".into());
+            cache.process(req, snd);
+            rcv.wait().unwrap();
+        }
+
+        // The 10 entries under "key1" at 200 a second, 5 to a page, take at least 50ms: each page
+        // waits for the one before.
+        let req = cache::dump_request(b"key1", None, 5, 200)
+            .with_extension(message::EXT_NAMESPACE, "n".into());
+        let requests = stream::iter_ok(vec![vec![(7, req)]]);
+        let mut core = Core::new().unwrap();
+        let started = Instant::now();
+        let responses = dispatch(requests, CacheService { cache: cache }, 1, &core.handle());
+        let frames = core.run(responses.flatten().collect()).unwrap();
+        assert!(started.elapsed() >= ::std::time::Duration::from_millis(50));
+
+        assert_eq!(frames.len(), 3);
+        let mut entries = vec![];
+        for &(req_id, ref frame) in &frames[..2] {
+            assert_eq!(req_id, 7);
+            assert_eq!((frame.op(), frame.code()), (Op::Dump, Code::Ok));
+            entries.extend(cache::decode_dump(frame.payload().unwrap().data()).unwrap());
+        }
+        let expected: Vec<_> = (10..20)
+            .map(|i| (format!("key{}", i).into_bytes(), message::payload(1, vec![i]), None))
+            .collect();
+        assert_eq!(entries, expected);
+        assert_eq!((frames[2].1.op(), frames[2].1.code()), (Op::Dump, Code::End));
+    }
+
+    #[test]
+    fn test_stat_service_latency() {
+        let clock = Arc::new(MockClock::default());
+        let service = StatService {
+            inner: Held::default(),
+            stats: Arc::new(Stats::default()),
+            clock: clock.clone(),
+        };
+
+        let resp = service.call(message::request(Op::Get, "foo".into(), None));
+        clock.advance(Duration::microseconds(250));
+        service.inner.release(message::response(Op::Get, Code::Miss, None));
+        resp.wait().unwrap();
+
+        let stats = service.stats.get_stats();
+        assert!(stats.contains("total_requests: 1,"));
+        assert!(stats.contains("total_request_time: 250 μs"));
+    }
+
+    #[test]
+    fn test_slow_log_service() {
+        let clock = Arc::new(MockClock::default());
+        let service = SlowLogService {
+            inner: Held::default(),
+            slow_log: Arc::new(SlowLog::new(Duration::milliseconds(10), 10)),
+            clock: clock.clone(),
+        };
+        let slow = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())));
+        let slow = service.call(slow);
+        clock.advance(Duration::milliseconds(20));
+        service.inner.release(message::response(Op::Set, Code::Ok, None));
+        slow.wait().unwrap();
+        let fast = service.call(message::request(Op::Get, "foo".into(), None));
+        service.inner.release(message::response(Op::Get, Code::Hit, None));
+        fast.wait().unwrap();
+
+        let list = |reset| {
+            let resp = service.call(slowlog::request(reset)).wait().unwrap();
+            slowlog::decode(resp.payload().unwrap().data()).unwrap()
+        };
+        let requests = list(true);
+        assert_eq!(requests.len(), 1);
+        assert_eq!((requests[0].op, &requests[0].key[..]), (Op::Set, &b"foo"[..]));
+        assert_eq!((requests[0].payload_len, requests[0].micros), (3, 20000));
+        assert!(list(false).is_empty());
+    }
+
+    #[test]
+    fn test_log_service() {
+        let payload = message::payload(1, "barbaz".into());
+        let req = message::request(Op::Set, "foo".into(), Some(payload));
+        assert_eq!(
+            describe(&req, Some(3)),
+            "op=Set key=\"foo\" type_id=1 len=6 payload=\"bar\"..."
+        );
+        let resp = message::response(Op::Get, Code::Miss, None);
+        assert_eq!(describe(&resp, None), "op=Get code=Miss");
+
+        let peer = "10.0.0.1:4567".parse().unwrap();
+        let new_service = LogService::new(|| Ok(Held::default())).ops(&[Op::Get]);
+        let service = new_service_for(&new_service, peer).unwrap();
+        assert_eq!(service.peer, Some(peer));
+        assert_eq!(new_service.new_service().unwrap().peer, None);
+    }
+
+    #[test]
+    fn test_stats_snapshot() {
+        let service = StatService {
+            inner: CacheService { cache: Arc::new(cache::Cache::new(10).unwrap()) },
+            stats: Arc::new(Stats::default()),
+            clock: Arc::new(MockClock::default()),
+        };
+        let requests = vec![
+            message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into()))),
+            message::request(Op::Get, "foo".into(), None),
+            message::request(Op::Get, "baz".into(), None),
+            message::request(Op::Info, vec![], None),
+        ];
+        for req in requests {
+            service.call(req).wait().unwrap();
+        }
+
+        let snapshot = service.stats.snapshot();
+        assert_eq!(snapshot.total_requests, 4);
+        assert_eq!(snapshot.requests_by_op.values().sum::<usize>(), snapshot.total_requests);
+        assert_eq!(snapshot.requests_by_op[&Op::Get], 2);
+        assert_eq!(snapshot.requests_by_op[&Op::Set], 1);
+        assert!(service.stats.get_stats().contains("requests_by_op: [Set=1 Get=2 Info=1]"));
+        assert_eq!((snapshot.hits, snapshot.misses), (1, 1));
+        assert!(snapshot.bytes_in > 0 && snapshot.bytes_out > 0);
+
+        let resp = service.call(message::request(Op::Stats, vec![], None)).wait().unwrap();
+        let payload = resp.payload().unwrap();
+        assert_eq!(payload.type_id(), stats::STATS_TYPE_ID);
+        let stats = ServerStats::decode(payload.data()).unwrap();
+        assert_eq!(stats.requests, snapshot);
+        assert_eq!(stats.cache.unwrap().keys, 1);
+
+        let resp = service.call(stats::json_request()).wait().unwrap();
+        let payload = resp.payload().unwrap();
+        assert_eq!(payload.type_id(), stats::STATS_JSON_TYPE_ID);
+        let stats: ::serde_json::Value = ::serde_json::from_slice(payload.data()).unwrap();
+        assert_eq!(stats["requests"]["hit_ratio"], json!(0.5));
+        assert_eq!(stats["cache"]["keys"], json!(1));
+    }
+
+    /// A sink that only flushes when allowed to.
+    #[derive(Default)]
+    struct SlowSink {
+        flushable: bool,
+        unflushed: usize,
+        max_unflushed: usize,
+    }
+
+    impl Sink for SlowSink {
+        type SinkItem = (RequestId, Message);
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+            self.unflushed += codec::encoded_len(&item.1);
+            self.max_unflushed = self.max_unflushed.max(self.unflushed);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            if self.flushable {
+                self.unflushed = 0;
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let large = message::response(
+            Op::Get,
+            Code::Hit,
+            Some(message::payload(1, vec![0; 40000])),
+        );
+        let len = codec::encoded_len(&large);
+        let mut sink = ByteBudget::new(SlowSink::default(), 100000);
+
+        assert!(sink.start_send((1, large.clone())).unwrap().is_ready());
+        assert!(sink.start_send((2, large.clone())).unwrap().is_ready());
+        assert!(sink.start_send((3, large.clone())).unwrap().is_not_ready());
+        assert_eq!(sink.buffered, 2 * len);
+
+        sink.inner.flushable = true;
+        assert!(sink.start_send((3, large.clone())).unwrap().is_ready());
+        assert_eq!(sink.buffered, len);
+        assert!(sink.inner.max_unflushed <= 100000);
+    }
+
+    #[test]
+    fn test_byte_budget_oversized() {
+        let large = message::response(
+            Op::Get,
+            Code::Hit,
+            Some(message::payload(1, vec![0; 40000])),
+        );
+        let mut sink = ByteBudget::new(SlowSink::default(), 1000);
+
+        assert!(sink.start_send((1, large.clone())).unwrap().is_ready());
+        assert!(sink.start_send((2, large.clone())).unwrap().is_not_ready());
+    }
+
+    #[test]
+    fn test_serve_on_thread() {
+        use client::Client;
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = serve_on_thread(addr, ServeOptions::default(), || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(100)?) })
+        }).unwrap();
+        let addr = server.local_addr();
+        assert!(addr.port() != 0);
+
+        let mut core = Core::new().unwrap();
+        let requests = Client::connect(&addr, &core.handle()).and_then(|client| {
+            client
+                .set("foo", "bar")
+                .and_then(move |_| client.get("foo"))
+        });
+        let resp = core.run(requests).unwrap();
+        assert_eq!(resp.code(), Code::Hit);
+        assert_eq!(resp.payload(), Some(&message::payload(1, "bar".into())));
+
+        let requests = Client::connect(&addr, &core.handle()).and_then(|client| {
+            client
+                .set_payload(vec![0, 1], message::payload(7, vec![2, 3]))
+                .and_then(move |_| client.del(vec![0, 1]))
+        });
+        let resp = core.run(requests).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(resp.payload(), Some(&message::payload(7, vec![2, 3])));
+
+        server.shutdown().unwrap();
+        assert!(core.run(Client::connect(&addr, &core.handle())).is_err());
+    }
+
+    #[test]
+    fn test_chunked_values() {
+        use client::Client;
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = serve_on_thread(addr, ServeOptions::default(), || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(100)?) })
+        }).unwrap();
+        let addr = server.local_addr();
+
+        // Three times as long as a frame's payload may be.
+        let value: Vec<u8> = (0..3 * codec::DEFAULT_MAX_PAYLOAD_LEN).map(|i| i as u8).collect();
+        let mut core = Core::new().unwrap();
+        let sent = value.clone();
+        let requests = Client::connect(&addr, &core.handle()).and_then(|client| {
+            client
+                .hello()
+                .and_then(move |_| client.set("foo", sent).map(|_| client))
+                .and_then(|client| client.get("foo"))
+        });
+        let resp = core.run(requests).unwrap();
+        assert_eq!(resp.code(), Code::Hit);
+        assert_eq!(resp.payload(), Some(&message::payload(1, value)));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        use client::Client;
+
+        let path = ::std::env::temp_dir().join(format!("rcache-{}.sock", ::std::process::id()));
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        // A socket left behind is replaced.
+        drop(UnixListener::bind(&path, &handle).unwrap());
+        let listener = bind_unix(&path, Some(0o700), &handle).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o700);
+        let dir = path.parent().unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let private = fs::read_dir(dir).unwrap().filter_map(|e| e.ok()).find(|e| {
+            e.file_name().to_str().map_or(false, |n| n.starts_with(&format!(".{}.", name)))
+        });
+        assert!(private.is_none());
+
+        let service = CacheService { cache: Arc::new(cache::Cache::new(100).unwrap()) };
+        let (shutdown_snd, shutdown_rcv) = oneshot::channel::<()>();
+        let serving = server(listener, service, ServeOptions::default(), shutdown_rcv, handle);
+        let (stopped_snd, stopped_rcv) = oneshot::channel();
+        let stopped = serving.then(|result| stopped_snd.send(result.is_ok()).map_err(|_| ()));
+        core.handle().spawn(stopped);
+
+        let requests = Client::connect_unix(&path, &core.handle()).and_then(|client| {
+            client
+                .set("foo", "bar")
+                .and_then(move |_| client.get("foo"))
+        });
+        let resp = core.run(requests).unwrap();
+        assert_eq!(resp.code(), Code::Hit);
+        assert_eq!(resp.payload(), Some(&message::payload(1, "bar".into())));
+        drop(shutdown_snd);
+
+        // The socket is removed once the server has stopped.
+        assert!(core.run(stopped_rcv).unwrap());
+        assert!(fs::symlink_metadata(&path).is_err());
+
+        // Anything but a socket is left alone.
+        fs::File::create(&path).unwrap();
+        assert!(bind_unix(&path, None, &core.handle()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_notifications() {
+        use client::Client;
+        use pubsub::{Change, Hub, Notification};
+
+        let hub = Hub::new();
+        let cache_options = cache::Options {
+            notifications: Some(hub.clone()),
+            ..cache::Options::default()
+        };
+        let options = ServeOptions {
+            notifications: Some(hub),
+            ..ServeOptions::default()
+        };
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = serve_on_thread(addr, options, move || {
+            let cache = cache::Cache::with_options(2, cache_options)?;
+            Ok(CacheService { cache: Arc::new(cache) })
+        }).unwrap();
+
+        let mut core = Core::new().unwrap();
+        let requests = Client::connect(&server.local_addr(), &core.handle()).and_then(|client| {
+            client
+                .subscribe("foo")
+                .and_then(move |resp| {
+                    assert_eq!(resp.code(), Code::Ok);
+                    let writes = vec![
+                        client.set("foo1", "a"),
+                        client.set("bar", "b"),
+                        client.del("foo1"),
+                        client.set("foo2", "c"),
+                        client.set("foo3", "d"),
+                        client.set("foo4", "e"),
+                    ];
+                    future::join_all(writes).map(|_| client)
+                })
+                .and_then(|mut client| client.notifications().unwrap().take(6).collect())
+        });
+        let notification = |change, key: &str| Notification { change: change, key: key.into() };
+        assert_eq!(
+            core.run(requests).unwrap(),
+            vec![
+                notification(Change::Set, "foo1"),
+                notification(Change::Deleted, "foo1"),
+                notification(Change::Set, "foo2"),
+                notification(Change::Set, "foo3"),
+                notification(Change::Evicted, "foo2"),
+                notification(Change::Set, "foo4"),
+            ]
+        );
+
+        server.shutdown().unwrap();
+    }
+
+    /// A sink whose writes always fail.
+    struct BrokenSink;
+
+    impl Sink for BrokenSink {
+        type SinkItem = (RequestId, Message);
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, _: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+        }
+    }
+
+    #[test]
+    fn test_connection_write_error() {
+        let stats = Arc::new(Stats::default());
+        let options = ServeOptions {
+            stats: Some(stats.clone()),
+            ..ServeOptions::default()
+        };
+
+        let request = (1, message::request(Op::Get, "foo".into(), None));
+        let requests = stream::iter_ok(vec![vec![request]]);
+        let service = CacheService { cache: Arc::new(cache::Cache::new(10).unwrap()) };
+        let pushes = stream::empty();
+        let max_encoded_len = (usize::max_value(), usize::max_value());
+        let core = Core::new().unwrap();
+        connection(requests, BrokenSink, service, pushes, max_encoded_len, &options, &core.handle())
+            .wait()
+            .unwrap();
+
+        assert_eq!(stats.connection_errors(), 1);
+    }
+
+    #[test]
+    fn test_connection_clean_close() {
+        let stats = Arc::new(Stats::default());
+        let options = ServeOptions {
+            stats: Some(stats.clone()),
+            ..ServeOptions::default()
+        };
+
+        let request = (1, message::request(Op::Get, "foo".into(), None));
+        let requests = stream::iter_ok(vec![vec![request]]);
+        let service = CacheService { cache: Arc::new(cache::Cache::new(10).unwrap()) };
+        let writer = SlowSink {
+            flushable: true,
+            ..SlowSink::default()
+        };
+        let pushes = stream::empty();
+        let max_encoded_len = (usize::max_value(), usize::max_value());
+        let core = Core::new().unwrap();
+        connection(requests, writer, service, pushes, max_encoded_len, &options, &core.handle())
+            .wait()
+            .unwrap();
+
+        assert_eq!(stats.connection_errors(), 0);
+    }
+
+    #[test]
+    fn test_connections_counted() {
+        let stats = Arc::new(Stats::default());
+        let options = ServeOptions {
+            stats: Some(stats.clone()),
+            ..ServeOptions::default()
+        };
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), options, || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(10)?) })
+        }).unwrap();
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        let req = message::request(Op::Get, "foo".into(), None);
+        assert_eq!(round_trip(&mut socket, 1, req).code(), Code::Miss);
+        assert_eq!(stats.snapshot().connections, 1);
+
+        drop(socket);
+        let started = time::Instant::now();
+        while stats.snapshot().connections > 0 {
+            assert!(started.elapsed() < time::Duration::from_secs(5), "still counted");
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        server.shutdown().unwrap();
+    }
+
+    /// A `Held` that can be shared with the test while a connection owns it.
+    struct SharedHeld(Arc<Held>);
+
+    impl Service for SharedHeld {
+        type Request = Message;
+        type Response = Message;
+        type Error = io::Error;
+        type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+        fn call(&self, req: Self::Request) -> Self::Future {
+            self.0.call(req)
+        }
+    }
+
+    impl NewService for SharedHeld {
+        type Request = Message;
+        type Response = Message;
+        type Error = io::Error;
+        type Instance = SharedHeld;
+
+        fn new_service(&self) -> io::Result<Self::Instance> {
+            Ok(SharedHeld(self.0.clone()))
+        }
+    }
+
+    /// Serves `held` on a thread of its own, with a connection to it that has sent a request the
+    /// service is holding on to.
+    fn serve_held(held: &Arc<Held>, options: ServeOptions) -> (ServerHandle, TcpStream) {
+        let service = SharedHeld(held.clone());
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), options, move || {
+            Ok(service)
+        }).unwrap();
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        send(&mut socket, 1, message::request(Op::Get, "foo".into(), None));
+        while held.calls.load(Ordering::SeqCst) == 0 {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        (server, socket)
+    }
+
+    #[test]
+    fn test_shutdown_drains() {
+        let held = Arc::new(Held::default());
+        let (server, mut socket) = serve_held(&held, ServeOptions::default());
+        let addr = server.local_addr();
+
+        let shutdown = thread::spawn(move || server.shutdown());
+        thread::sleep(time::Duration::from_millis(100));
+        assert!(TcpStream::connect(addr).is_err());
+
+        // The request read before the shutdown is still answered, and then the connection closes.
+        held.release(message::response(Op::Get, Code::Miss, None));
+        let (id, resp) = read_frame(&mut socket, &mut BytesMut::new());
+        assert_eq!((id, resp.code()), (1, Code::Miss));
+        closed(&mut socket);
+        shutdown.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_drain_timeout() {
+        let held = Arc::new(Held::default());
+        let options = ServeOptions {
+            drain_timeout: Some(time::Duration::from_millis(100)),
+            ..ServeOptions::default()
+        };
+        let (server, mut socket) = serve_held(&held, options);
+
+        let started = time::Instant::now();
+        server.shutdown().unwrap();
+        assert!(started.elapsed() >= time::Duration::from_millis(100));
+        assert!(closed(&mut socket) < time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_batched_dispatch() {
+        use std::time;
+
+        let held = Arc::new(Held::default());
+        let batch = (0..3)
+            .map(|req_id| (req_id, message::request(Op::Get, vec![], None)))
+            .collect();
+        let service = SharedHeld(held.clone());
+        let responses = thread::spawn(move || {
+            let core = Core::new().unwrap();
+            dispatch(stream::iter_ok(vec![batch]), service, 1, &core.handle())
+                .flatten()
+                .collect()
+                .wait()
+                .unwrap()
+        });
+
+        // The whole batch is dispatched before any response is available.
+        while held.calls.load(Ordering::SeqCst) < 3 {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+
+        // Answer out of order, tagging each response with the position of its request.
+        let pending: Vec<_> = held.pending.lock().unwrap().drain(..).collect();
+        for (i, snd) in pending.into_iter().enumerate().rev() {
+            let payload = message::payload(1, vec![i as u8]);
+            let resp = message::response(Op::Get, Code::Hit, Some(payload));
+            snd.send(resp).unwrap();
+        }
+
+        let responses = responses.join().unwrap();
+        let correlated: Vec<_> = responses
+            .iter()
+            .map(|&(req_id, ref resp)| (req_id, resp.payload().unwrap().data()[0]))
+            .collect();
+        assert_eq!(correlated, vec![(0, 0), (1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        use std::time;
+
+        let held = Arc::new(Held::default());
+        let batches: Vec<_> = (0..5)
+            .map(|req_id| vec![(req_id, message::request(Op::Get, vec![], None))])
+            .collect();
+        let service = SharedHeld(held.clone());
+        let responses = thread::spawn(move || {
+            let core = Core::new().unwrap();
+            dispatch(stream::iter_ok(batches), service, 2, &core.handle())
+                .flatten()
+                .collect()
+                .wait()
+                .unwrap()
+        });
+        let wait_for = |calls| while held.calls.load(Ordering::SeqCst) < calls {
+            thread::sleep(time::Duration::from_millis(1));
+        };
+
+        // No more requests are taken until those in flight are answered.
+        wait_for(2);
+        thread::sleep(time::Duration::from_millis(50));
+        assert_eq!(held.calls.load(Ordering::SeqCst), 2);
+        let hit = message::response(Op::Get, Code::Hit, None);
+        held.release(hit.clone());
+        wait_for(4);
+        held.release(hit.clone());
+        wait_for(5);
+        held.release(hit);
+
+        let req_ids: Vec<_> = responses.join().unwrap().iter().map(|&(id, _)| id).collect();
+        assert_eq!(req_ids, vec![0, 1, 2, 3, 4]);
+    }
+
+    fn pipelined_batches(count: usize, max_batch: usize) -> Vec<Vec<(RequestId, Message)>> {
+        use tokio_io::codec::{Decoder, Encoder};
+        use bytes::BytesMut;
+
+        let mut buf = BytesMut::new();
+        let mut codec = BatchCodec::new(CacheCodec::default(), max_batch);
+        for req_id in 0..count as RequestId {
+            let msg = message::request(Op::Get, "foo".into(), None);
+            codec.encode((req_id, msg), &mut buf).unwrap();
+        }
+        let mut batches = vec![];
+        while let Some(batch) = codec.decode(&mut buf).unwrap() {
+            batches.push(batch);
+        }
+        batches
+    }
+
+    /// Answers every request immediately.
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = Message;
+        type Response = Message;
+        type Error = io::Error;
+        type Future = future::FutureResult<Message, io::Error>;
+
+        fn call(&self, req: Self::Request) -> Self::Future {
+            future::ok(message::response(req.op(), Code::Miss, None))
+        }
+    }
+
+    #[bench]
+    fn bench_dispatch_per_frame(b: &mut Bencher) {
+        let batches = pipelined_batches(1000, 1);
+        let core = Core::new().unwrap();
+        b.iter(|| {
+            let responses = dispatch(stream::iter_ok(batches.clone()), Echo, 1, &core.handle());
+            responses.flatten().collect().wait()
+        });
+    }
+
+    #[bench]
+    fn bench_dispatch_batched(b: &mut Bencher) {
+        let batches = pipelined_batches(1000, 64);
+        let core = Core::new().unwrap();
+        b.iter(|| {
+            let responses = dispatch(stream::iter_ok(batches.clone()), Echo, 1, &core.handle());
+            responses.flatten().collect().wait()
+        });
+    }
+
+    /// Sends `req` over `socket` and reads back its response.
+    fn round_trip(socket: &mut TcpStream, req_id: RequestId, req: Message) -> Message {
+        send(socket, req_id, req);
+        let (id, resp) = read_frame(socket, &mut BytesMut::new());
+        assert_eq!(id, req_id);
+        resp
+    }
+
+    fn send(socket: &mut TcpStream, req_id: RequestId, req: Message) {
+        use tokio_io::codec::Encoder;
+        use std::io::Write;
+
+        let mut buf = BytesMut::new();
+        CacheCodec::default().encode((req_id, req), &mut buf).unwrap();
+        socket.write_all(&buf).unwrap();
+    }
+
+    /// Reads the next frame from `socket`, buffering what follows it in `buf`.
+    fn read_frame(socket: &mut TcpStream, buf: &mut BytesMut) -> (RequestId, Message) {
+        use tokio_io::codec::Decoder;
+        use std::io::Read;
+
+        let mut codec = CacheCodec::default();
+        loop {
+            if let Some(frame) = codec.decode(buf).unwrap() {
+                return frame;
+            }
+            let mut chunk = [0; 1024];
+            let n = socket.read(&mut chunk).unwrap();
+            assert!(n > 0, "connection closed");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Waits for the server to close `socket`, returning how long that took.
+    fn closed(socket: &mut TcpStream) -> time::Duration {
+        use std::io::Read;
+
+        let started = time::Instant::now();
+        socket.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+        assert_eq!(socket.read(&mut [0; 1]).unwrap(), 0);
+        started.elapsed()
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let options = ServeOptions {
+            handshake_timeout: Some(time::Duration::from_millis(100)),
+            idle_timeout: Some(time::Duration::from_millis(500)),
+            ..ServeOptions::default()
+        };
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), options, || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(100)?) })
+        }).unwrap();
+
+        // Without a handshake, the connection is dropped after the handshake timeout.
+        let mut silent = TcpStream::connect(server.local_addr()).unwrap();
+        assert!(closed(&mut silent) < time::Duration::from_millis(500));
+
+        // After a handshake, it outlives the handshake timeout until it is idle for long enough.
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        let version = message::encode_u32(codec::PROTOCOL_VERSION);
+        let req = message::request(Op::Hello, vec![], Some(message::payload(0, version)));
+        assert_eq!(round_trip(&mut socket, 1, req).code(), Code::Ok);
+        thread::sleep(time::Duration::from_millis(200));
+        let req = message::request(Op::Get, "foo".into(), None);
+        assert_eq!(round_trip(&mut socket, 2, req).code(), Code::Miss);
+        assert!(closed(&mut socket) >= time::Duration::from_millis(400));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_handshake_timeout_with_auth() {
+        let options = ServeOptions {
+            handshake_timeout: Some(time::Duration::from_millis(100)),
+            idle_timeout: Some(time::Duration::from_millis(500)),
+            require_auth: true,
+            ..ServeOptions::default()
+        };
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), options, || {
+            let mut credentials = HashMap::new();
+            credentials.insert(b"reader".to_vec(), Role::ReadOnly);
+            let service = CacheService { cache: Arc::new(cache::Cache::new(100)?) };
+            Ok(AuthService::new(service, credentials))
+        }).unwrap();
+        let version = message::encode_u32(codec::PROTOCOL_VERSION);
+        let hello = || {
+            message::request(Op::Hello, vec![], Some(message::payload(0, version.clone())))
+        };
+        let auth = |secret: &str| {
+            message::request(Op::Auth, vec![], Some(message::payload(0, secret.into())))
+        };
+
+        // A Hello alone, or with a failed Auth, doesn't complete the handshake.
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        assert_eq!(round_trip(&mut socket, 1, hello()).code(), Code::Ok);
+        assert!(closed(&mut socket) < time::Duration::from_millis(500));
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        assert_eq!(round_trip(&mut socket, 1, hello()).code(), Code::Ok);
+        assert_eq!(round_trip(&mut socket, 2, auth("wrong")).code(), Code::Unauthorized);
+        assert!(closed(&mut socket) < time::Duration::from_millis(500));
+
+        // Once authenticated, the connection outlives the handshake timeout.
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        assert_eq!(round_trip(&mut socket, 1, hello()).code(), Code::Ok);
+        assert_eq!(round_trip(&mut socket, 2, auth("reader")).code(), Code::Ok);
+        assert!(closed(&mut socket) >= time::Duration::from_millis(400));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_checksum_mismatch_answered() {
+        use tokio_io::codec::Encoder;
+        use std::io::Write;
+
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), ServeOptions::default(), || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(100)?) })
+        }).unwrap();
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+
+        let mut codec = CacheCodec::default().checksum(true);
+        let mut buf = BytesMut::new();
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())));
+        codec.encode((1, req), &mut buf).unwrap();
+        let len = buf.len();
+        buf[len - 5] ^= 1;
+        let req = message::request(Op::Get, "foo".into(), None);
+        codec.encode((2, req), &mut buf).unwrap();
+        let forged = message::payload(0, "forged".into());
+        codec.encode((3, message::response(Op::Get, Code::Error, Some(forged))), &mut buf).unwrap();
+        codec.encode((4, message::request(Op::Ping, vec![], None)), &mut buf).unwrap();
+        let len = buf.len();
+        buf[len - 1] ^= 1;
+        socket.write_all(&buf).unwrap();
+
+        // The corrupted Set is refused, so the Get that follows misses. A frame merely sent with
+        // the error code isn't echoed back. Nor is a corrupted Ping answered as one that isn't.
+        let mut buf = BytesMut::new();
+        let (id, resp) = read_frame(&mut socket, &mut buf);
+        assert_eq!((id, resp.code()), (1, Code::Error));
+        assert_eq!(resp.extensions().len(), 0);
+        let (id, resp) = read_frame(&mut socket, &mut buf);
+        assert_eq!((id, resp.code()), (2, Code::Miss));
+        let (id, resp) = read_frame(&mut socket, &mut buf);
+        assert_eq!(id, 3);
+        assert_ne!(resp.payload().map(|p| p.data()), Some(&b"forged"[..]));
+        let (id, resp) = read_frame(&mut socket, &mut buf);
+        assert_eq!((id, resp.op(), resp.code()), (4, Op::Ping, Code::Error));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_hello_old_version() {
+        let version = message::encode_u32(codec::MIN_PROTOCOL_VERSION);
+        let req = message::request(Op::Hello, vec![], Some(message::payload(0, version)));
+        let resp = hello(&req);
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(
+            message::decode_u32(resp.payload().unwrap().data()).unwrap(),
+            codec::PROTOCOL_VERSION
+        );
+    }
+
+    #[test]
+    fn test_hello_features() {
+        let offer = codec::hello_payload(codec::PROTOCOL_VERSION, codec::FEATURE_CAS | 1 << 31);
+        let resp = hello(&message::request(Op::Hello, vec![], Some(offer)));
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(
+            codec::decode_hello(resp.payload().unwrap()).unwrap(),
+            (codec::PROTOCOL_VERSION, codec::FEATURE_CAS)
+        );
+
+        let offer = codec::hello_payload(codec::PROTOCOL_VERSION + 1, codec::FEATURE_CAS);
+        let resp = hello(&message::request(Op::Hello, vec![], Some(offer)));
+        assert_eq!(resp.code(), Code::Error);
+    }
+
+    #[test]
+    fn test_hello_limits() {
+        let limits = cache::Limits {
+            max_key_len: Some(16),
+            max_value_len: Some(1024),
+        };
+        let options = ServeOptions {
+            limits: limits,
+            ..ServeOptions::default()
+        };
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), options, || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(100)?) })
+        }).unwrap();
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        let offer = codec::hello_payload(codec::PROTOCOL_VERSION, 0);
+        let resp = round_trip(&mut socket, 1, message::request(Op::Hello, vec![], Some(offer)));
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(cache::decode_limits(&resp).unwrap(), limits);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_max_frame_len() {
+        use tokio_io::codec::Encoder;
+        use std::io::Write;
+
+        let options = ServeOptions {
+            max_frame_len: Some(1024),
+            ..ServeOptions::default()
+        };
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), options, || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(100)?) })
+        }).unwrap();
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        let req = message::request(Op::Get, "foo".into(), None);
+        assert_eq!(round_trip(&mut socket, 1, req).code(), Code::Miss);
+
+        // The 22 byte header of the oversized Set is enough to refuse it, and the rest of it is
+        // discarded as it arrives, keeping the connection.
+        let mut buf = BytesMut::new();
+        let req = message::request(Op::Set, "foo".into(), Some(message::payload(1, vec![0; 2048])));
+        CacheCodec::default().encode((2, req), &mut buf).unwrap();
+        socket.write_all(&buf[..22]).unwrap();
+        let (id, resp) = read_frame(&mut socket, &mut BytesMut::new());
+        assert_eq!((id, resp.code()), (2, Code::ValueTooLarge));
+        socket.write_all(&buf[22..]).unwrap();
+        let req = message::request(Op::Get, "foo".into(), None);
+        assert_eq!(round_trip(&mut socket, 3, req).code(), Code::Miss);
+        server.shutdown().unwrap();
+
+        // And so is an oversized key, by the server's key limit.
+        let options = ServeOptions {
+            max_key_len: Some(4),
+            ..ServeOptions::default()
+        };
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), options, || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(100)?) })
+        }).unwrap();
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        let req = message::request(Op::Get, "fooba".into(), None);
+        assert_eq!(round_trip(&mut socket, 1, req).code(), Code::KeyTooLarge);
+        let req = message::request(Op::Get, "foob".into(), None);
+        assert_eq!(round_trip(&mut socket, 2, req).code(), Code::Miss);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_require_hello() {
+        use std::io::Write;
+
+        let options = ServeOptions {
+            require_hello: true,
+            ..ServeOptions::default()
+        };
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), options, || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(100)?) })
+        }).unwrap();
+
+        // Something other than the protocol is turned away.
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        socket.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        closed(&mut socket);
+
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        let offer = codec::hello_payload(codec::PROTOCOL_VERSION, codec::SUPPORTED_FEATURES);
+        let req = message::request(Op::Hello, vec![], Some(offer));
+        assert_eq!(round_trip(&mut socket, 1, req).code(), Code::Ok);
+        let req = message::request(Op::Get, "foo".into(), None);
+        assert_eq!(round_trip(&mut socket, 2, req).code(), Code::Miss);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_ping_keeps_connection_open() {
+        let options = ServeOptions {
+            idle_timeout: Some(time::Duration::from_millis(300)),
+            keepalive: Some(time::Duration::from_secs(60)),
+            ..ServeOptions::default()
+        };
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), options, || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(100)?) })
+        }).unwrap();
+
+        let mut socket = TcpStream::connect(server.local_addr()).unwrap();
+        for id in 1..5 {
+            thread::sleep(time::Duration::from_millis(150));
+            let req = message::request(Op::Ping, vec![], Some(message::payload(0, "hi".into())));
+            let resp = round_trip(&mut socket, id, req);
+            assert_eq!(resp.code(), Code::Ok);
+            assert_eq!(resp.payload(), Some(&message::payload(0, "hi".into())));
+        }
+        assert!(closed(&mut socket) >= time::Duration::from_millis(250));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_hello_wrong_version() {
+        let req = message::request(Op::Hello, vec![], Some(message::payload(0, vec![0; 4])));
+        assert_eq!(hello(&req).code(), Code::Error);
+    }
+
+    #[test]
+    fn test_connection_pushes() {
+        use futures::sync::mpsc;
+
+        let (writer, written) = mpsc::unbounded();
+        let writer = writer.sink_map_err(|_| io::Error::new(io::ErrorKind::Other, "closed"));
+        let request = (1, message::request(Op::Get, "foo".into(), None));
+        let requests = stream::iter_ok(vec![vec![request]]);
+        let push = message::response(Op::Del, Code::Ok, None);
+        let pushes = stream::iter_ok(vec![push.clone()]);
+        let service = CacheService { cache: Arc::new(cache::Cache::new(10).unwrap()) };
+        let max_encoded_len = (usize::max_value(), usize::max_value());
+        let options = ServeOptions::default();
+        let core = Core::new().unwrap();
+        connection(requests, writer, service, pushes, max_encoded_len, &options, &core.handle())
+            .wait()
+            .unwrap();
+
+        let frames: Vec<_> = written.collect().wait().unwrap();
+        assert_eq!(frames.len(), 2);
+        let (solicited, unsolicited): (Vec<_>, Vec<_>) =
+            frames.into_iter().partition(|&(id, _)| !codec::is_unsolicited(id));
+        assert_eq!(solicited[0].0, 1);
+        assert_eq!(solicited[0].1.code(), Code::Miss);
+        assert_eq!(unsolicited[0].1, push);
+    }
+
+    /// Answers every request immediately, but only on the second poll, so that the connection
+    /// waits on each response in turn.
+    struct YieldingEcho;
+
+    impl Service for YieldingEcho {
+        type Request = Message;
+        type Response = Message;
+        type Error = io::Error;
+        type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+        fn call(&self, req: Self::Request) -> Self::Future {
+            let mut yielded = false;
+            let resp = message::response(req.op(), Code::Miss, None);
+            Box::new(future::poll_fn(move || if yielded {
+                Ok(Async::Ready(resp.clone()))
+            } else {
+                yielded = true;
+                futures::task::current().notify();
+                Ok(Async::NotReady)
+            }))
+        }
+    }
+
+    /// A sink recording the frames sent to it and how often it is flushed.
+    #[derive(Clone, Default)]
+    struct RecordingSink {
+        frames: Arc<Mutex<Vec<(RequestId, Message)>>>,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl Sink for RecordingSink {
+        type SinkItem = (RequestId, Message);
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+            self.frames.lock().unwrap().push(item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// Writes the responses to 100 pipelined requests, decoded `max_batch` at a time, returning
+    /// the sink they were written to.
+    fn write_pipelined(max_batch: usize) -> RecordingSink {
+        let sink = RecordingSink::default();
+        let requests = stream::iter_ok(pipelined_batches(100, max_batch));
+        let options = ServeOptions::default();
+        let max_encoded_len = (1 << 20, 1 << 20);
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let pushes = stream::empty();
+        connection(requests, sink.clone(), YieldingEcho, pushes, max_encoded_len, &options, &handle)
+            .wait()
+            .unwrap();
+        sink
+    }
+
+    #[test]
+    fn test_flush_per_batch() {
+        // Each flush is a write to the socket.
+        assert_eq!(write_pipelined(1).flushes.load(Ordering::SeqCst), 100);
+        let sink = write_pipelined(100);
+        assert_eq!(sink.flushes.load(Ordering::SeqCst), 1);
+
+        let ids: Vec<_> = sink.frames.lock().unwrap().iter().map(|&(id, _)| id).collect();
+        assert_eq!(ids, (0..100).collect::<Vec<_>>());
+    }
+
+    #[bench]
+    fn bench_write_flush_per_frame(b: &mut Bencher) {
+        b.iter(|| write_pipelined(1));
+    }
+
+    #[bench]
+    fn bench_write_flush_per_batch(b: &mut Bencher) {
+        b.iter(|| write_pipelined(100));
+    }
+
+    /// Whether the server has kept `socket` open for a little while.
+    fn stays_open(socket: &mut TcpStream) -> bool {
+        use std::io::Read;
+
+        socket.set_read_timeout(Some(time::Duration::from_millis(200))).unwrap();
+        match socket.read(&mut [0; 1]) {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(e) => e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut,
+        }
+    }
+
+    #[test]
+    fn test_max_connections_per_ip() {
+        let options = ServeOptions {
+            max_connections_per_ip: Some(2),
+            ..ServeOptions::default()
+        };
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), options, || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(100)?) })
+        }).unwrap();
+        let addr = server.local_addr();
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        let mut third = TcpStream::connect(addr).unwrap();
+        assert!(stays_open(&mut first));
+        assert!(stays_open(&mut second));
+        assert!(!stays_open(&mut third));
+
+        // Closing a connection frees up its slot, once the server has seen it close.
+        drop(first);
+        let mut accepted = false;
+        for _ in 0..10 {
+            let mut fourth = TcpStream::connect(addr).unwrap();
+            if stays_open(&mut fourth) {
+                accepted = true;
+                break;
+            }
+        }
+        assert!(accepted);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_client_list_and_kick() {
+        let stats = Arc::new(Stats::default());
+        let options = ServeOptions {
+            stats: Some(stats.clone()),
+            ..ServeOptions::default()
+        };
+        let server = serve_on_thread("127.0.0.1:0".parse().unwrap(), options, || {
+            Ok(CacheService { cache: Arc::new(cache::Cache::new(10)?) })
+        }).unwrap();
+        let mut admin = TcpStream::connect(server.local_addr()).unwrap();
+        let mut other = TcpStream::connect(server.local_addr()).unwrap();
+        let get = message::request(Op::Get, "foo".into(), None);
+        assert_eq!(round_trip(&mut other, 1, get).code(), Code::Miss);
+
+        let list = message::request(Op::ClientList, vec![], None);
+        let resp = round_trip(&mut admin, 1, list);
+        let listed = clients::decode(resp.payload().unwrap().data()).unwrap();
+        assert_eq!(listed.len(), 2);
+        let other_addr = other.local_addr().unwrap();
+        let client = listed.iter().find(|client| client.addr == Some(other_addr)).unwrap();
+        assert_eq!((client.ops, client.last_op), (1, Some(Op::Get)));
+
+        let kick = |id: u64| {
+            message::request(Op::Kick, vec![], Some(message::payload(0, message::encode_u64(id))))
+        };
+        assert_eq!(round_trip(&mut admin, 2, kick(client.id)).code(), Code::Ok);
+        assert!(!stays_open(&mut other));
+        assert_eq!(round_trip(&mut admin, 3, kick(client.id + 100)).code(), Code::Miss);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_connection_slot() {
+        let open = Rc::new(RefCell::new(HashMap::new()));
+        let ip = "127.0.0.1".parse().unwrap();
+        let other = "127.0.0.2".parse().unwrap();
+
+        let slot = ConnectionSlot::take(&open, ip, Some(1)).unwrap();
+        assert!(ConnectionSlot::take(&open, ip, Some(1)).is_none());
+        assert!(ConnectionSlot::take(&open, other, Some(1)).is_some());
+        drop(slot);
+        assert!(open.borrow().is_empty());
+        assert!(ConnectionSlot::take(&open, ip, Some(1)).is_some());
     }
 }
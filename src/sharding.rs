@@ -0,0 +1,276 @@
+use futures::{future, Future};
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+
+use client::Client;
+use message::{self, Message, Op};
+
+/// The points each node gets on a `HashRing`. More points spread the keys more evenly between
+/// the nodes, at the cost of a larger ring.
+pub static VIRTUAL_NODES: usize = 160;
+
+/// Maps keys to nodes by consistent hashing. Each node is hashed to `VIRTUAL_NODES` points on a
+/// ring of u64s, and a key belongs to the node owning the first point at or after the key's
+/// hash, wrapping around. Adding a node only moves keys to it, from the nodes whose points it
+/// lands just before, and removing one only moves its own keys, so most keys stay where they
+/// were whenever the node list changes.
+///
+/// Keys and nodes are hashed by `hash`, which doesn't depend on the process or the version of
+/// Rust, so that every client with the same nodes maps keys alike.
+#[derive(Debug, Clone, Default)]
+pub struct HashRing {
+    points: BTreeMap<u64, SocketAddr>,
+}
+
+impl HashRing {
+    pub fn new() -> Self {
+        HashRing::default()
+    }
+
+    /// Adds the points of `node`, if it isn't on the ring yet.
+    pub fn add(&mut self, node: SocketAddr) {
+        for point in points(&node) {
+            // On the rare collision between two nodes, the first to claim the point keeps it.
+            self.points.entry(point).or_insert(node);
+        }
+    }
+
+    /// Removes the points of `node`.
+    pub fn remove(&mut self, node: &SocketAddr) {
+        for point in points(node) {
+            if self.points.get(&point) == Some(node) {
+                self.points.remove(&point);
+            }
+        }
+    }
+
+    /// The node `key` belongs to, or `None` if the ring is empty.
+    pub fn node(&self, key: &[u8]) -> Option<SocketAddr> {
+        let hash = hash(key);
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, &node)| node)
+    }
+}
+
+/// The points of `node` on a `HashRing`: the hashes of its address followed by `#` and each
+/// number below `VIRTUAL_NODES`.
+fn points(node: &SocketAddr) -> Vec<u64> {
+    (0..VIRTUAL_NODES).map(|i| hash(format!("{}#{}", node, i).as_bytes())).collect()
+}
+
+/// 64-bit FNV-1a, finished with the 64-bit mix of MurmurHash3 so that keys differing only in
+/// their last bytes, such as the points of a node, still land all over the ring.
+fn hash(data: &[u8]) -> u64 {
+    let mut hash = data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ hash >> 33
+}
+
+/// A client spreading keys over several servers, each holding a share of them, as placed by a
+/// `HashRing`. Requests are routed by their key to the client of the node it belongs to, so
+/// requests for the server rather than a key, such as `Op::Stats`, and requests naming several
+/// keys, such as `Op::MGet`, are refused; send those to the nodes' clients, see `node_client`.
+///
+/// Nodes can be added and removed while the client is in use. Only the keys of the nodes whose
+/// share changed are routed elsewhere, and miss until they are set again on their new node.
+#[derive(Default)]
+pub struct ShardedClient {
+    ring: HashRing,
+    clients: BTreeMap<SocketAddr, Client>,
+}
+
+impl ShardedClient {
+    /// A client without nodes, see `add_node`.
+    pub fn new() -> Self {
+        ShardedClient::default()
+    }
+
+    /// Connects to every node in `nodes`, failing if any of the connections fail.
+    pub fn connect(
+        nodes: &[SocketAddr],
+        handle: &Handle,
+    ) -> Box<Future<Item = ShardedClient, Error = io::Error>> {
+        let connections = nodes.iter().map(|&node| {
+            Client::connect(&node, handle).map(move |client| (node, client))
+        });
+        Box::new(future::join_all(connections.collect::<Vec<_>>()).map(|clients| {
+            let mut sharded = ShardedClient::new();
+            for (node, client) in clients {
+                sharded.add_node(node, client);
+            }
+            sharded
+        }))
+    }
+
+    /// Gives `node` a share of the keys, sending their requests over `client`, which should be
+    /// connected to it. Replaces the client of a node that was already added.
+    pub fn add_node(&mut self, node: SocketAddr, client: Client) {
+        self.ring.add(node);
+        self.clients.insert(node, client);
+    }
+
+    /// Moves the keys of `node` to the other nodes, returning its client.
+    pub fn remove_node(&mut self, node: &SocketAddr) -> Option<Client> {
+        self.ring.remove(node);
+        self.clients.remove(node)
+    }
+
+    /// The nodes keys are spread over, in address order.
+    pub fn nodes(&self) -> Vec<SocketAddr> {
+        self.clients.keys().cloned().collect()
+    }
+
+    /// The node `key` belongs to, or `None` without nodes.
+    pub fn node_for(&self, key: &[u8]) -> Option<SocketAddr> {
+        self.ring.node(key)
+    }
+
+    /// The client connected to `node`.
+    pub fn node_client(&self, node: &SocketAddr) -> Option<&Client> {
+        self.clients.get(node)
+    }
+
+    pub fn get<K: Into<Vec<u8>>>(&self, key: K) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::Get, key.into(), None))
+    }
+
+    pub fn set<K, V>(&self, key: K, value: V) -> Box<Future<Item = Message, Error = io::Error>>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        let payload = message::payload(1, value.into());
+        self.call(message::request(Op::Set, key.into(), Some(payload)))
+    }
+
+    /// Deletes `key`, responding with its value, or `Code::Miss` if it wasn't present.
+    pub fn del<K: Into<Vec<u8>>>(&self, key: K) -> Box<Future<Item = Message, Error = io::Error>> {
+        self.call(message::request(Op::Del, key.into(), None))
+    }
+}
+
+impl Service for ShardedClient {
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+    fn call(&self, req: Message) -> Self::Future {
+        match req.op() {
+            Op::Stats | Op::Sample | Op::Scan | Op::ScanStream | Op::MGet | Op::MultiDel |
+            Op::Rename | Op::Info | Op::Range | Op::FlushNamespace | Op::FlushAll | Op::Hello |
+            Op::Auth | Op::Ping | Op::Subscribe | Op::Unsubscribe | Op::Replicate |
+            Op::AssignSlots | Op::HotKeys | Op::SlowLog | Op::Batch | Op::Multi | Op::Exec |
+            Op::Discard | Op::ClientList | Op::Kick | Op::Dump | Op::Restore => {
+                let reason = "the request isn't for a single key";
+                return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, reason)));
+            }
+            _ => {}
+        }
+        let client = req.key()
+            .and_then(|key| self.ring.node(key))
+            .and_then(|node| self.clients.get(&node));
+        match client {
+            Some(client) => client.call(req),
+            None => {
+                let err = io::Error::new(io::ErrorKind::NotConnected, "no nodes to send to");
+                Box::new(future::err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::Cache;
+    use message::Code;
+    use service::{self, CacheService, ServeOptions};
+    use std::sync::Arc;
+    use tokio_core::reactor::Core;
+
+    fn node(port: u16) -> SocketAddr {
+        format!("10.0.0.1:{}", port).parse().unwrap()
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key:{}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_hash_ring() {
+        let mut ring = HashRing::new();
+        assert_eq!(ring.node(b"foo"), None);
+        for port in 1..5 {
+            ring.add(node(port));
+        }
+        let before: Vec<_> = (0..10000).map(|i| ring.node(&key(i)).unwrap()).collect();
+        for port in 1..5 {
+            let share = before.iter().filter(|&&n| n == node(port)).count();
+            assert!(share > 1500 && share < 3500, "{} keys on node {}", share, port);
+        }
+
+        // Keys only move to the added node, and back once it's removed.
+        ring.add(node(5));
+        let mut moved = 0;
+        for (i, &was) in before.iter().enumerate() {
+            let now = ring.node(&key(i)).unwrap();
+            if now != was {
+                assert_eq!(now, node(5));
+                moved += 1;
+            }
+        }
+        assert!(moved > 1000 && moved < 3000, "{} keys moved", moved);
+        ring.remove(&node(5));
+        let after: Vec<_> = (0..10000).map(|i| ring.node(&key(i)).unwrap()).collect();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn test_sharded_client() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let servers: Vec<_> = (0..2)
+            .map(|_| {
+                service::serve_on_thread(addr, ServeOptions::default(), || {
+                    Ok(CacheService { cache: Arc::new(Cache::new(100)?) })
+                }).unwrap()
+            })
+            .collect();
+        let nodes: Vec<_> = servers.iter().map(|server| server.local_addr()).collect();
+
+        let mut core = Core::new().unwrap();
+        let client = core.run(ShardedClient::connect(&nodes, &core.handle())).unwrap();
+        for i in 0..20 {
+            assert_eq!(core.run(client.set(key(i), "v")).unwrap().code(), Code::Ok);
+        }
+        // Each key is on its own node only.
+        for i in 0..20 {
+            let node = client.node_for(&key(i)).unwrap();
+            for other in &nodes {
+                let get = client.node_client(other).unwrap().get(key(i));
+                let expected = if *other == node { Code::Hit } else { Code::Miss };
+                assert_eq!(core.run(get).unwrap().code(), expected);
+            }
+        }
+        assert!(core.run(client.call(message::request(Op::Stats, vec![], None))).is_err());
+
+        let mut client = client;
+        client.remove_node(&nodes[0]);
+        client.remove_node(&nodes[1]);
+        assert!(core.run(client.get(key(0))).is_err());
+        for server in servers {
+            server.shutdown().unwrap();
+        }
+    }
+}
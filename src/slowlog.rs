@@ -0,0 +1,190 @@
+use bytes::{Buf, BufMut, BigEndian};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use time::{Duration, Timespec};
+
+use message::{self, Message, Op};
+use error;
+
+/// The slow requests a `SlowLog` keeps for servers whose configuration doesn't say.
+pub static DEFAULT_SLOW_LOG_LEN: usize = 128;
+
+/// Payload `type_id` flag on an `Op::SlowLog` request emptying the log once it is listed, see
+/// `message::flags`.
+pub static SLOW_LOG_RESET: u32 = 1;
+
+/// Builds an `Op::SlowLog` request, emptying the log if `reset` is set.
+pub fn request(reset: bool) -> Message {
+    let flags = if reset { SLOW_LOG_RESET } else { 0 };
+    message::request(Op::SlowLog, vec![], Some(message::flags(flags)))
+}
+
+/// A request that took longer to answer than the threshold of the `SlowLog` it was recorded in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowRequest {
+    pub op: Op,
+    pub key: Vec<u8>,
+    /// The size of the request's payload, 0 without one.
+    pub payload_len: usize,
+    /// When the request arrived.
+    pub at: Timespec,
+    /// How long it took to answer, in microseconds.
+    pub micros: u64,
+}
+
+impl fmt::Display for SlowRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({} bytes) took {} μs",
+            self.op,
+            String::from_utf8_lossy(&self.key),
+            self.payload_len,
+            self.micros
+        )
+    }
+}
+
+/// The latest requests slower than a threshold, see `service::SlowLogService`. Only the newest
+/// `len` are kept, so that the log takes bounded memory however slow the server gets.
+pub struct SlowLog {
+    threshold: Duration,
+    len: usize,
+    print: bool,
+    requests: Mutex<VecDeque<SlowRequest>>,
+}
+
+impl SlowLog {
+    /// A log of up to `len` of the requests taking longer than `threshold`.
+    pub fn new(threshold: Duration, len: usize) -> Self {
+        SlowLog {
+            threshold: threshold,
+            len: len,
+            print: false,
+            requests: Mutex::new(VecDeque::with_capacity(len)),
+        }
+    }
+
+    /// Also log every slow request as it is recorded, at `Level::Warn`, as `service::LogService`
+    /// logs requests.
+    pub fn print(mut self, enabled: bool) -> Self {
+        self.print = enabled;
+        self
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    /// Records `request` if it took longer than the threshold, dropping the oldest request once
+    /// the log is full.
+    pub fn record(&self, request: SlowRequest) {
+        if Duration::microseconds(request.micros as i64) <= self.threshold || self.len == 0 {
+            return;
+        }
+        if self.print {
+            warn!("Slow request: {}", request);
+        }
+        let mut requests = self.requests.lock().unwrap();
+        if requests.len() == self.len {
+            requests.pop_back();
+        }
+        requests.push_front(request);
+    }
+
+    /// The recorded requests, newest first.
+    pub fn requests(&self) -> Vec<SlowRequest> {
+        self.requests.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Empties the log, returning what it held, newest first.
+    pub fn reset(&self) -> Vec<SlowRequest> {
+        self.requests.lock().unwrap().drain(..).collect()
+    }
+}
+
+/// Packs `requests` for an `Op::SlowLog` response, all integers big endian. For each request:
+/// its op as a u8, when it arrived in milliseconds since the Unix epoch as a u64, how long it
+/// took in microseconds as a u64, its payload size as a u64, and the length of its key as a u32
+/// followed by the key.
+pub fn encode(requests: &[SlowRequest]) -> Vec<u8> {
+    let mut data = vec![];
+    for request in requests {
+        let at = request.at.sec as u64 * 1000 + request.at.nsec as u64 / 1000000;
+        data.put_u8(request.op as u8);
+        data.put_u64::<BigEndian>(at);
+        data.put_u64::<BigEndian>(request.micros);
+        data.put_u64::<BigEndian>(request.payload_len as u64);
+        data.put_u32::<BigEndian>(request.key.len() as u32);
+        data.put_slice(&request.key);
+    }
+    data
+}
+
+/// Unpacks the requests packed by `encode`.
+pub fn decode(data: &[u8]) -> Result<Vec<SlowRequest>, error::Error> {
+    let truncated = || error::Error::new(error::ErrorKind::InvalidData, "truncated slow log");
+    let mut requests = vec![];
+    let mut cursor = io::Cursor::new(data);
+    while cursor.remaining() > 0 {
+        if cursor.remaining() < 1 + 8 * 3 + 4 {
+            return Err(truncated());
+        }
+        let op = Op::try_from(cursor.get_u8())?;
+        let at = cursor.get_u64::<BigEndian>();
+        let micros = cursor.get_u64::<BigEndian>();
+        let payload_len = cursor.get_u64::<BigEndian>() as usize;
+        let len = cursor.get_u32::<BigEndian>() as usize;
+        if cursor.remaining() < len {
+            return Err(truncated());
+        }
+        let mut key = vec![0; len];
+        cursor.copy_to_slice(&mut key);
+        requests.push(SlowRequest {
+            op: op,
+            key: key,
+            payload_len: payload_len,
+            at: Timespec::new((at / 1000) as i64, (at % 1000) as i32 * 1000000),
+            micros: micros,
+        });
+    }
+    Ok(requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(key: &str, micros: u64) -> SlowRequest {
+        SlowRequest {
+            op: Op::Get,
+            key: key.into(),
+            payload_len: 0,
+            at: Timespec::new(1000, 250000000),
+            micros: micros,
+        }
+    }
+
+    #[test]
+    fn test_slow_log() {
+        let log = SlowLog::new(Duration::milliseconds(10), 2);
+        log.record(request("fast", 10000));
+        log.record(request("a", 10001));
+        log.record(request("b", 20000));
+        log.record(request("c", 30000));
+        // Only the newest are kept.
+        let requests = log.requests();
+        assert_eq!(requests, vec![request("c", 30000), request("b", 20000)]);
+        assert_eq!(requests[0].to_string(), "Get c (0 bytes) took 30000 μs");
+
+        assert_eq!(decode(&encode(&requests)).unwrap(), requests);
+        let data = encode(&requests);
+        assert!(decode(&data[..data.len() - 1]).is_err());
+
+        assert_eq!(log.reset(), requests);
+        assert!(log.requests().is_empty());
+    }
+}
@@ -1,39 +1,729 @@
-use std::sync::{Arc, atomic};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use bytes::{Buf, BufMut, BigEndian};
+use cache::{CacheStats, Limits};
+use clients::Clients;
+use message::{self, Code, Message, Op};
+use error;
+use serde_json;
+
+/// The upper bounds, in microseconds, of the buckets `Stats` sorts request latencies into. A last
+/// bucket holds the requests slower than all of them.
+pub static LATENCY_BUCKETS: &'static [usize] =
+    &[100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 100_000];
+
 /// `Stats` middleware
 ///
-#[derive(Default)]
+/// The counters are kept together behind a lock, so that a `snapshot` always sees every counter
+/// as of the same moment, and for example the per-op request counts always sum to the total.
 pub struct Stats {
-    total_requests: Arc<atomic::AtomicUsize>,
-    total_request_time: Arc<atomic::AtomicUsize>,
+    counters: Mutex<StatsSnapshot>,
+    started: Instant,
+    clients: Arc<Clients>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            counters: Mutex::new(StatsSnapshot::default()),
+            started: Instant::now(),
+            clients: Arc::new(Clients::default()),
+        }
+    }
+}
+
+/// The value of every counter in `Stats` at one moment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsSnapshot {
+    pub total_requests: usize,
+    /// In microseconds.
+    pub total_request_time: usize,
+    pub requests_by_op: BTreeMap<Op, usize>,
+    /// The number of requests in each of the `LATENCY_BUCKETS`, followed by the number slower
+    /// than all of them.
+    pub latency_buckets: Vec<usize>,
+    /// Reads answered with `Code::Hit`.
+    pub hits: usize,
+    /// Reads answered with `Code::Miss`.
+    pub misses: usize,
+    /// Reads answered with `Code::NotFound`, from the marker of a key known to be missing, see
+    /// `cache::NOT_FOUND_TYPE_ID`. They count as neither hits nor misses.
+    pub negative_hits: usize,
+    /// The encoded size of the requests.
+    pub bytes_in: usize,
+    /// The encoded size of the responses.
+    pub bytes_out: usize,
+    pub worker_panics: usize,
+    pub connection_errors: usize,
+    /// The connections open to the server.
+    pub connections: usize,
+    /// How far a replica was behind its primary when it applied the last write streamed to it,
+    /// in milliseconds, see `replica::follow`. `None` unless the server is a replica.
+    pub replication_lag: Option<usize>,
+    /// How far warming the cache up on startup got, see `warmup::warm_up`. `None` unless the
+    /// server warms up.
+    pub warmup: Option<Warmup>,
+}
+
+/// The progress of warming the cache up, see `StatsSnapshot::warmup`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Warmup {
+    /// The entries stored so far.
+    pub loaded: usize,
+    /// The entries the cache refused to store, other than those written meanwhile.
+    pub failed: usize,
+    pub done: bool,
+}
+
+impl Default for StatsSnapshot {
+    fn default() -> Self {
+        StatsSnapshot {
+            total_requests: 0,
+            total_request_time: 0,
+            requests_by_op: BTreeMap::new(),
+            latency_buckets: vec![0; LATENCY_BUCKETS.len() + 1],
+            hits: 0,
+            misses: 0,
+            negative_hits: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            worker_panics: 0,
+            connection_errors: 0,
+            connections: 0,
+            replication_lag: None,
+            warmup: None,
+        }
+    }
+}
+
+impl StatsSnapshot {
+    /// In microseconds.
+    pub fn avg_request_time(&self) -> usize {
+        if self.total_requests > 0 {
+            self.total_request_time / self.total_requests
+        } else {
+            0
+        }
+    }
+
+    /// The share of reads that found their key, 0 before any read.
+    pub fn hit_ratio(&self) -> f64 {
+        if self.hits + self.misses > 0 {
+            self.hits as f64 / (self.hits + self.misses) as f64
+        } else {
+            0.0
+        }
+    }
 }
 
 impl Stats {
-    pub fn incr_total_requests(&self) {
-        self.total_requests.fetch_add(1, atomic::Ordering::SeqCst);
+    /// Counts a request for `op`, answered with `code`, that took `micros` to answer.
+    pub fn record_request(&self, op: Op, code: Code, micros: usize) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.total_requests += 1;
+        counters.total_request_time += micros;
+        *counters.requests_by_op.entry(op).or_insert(0) += 1;
+
+        let bucket = LATENCY_BUCKETS.iter().position(|&bound| micros <= bound);
+        counters.latency_buckets[bucket.unwrap_or(LATENCY_BUCKETS.len())] += 1;
+        if !op.is_write() {
+            match code {
+                Code::Hit => counters.hits += 1,
+                Code::Miss => counters.misses += 1,
+                Code::NotFound => counters.negative_hits += 1,
+                _ => (),
+            }
+        }
     }
 
-    pub fn add_request_time(&self, micros: usize) {
-        self.total_request_time.fetch_add(
-            micros,
-            atomic::Ordering::SeqCst,
-        );
+    /// Counts the encoded size of a request and of its response.
+    pub fn record_transfer(&self, bytes_in: usize, bytes_out: usize) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.bytes_in += bytes_in;
+        counters.bytes_out += bytes_out;
+    }
+
+    pub fn incr_worker_panics(&self) {
+        self.counters.lock().unwrap().worker_panics += 1;
+    }
+
+    pub fn incr_connection_errors(&self) {
+        self.counters.lock().unwrap().connection_errors += 1;
+    }
+
+    /// Counts a connection the server accepted, until `connection_closed`.
+    pub fn connection_opened(&self) {
+        self.counters.lock().unwrap().connections += 1;
+    }
+
+    pub fn connection_closed(&self) {
+        self.counters.lock().unwrap().connections -= 1;
+    }
+
+    /// Records how far behind its primary a replica is, in milliseconds.
+    pub fn set_replication_lag(&self, millis: usize) {
+        self.counters.lock().unwrap().replication_lag = Some(millis);
+    }
+
+    /// Records how far warming the cache up got.
+    pub fn set_warmup(&self, warmup: Warmup) {
+        self.counters.lock().unwrap().warmup = Some(warmup);
+    }
+
+    pub fn connection_errors(&self) -> usize {
+        self.counters.lock().unwrap().connection_errors
+    }
+
+    /// How long ago the stats started being kept, in seconds.
+    pub fn uptime(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// The clients connected to the server, which registers them when it counts connections.
+    pub fn clients(&self) -> &Arc<Clients> {
+        &self.clients
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.counters.lock().unwrap().clone()
     }
 
     pub fn get_stats(&self) -> String {
-        let total_requests = self.total_requests.load(atomic::Ordering::SeqCst);
-        let total_requests_time = self.total_request_time.load(atomic::Ordering::SeqCst);
+        self.snapshot().to_string()
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let by_op: Vec<String> = self.requests_by_op
+            .iter()
+            .map(|(op, count)| format!("{}={}", op, count))
+            .collect();
+
+        write!(
+            f,
+            "total_requests: {}, total_request_time: {} μs, avg_request_time: {} μs, \
+             requests_by_op: [{}], hits: {}, misses: {}, hit_ratio: {:.3}, negative_hits: {}, \
+             bytes_in: {}, bytes_out: {}, worker_panics: {}, connection_errors: {}, \
+             connections: {}",
+            self.total_requests,
+            self.total_request_time,
+            self.avg_request_time(),
+            by_op.join(" "),
+            self.hits,
+            self.misses,
+            self.hit_ratio(),
+            self.negative_hits,
+            self.bytes_in,
+            self.bytes_out,
+            self.worker_panics,
+            self.connection_errors,
+            self.connections
+        )?;
+        if let Some(lag) = self.replication_lag {
+            write!(f, ", replication_lag: {} ms", lag)?;
+        }
+        if let Some(warmup) = self.warmup {
+            let state = if warmup.done { "done" } else { "in progress" };
+            write!(f, ", warmup: {} entries, {} failed ({})", warmup.loaded, warmup.failed, state)?;
+        }
+        Ok(())
+    }
+}
+
+/// `type_id` of the payload `StatService` answers `Op::Stats` with, as packed by
+/// `ServerStats::encode`.
+pub const STATS_TYPE_ID: u32 = 18;
 
-        let avg_request_time = if total_requests > 0 {
-            total_requests_time / total_requests
+/// `type_id` of the payload of an `Op::Stats` request asking for the stats as JSON, and of the
+/// response's, see `ServerStats::to_json`.
+pub const STATS_JSON_TYPE_ID: u32 = 21;
+
+/// Builds an `Op::Stats` request asking for the stats as JSON, see `STATS_JSON_TYPE_ID`. The
+/// payload holds a single zero byte, as one without data is sent without its `type_id`.
+pub fn json_request() -> Message {
+    message::request(Op::Stats, vec![], Some(message::payload(STATS_JSON_TYPE_ID, vec![0])))
+}
+
+/// The layout of `ServerStats::encode`, bumped when it changes. It is the `version` of
+/// `ServerStats::to_json` too.
+static STATS_VERSION: u8 = 10;
+
+/// Everything `Op::Stats` reports: the counters kept by `StatService`, and the cache's own stats
+/// when the service it wraps reports them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerStats {
+    /// How long the server has been up, in seconds, see `Stats::uptime`.
+    pub uptime: u64,
+    pub requests: StatsSnapshot,
+    pub cache: Option<CacheStats>,
+    /// The longest keys and values the cache takes, as its `Op::Stats` response advertises them.
+    pub limits: Limits,
+}
+
+impl ServerStats {
+    /// Packs the stats, all integers big endian: a version byte, then a byte that is 1 if the
+    /// cache's stats follow, as the number of keys as a u32, the evictions and bytes used as
+    /// u64s, and the number of hot keys as a u32 followed by each one's count as a u64, its
+    /// length as a u32 and the key. Then the `StatsSnapshot` counters as u64s, in the order
+    /// `total_requests`, `total_request_time`, `hits`, `misses`, `bytes_in`, `bytes_out`,
+    /// `worker_panics` and `connection_errors`, followed by the `uptime` and `connections` as
+    /// u64s, a byte that is 1 if the `replication_lag` follows as a u64, and a byte that is 1 if
+    /// the `warmup` follows, as the entries loaded and failed as u64s and a byte that is 1 once
+    /// done, and the `negative_hits` as a u64. Then a byte that is 1 if the `max_key_len` of the
+    /// `limits` follows as a u64, and the same for the `max_value_len`. Then the number of ops
+    /// counted as a u32, followed by each op's code as a u8 and its count as a u64, and the
+    /// number of latency buckets as a u32, followed by each bucket's count as a u64; see
+    /// `LATENCY_BUCKETS` for their bounds. Version 1 didn't have the replication lag, versions
+    /// before 3 didn't have the hot keys, versions before 4 the uptime and connections, versions
+    /// before 5 the warmup, versions before 7 the negative hits, versions before 9 the entries
+    /// the warmup failed to load, and versions before 10 the limits.
+    /// Versions 6 and 7 ended with the connected clients, which only `Op::ClientList` lists
+    /// since, as it is for admins alone.
+    pub fn encode(&self) -> Vec<u8> {
+        let requests = &self.requests;
+        let mut data = vec![];
+        data.put_u8(STATS_VERSION);
+        match self.cache {
+            Some(ref cache) => {
+                data.put_u8(1);
+                data.put_u32::<BigEndian>(cache.keys);
+                data.put_u64::<BigEndian>(cache.evictions);
+                data.put_u64::<BigEndian>(cache.used_bytes);
+                data.put_u32::<BigEndian>(cache.hot_keys.len() as u32);
+                for &(ref key, count) in &cache.hot_keys {
+                    data.put_u64::<BigEndian>(count);
+                    data.put_u32::<BigEndian>(key.len() as u32);
+                    data.put_slice(key);
+                }
+            }
+            None => data.put_u8(0),
+        }
+        let counters = [
+            requests.total_requests,
+            requests.total_request_time,
+            requests.hits,
+            requests.misses,
+            requests.bytes_in,
+            requests.bytes_out,
+            requests.worker_panics,
+            requests.connection_errors,
+        ];
+        for &counter in &counters {
+            data.put_u64::<BigEndian>(counter as u64);
+        }
+        data.put_u64::<BigEndian>(self.uptime);
+        data.put_u64::<BigEndian>(requests.connections as u64);
+        match requests.replication_lag {
+            Some(lag) => {
+                data.put_u8(1);
+                data.put_u64::<BigEndian>(lag as u64);
+            }
+            None => data.put_u8(0),
+        }
+        match requests.warmup {
+            Some(warmup) => {
+                data.put_u8(1);
+                data.put_u64::<BigEndian>(warmup.loaded as u64);
+                data.put_u64::<BigEndian>(warmup.failed as u64);
+                data.put_u8(warmup.done as u8);
+            }
+            None => data.put_u8(0),
+        }
+        data.put_u64::<BigEndian>(requests.negative_hits as u64);
+        for &limit in &[self.limits.max_key_len, self.limits.max_value_len] {
+            match limit {
+                Some(max) => {
+                    data.put_u8(1);
+                    data.put_u64::<BigEndian>(max as u64);
+                }
+                None => data.put_u8(0),
+            }
+        }
+        data.put_u32::<BigEndian>(requests.requests_by_op.len() as u32);
+        for (&op, &count) in &requests.requests_by_op {
+            data.put_u8(op as u8);
+            data.put_u64::<BigEndian>(count as u64);
+        }
+        data.put_u32::<BigEndian>(requests.latency_buckets.len() as u32);
+        for &count in &requests.latency_buckets {
+            data.put_u64::<BigEndian>(count as u64);
+        }
+        data
+    }
+
+    /// Unpacks stats packed by `encode`. Counts of ops this version doesn't know are dropped.
+    pub fn decode(data: &[u8]) -> Result<Self, error::Error> {
+        let truncated = || error::Error::new(error::ErrorKind::InvalidData, "truncated stats");
+        let mut cursor = io::Cursor::new(data);
+        if cursor.remaining() < 2 {
+            return Err(truncated());
+        }
+        let version = cursor.get_u8();
+        if version < 1 || version > STATS_VERSION {
+            return Err(error::Error::new(error::ErrorKind::InvalidData, "unknown stats version"));
+        }
+        let cache = if cursor.get_u8() == 1 {
+            if cursor.remaining() < 4 + 8 + 8 {
+                return Err(truncated());
+            }
+            let mut cache = CacheStats {
+                keys: cursor.get_u32::<BigEndian>(),
+                evictions: cursor.get_u64::<BigEndian>(),
+                used_bytes: cursor.get_u64::<BigEndian>(),
+                hot_keys: vec![],
+            };
+            if version > 2 {
+                if cursor.remaining() < 4 {
+                    return Err(truncated());
+                }
+                for _ in 0..cursor.get_u32::<BigEndian>() {
+                    if cursor.remaining() < 8 + 4 {
+                        return Err(truncated());
+                    }
+                    let count = cursor.get_u64::<BigEndian>();
+                    let len = cursor.get_u32::<BigEndian>() as usize;
+                    if cursor.remaining() < len {
+                        return Err(truncated());
+                    }
+                    let mut key = vec![0; len];
+                    cursor.copy_to_slice(&mut key);
+                    cache.hot_keys.push((key, count));
+                }
+            }
+            Some(cache)
         } else {
-            0
+            None
+        };
+
+        if cursor.remaining() < 8 * 8 + 1 + 4 {
+            return Err(truncated());
+        }
+        let mut requests = StatsSnapshot::default();
+        requests.total_requests = cursor.get_u64::<BigEndian>() as usize;
+        requests.total_request_time = cursor.get_u64::<BigEndian>() as usize;
+        requests.hits = cursor.get_u64::<BigEndian>() as usize;
+        requests.misses = cursor.get_u64::<BigEndian>() as usize;
+        requests.bytes_in = cursor.get_u64::<BigEndian>() as usize;
+        requests.bytes_out = cursor.get_u64::<BigEndian>() as usize;
+        requests.worker_panics = cursor.get_u64::<BigEndian>() as usize;
+        requests.connection_errors = cursor.get_u64::<BigEndian>() as usize;
+        let mut uptime = 0;
+        if version > 3 {
+            if cursor.remaining() < 8 * 2 + 1 + 4 {
+                return Err(truncated());
+            }
+            uptime = cursor.get_u64::<BigEndian>();
+            requests.connections = cursor.get_u64::<BigEndian>() as usize;
+        }
+        if version > 1 && cursor.get_u8() == 1 {
+            if cursor.remaining() < 8 + 4 {
+                return Err(truncated());
+            }
+            requests.replication_lag = Some(cursor.get_u64::<BigEndian>() as usize);
+        }
+        if version > 4 {
+            if cursor.remaining() < 1 + 4 {
+                return Err(truncated());
+            }
+            if cursor.get_u8() == 1 {
+                let counts = if version > 8 { 2 } else { 1 };
+                if cursor.remaining() < 8 * counts + 1 + 4 {
+                    return Err(truncated());
+                }
+                let loaded = cursor.get_u64::<BigEndian>() as usize;
+                let failed = if version > 8 { cursor.get_u64::<BigEndian>() as usize } else { 0 };
+                requests.warmup = Some(Warmup {
+                    loaded: loaded,
+                    failed: failed,
+                    done: cursor.get_u8() == 1,
+                });
+            }
+        }
+        if version > 6 {
+            if cursor.remaining() < 8 + 4 {
+                return Err(truncated());
+            }
+            requests.negative_hits = cursor.get_u64::<BigEndian>() as usize;
+        }
+        let mut limits = Limits::default();
+        if version > 9 {
+            for limit in &mut [&mut limits.max_key_len, &mut limits.max_value_len] {
+                if cursor.remaining() < 1 + 4 {
+                    return Err(truncated());
+                }
+                if cursor.get_u8() == 1 {
+                    if cursor.remaining() < 8 + 4 {
+                        return Err(truncated());
+                    }
+                    **limit = Some(cursor.get_u64::<BigEndian>() as usize);
+                }
+            }
+        }
+
+        let ops = cursor.get_u32::<BigEndian>() as usize;
+        if cursor.remaining() / (1 + 8) < ops {
+            return Err(truncated());
+        }
+        for _ in 0..ops {
+            let op = Op::try_from(cursor.get_u8());
+            let count = cursor.get_u64::<BigEndian>() as usize;
+            if let Ok(op) = op {
+                requests.requests_by_op.insert(op, count);
+            }
+        }
+
+        if cursor.remaining() < 4 {
+            return Err(truncated());
+        }
+        let buckets = cursor.get_u32::<BigEndian>() as usize;
+        // The clients of versions 6 and 7 are skipped.
+        let listed_clients = version == 6 || version == 7;
+        if cursor.remaining() < buckets * 8
+            || !listed_clients && cursor.remaining() != buckets * 8
+        {
+            return Err(truncated());
+        }
+        requests.latency_buckets =
+            (0..buckets).map(|_| cursor.get_u64::<BigEndian>() as usize).collect();
+
+        Ok(ServerStats {
+            uptime: uptime,
+            requests: requests,
+            cache: cache,
+            limits: limits,
+        })
+    }
+
+    /// The stats as a JSON object, for clients that would rather not unpack `encode`'s layout.
+    /// It has the `version` of the layout and the `uptime` in seconds, a `cache` object with the
+    /// cache's `keys`, `evictions`, `used_bytes` and `hot_keys`, a list of `[key, count]` pairs
+    /// with the keys lossily decoded as UTF-8, or null without the cache's stats, and a
+    /// `requests` object with the `StatsSnapshot` counters by name, the `hit_ratio` and
+    /// `avg_request_time`, `requests_by_op` as an object keyed by op name, `latency_buckets` as
+    /// a list of `[bound, count]` pairs, the last bound null, and `warmup` as an object with the
+    /// entries `loaded` and `failed` and whether it is `done`, or null. The `limits` are an
+    /// object with the `max_key_len` and `max_value_len`, each null if unlimited.
+    pub fn to_json(&self) -> Vec<u8> {
+        let requests = &self.requests;
+        let cache = self.cache.as_ref().map(|cache| {
+            let hot_keys: Vec<_> = cache
+                .hot_keys
+                .iter()
+                .map(|&(ref key, count)| json!([String::from_utf8_lossy(key), count]))
+                .collect();
+            json!({
+                "keys": cache.keys,
+                "evictions": cache.evictions,
+                "used_bytes": cache.used_bytes,
+                "hot_keys": hot_keys,
+            })
+        });
+        let by_op: serde_json::Map<String, serde_json::Value> = requests
+            .requests_by_op
+            .iter()
+            .map(|(op, &count)| (op.to_string(), json!(count)))
+            .collect();
+        let buckets: Vec<_> = requests
+            .latency_buckets
+            .iter()
+            .enumerate()
+            .map(|(i, &count)| json!([LATENCY_BUCKETS.get(i), count]))
+            .collect();
+        let stats = json!({
+            "version": STATS_VERSION,
+            "uptime": self.uptime,
+            "cache": cache,
+            "limits": {
+                "max_key_len": self.limits.max_key_len,
+                "max_value_len": self.limits.max_value_len,
+            },
+            "requests": {
+                "total_requests": requests.total_requests,
+                "total_request_time": requests.total_request_time,
+                "avg_request_time": requests.avg_request_time(),
+                "requests_by_op": by_op,
+                "latency_buckets": buckets,
+                "hits": requests.hits,
+                "misses": requests.misses,
+                "hit_ratio": requests.hit_ratio(),
+                "negative_hits": requests.negative_hits,
+                "bytes_in": requests.bytes_in,
+                "bytes_out": requests.bytes_out,
+                "worker_panics": requests.worker_panics,
+                "connection_errors": requests.connection_errors,
+                "connections": requests.connections,
+                "replication_lag": requests.replication_lag,
+                "warmup": requests.warmup.map(|warmup| json!({
+                    "loaded": warmup.loaded,
+                    "failed": warmup.failed,
+                    "done": warmup.done,
+                })),
+            },
+        });
+        stats.to_string().into_bytes()
+    }
+}
+
+impl fmt::Display for ServerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref cache) = self.cache {
+            write!(
+                f,
+                "keys: {}, evictions: {}, used_bytes: {}, ",
+                cache.keys,
+                cache.evictions,
+                cache.used_bytes
+            )?;
+            if !cache.hot_keys.is_empty() {
+                let hot_keys: Vec<String> = cache
+                    .hot_keys
+                    .iter()
+                    .map(|&(ref key, count)| format!("{}={}", String::from_utf8_lossy(key), count))
+                    .collect();
+                write!(f, "hot_keys: [{}], ", hot_keys.join(" "))?;
+            }
+        }
+        write!(f, "uptime: {} s, {}", self.uptime, self.requests)?;
+        if let Some(max) = self.limits.max_key_len {
+            write!(f, ", max_key_len: {}", max)?;
+        }
+        if let Some(max) = self.limits.max_value_len {
+            write!(f, ", max_value_len: {}", max)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_buckets_and_hits() {
+        let stats = Stats::default();
+        stats.record_request(Op::Get, Code::Hit, 100);
+        stats.record_request(Op::Get, Code::Miss, 101);
+        stats.record_request(Op::Del, Code::Miss, 1_000_000);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.latency_buckets[0], 1);
+        assert_eq!(snapshot.latency_buckets[1], 1);
+        assert_eq!(snapshot.latency_buckets[LATENCY_BUCKETS.len()], 1);
+        assert_eq!(snapshot.latency_buckets.iter().sum::<usize>(), 3);
+        // A Del of a missing key isn't a read.
+        assert_eq!((snapshot.hits, snapshot.misses), (1, 1));
+    }
+
+    #[test]
+    fn test_negative_hits() {
+        let stats = Stats::default();
+        stats.record_request(Op::Get, Code::Hit, 100);
+        stats.record_request(Op::Get, Code::Miss, 100);
+        stats.record_request(Op::Get, Code::NotFound, 100);
+        stats.record_request(Op::Get, Code::NotFound, 100);
+
+        // Negative hits are counted apart, and left out of the hit ratio.
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.hits, snapshot.misses), (1, 1));
+        assert_eq!(snapshot.negative_hits, 2);
+        assert_eq!(snapshot.hit_ratio(), 0.5);
+        assert_eq!(snapshot.latency_buckets.iter().sum::<usize>(), 4);
+    }
+
+    #[test]
+    fn test_server_stats_encoding() {
+        let stats = Stats::default();
+        stats.record_request(Op::Get, Code::Hit, 100);
+        stats.record_request(Op::Get, Code::NotFound, 100);
+        stats.record_request(Op::Set, Code::Ok, 3000);
+        stats.record_transfer(20, 30);
+        stats.incr_connection_errors();
+
+        let cached = ServerStats {
+            uptime: 60,
+            requests: stats.snapshot(),
+            cache: Some(CacheStats {
+                keys: 3,
+                evictions: 1,
+                used_bytes: 42,
+                hot_keys: vec![(b"foo".to_vec(), 30), (b"bar".to_vec(), 20)],
+            }),
+            limits: Limits {
+                max_key_len: Some(250),
+                max_value_len: None,
+            },
+        };
+        assert_eq!(ServerStats::decode(&cached.encode()).unwrap(), cached);
+        assert!(cached.to_string().contains("hot_keys: [foo=30 bar=20], "));
+        assert!(cached.to_string().ends_with(", max_key_len: 250"));
+
+        stats.set_replication_lag(250);
+        stats.set_warmup(Warmup {
+            loaded: 10,
+            failed: 1,
+            done: false,
+        });
+        let uncached = ServerStats {
+            uptime: 0,
+            requests: stats.snapshot(),
+            cache: None,
+            limits: Limits::default(),
         };
+        let data = uncached.encode();
+        assert_eq!(ServerStats::decode(&data).unwrap(), uncached);
+        assert!(ServerStats::decode(&data[..data.len() - 1]).is_err());
+        assert!(uncached.to_string().ends_with(
+            "replication_lag: 250 ms, warmup: 10 entries, 1 failed (in progress)"
+        ));
+    }
+
+    #[test]
+    fn test_server_stats_json() {
+        let stats = Stats::default();
+        stats.record_request(Op::Get, Code::Hit, 100);
+        stats.record_request(Op::Get, Code::Miss, 200);
+        stats.record_request(Op::Get, Code::Hit, 300);
+        stats.record_request(Op::Get, Code::Hit, 1_000_000);
+        stats.connection_opened();
+        let server_stats = ServerStats {
+            uptime: 60,
+            requests: stats.snapshot(),
+            cache: Some(CacheStats {
+                keys: 3,
+                evictions: 1,
+                used_bytes: 42,
+                hot_keys: vec![(b"foo".to_vec(), 30)],
+            }),
+            limits: Limits {
+                max_key_len: None,
+                max_value_len: Some(1024),
+            },
+        };
+
+        let json: serde_json::Value = serde_json::from_slice(&server_stats.to_json()).unwrap();
+        assert_eq!(json["version"], json!(STATS_VERSION));
+        assert_eq!(json["uptime"], json!(60));
+        assert_eq!(json["cache"]["used_bytes"], json!(42));
+        assert_eq!(json["cache"]["hot_keys"], json!([["foo", 30]]));
+        let limits = json!({"max_key_len": null, "max_value_len": 1024});
+        assert_eq!(json["limits"], limits);
+        let requests = &json["requests"];
+        assert_eq!(requests["requests_by_op"], json!({"Get": 4}));
+        assert_eq!(requests["hit_ratio"], json!(0.75));
+        assert_eq!(requests["negative_hits"], json!(0));
+        assert_eq!(requests["connections"], json!(1));
+        assert_eq!(requests["replication_lag"], json!(null));
+        assert_eq!(requests["warmup"], json!(null));
+        assert_eq!(requests["latency_buckets"][0], json!([100, 1]));
+        assert_eq!(requests["latency_buckets"][LATENCY_BUCKETS.len()], json!([null, 1]));
+        assert_eq!(json["clients"], json!(null));
 
-        format!(
-            "total_requests: {}, total_request_time: {} μs, avg_request_time: {} μs",
-            total_requests,
-            total_requests_time,
-            avg_request_time
-        )
+        stats.connection_closed();
+        assert_eq!(stats.snapshot().connections, 0);
     }
 }
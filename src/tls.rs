@@ -0,0 +1,46 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use native_tls::{self, Certificate, Pkcs12, TlsAcceptor, TlsConnector};
+
+/// Builds a `TlsAcceptor` for `ServeOptions::tls` from the PKCS #12 archive at `path`, which
+/// holds the server's private key and certificate chain, encrypted with `password`.
+pub fn acceptor<P: AsRef<Path>>(path: P, password: &str) -> io::Result<TlsAcceptor> {
+    let pkcs12 = Pkcs12::from_der(&read(path)?, password).map_err(tls_error)?;
+    TlsAcceptor::builder(pkcs12).and_then(|builder| builder.build()).map_err(tls_error)
+}
+
+/// Builds a `TlsConnector` for `Client::connect_tls`, trusting the PEM encoded certificate at
+/// `ca` as well as the system's roots, which is how a client comes to trust a server with a
+/// self-signed certificate.
+pub fn connector<P: AsRef<Path>>(ca: Option<P>) -> io::Result<TlsConnector> {
+    let mut builder = TlsConnector::builder().map_err(tls_error)?;
+    if let Some(ca) = ca {
+        let cert = Certificate::from_pem(&read(ca)?).map_err(tls_error)?;
+        builder.add_root_certificate(cert).map_err(tls_error)?;
+    }
+    builder.build().map_err(tls_error)
+}
+
+fn tls_error(e: native_tls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_files() {
+        let missing = "/nonexistent/rcache.p12";
+        let not_found = Some(io::ErrorKind::NotFound);
+        assert_eq!(acceptor(missing, "").err().map(|e| e.kind()), not_found);
+        assert_eq!(connector(Some(missing)).err().map(|e| e.kind()), not_found);
+    }
+}
@@ -0,0 +1,127 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use bincode;
+use serde_json;
+use std::io;
+
+use message::{self, Payload};
+
+/// The lowest `type_id` a `TypedPayload` may use. Those below are left to rcache, which gives
+/// some of them a meaning, such as `message::TYPE_I64` and `message::KEY_LIST_TYPE_ID`.
+pub const MIN_TYPE_ID: u32 = 256;
+
+/// How a `TypedPayload` is encoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Compact, but only readable by Rust clients that know the type.
+    Bincode,
+    /// UTF-8 JSON, readable by any client.
+    Json,
+}
+
+/// A type stored in the cache as a payload with its own `type_id`, through `encode` and
+/// `decode`, or `Client::set_typed` and `Client::get_typed`. Implementing the trait registers
+/// the type:
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize)]
+/// struct Session { user: String, expires: u64 }
+///
+/// impl TypedPayload for Session {
+///     const TYPE_ID: u32 = 300;
+/// }
+/// ```
+///
+/// The `type_id` is what tells the types apart in the cache, so it must be unique among the
+/// types sharing a cache, and stay the same for as long as values of the type are stored.
+pub trait TypedPayload: Serialize + DeserializeOwned {
+    /// At least `MIN_TYPE_ID`.
+    const TYPE_ID: u32;
+    const FORMAT: Format = Format::Bincode;
+}
+
+/// Encodes `value` as a payload of its type's `TYPE_ID`.
+pub fn encode<T: TypedPayload>(value: &T) -> io::Result<Payload> {
+    if T::TYPE_ID < MIN_TYPE_ID {
+        return Err(invalid("type ids below MIN_TYPE_ID are reserved"));
+    }
+    let data = match T::FORMAT {
+        Format::Bincode => bincode::serialize(value).map_err(|e| invalid(&e.to_string()))?,
+        Format::Json => serde_json::to_vec(value).map_err(|e| invalid(&e.to_string()))?,
+    };
+    Ok(message::payload(T::TYPE_ID, data))
+}
+
+/// Decodes a payload written by `encode`. Fails if the payload holds another type.
+pub fn decode<T: TypedPayload>(payload: &Payload) -> io::Result<T> {
+    if payload.type_id() != T::TYPE_ID {
+        return Err(invalid(&format!(
+            "expected a payload of type {}, got {}",
+            T::TYPE_ID,
+            payload.type_id()
+        )));
+    }
+    match T::FORMAT {
+        Format::Bincode => {
+            bincode::deserialize(payload.data()).map_err(|e| invalid(&e.to_string()))
+        }
+        Format::Json => serde_json::from_slice(payload.data()).map_err(|e| invalid(&e.to_string())),
+    }
+}
+
+fn invalid(description: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Session {
+        user: String,
+        expires: u64,
+    }
+
+    impl TypedPayload for Session {
+        const TYPE_ID: u32 = 300;
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Point(i32, i32);
+
+    impl TypedPayload for Point {
+        const TYPE_ID: u32 = 301;
+        const FORMAT: Format = Format::Json;
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Reserved;
+
+    impl TypedPayload for Reserved {
+        const TYPE_ID: u32 = 1;
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let session = Session {
+            user: "alice".to_owned(),
+            expires: 1000,
+        };
+        let payload = encode(&session).unwrap();
+        assert_eq!(payload.type_id(), 300);
+        assert_eq!(decode::<Session>(&payload).unwrap(), session);
+
+        let payload = encode(&Point(1, -2)).unwrap();
+        assert_eq!(payload.data(), b"[1,-2]");
+        assert_eq!(decode::<Point>(&payload).unwrap(), Point(1, -2));
+    }
+
+    #[test]
+    fn test_errors() {
+        let kind = |payload: &Payload| decode::<Session>(payload).err().map(|e| e.kind());
+        assert_eq!(kind(&encode(&Point(1, 2)).unwrap()), Some(io::ErrorKind::InvalidData));
+        assert_eq!(kind(&message::payload(300, vec![0xFF])), Some(io::ErrorKind::InvalidData));
+        assert!(encode(&Reserved).is_err());
+    }
+}
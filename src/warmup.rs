@@ -0,0 +1,303 @@
+use futures::{stream, Future, Stream};
+use futures::sync::oneshot;
+use tokio_core::reactor::Core;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+use time::Timespec;
+
+use cache::{self, Cache};
+use client::Client;
+use message::{self, Code, Op, Payload};
+use stats::{Stats, Warmup};
+
+/// The keys a warmup lists from a peer at once, see `Source::Peer`.
+static PAGE_LEN: u32 = 1000;
+
+/// The Gets a warmup has in flight to a peer at once.
+static GETS_IN_FLIGHT: usize = 64;
+
+/// How many times a warmup retries storing an entry the cache is too busy for, see
+/// `cache::Options::max_queued`, before giving up.
+static BUSY_RETRIES: u32 = 10;
+
+/// How long a warmup waits before retrying an entry the cache was too busy for the first time.
+/// It waits twice as long before each retry after that.
+static BUSY_BACKOFF_MS: u64 = 10;
+
+/// Where `warm_up` loads entries from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// The snapshot saved by `Cache::save_to` at this path.
+    Snapshot(PathBuf),
+    /// The keys starting with a prefix, in the default namespace of the server at an address.
+    Peer(SocketAddr, Vec<u8>),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Snapshot(ref path) => write!(f, "{}", path.display()),
+            Source::Peer(addr, ref prefix) if prefix.is_empty() => write!(f, "{}", addr),
+            Source::Peer(addr, ref prefix) => {
+                write!(f, "{} ({}*)", addr, String::from_utf8_lossy(prefix))
+            }
+        }
+    }
+}
+
+/// Loads the entries from `source` into `cache` on a thread of its own, so that a freshly
+/// started server already has them when clients ask, rather than taking a storm of misses. The
+/// server can serve meanwhile: entries are stored with `Op::Add`, so that writes made while
+/// warming up win over what is loaded. Expired entries are skipped, and the others keep when
+/// they expire. How far the warmup got goes in `stats`, see `StatsSnapshot::warmup`, including
+/// the entries the cache refused, which are logged.
+///
+/// The warmup stops early if `cache` is dropped, or stays too busy to store an entry after
+/// `BUSY_RETRIES` retries. Entries loaded before a failure stay.
+pub fn warm_up(
+    cache: &Arc<Cache>,
+    source: Source,
+    stats: Option<Arc<Stats>>,
+) -> io::Result<thread::JoinHandle<()>> {
+    let mut loader = Loader {
+        cache: Arc::downgrade(cache),
+        stats: stats,
+        now: cache.clock().now(),
+        loaded: 0,
+        failed: 0,
+    };
+    thread::Builder::new().name("rcache-warmup".to_owned()).spawn(move || {
+        loader.report(false);
+        let warmed = match source {
+            Source::Snapshot(ref path) => {
+                cache::read_snapshot(path).and_then(|entries| {
+                    for (key, payload, expires_at) in entries {
+                        loader.load(key, payload, expires_at)?;
+                    }
+                    Ok(())
+                })
+            }
+            Source::Peer(addr, ref prefix) => from_peer(addr, prefix, &mut loader),
+        };
+        match warmed {
+            Ok(()) => info!("Warmed up with {} entries from {}.", loader.loaded, source),
+            Err(e) => warn!("Failed to warm up from {}: {}.", source, e),
+        }
+        loader.report(true);
+    })
+}
+
+/// Stores the entries a warmup loads, counting them.
+struct Loader {
+    cache: Weak<Cache>,
+    stats: Option<Arc<Stats>>,
+    /// When the warmup started, to skip the entries expired by then.
+    now: Timespec,
+    loaded: usize,
+    /// The entries the cache refused, other than those written meanwhile.
+    failed: usize,
+}
+
+impl Loader {
+    fn load(
+        &mut self,
+        key: Vec<u8>,
+        payload: Payload,
+        expires_at: Option<Timespec>,
+    ) -> io::Result<()> {
+        let mut req = cache::stored_request(Op::Add, &key, Some(payload));
+        if let Some(expires_at) = expires_at {
+            if expires_at <= self.now {
+                return Ok(());
+            }
+            let at = message::encode_u64(expires_at.sec as u64);
+            req = req.with_extension(message::EXT_EXPIRES_AT, at);
+        }
+        let mut backoff = Duration::from_millis(BUSY_BACKOFF_MS);
+        let mut retries = 0;
+        let resp = loop {
+            let cache = self.cache.upgrade().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, "the cache was dropped")
+            })?;
+            let (snd, rcv) = oneshot::channel();
+            cache.process(req.clone(), snd);
+            let resp = rcv.wait().map_err(|_| {
+                io::Error::new(io::ErrorKind::Other, "cache worker stopped")
+            })?;
+            if resp.code() != Code::Busy {
+                break resp;
+            }
+            if retries == BUSY_RETRIES {
+                return Err(io::Error::new(io::ErrorKind::Other, "the cache stayed busy"));
+            }
+            drop(cache);
+            thread::sleep(backoff);
+            backoff *= 2;
+            retries += 1;
+        };
+        match resp.code() {
+            Code::Ok => self.loaded += 1,
+            // Written since the warmup started, which wins.
+            Code::Conflict => return Ok(()),
+            code => {
+                let reason = resp.payload().map(|p| String::from_utf8_lossy(p.data()).into_owned());
+                warn!(
+                    "Failed to warm up {:?}: {} {}.",
+                    String::from_utf8_lossy(&key),
+                    code,
+                    reason.unwrap_or_default()
+                );
+                self.failed += 1;
+            }
+        }
+        self.report(false);
+        Ok(())
+    }
+
+    fn report(&self, done: bool) {
+        if let Some(ref stats) = self.stats {
+            stats.set_warmup(Warmup {
+                loaded: self.loaded,
+                failed: self.failed,
+                done: done,
+            });
+        }
+    }
+}
+
+/// Loads the keys starting with `prefix` from the server at `addr`, listing them a page at a
+/// time with `Op::Scan` and fetching each with a Get, which carries when it expires.
+fn from_peer(addr: SocketAddr, prefix: &[u8], loader: &mut Loader) -> io::Result<()> {
+    let mut core = Core::new()?;
+    let client = core.run(Client::connect(&addr, &core.handle()))?;
+    // Keys with `*` or `?` in the prefix match more than they should, so they are checked again.
+    let mut pattern = prefix.to_vec();
+    pattern.push(b'*');
+    let mut after = None;
+    loop {
+        let resp = core.run(client.scan_matching(after.take(), PAGE_LEN, &pattern))?;
+        let keys = match (resp.code(), resp.payload()) {
+            (Code::Ok, Some(payload)) => message::decode_keys(payload.data())?,
+            (code, _) => {
+                let unexpected = format!("unexpected response to scan: {}", code);
+                return Err(io::Error::new(io::ErrorKind::Other, unexpected));
+            }
+        };
+        after = match keys.last() {
+            Some(last) => Some(last.clone()),
+            None => return Ok(()),
+        };
+        let gets = stream::iter_ok(keys.into_iter().filter(|key| key.starts_with(prefix)))
+            .map(|key| client.get(key.clone()).map(|resp| (key, resp)))
+            .buffered(GETS_IN_FLIGHT)
+            .collect();
+        for (key, resp) in core.run(gets)? {
+            let expires_at = match resp.extension(message::EXT_EXPIRES_AT) {
+                Some(at) => Some(Timespec::new(message::decode_u64(at)? as i64, 0)),
+                None => None,
+            };
+            if let (Code::Hit, Some(payload)) = (resp.code(), resp.payload()) {
+                loader.load(key, payload.clone(), expires_at)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::Message;
+    use service::{self, CacheService, ServeOptions};
+
+    fn call(cache: &Cache, req: Message) -> Message {
+        let (snd, rcv) = oneshot::channel();
+        cache.process(req, snd);
+        rcv.wait().unwrap()
+    }
+
+    fn set(cache: &Cache, key: &str, value: &str) {
+        let req = message::request(Op::Set, key.into(), Some(message::payload(1, value.into())));
+        assert_eq!(call(cache, req).code(), Code::Ok);
+    }
+
+    fn get(cache: &Cache, key: &str) -> Option<Vec<u8>> {
+        let resp = call(cache, message::request(Op::Get, key.into(), None));
+        resp.payload().map(|p| p.data().to_vec())
+    }
+
+    #[test]
+    fn test_warm_up_from_peer() {
+        let peer = Arc::new(Cache::new(100).unwrap());
+        set(&peer, "user:1", "a");
+        set(&peer, "user:2", "b");
+        set(&peer, "session:1", "c");
+        let req = message::request(Op::Set, "user:3".into(), Some(message::payload(1, "d".into())))
+            .with_extension(message::EXT_TTL, message::encode_u64(60));
+        call(&peer, req);
+        let cache = peer.clone();
+        let server = service::serve_on_thread(
+            "127.0.0.1:0".parse().unwrap(),
+            ServeOptions::default(),
+            move || Ok(CacheService { cache: cache.clone() }),
+        ).unwrap();
+
+        // What was written before the warmup wins.
+        let cache = Arc::new(Cache::new(100).unwrap());
+        set(&cache, "user:2", "new");
+        let stats = Arc::new(Stats::default());
+        let source = Source::Peer(server.local_addr(), "user:".into());
+        warm_up(&cache, source, Some(stats.clone())).unwrap().join().unwrap();
+
+        assert_eq!(get(&cache, "user:1"), Some("a".into()));
+        assert_eq!(get(&cache, "user:2"), Some("new".into()));
+        assert_eq!(get(&cache, "session:1"), None);
+        let resp = call(&cache, message::request(Op::Get, "user:3".into(), None));
+        assert!(resp.extension(message::EXT_EXPIRES_AT).is_some());
+        let warmup = Warmup {
+            loaded: 2,
+            failed: 0,
+            done: true,
+        };
+        assert_eq!(stats.snapshot().warmup, Some(warmup));
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_warm_up_from_snapshot() {
+        let name = format!("rcache-warmup-{}.snap", ::std::process::id());
+        let path = ::std::env::temp_dir().join(name);
+        let saved = Cache::new(10).unwrap();
+        set(&saved, "a", "1");
+        set(&saved, "b", "2");
+        saved.save_to(&path).unwrap();
+
+        let cache = Arc::new(Cache::new(10).unwrap());
+        warm_up(&cache, Source::Snapshot(path.clone()), None).unwrap().join().unwrap();
+        assert_eq!(get(&cache, "a"), Some("1".into()));
+        assert_eq!(get(&cache, "b"), Some("2".into()));
+
+        // Entries the cache refuses are counted, and the others still loaded.
+        let options = cache::Options {
+            prefix_quotas: vec![(b"a".to_vec(), 0)],
+            ..cache::Options::default()
+        };
+        let cache = Arc::new(Cache::with_options(10, options).unwrap());
+        let stats = Arc::new(Stats::default());
+        let source = Source::Snapshot(path.clone());
+        warm_up(&cache, source, Some(stats.clone())).unwrap().join().unwrap();
+        assert_eq!(get(&cache, "a"), None);
+        assert_eq!(get(&cache, "b"), Some("2".into()));
+        let warmup = Warmup {
+            loaded: 1,
+            failed: 1,
+            done: true,
+        };
+        assert_eq!(stats.snapshot().warmup, Some(warmup));
+        let _ = ::std::fs::remove_file(&path);
+    }
+}